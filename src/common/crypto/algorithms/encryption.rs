@@ -135,6 +135,9 @@ pub enum EccSchemeAlgorithm {
     /// ECDH: Elliptic Curve Diffie-Hellman for key agreement.
     EcDh(EccCurves),
     /// ECDAA: Elliptic Curve Direct Anonymous Attestation.
+    ///
+    /// The DAA join and signing flow is not implemented, so the Linux TPM provider rejects
+    /// keys with this scheme.
     EcDaa(EccCurves),
     /// SM2: A Chinese cryptographic standard for digital signatures and key exchange.
     Sm2(EccCurves),
@@ -40,6 +40,11 @@ pub enum SecurityModuleError {
     ///
     /// This variant contains a descriptive error message.
     InitializationError(String),
+    /// Error that occurs when a PIN, PUK or key is rejected by the security module.
+    ///
+    /// This variant contains a descriptive error message and, if known, the number of
+    /// attempts left before the credential is blocked.
+    AuthenticationFailed { reason: String, retries: Option<u8> },
 }
 
 impl fmt::Display for SecurityModuleError {
@@ -72,6 +77,22 @@ impl fmt::Display for SecurityModuleError {
             SecurityModuleError::InitializationError(ref error_msg) => {
                 write!(f, "Initialization error: {}", error_msg)
             }
+            SecurityModuleError::AuthenticationFailed {
+                ref reason,
+                retries: Some(retries),
+            } => {
+                write!(
+                    f,
+                    "Authentication failed: {} ({} retries left)",
+                    reason, retries
+                )
+            }
+            SecurityModuleError::AuthenticationFailed {
+                ref reason,
+                retries: None,
+            } => {
+                write!(f, "Authentication failed: {}", reason)
+            }
         }
     }
 }
@@ -96,6 +117,7 @@ impl std::error::Error for SecurityModuleError {
             SecurityModuleError::EncryptionError(_) => None,
             SecurityModuleError::SignatureVerificationError(_) => None,
             SecurityModuleError::InitializationError(_) => None,
+            SecurityModuleError::AuthenticationFailed { .. } => None,
         }
     }
 }
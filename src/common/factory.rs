@@ -1,4 +1,7 @@
-use super::traits::{log_config::LogConfig, module_provider::Provider};
+use super::{
+    error::SecurityModuleError,
+    traits::{log_config::LogConfig, module_provider::Provider},
+};
 #[cfg(feature = "hsm")]
 use crate::hsm::core::instance::{HsmInstance, HsmType};
 #[cfg(feature = "tpm")]
@@ -30,14 +33,24 @@ pub enum SecurityModule {
 ///
 /// This implementation allows for easy instantiation of `SecurityModule` variants
 /// from string identifiers, facilitating user or configuration-based module selection.
-impl From<&str> for SecurityModule {
-    fn from(item: &str) -> Self {
-        match item {
-            #[cfg(feature = "tpm")]
-            "TPM" => SecurityModule::Tpm(TpmType::default()),
+/// An HSM type is selected with `HSM:<type>`, e.g. `HSM:YubiKey` or `HSM:YubiKey:12345678`.
+impl TryFrom<&str> for SecurityModule {
+    type Error = SecurityModuleError;
+
+    fn try_from(item: &str) -> Result<Self, Self::Error> {
+        match item.split_once(':') {
             #[cfg(feature = "hsm")]
-            "HSM" => SecurityModule::Hsm(HsmType::default()),
-            _ => panic!("Unsupported Security Module type"),
+            Some(("HSM", hsm_type)) => HsmType::try_from(hsm_type).map(SecurityModule::Hsm),
+            _ => match item {
+                #[cfg(feature = "tpm")]
+                "TPM" => Ok(SecurityModule::Tpm(TpmType::default())),
+                #[cfg(feature = "hsm")]
+                "HSM" => Ok(SecurityModule::Hsm(HsmType::default())),
+                _ => Err(SecurityModuleError::InitializationError(format!(
+                    "Unsupported Security Module type: {}",
+                    item
+                ))),
+            },
         }
     }
 }
@@ -62,7 +75,9 @@ impl SecModules {
     /// Retrieves or creates an instance of a security module based on the provided key and type.
     ///
     /// If an instance for the given module and key does not exist, it is created and stored.
-    /// Otherwise, the existing instance is returned.
+    /// Otherwise, the existing instance is returned. `HsmType::YubiKey(None)` is resolved to
+    /// the serial number of the attached YubiKey first, so that it shares the instance of
+    /// that device.
     ///
     /// # Arguments
     ///
@@ -71,13 +86,14 @@ impl SecModules {
     ///
     /// # Returns
     ///
-    /// An `Option` containing an `Arc<Mutex<dyn Provider>>` to the requested module instance,
-    /// or `None` if the module type is not supported or an error occurs during instance creation.
+    /// A `Result` containing an `Arc<Mutex<dyn Provider>>` to the requested module instance,
+    /// or a `SecurityModuleError` if the module type is not supported or the instance can not
+    /// be created.
     pub fn get_instance(
         key_id: String,
         module: SecurityModule,
         log: Option<Box<dyn LogConfig>>,
-    ) -> Option<Arc<Mutex<dyn Provider>>> {
+    ) -> Result<Arc<Mutex<dyn Provider>>, SecurityModuleError> {
         // Initialize logging once
         if !*LOGGING_INITIALIZED.lock().unwrap() {
             if let Some(log_inst) = log {
@@ -87,13 +103,15 @@ impl SecModules {
         }
 
         // Check if requested instance is in cache. If not, create a new instance
+        let module = SecModule::resolve(module)?;
         let mut instances = INSTANCES.lock().unwrap();
-        if !instances.contains_key(&module) {
-            let instance = SecModule::create_instance(key_id, &module);
-            instances.insert(module.clone(), instance?);
+        if let Some(instance) = instances.get(&module) {
+            return Ok(instance.clone());
         }
+        let instance = SecModule::create_instance(key_id, &module)?;
+        instances.insert(module, instance.clone());
 
-        instances.get(&module).cloned()
+        Ok(instance)
     }
 }
 
@@ -122,17 +140,28 @@ impl SecModule {
     /// # Returns
     ///
     /// An `Arc<Mutex<dyn Provider>>` representing the created module instance,
-    /// or `None` if the module type is not supported or an error occurs during instance creation.
+    /// or a `SecurityModuleError` if the module type is not supported by this build.
     fn create_instance(
         key_id: String,
         module: &SecurityModule,
-    ) -> Option<Arc<Mutex<dyn Provider>>> {
+    ) -> Result<Arc<Mutex<dyn Provider>>, SecurityModuleError> {
         match module {
             #[cfg(feature = "hsm")]
-            SecurityModule::Hsm(hsm_type) => Some(HsmInstance::create_instance(key_id, hsm_type)),
+            SecurityModule::Hsm(hsm_type) => HsmInstance::create_instance(key_id, hsm_type),
             #[cfg(feature = "tpm")]
-            SecurityModule::Tpm(tpm_type) => Some(TpmInstance::create_instance(key_id, tpm_type)),
+            SecurityModule::Tpm(tpm_type) => Ok(TpmInstance::create_instance(key_id, tpm_type)),
             _ => unimplemented!(),
         }
     }
+
+    /// Resolves a module type that selects its device implicitly to the device in use, so
+    /// that both refer to the same cached instance.
+    fn resolve(module: SecurityModule) -> Result<SecurityModule, SecurityModuleError> {
+        match module {
+            #[cfg(feature = "hsm")]
+            SecurityModule::Hsm(hsm_type) => hsm_type.resolve().map(SecurityModule::Hsm),
+            #[allow(unreachable_patterns)]
+            module => Ok(module),
+        }
+    }
 }
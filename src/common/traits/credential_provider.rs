@@ -0,0 +1,307 @@
+use crate::common::error::SecurityModuleError;
+use std::{
+    collections::HashMap,
+    env,
+    fmt::{self, Debug},
+    io::{self, Write},
+};
+use zeroize::Zeroizing;
+
+/// Prefix of the environment variables read by [`EnvCredentials::default`].
+pub const DEFAULT_ENV_PREFIX: &str = "CRYPTO_LAYER";
+
+/// A credential a provider needs from the user to access a security module.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Credential {
+    /// The PIN of a smart card or security key.
+    Pin,
+    /// The PIN unblocking key of a smart card or security key.
+    Puk,
+    /// The key authenticating administrative operations, such as generating keys on a YubiKey.
+    ManagementKey,
+    /// The auth value of the TPM key with the given id.
+    AuthValue(String),
+}
+
+impl fmt::Display for Credential {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Credential::Pin => write!(f, "PIN"),
+            Credential::Puk => write!(f, "PUK"),
+            Credential::ManagementKey => write!(f, "management key"),
+            Credential::AuthValue(key_id) => write!(f, "auth value of key '{}'", key_id),
+        }
+    }
+}
+
+/// A credential value, which is wiped from memory when dropped and kept out of debug output.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Secret(Zeroizing<Vec<u8>>);
+
+impl Secret {
+    /// Returns the raw bytes of the secret.
+    pub fn expose(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl From<Vec<u8>> for Secret {
+    fn from(value: Vec<u8>) -> Self {
+        Self(Zeroizing::new(value))
+    }
+}
+
+impl From<&[u8]> for Secret {
+    fn from(value: &[u8]) -> Self {
+        value.to_vec().into()
+    }
+}
+
+impl From<&str> for Secret {
+    fn from(value: &str) -> Self {
+        value.as_bytes().into()
+    }
+}
+
+impl Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Secret(..)")
+    }
+}
+
+/// Supplies PINs, management keys and auth values to providers, and asks the user to
+/// confirm their presence.
+///
+/// Providers never fall back to default credentials. If a credential they need is not
+/// available, the operation fails.
+pub trait CredentialProvider: Send + Sync + Debug {
+    /// Returns the requested credential.
+    ///
+    /// # Arguments
+    ///
+    /// * `credential` - The credential the provider needs.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the credential, or `None` if it is not available. On failure,
+    /// it returns a `SecurityModuleError`.
+    fn credential(&self, credential: &Credential) -> Result<Option<Secret>, SecurityModuleError>;
+
+    /// Asks the user to confirm their presence before an operation that requires it, e.g. by
+    /// touching the device.
+    ///
+    /// # Arguments
+    ///
+    /// * `prompt` - A message describing the operation.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` that, on success, contains `Ok(())`. If the user did not confirm, it returns
+    /// a `SecurityModuleError` and the operation is cancelled.
+    fn confirm_presence(&self, prompt: &str) -> Result<(), SecurityModuleError>;
+
+    /// Whether the provider can ask the user to confirm their presence at all. Providers that
+    /// cannot are not asked before operations where the device itself waits for a touch.
+    fn can_confirm_presence(&self) -> bool {
+        true
+    }
+}
+
+/// Reads credentials from environment variables.
+///
+/// With the prefix `CRYPTO_LAYER`, the variables are `CRYPTO_LAYER_PIN`, `CRYPTO_LAYER_PUK`,
+/// `CRYPTO_LAYER_MANAGEMENT_KEY` (hex encoded) and `CRYPTO_LAYER_AUTH_<KEY_ID>`. In the key
+/// id, lower-case ASCII letters are upper-cased, digits are kept and every other byte is
+/// written as `_` followed by its two digit hex value, e.g. `my-key` becomes `MY_2DKEY`. User
+/// presence cannot be confirmed, so devices requiring a touch wait for it without a prompt.
+#[derive(Debug, Clone)]
+pub struct EnvCredentials {
+    prefix: String,
+}
+
+impl EnvCredentials {
+    /// Creates a provider reading the variables starting with `prefix`.
+    pub fn new(prefix: &str) -> Self {
+        Self {
+            prefix: prefix.to_string(),
+        }
+    }
+
+    /// Returns the name of the variable holding `credential`.
+    pub fn variable(&self, credential: &Credential) -> String {
+        match credential {
+            Credential::Pin => format!("{}_PIN", self.prefix),
+            Credential::Puk => format!("{}_PUK", self.prefix),
+            Credential::ManagementKey => format!("{}_MANAGEMENT_KEY", self.prefix),
+            Credential::AuthValue(key_id) => {
+                let key_id: String = key_id
+                    .bytes()
+                    .map(|byte| match byte {
+                        b'a'..=b'z' | b'0'..=b'9' => {
+                            (byte.to_ascii_uppercase() as char).to_string()
+                        }
+                        _ => format!("_{:02X}", byte),
+                    })
+                    .collect();
+                format!("{}_AUTH_{}", self.prefix, key_id)
+            }
+        }
+    }
+}
+
+impl Default for EnvCredentials {
+    fn default() -> Self {
+        Self::new(DEFAULT_ENV_PREFIX)
+    }
+}
+
+impl CredentialProvider for EnvCredentials {
+    fn credential(&self, credential: &Credential) -> Result<Option<Secret>, SecurityModuleError> {
+        let variable = self.variable(credential);
+        match env::var(&variable) {
+            Ok(value) => decode(credential, Zeroizing::new(value)).map(Some),
+            Err(env::VarError::NotPresent) => Ok(None),
+            Err(e) => Err(SecurityModuleError::InitializationError(format!(
+                "Failed to read {}: {}",
+                variable, e
+            ))),
+        }
+    }
+
+    fn confirm_presence(&self, prompt: &str) -> Result<(), SecurityModuleError> {
+        Err(presence_denied(prompt))
+    }
+
+    fn can_confirm_presence(&self) -> bool {
+        false
+    }
+}
+
+/// Returns fixed credentials, intended for tests and provisioning scripts.
+#[derive(Debug, Clone)]
+pub struct StaticCredentials {
+    credentials: HashMap<Credential, Secret>,
+    presence: bool,
+}
+
+impl StaticCredentials {
+    /// Creates a provider without any credentials that confirms user presence.
+    pub fn new() -> Self {
+        Self {
+            credentials: HashMap::new(),
+            presence: true,
+        }
+    }
+
+    /// Adds a credential, replacing an earlier value.
+    pub fn with(mut self, credential: Credential, value: impl Into<Secret>) -> Self {
+        self.credentials.insert(credential, value.into());
+        self
+    }
+
+    /// Sets whether user presence is confirmed.
+    pub fn with_presence(mut self, presence: bool) -> Self {
+        self.presence = presence;
+        self
+    }
+}
+
+impl Default for StaticCredentials {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CredentialProvider for StaticCredentials {
+    fn credential(&self, credential: &Credential) -> Result<Option<Secret>, SecurityModuleError> {
+        Ok(self.credentials.get(credential).cloned())
+    }
+
+    fn confirm_presence(&self, prompt: &str) -> Result<(), SecurityModuleError> {
+        if self.presence {
+            Ok(())
+        } else {
+            Err(presence_denied(prompt))
+        }
+    }
+}
+
+/// Prompts for credentials on the terminal.
+///
+/// Prompts are written to stderr. Credentials are read from the terminal with echo turned off,
+/// confirmations from stdin. Management keys are entered hex encoded. An empty line means the
+/// credential is not available, while the end of input fails a confirmation.
+#[derive(Debug, Clone, Default)]
+pub struct TerminalPrompt;
+
+impl TerminalPrompt {
+    /// Writes `prompt` and reads a line without its line ending. Hidden input is read from the
+    /// terminal without echo, other input from stdin, where the end of input fails.
+    fn read_line(&self, prompt: &str, hidden: bool) -> io::Result<Zeroizing<String>> {
+        let mut stderr = io::stderr();
+        write!(stderr, "{}", prompt)?;
+        stderr.flush()?;
+
+        if hidden {
+            return rpassword::read_password().map(Zeroizing::new);
+        }
+        let mut line = Zeroizing::new(String::new());
+        if io::stdin().read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+
+        let len = line.trim_end_matches(['\r', '\n']).len();
+        line.truncate(len);
+        Ok(line)
+    }
+}
+
+impl CredentialProvider for TerminalPrompt {
+    fn credential(&self, credential: &Credential) -> Result<Option<Secret>, SecurityModuleError> {
+        let line = self
+            .read_line(&format!("Enter {}: ", credential), true)
+            .map_err(|e| {
+                SecurityModuleError::InitializationError(format!(
+                    "Failed to read {}: {}",
+                    credential, e
+                ))
+            })?;
+        if line.is_empty() {
+            return Ok(None);
+        }
+        decode(credential, line).map(Some)
+    }
+
+    /// Asks the user to confirm with "y" or "yes". Any other answer, or the end of input,
+    /// denies presence.
+    fn confirm_presence(&self, prompt: &str) -> Result<(), SecurityModuleError> {
+        let answer = self
+            .read_line(&format!("{} [y/N]: ", prompt), false)
+            .map_err(|_| presence_denied(prompt))?;
+        match answer.trim().to_ascii_lowercase().as_str() {
+            "y" | "yes" => Ok(()),
+            _ => Err(presence_denied(prompt)),
+        }
+    }
+}
+
+/// Decodes a credential entered as text. Management keys are hex encoded, all other
+/// credentials are taken as is.
+fn decode(
+    credential: &Credential,
+    value: Zeroizing<String>,
+) -> Result<Secret, SecurityModuleError> {
+    match credential {
+        Credential::ManagementKey => hex::decode(value.trim()).map(Secret::from).map_err(|e| {
+            SecurityModuleError::InitializationError(format!(
+                "The management key is not hex encoded: {}",
+                e
+            ))
+        }),
+        _ => Ok(value.as_str().into()),
+    }
+}
+
+pub(crate) fn presence_denied(prompt: &str) -> SecurityModuleError {
+    SecurityModuleError::InitializationError(format!("User presence not confirmed: {}", prompt))
+}
@@ -91,4 +91,32 @@ pub trait KeyHandle: Send + Sync + Debug {
             "Method not implemented".to_owned(),
         ))
     }
+    /// Produces evidence that the key is protected by the security module.
+    ///
+    /// The format of the evidence depends on the security module. It allows a relying party
+    /// to check that the key is hardware-bound without trusting the caller.
+    ///
+    /// # Arguments
+    /// * `nonce` - A fresh nonce chosen by the relying party, included in the evidence so that
+    ///   it can not be replayed.
+    ///
+    /// # Returns
+    /// A `Result` containing the serialized attestation on success, or a `SecurityModuleError` on failure.
+    #[tracing::instrument(skip(_nonce))]
+    fn attest_key(&self, _nonce: &[u8]) -> Result<Vec<u8>, SecurityModuleError> {
+        Err(SecurityModuleError::InitializationError(
+            "Method not implemented".to_owned(),
+        ))
+    }
+    /// Returns the certificate stored for the key, followed by the certificates of the
+    /// issuing CAs.
+    ///
+    /// # Returns
+    /// A `Result` containing the DER encoded certificates on success, or a `SecurityModuleError` on failure.
+    #[tracing::instrument]
+    fn certificate_chain(&self) -> Result<Vec<Vec<u8>>, SecurityModuleError> {
+        Err(SecurityModuleError::InitializationError(
+            "Method not implemented".to_owned(),
+        ))
+    }
 }
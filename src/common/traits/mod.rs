@@ -1,3 +1,4 @@
+pub mod credential_provider;
 pub mod key_handle;
 pub mod module_provider;
 pub mod module_provider_config;
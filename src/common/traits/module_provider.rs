@@ -1,6 +1,6 @@
-use super::key_handle::KeyHandle;
+use super::{credential_provider::CredentialProvider, key_handle::KeyHandle};
 use crate::common::error::SecurityModuleError;
-use std::{any::Any, fmt::Debug};
+use std::{any::Any, fmt::Debug, sync::Arc};
 
 /// Defines the interface for a security module provider.
 ///
@@ -55,4 +55,16 @@ pub trait Provider: Send + Sync + KeyHandle + Debug {
     /// A `Result` that, on success, contains `Ok(())`, indicating that the module was initialized successfully.
     /// On failure, it returns a `SecurityModuleError`.
     fn initialize_module(&mut self) -> Result<(), SecurityModuleError>;
+
+    /// Sets the source of the PINs, management keys and auth values the provider needs.
+    ///
+    /// Should be called before `initialize_module`. Providers that do not need credentials
+    /// ignore it.
+    ///
+    /// # Arguments
+    ///
+    /// * `credentials` - The `CredentialProvider` to ask for credentials and user presence.
+    fn set_credential_provider(&mut self, credentials: Arc<dyn CredentialProvider>) {
+        let _ = credentials;
+    }
 }
@@ -0,0 +1,137 @@
+use super::provider::ProviderFFI;
+use crate::common::{
+    error::SecurityModuleError,
+    traits::credential_provider::{presence_denied, Credential, CredentialProvider, Secret},
+};
+use std::{
+    ffi::{c_void, CString},
+    fmt,
+    os::raw::c_char,
+    ptr,
+    sync::Arc,
+};
+use zeroize::Zeroizing;
+
+/// Credential kinds passed to a [`CredentialCallback`].
+pub const CREDENTIAL_PIN: u32 = 0;
+pub const CREDENTIAL_PUK: u32 = 1;
+pub const CREDENTIAL_MANAGEMENT_KEY: u32 = 2;
+pub const CREDENTIAL_AUTH_VALUE: u32 = 3;
+
+/// Size of the buffer a [`CredentialCallback`] writes the credential to.
+const CREDENTIAL_CAPACITY: usize = 256;
+
+/// Asks the application for a credential.
+///
+/// `key_id` is the id of the key for `CREDENTIAL_AUTH_VALUE` and null otherwise. The callback
+/// writes the credential to `output`, which holds `output_capacity` bytes, and returns its
+/// length, `0` if it is not available, or a negative value to cancel the operation.
+pub type CredentialCallback = unsafe extern "C" fn(
+    kind: u32,
+    key_id: *const c_char,
+    output: *mut u8,
+    output_capacity: usize,
+    user_data: *mut c_void,
+) -> isize;
+
+/// Asks the user to confirm their presence, returning `0` if they did.
+pub type PresenceCallback =
+    unsafe extern "C" fn(prompt: *const c_char, user_data: *mut c_void) -> i32;
+
+/// A [`CredentialProvider`] calling back into the application.
+pub struct CallbackCredentials {
+    credential: CredentialCallback,
+    presence: Option<PresenceCallback>,
+    user_data: *mut c_void,
+}
+
+// The application guarantees that the callbacks and `user_data` can be used from any thread.
+unsafe impl Send for CallbackCredentials {}
+unsafe impl Sync for CallbackCredentials {}
+
+impl fmt::Debug for CallbackCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CallbackCredentials")
+            .field("presence", &self.presence.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl CredentialProvider for CallbackCredentials {
+    fn credential(&self, credential: &Credential) -> Result<Option<Secret>, SecurityModuleError> {
+        let (kind, key_id) = match credential {
+            Credential::Pin => (CREDENTIAL_PIN, None),
+            Credential::Puk => (CREDENTIAL_PUK, None),
+            Credential::ManagementKey => (CREDENTIAL_MANAGEMENT_KEY, None),
+            Credential::AuthValue(key_id) => (
+                CREDENTIAL_AUTH_VALUE,
+                Some(
+                    CString::new(key_id.as_str())
+                        .map_err(|e| SecurityModuleError::InitializationError(e.to_string()))?,
+                ),
+            ),
+        };
+
+        let mut output = Zeroizing::new(vec![0; CREDENTIAL_CAPACITY]);
+        let len = unsafe {
+            (self.credential)(
+                kind,
+                key_id
+                    .as_ref()
+                    .map_or(ptr::null(), |key_id| key_id.as_ptr()),
+                output.as_mut_ptr(),
+                CREDENTIAL_CAPACITY,
+                self.user_data,
+            )
+        };
+        match usize::try_from(len) {
+            Ok(0) => Ok(None),
+            Ok(len) if len <= CREDENTIAL_CAPACITY => Ok(Some(output[..len].into())),
+            _ => Err(SecurityModuleError::InitializationError(format!(
+                "The application did not provide the {}",
+                credential
+            ))),
+        }
+    }
+
+    fn confirm_presence(&self, prompt: &str) -> Result<(), SecurityModuleError> {
+        let Some(presence) = self.presence else {
+            return Err(presence_denied(prompt));
+        };
+        let c_prompt = CString::new(prompt).map_err(|_| presence_denied(prompt))?;
+        match unsafe { presence(c_prompt.as_ptr(), self.user_data) } {
+            0 => Ok(()),
+            _ => Err(presence_denied(prompt)),
+        }
+    }
+
+    fn can_confirm_presence(&self) -> bool {
+        self.presence.is_some()
+    }
+}
+
+/// Sets callbacks the provider asks for PINs, management keys, auth values and user presence.
+/// Without `presence`, the user is not prompted before operations the device waits for a
+/// touch for. Should be called before `initialize_module`.
+/// # Safety
+/// Assumes `provider_ffi` is a valid pointer. The callbacks must be safe to call from any
+/// thread with `user_data` for as long as the provider is alive.
+#[no_mangle]
+pub unsafe extern "C" fn provider_set_credential_callback(
+    provider_ffi: *mut ProviderFFI,
+    credential: Option<CredentialCallback>,
+    presence: Option<PresenceCallback>,
+    user_data: *mut c_void,
+) -> i32 {
+    let (Some(credential), false) = (credential, provider_ffi.is_null()) else {
+        return -1; // Return error if any pointer is null
+    };
+
+    let provider = &mut *provider_ffi;
+    (*provider.provider).set_credential_provider(Arc::new(CallbackCredentials {
+        credential,
+        presence,
+        user_data,
+    }));
+    0
+}
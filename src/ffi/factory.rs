@@ -40,11 +40,14 @@ pub unsafe extern "C" fn secmodules_get_instance(
         Err(_) => return ptr::null_mut(),
     };
 
-    let module = SecurityModule::from(module_type_str);
+    let module = match SecurityModule::try_from(module_type_str) {
+        Ok(module) => module,
+        Err(_) => return ptr::null_mut(),
+    };
   
     match SecModules::get_instance(key_id_str.to_string(), module, None) {
-        Some(provider) => ProviderFFI::new(provider),
-        None => ptr::null_mut(),
+        Ok(provider) => ProviderFFI::new(provider),
+        Err(_) => ptr::null_mut(),
     }
 }
 
@@ -1,2 +1,3 @@
+mod credentials;
 pub mod factory;
 mod provider;
@@ -1,4 +1,6 @@
-use crate::common::traits::module_provider::Provider;
+use crate::common::{error::SecurityModuleError, traits::module_provider::Provider};
+#[cfg(feature = "yubi")]
+use crate::hsm::yubikey::YubiKeyProvider;
 use std::sync::{Arc, Mutex};
 
 /// Represents the types of HSMs supported by the HSM system.
@@ -9,7 +11,8 @@ use std::sync::{Arc, Mutex};
 ///
 /// # Variants
 ///
-/// - `YubiKey`: Represents a YubiKey HSM.
+/// - `YubiKey`: Represents a YubiKey HSM, optionally selected by its serial number. Without a
+///   serial number, exactly one YubiKey must be attached.
 /// - `NitroKey`: Represents a NitroKey HSM.
 ///
 /// # Examples
@@ -17,27 +20,56 @@ use std::sync::{Arc, Mutex};
 /// Converting from a string to a `HsmType`:
 ///
 /// ```
-/// let HSM_type: HsmType = "YubiKey".into();
-/// assert_eq!(HSM_type, HsmType::YubiKey);
+/// let HSM_type = HsmType::try_from("YubiKey").unwrap();
+/// assert_eq!(HSM_type, HsmType::YubiKey(None));
+///
+/// let HSM_type = HsmType::try_from("YubiKey:12345678").unwrap();
+/// assert_eq!(HSM_type, HsmType::YubiKey(Some(12345678)));
 /// ```
-#[repr(C)]
 #[derive(Eq, Hash, PartialEq, Default, Clone, Debug)]
 pub enum HsmType {
     #[default]
     NitroKey,
-    YubiKey,
+    /// The serial number of the YubiKey to use. Instances for different serial numbers are
+    /// independent of each other.
+    YubiKey(Option<u32>),
 }
 
-// Implement From<&str> for HsmType to convert string arguments into enum variants.
-impl From<&str> for HsmType {
+impl HsmType {
+    /// Resolves `YubiKey(None)` to the serial number of the attached YubiKey, leaving other
+    /// types as they are.
+    ///
+    /// # Returns
+    ///
+    /// The resolved `HsmType`, or a `SecurityModuleError` if no or more than one YubiKey is
+    /// attached.
+    pub fn resolve(self) -> Result<Self, SecurityModuleError> {
+        match self {
+            #[cfg(feature = "yubi")]
+            HsmType::YubiKey(None) => match YubiKeyProvider::list_devices()?.as_slice() {
+                [device] => Ok(HsmType::YubiKey(Some(device.serial.0))),
+                [] => Err(SecurityModuleError::InitializationError(
+                    "No YubiKey is attached".to_string(),
+                )),
+                _ => Err(SecurityModuleError::InitializationError(
+                    "Several YubiKeys are attached, select one by its serial number".to_string(),
+                )),
+            },
+            hsm_type => Ok(hsm_type),
+        }
+    }
+}
+
+// Implement TryFrom<&str> for HsmType to convert string arguments into enum variants.
+impl TryFrom<&str> for HsmType {
+    type Error = SecurityModuleError;
+
     /// Converts a string slice into a `HsmType` enum variant.
     ///
     /// This implementation allows for creating `HsmType` variants from string literals,
     /// facilitating easier parsing and handling of HSM types from text sources.
     ///
-    /// # Panics
-    ///
-    /// Panics if the string does not match any of the supported HSM types.
+    /// A YubiKey is selected by appending its serial number, as in `YubiKey:12345678`.
     ///
     /// # Parameters
     ///
@@ -45,12 +77,27 @@ impl From<&str> for HsmType {
     ///
     /// # Returns
     ///
-    /// A `HsmType` variant corresponding to the input string.
-    fn from(s: &str) -> Self {
-        match s {
-            "YubiKey" => HsmType::YubiKey,
-            "NitroKey" => HsmType::NitroKey,
-            _ => panic!("Unsupported HsmType"),
+    /// A `HsmType` variant corresponding to the input string, or a `SecurityModuleError` if
+    /// the string names no supported HSM type or the serial number is invalid.
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        match s.split_once(':') {
+            Some(("YubiKey", serial)) => serial
+                .parse()
+                .map(|serial| HsmType::YubiKey(Some(serial)))
+                .map_err(|_| {
+                    SecurityModuleError::InitializationError(format!(
+                        "Invalid YubiKey serial number: {}",
+                        serial
+                    ))
+                }),
+            _ => match s {
+                "YubiKey" => Ok(HsmType::YubiKey(None)),
+                "NitroKey" => Ok(HsmType::NitroKey),
+                _ => Err(SecurityModuleError::InitializationError(format!(
+                    "Unsupported HsmType: {}",
+                    s
+                ))),
+            },
         }
     }
 }
@@ -80,22 +127,39 @@ impl HsmInstance {
     /// Creates a new instance of a provider based on the specified HSM type.
     ///
     /// This method initializes an HSM instance according to the HSM type provided.
-    /// It is currently stubbed with `todo!()`, indicating that the implementation is incomplete
-    /// and needs to be provided.
+    /// NitroKeys are not supported yet.
     ///
     /// # Parameters
     ///
-    /// - `_key_id`: A `String` specifying the key identifier for the HSM instance.
+    /// - `key_id`: A `String` specifying the key identifier for the HSM instance.
     /// - `hpm_type`: A reference to a `HsmType` specifying the type of HSM for the HSM instance.
     ///
     /// # Returns
     ///
     /// An `Arc<Mutex<dyn Provider>>`, wrapping the provider for the HSM instance in a thread-safe
-    /// reference-counting pointer.
-    pub fn create_instance(_key_id: String, hpm_type: &HsmType) -> Arc<Mutex<dyn Provider>> {
+    /// reference-counting pointer, or a `SecurityModuleError` if the HSM type is not supported
+    /// by this build.
+    #[cfg_attr(not(feature = "yubi"), allow(unused_variables))]
+    pub fn create_instance(
+        key_id: String,
+        hpm_type: &HsmType,
+    ) -> Result<Arc<Mutex<dyn Provider>>, SecurityModuleError> {
         match hpm_type {
-            HsmType::YubiKey => todo!(),
-            HsmType::NitroKey => todo!(),
+            #[cfg(feature = "yubi")]
+            HsmType::YubiKey(serial) => {
+                let instance = match serial {
+                    Some(serial) => YubiKeyProvider::with_serial(key_id, (*serial).into()),
+                    None => YubiKeyProvider::new(key_id),
+                };
+                Ok(Arc::new(Mutex::new(instance)))
+            }
+            #[cfg(not(feature = "yubi"))]
+            HsmType::YubiKey(_) => Err(SecurityModuleError::InitializationError(
+                "YubiKeys require the yubi feature".to_string(),
+            )),
+            HsmType::NitroKey => Err(SecurityModuleError::InitializationError(
+                "NitroKeys are not supported yet".to_string(),
+            )),
         }
     }
 }
@@ -17,14 +17,18 @@
 /// The `HsmProviderConfig` struct defines the configuration parameters for an HSM provider. It contains:
 ///
 /// - `key_algorithm`: Specifies the asymmetric encryption algorithm supported by the HSM.
-/// - `key_usage`: Specifies the key usages supported by the HSM.
+/// - `key_usages`: Specifies the usages the key is created for, recorded with the key.
+/// - `slot`: Optionally pins the key to a PIV slot instead of the next free retired slot.
+/// - `pin_policy` and `touch_policy`: When the device requires the PIN or a touch to use the key.
+/// - `placeholder_certificate`: Whether a self-signed certificate is stored with the key.
+/// - `hash` and `rsa_padding`: The hash and RSA padding signatures are created with.
 ///
 /// ## Usage
 ///
 /// To use this module, follow these steps:
 ///
 /// 1. Import the required modules: `crypto::{algorithms::encryption::AsymmetricEncryption, KeyUsage}`.
-/// 2. Define a new configuration using `HsmProviderConfig::new`.
+/// 2. Define a new configuration using `HsmProviderConfig::new`, or set its fields directly.
 /// 3. Pass the configuration to the HSM provider for initialization.
 ///
 /// ## Example
@@ -37,15 +41,19 @@
 /// use crate::hsm::HsmProviderConfig;
 ///
 /// // Define the HSM configuration with RSA encryption and key usage for signing and encryption
-/// let config = HsmProviderConfig::new(
-///     AsymmetricEncryption::Rsa(KeyBits::Bits2048),
-///     vec![KeyUsage::SignEncrypt],
-/// );
+/// let config = Box::new(HsmProviderConfig {
+///     key_algorithm: AsymmetricEncryption::Rsa(KeyBits::Bits2048),
+///     key_usages: vec![KeyUsage::SignEncrypt],
+///     ..Default::default()
+/// });
 ///
 /// // Pass the configuration to the HSM provider for initialization
 /// let provider = initialize_hsm_provider(config);
 /// ```
-use crate::common::crypto::{algorithms::encryption::AsymmetricEncryption, KeyUsage};
+use crate::common::crypto::{
+    algorithms::{encryption::AsymmetricEncryption, hashes::Hash},
+    KeyUsage,
+};
 use crate::common::traits::module_provider_config::ProviderConfig;
 
 /// The core functionality for hardware security module (HSM) providers.
@@ -61,10 +69,95 @@ use std::any::Any;
 pub mod yubikey;
 
 /// Configuration parameters for an HSM provider.
-#[derive(Debug)]
+#[derive(Debug, Clone, Default)]
 pub struct HsmProviderConfig {
     /// The asymmetric encryption algorithm supported by the HSM.
-    pub(super) key_algorithm: AsymmetricEncryption,
+    ///
+    /// YubiKeys support RSA-1024, RSA-2048, P-256 and P-384 keys, and with firmware 5.7 or
+    /// later also RSA-3072, RSA-4096, Ed25519 and X25519 keys.
+    pub key_algorithm: AsymmetricEncryption,
+    /// The usages the key is created for.
+    pub key_usages: Vec<KeyUsage>,
+    /// The slot to create the key in. Without one, the key is created in the first retired
+    /// slot not holding a key.
+    pub slot: Option<PivSlot>,
+    /// Whether an existing key may be replaced: a key with the same id created by this
+    /// provider, or a key in `slot` that was not created by it, e.g. one enrolled with PIV
+    /// tools. Keys created by this provider are never replaced by a key with a different id.
+    pub replace_existing_key: bool,
+    /// When the PIN has to be verified to use the key.
+    pub pin_policy: PinPolicy,
+    /// When the device has to be touched to use the key.
+    pub touch_policy: TouchPolicy,
+    /// Whether to store a self-signed certificate for the key, so that PIV tools and smart
+    /// card drivers recognize the slot as populated until a certificate is issued. Not
+    /// supported for the algorithms added by firmware 5.7, nor with an AES management key.
+    pub placeholder_certificate: bool,
+    /// The hash the data is signed with, SHA-256 if not set. YubiKeys support SHA-256,
+    /// SHA-384 and SHA-512.
+    pub hash: Option<Hash>,
+    /// The padding of RSA signatures.
+    pub rsa_padding: RsaSignaturePadding,
+}
+
+/// A PIV key slot.
+///
+/// The standard slots have a purpose operating systems and PIV tools rely on, and are only
+/// used when a key is pinned to them. The retired slots are meant for additional keys, such
+/// as old key management keys, and are used for keys without a slot.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PivSlot {
+    /// Slot 9a, authenticating the card holder, e.g. for smart card login or SSH.
+    Authentication,
+    /// Slot 9c, signing documents, e-mails and code. Requires the PIN for every signature.
+    Signature,
+    /// Slot 9d, decrypting e-mails and files for the card holder.
+    KeyManagement,
+    /// Slot 9e, authenticating the card itself, e.g. for physical access. Never requires the PIN.
+    CardAuthentication,
+    /// Retired slot 1 to 20, stored in slot 82 to 95.
+    Retired(u8),
+}
+
+/// When the PIN has to be verified before a key can be used. Set when the key is created and
+/// enforced by the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum PinPolicy {
+    /// The default of the slot, `Always` for the signature slot, `Never` for the card
+    /// authentication slot and `Once` otherwise.
+    #[default]
+    Default,
+    /// The key can be used without the PIN.
+    Never,
+    /// The PIN has to be verified once per session.
+    Once,
+    /// The PIN has to be verified before every operation.
+    Always,
+}
+
+/// When the device has to be touched before a key can be used. Set when the key is created
+/// and enforced by the device.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum TouchPolicy {
+    /// The default of the device, `Never`.
+    #[default]
+    Default,
+    /// The key can be used without a touch.
+    Never,
+    /// The device has to be touched for every operation.
+    Always,
+    /// A touch is valid for 15 seconds, covering several operations.
+    Cached,
+}
+
+/// The padding of RSA signatures. The device signs the padded digest with the raw key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum RsaSignaturePadding {
+    /// RSASSA-PKCS1-v1_5, deterministic and understood by all verifiers.
+    #[default]
+    Pkcs1v15,
+    /// RSASSA-PSS with MGF1 of the signature hash and a salt as long as the digest.
+    Pss,
 }
 
 impl ProviderConfig for HsmProviderConfig {
@@ -80,7 +173,6 @@ impl HsmProviderConfig {
     /// # Arguments
     ///
     /// - `key_algorithm`: The asymmetric encryption algorithm supported by the HSM.
-    /// - `key_usage`: The key usages supported by the HSM.
     ///
     /// # Returns
     ///
@@ -89,6 +181,7 @@ impl HsmProviderConfig {
     pub fn new(key_algorithm: AsymmetricEncryption) -> Box<dyn Any> {
         Box::new(Self {
             key_algorithm,
+            ..Default::default()
         })
     }
 }
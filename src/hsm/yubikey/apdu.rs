@@ -0,0 +1,398 @@
+//! Raw PIV commands for the key algorithms the `yubikey` crate does not support yet.
+//!
+//! Firmware 5.7 added RSA-3072, RSA-4096, Ed25519 and X25519 keys. They are generated and
+//! used with the same GENERATE ASYMMETRIC KEY PAIR and GENERAL AUTHENTICATE commands as the
+//! other algorithms, see
+//! https://docs.yubico.com/yesdk/users-manual/application-piv/apdu/generate-pair.html and
+//! https://docs.yubico.com/yesdk/users-manual/application-piv/apdu/auth-sign.html
+//!
+//! Firmware 5.7 also ships with an AES-192 management key, which the `yubikey` crate can not
+//! authenticate with either. The commands that require the management key are sent raw as well
+//! on such devices.
+
+use super::metadata::PivAlgorithm;
+use ::yubikey::{piv::SlotId, Error, PinPolicy, Result, TouchPolicy};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey, EcPoint},
+    error::ErrorStack,
+    nid::Nid,
+    pkey::{Id, PKey, Public},
+    rand::rand_bytes,
+    rsa::Rsa,
+    symm::{Cipher, Crypter, Mode},
+};
+
+/// The AID of the PIV application.
+const PIV_AID: [u8; 5] = [0xa0, 0x00, 0x00, 0x03, 0x08];
+
+const INS_VERIFY: u8 = 0x20;
+const INS_GENERATE: u8 = 0x47;
+const INS_GENERAL_AUTHENTICATE: u8 = 0x87;
+const INS_SELECT: u8 = 0xa4;
+const INS_GET_RESPONSE: u8 = 0xc0;
+const INS_PUT_DATA: u8 = 0xdb;
+const INS_GET_METADATA: u8 = 0xf7;
+const INS_SET_MANAGEMENT_KEY: u8 = 0xff;
+
+/// The largest command data sent without command chaining.
+const MAX_CHUNK: usize = 255;
+
+/// The reference of the PIV application PIN.
+const PIN_REFERENCE: u8 = 0x80;
+/// The reference of the management key.
+const MANAGEMENT_KEY_REFERENCE: u8 = 0x9b;
+/// The algorithm ids of management keys.
+pub(crate) const TDES: u8 = 0x03;
+pub(crate) const AES128: u8 = 0x08;
+pub(crate) const AES192: u8 = 0x0a;
+pub(crate) const AES256: u8 = 0x0c;
+
+/// Sends command APDUs to a card and returns the response, including the status word.
+pub(crate) trait Transport {
+    fn transmit(&mut self, command: &[u8]) -> Result<Vec<u8>>;
+}
+
+impl Transport for pcsc::Transaction<'_> {
+    fn transmit(&mut self, command: &[u8]) -> Result<Vec<u8>> {
+        let mut response = [0; pcsc::MAX_BUFFER_SIZE_EXTENDED];
+        pcsc::Card::transmit(self, command, &mut response)
+            .map(<[u8]>::to_vec)
+            .map_err(|e| Error::PcscError { inner: Some(e) })
+    }
+}
+
+/// Sends a command, chaining data that does not fit into one APDU, and collects a response
+/// the card returns in several parts.
+pub(crate) fn command(
+    card: &mut dyn Transport,
+    ins: u8,
+    p1: u8,
+    p2: u8,
+    data: &[u8],
+) -> Result<Vec<u8>> {
+    let mut chunks = data.chunks(MAX_CHUNK).peekable();
+    let mut response = if data.is_empty() {
+        card.transmit(&[0x00, ins, p1, p2])?
+    } else {
+        loop {
+            let chunk = chunks.next().unwrap();
+            let last = chunks.peek().is_none();
+            let mut apdu = vec![
+                if last { 0x00 } else { 0x10 },
+                ins,
+                p1,
+                p2,
+                chunk.len() as u8,
+            ];
+            apdu.extend_from_slice(chunk);
+            let response = card.transmit(&apdu)?;
+            if last {
+                break response;
+            }
+            status(&response)?;
+        }
+    };
+
+    let mut data = Vec::new();
+    loop {
+        let (body, sw) = split(&response)?;
+        data.extend_from_slice(body);
+        match sw {
+            [0x61, _] => response = card.transmit(&[0x00, INS_GET_RESPONSE, 0x00, 0x00, 0x00])?,
+            _ => {
+                check(sw)?;
+                return Ok(data);
+            }
+        }
+    }
+}
+
+/// Selects the PIV application.
+pub(crate) fn select(card: &mut dyn Transport) -> Result<()> {
+    command(card, INS_SELECT, 0x04, 0x00, &PIV_AID).map(|_| ())
+}
+
+/// Verifies the PIN, padded with `0xff` to 8 bytes.
+pub(crate) fn verify_pin(card: &mut dyn Transport, pin: &[u8]) -> Result<()> {
+    if pin.len() > 8 {
+        return Err(Error::SizeError);
+    }
+    let mut padded = [0xff; 8];
+    padded[..pin.len()].copy_from_slice(pin);
+    command(card, INS_VERIFY, 0x00, PIN_REFERENCE, &padded).map(|_| ())
+}
+
+/// Returns the algorithm of the management key, read from its metadata. Firmware before 5.3
+/// reports no metadata, and only supports 3DES management keys.
+pub(crate) fn management_key_algorithm(card: &mut dyn Transport) -> Result<u8> {
+    match command(card, INS_GET_METADATA, 0x00, MANAGEMENT_KEY_REFERENCE, &[]) {
+        Ok(metadata) => find(&metadata, &[0x01])
+            .and_then(|algorithm| algorithm.first().copied())
+            .ok_or(Error::ParseError),
+        Err(Error::NotSupported) => Ok(TDES),
+        Err(e) => Err(e),
+    }
+}
+
+/// Authenticates with a 3DES or AES management key, whichever type the device holds.
+///
+/// The card encrypts a random witness, which is returned decrypted along with a challenge
+/// for the card, and the card proves it knows the key by encrypting the challenge.
+pub(crate) fn authenticate(card: &mut dyn Transport, key: &[u8]) -> Result<()> {
+    let algorithm = management_key_algorithm(card)?;
+    let cipher = management_key_cipher(algorithm)?;
+    if key.len() != cipher.key_len() {
+        return Err(Error::AuthenticationError);
+    }
+
+    let response = command(
+        card,
+        INS_GENERAL_AUTHENTICATE,
+        algorithm,
+        MANAGEMENT_KEY_REFERENCE,
+        &tlv(&[0x7c], &tlv(&[0x80], &[])),
+    )?;
+    let witness = find(&response, &[0x7c])
+        .and_then(|template| find(template, &[0x80]))
+        .ok_or(Error::AuthenticationError)?;
+    let witness = ecb(cipher, Mode::Decrypt, key, witness)?;
+
+    let mut challenge = vec![0; cipher.block_size()];
+    rand_bytes(&mut challenge).map_err(openssl_error)?;
+    let mut template = tlv(&[0x80], &witness);
+    template.extend(tlv(&[0x81], &challenge));
+    let response = command(
+        card,
+        INS_GENERAL_AUTHENTICATE,
+        algorithm,
+        MANAGEMENT_KEY_REFERENCE,
+        &tlv(&[0x7c], &template),
+    )?;
+    let proof = find(&response, &[0x7c])
+        .and_then(|template| find(template, &[0x82]))
+        .ok_or(Error::AuthenticationError)?;
+    if proof == ecb(cipher, Mode::Encrypt, key, &challenge)? {
+        Ok(())
+    } else {
+        Err(Error::AuthenticationError)
+    }
+}
+
+/// Replaces the management key with a 3DES key. Requires authentication with the current one.
+pub(crate) fn set_management_key(
+    card: &mut dyn Transport,
+    key: &[u8; 24],
+    require_touch: bool,
+) -> Result<()> {
+    let mut data = vec![TDES, MANAGEMENT_KEY_REFERENCE, key.len() as u8];
+    data.extend_from_slice(key);
+    let p2 = if require_touch { 0xfe } else { 0xff };
+    command(card, INS_SET_MANAGEMENT_KEY, 0xff, p2, &data).map(|_| ())
+}
+
+/// Stores a data object, deleting it if `data` is empty. Requires authentication with the
+/// management key.
+pub(crate) fn put_data(card: &mut dyn Transport, object_id: u32, data: &[u8]) -> Result<()> {
+    let mut template = tlv(&[0x5c], &object_id.to_be_bytes()[1..]);
+    template.extend(tlv(&[0x53], data));
+    command(card, INS_PUT_DATA, 0x3f, 0xff, &template).map(|_| ())
+}
+
+/// Returns the id of the certificate object of `slot`.
+pub(crate) fn certificate_object(slot: SlotId) -> Result<u32> {
+    match slot {
+        SlotId::Authentication => Ok(0x005f_c105),
+        SlotId::Signature => Ok(0x005f_c10a),
+        SlotId::KeyManagement => Ok(0x005f_c10b),
+        SlotId::CardAuthentication => Ok(0x005f_c101),
+        SlotId::Retired(retired) => Ok(0x005f_c10d + (u8::from(retired) - 0x82) as u32),
+        SlotId::Attestation => Ok(0x005f_ff01),
+        SlotId::Management(_) => Err(Error::InvalidObject),
+    }
+}
+
+/// Encodes a certificate object like `Certificate::write` with `CertInfo::Uncompressed`.
+pub(crate) fn encode_certificate(certificate: &[u8]) -> Vec<u8> {
+    let mut data = tlv(&[0x70], certificate);
+    data.extend_from_slice(&[0x71, 0x01, 0x00, 0xfe, 0x00]);
+    data
+}
+
+/// Generates a key in `slot` and returns its DER encoded `SubjectPublicKeyInfo`.
+pub(crate) fn generate(
+    card: &mut dyn Transport,
+    slot: SlotId,
+    algorithm: PivAlgorithm,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+) -> Result<Vec<u8>> {
+    let mut template = tlv(&[0x80], &[algorithm.into()]);
+    if pin_policy != PinPolicy::Default {
+        template.extend(tlv(&[0xaa], &[pin_policy.into()]));
+    }
+    if touch_policy != TouchPolicy::Default {
+        template.extend(tlv(&[0xab], &[touch_policy.into()]));
+    }
+    let response = command(
+        card,
+        INS_GENERATE,
+        0x00,
+        slot.into(),
+        &tlv(&[0xac], &template),
+    )?;
+    let public_key = find(&response, &[0x7f, 0x49]).ok_or(Error::ParseError)?;
+    public_key_info(algorithm, public_key)
+}
+
+/// Applies the private key of `slot` to `input` with GENERAL AUTHENTICATE.
+///
+/// RSA keys apply the raw private key operation and Ed25519 keys sign the message. X25519
+/// keys derive the shared secret with the public key given as `input`.
+pub(crate) fn general_authenticate(
+    card: &mut dyn Transport,
+    algorithm: PivAlgorithm,
+    slot: SlotId,
+    input: &[u8],
+) -> Result<Vec<u8>> {
+    let tag = match algorithm {
+        PivAlgorithm::X25519 | PivAlgorithm::EccP256 | PivAlgorithm::EccP384 => 0x85,
+        _ => 0x81,
+    };
+    let mut template = tlv(&[0x82], &[]);
+    template.extend(tlv(&[tag], input));
+    let response = command(
+        card,
+        INS_GENERAL_AUTHENTICATE,
+        algorithm.into(),
+        slot.into(),
+        &tlv(&[0x7c], &template),
+    )?;
+    find(&response, &[0x7c])
+        .and_then(|template| find(template, &[0x82]))
+        .map(<[u8]>::to_vec)
+        .ok_or(Error::ParseError)
+}
+
+/// Builds the `SubjectPublicKeyInfo` of the public key template of a generated key.
+fn public_key_info(algorithm: PivAlgorithm, template: &[u8]) -> Result<Vec<u8>> {
+    let public_key = if algorithm.is_rsa() {
+        let modulus = find(template, &[0x81]).ok_or(Error::ParseError)?;
+        let exponent = find(template, &[0x82]).ok_or(Error::ParseError)?;
+        BigNum::from_slice(modulus)
+            .and_then(|n| Ok((n, BigNum::from_slice(exponent)?)))
+            .and_then(|(n, e)| Rsa::from_public_components(n, e))
+            .and_then(PKey::from_rsa)
+    } else {
+        let point = find(template, &[0x86]).ok_or(Error::ParseError)?;
+        match algorithm {
+            PivAlgorithm::EccP256 => ec_public_key(Nid::X9_62_PRIME256V1, point),
+            PivAlgorithm::EccP384 => ec_public_key(Nid::SECP384R1, point),
+            PivAlgorithm::Ed25519 => PKey::public_key_from_raw_bytes(point, Id::ED25519),
+            PivAlgorithm::X25519 => PKey::public_key_from_raw_bytes(point, Id::X25519),
+            _ => return Err(Error::AlgorithmError),
+        }
+    };
+    public_key
+        .and_then(|key| key.public_key_to_der())
+        .map_err(openssl_error)
+}
+
+fn ec_public_key(curve: Nid, point: &[u8]) -> std::result::Result<PKey<Public>, ErrorStack> {
+    let group = EcGroup::from_curve_name(curve)?;
+    let mut context = BigNumContext::new()?;
+    let point = EcPoint::from_bytes(&group, point, &mut context)?;
+    EcKey::from_public_key(&group, &point).and_then(PKey::from_ec_key)
+}
+
+/// Encodes a BER-TLV data object.
+pub(crate) fn tlv(tag: &[u8], value: &[u8]) -> Vec<u8> {
+    let mut encoded = tag.to_vec();
+    match value.len() {
+        len @ 0..=0x7f => encoded.push(len as u8),
+        len @ 0x80..=0xff => encoded.extend_from_slice(&[0x81, len as u8]),
+        len => encoded.extend_from_slice(&[0x82, (len >> 8) as u8, len as u8]),
+    }
+    encoded.extend_from_slice(value);
+    encoded
+}
+
+/// Returns the value of the first data object with `tag` among the objects in `data`.
+pub(crate) fn find<'a>(mut data: &'a [u8], tag: &[u8]) -> Option<&'a [u8]> {
+    while !data.is_empty() {
+        // Tags whose low five bits are set continue in the next byte.
+        let tag_len = if data[0] & 0x1f == 0x1f { 2 } else { 1 };
+        let (len, len_len) = match *data.get(tag_len)? {
+            len @ 0..=0x7f => (len as usize, 1),
+            0x81 => (*data.get(tag_len + 1)? as usize, 2),
+            0x82 => (
+                u16::from_be_bytes([*data.get(tag_len + 1)?, *data.get(tag_len + 2)?]) as usize,
+                3,
+            ),
+            _ => return None,
+        };
+        let start = tag_len + len_len;
+        let value = data.get(start..start + len)?;
+        if &data[..tag_len] == tag {
+            return Some(value);
+        }
+        data = &data[start + len..];
+    }
+    None
+}
+
+fn split(response: &[u8]) -> Result<(&[u8], [u8; 2])> {
+    match response.len().checked_sub(2) {
+        Some(len) => Ok((&response[..len], [response[len], response[len + 1]])),
+        None => Err(Error::ParseError),
+    }
+}
+
+fn status(response: &[u8]) -> Result<()> {
+    split(response).and_then(|(_, sw)| check(sw))
+}
+
+/// Maps a status word to the error the `yubikey` crate reports for it.
+fn check(sw: [u8; 2]) -> Result<()> {
+    match sw {
+        [0x90, 0x00] => Ok(()),
+        [0x63, tries] if tries & 0xf0 == 0xc0 => Err(Error::WrongPin {
+            tries: tries & 0x0f,
+        }),
+        [0x69, 0x83] => Err(Error::PinLocked),
+        [0x69, 0x82] => Err(Error::AuthenticationError),
+        [0x6a, 0x80] | [0x6a, 0x86] => Err(Error::AlgorithmError),
+        [0x6a, 0x82] | [0x6a, 0x88] => Err(Error::NotFound),
+        [0x6d, 0x00] => Err(Error::NotSupported),
+        _ => Err(Error::GenericError),
+    }
+}
+
+fn management_key_cipher(algorithm: u8) -> Result<Cipher> {
+    match algorithm {
+        TDES => Ok(Cipher::des_ede3_ecb()),
+        AES128 => Ok(Cipher::aes_128_ecb()),
+        AES192 => Ok(Cipher::aes_192_ecb()),
+        AES256 => Ok(Cipher::aes_256_ecb()),
+        _ => Err(Error::AlgorithmError),
+    }
+}
+
+fn ecb(cipher: Cipher, mode: Mode, key: &[u8], block: &[u8]) -> Result<Vec<u8>> {
+    if block.len() != cipher.block_size() {
+        return Err(Error::AuthenticationError);
+    }
+    let mut crypter = Crypter::new(cipher, mode, key, None).map_err(openssl_error)?;
+    crypter.pad(false);
+    let mut output = vec![0; block.len() + cipher.block_size()];
+    let mut len = crypter.update(block, &mut output).map_err(openssl_error)?;
+    len += crypter
+        .finalize(&mut output[len..])
+        .map_err(openssl_error)?;
+    output.truncate(len);
+    Ok(output)
+}
+
+fn openssl_error(_: ErrorStack) -> Error {
+    Error::GenericError
+}
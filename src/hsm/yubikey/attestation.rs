@@ -0,0 +1,281 @@
+use super::YubiKeyProvider;
+use crate::{common::error::SecurityModuleError, hsm::core::error::HsmError};
+use ::yubikey::{piv::SlotId, PinPolicy, Serial, TouchPolicy, Version};
+use base64::{engine::general_purpose, Engine};
+use openssl::{
+    stack::Stack,
+    x509::{store::X509StoreBuilder, X509StoreContext, X509},
+};
+use tracing::instrument;
+use x509_cert::der::{asn1::ObjectIdentifier, Decode, Encode};
+
+/// Yubico extensions of attestation certificates, see
+/// https://developers.yubico.com/PIV/Introduction/PIV_attestation.html
+const OID_FIRMWARE_VERSION: ObjectIdentifier =
+    ObjectIdentifier::new_unwrap("1.3.6.1.4.1.41482.3.3");
+const OID_SERIAL: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.41482.3.7");
+const OID_POLICY: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.41482.3.8");
+const OID_FORM_FACTOR: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.6.1.4.1.41482.3.9");
+
+/// The Yubico PIV Root CA (serial 263751), published at
+/// https://developers.yubico.com/PIV/Introduction/piv-attestation-ca.pem. Attestations are
+/// verified against it unless other trust anchors are given.
+pub const YUBICO_PIV_ROOT_CA: &[u8] = include_bytes!("piv-attestation-ca.pem");
+
+/// The form factor of a YubiKey, as reported in attestation certificates.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FormFactor {
+    UsbAKeychain,
+    UsbANano,
+    UsbCKeychain,
+    UsbCNano,
+    UsbCLightning,
+    UsbABio,
+    UsbCBio,
+    /// A form factor this version does not know.
+    Unknown(u8),
+}
+
+impl From<u8> for FormFactor {
+    fn from(value: u8) -> Self {
+        match value {
+            0x01 => Self::UsbAKeychain,
+            0x02 => Self::UsbANano,
+            0x03 => Self::UsbCKeychain,
+            0x04 => Self::UsbCNano,
+            0x05 => Self::UsbCLightning,
+            0x06 => Self::UsbABio,
+            0x07 => Self::UsbCBio,
+            value => Self::Unknown(value),
+        }
+    }
+}
+
+/// Proves that a key was generated on a YubiKey.
+///
+/// The attestation certificate certifies the public key of the slot and is signed by the
+/// device-specific attestation key in slot f9, whose certificate is signed by Yubico.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Attestation {
+    /// The DER encoded attestation certificate of the key.
+    pub certificate: Vec<u8>,
+    /// The DER encoded certificate of the attestation key in slot f9.
+    pub intermediate: Vec<u8>,
+    /// The firmware version of the device.
+    pub firmware_version: Option<Version>,
+    /// The serial number of the device.
+    pub serial: Option<Serial>,
+    /// The PIN policy of the key.
+    pub pin_policy: Option<PinPolicy>,
+    /// The touch policy of the key.
+    pub touch_policy: Option<TouchPolicy>,
+    /// The form factor of the device.
+    pub form_factor: Option<FormFactor>,
+}
+
+impl Attestation {
+    /// Parses the Yubico extensions of an attestation certificate.
+    ///
+    /// # Arguments
+    ///
+    /// * `certificate` - The DER encoded attestation certificate.
+    /// * `intermediate` - The DER encoded certificate of slot f9.
+    pub fn from_der(
+        certificate: Vec<u8>,
+        intermediate: Vec<u8>,
+    ) -> Result<Self, SecurityModuleError> {
+        let parsed = x509_cert::Certificate::from_der(&certificate)
+            .map_err(|e| invalid(&format!("Invalid attestation certificate: {}", e)))?;
+        let mut attestation = Self {
+            certificate,
+            intermediate,
+            firmware_version: None,
+            serial: None,
+            pin_policy: None,
+            touch_policy: None,
+            form_factor: None,
+        };
+
+        let extensions = parsed.tbs_certificate.extensions.unwrap_or_default();
+        for extension in extensions {
+            let value = extension.extn_value.as_bytes();
+            match extension.extn_id {
+                OID_FIRMWARE_VERSION => {
+                    let [major, minor, patch] = value else {
+                        return Err(invalid("Invalid firmware version extension"));
+                    };
+                    attestation.firmware_version = Some(Version {
+                        major: *major,
+                        minor: *minor,
+                        patch: *patch,
+                    });
+                }
+                OID_SERIAL => {
+                    let serial = u32::from_der(value)
+                        .map_err(|_| invalid("Invalid serial number extension"))?;
+                    attestation.serial = Some(Serial(serial));
+                }
+                OID_POLICY => {
+                    let [pin, touch] = value else {
+                        return Err(invalid("Invalid policy extension"));
+                    };
+                    attestation.pin_policy = PinPolicy::try_from(*pin).ok();
+                    attestation.touch_policy = TouchPolicy::try_from(*touch).ok();
+                }
+                OID_FORM_FACTOR => {
+                    let [form_factor] = value else {
+                        return Err(invalid("Invalid form factor extension"));
+                    };
+                    attestation.form_factor = Some(FormFactor::from(*form_factor));
+                }
+                _ => {}
+            }
+        }
+        Ok(attestation)
+    }
+
+    /// Parses an attestation serialized by [`Attestation::to_pem`].
+    pub fn from_pem(pem: &[u8]) -> Result<Self, SecurityModuleError> {
+        let certificates = X509::stack_from_pem(pem).map_err(|e| invalid(&e.to_string()))?;
+        let [certificate, intermediate] = certificates.as_slice() else {
+            return Err(invalid(
+                "An attestation consists of the attestation and the f9 certificate",
+            ));
+        };
+        Self::from_der(
+            certificate.to_der().map_err(|e| invalid(&e.to_string()))?,
+            intermediate.to_der().map_err(|e| invalid(&e.to_string()))?,
+        )
+    }
+
+    /// Serializes the attestation certificate followed by the certificate of slot f9 as PEM.
+    pub fn to_pem(&self) -> Vec<u8> {
+        [&self.certificate, &self.intermediate]
+            .iter()
+            .flat_map(|der| {
+                format!(
+                    "-----BEGIN CERTIFICATE-----\n{}\n-----END CERTIFICATE-----\n",
+                    general_purpose::STANDARD.encode(der)
+                )
+                .into_bytes()
+            })
+            .collect()
+    }
+
+    /// Returns the DER encoded `SubjectPublicKeyInfo` certified by the attestation certificate.
+    pub fn public_key(&self) -> Result<Vec<u8>, SecurityModuleError> {
+        x509_cert::Certificate::from_der(&self.certificate)
+            .and_then(|certificate| certificate.tbs_certificate.subject_public_key_info.to_der())
+            .map_err(|e| invalid(&format!("Invalid attestation certificate: {}", e)))
+    }
+
+    /// Verifies that the attestation certificate chains to one of the trust anchors via the
+    /// certificate of slot f9.
+    ///
+    /// # Arguments
+    ///
+    /// * `trust_anchors` - PEM encoded certificates of the Yubico PIV attestation CAs, or
+    ///   `None` for [`YUBICO_PIV_ROOT_CA`].
+    pub fn verify(&self, trust_anchors: Option<&[u8]>) -> Result<(), SecurityModuleError> {
+        let anchors = X509::stack_from_pem(trust_anchors.unwrap_or(YUBICO_PIV_ROOT_CA))
+            .map_err(verification_error)?;
+        if anchors.is_empty() {
+            return Err(SecurityModuleError::SignatureVerificationError(
+                "No trust anchors given".to_string(),
+            ));
+        }
+        let mut store = X509StoreBuilder::new().map_err(verification_error)?;
+        for anchor in anchors {
+            store.add_cert(anchor).map_err(verification_error)?;
+        }
+        let store = store.build();
+
+        let certificate = X509::from_der(&self.certificate).map_err(verification_error)?;
+        let mut chain = Stack::new().map_err(verification_error)?;
+        chain
+            .push(X509::from_der(&self.intermediate).map_err(verification_error)?)
+            .map_err(verification_error)?;
+
+        let mut context = X509StoreContext::new().map_err(verification_error)?;
+        context
+            .init(&store, &certificate, &chain, |context| {
+                Ok(match context.verify_cert()? {
+                    true => Ok(()),
+                    false => Err(context.error()),
+                })
+            })
+            .map_err(verification_error)?
+            .map_err(|e| {
+                SecurityModuleError::SignatureVerificationError(format!(
+                    "The attestation does not chain to a trust anchor: {}",
+                    e.error_string()
+                ))
+            })
+    }
+}
+
+impl YubiKeyProvider {
+    /// Attests that the created or loaded key was generated on the YubiKey and verifies the
+    /// attestation. `attest_key` returns the attestation without verifying it, for relying
+    /// parties that verify it themselves.
+    ///
+    /// Requires firmware 4.3 or later. Imported keys cannot be attested.
+    ///
+    /// # Arguments
+    ///
+    /// * `trust_anchors` - PEM encoded certificates of the Yubico PIV attestation CAs the
+    ///   attestation is verified against, or `None` for [`YUBICO_PIV_ROOT_CA`].
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the verified `Attestation` on success, or a `SecurityModuleError`
+    /// if the device cannot attest the key or the attestation does not verify.
+    #[instrument(skip(trust_anchors))]
+    pub fn attestation(
+        &self,
+        trust_anchors: Option<&[u8]>,
+    ) -> Result<Attestation, SecurityModuleError> {
+        let attestation = self.read_attestation()?;
+        attestation.verify(trust_anchors)?;
+        Ok(attestation)
+    }
+
+    /// Reads the attestation of the key and the certificate of slot f9 from the device.
+    pub(super) fn read_attestation(&self) -> Result<Attestation, SecurityModuleError> {
+        let slot = self.slot_id.ok_or_else(|| {
+            SecurityModuleError::Hsm(HsmError::DeviceSpecific("No key loaded".to_string()))
+        })?;
+
+        let (certificate, intermediate) = {
+            let mut yubikey = self.device()?;
+            let certificate = yubikey.attest(slot).map_err(|e| match e {
+                ::yubikey::Error::NotSupported => SecurityModuleError::Hsm(
+                    HsmError::UnsupportedFeature("The device cannot attest keys".to_string()),
+                ),
+                e => SecurityModuleError::Hsm(HsmError::DeviceSpecific(e.to_string())),
+            })?;
+            let intermediate = yubikey
+                .read_certificate(SlotId::Attestation)
+                .map_err(|e| SecurityModuleError::Hsm(HsmError::DeviceSpecific(e.to_string())))?;
+            (certificate, intermediate)
+        };
+
+        let attestation = Attestation::from_der(certificate, intermediate)?;
+        if let Some(metadata) = &self.metadata {
+            if attestation.public_key()? != metadata.public_key {
+                return Err(SecurityModuleError::SignatureVerificationError(
+                    "The attestation certifies a different key".to_string(),
+                ));
+            }
+        }
+        Ok(attestation)
+    }
+}
+
+fn verification_error(e: openssl::error::ErrorStack) -> SecurityModuleError {
+    SecurityModuleError::SignatureVerificationError(e.to_string())
+}
+
+fn invalid(message: &str) -> SecurityModuleError {
+    SecurityModuleError::Hsm(HsmError::DeviceSpecific(message.to_string()))
+}
@@ -0,0 +1,190 @@
+use super::{
+    device::PivDevice,
+    metadata::{KeyMetadata, PivAlgorithm, MAX_OBJECT_SIZE},
+    pin::{authenticate, verify_pin},
+    provider::{chain_object, migrate_legacy_record},
+    YubiKeyProvider,
+};
+use crate::{common::error::SecurityModuleError, hsm::core::error::HsmError};
+use std::{
+    str::FromStr,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::instrument;
+use x509_cert::{
+    der::{Decode, Encode, Reader, SliceReader},
+    name::Name,
+    serial_number::SerialNumber,
+    spki::SubjectPublicKeyInfoOwned,
+    time::Validity,
+};
+
+/// How long placeholder certificates are valid.
+const PLACEHOLDER_VALIDITY: Duration = Duration::from_secs(10 * 365 * 24 * 60 * 60);
+
+impl YubiKeyProvider {
+    /// Stores a certificate issued for the created or loaded key in the certificate object of
+    /// its slot, where PIV middleware like OpenSC and the Windows smart card driver look for it.
+    ///
+    /// # Arguments
+    ///
+    /// * `certificate` - The DER encoded certificate of the key, replacing any stored one.
+    /// * `chain` - The DER encoded certificates of the issuing CAs, starting with the issuer
+    ///   of `certificate`. They are stored in a separate object of at most 3063 bytes.
+    #[instrument(skip(certificate, chain))]
+    pub fn store_certificate(
+        &self,
+        certificate: &[u8],
+        chain: &[Vec<u8>],
+    ) -> Result<(), SecurityModuleError> {
+        let metadata = self.metadata.as_ref().ok_or_else(|| {
+            SecurityModuleError::Hsm(HsmError::DeviceSpecific("No key loaded".to_string()))
+        })?;
+        let parsed = x509_cert::Certificate::from_der(certificate)
+            .map_err(|_| invalid("The certificate is not a DER encoded X.509 certificate"))?;
+        let public_key = parsed
+            .tbs_certificate
+            .subject_public_key_info
+            .to_der()
+            .map_err(|e| invalid(&e.to_string()))?;
+        if public_key != metadata.public_key {
+            return Err(invalid("The certificate was not issued for the key"));
+        }
+
+        let mut chain_data = Vec::new();
+        for issuer in chain {
+            x509_cert::Certificate::from_der(issuer).map_err(|_| {
+                invalid("The chain contains a certificate that is not DER encoded X.509")
+            })?;
+            chain_data.extend_from_slice(issuer);
+        }
+        if chain_data.len() > MAX_OBJECT_SIZE {
+            return Err(invalid(&format!(
+                "The chain of {} bytes exceeds the object size limit of {} bytes",
+                chain_data.len(),
+                MAX_OBJECT_SIZE
+            )));
+        }
+
+        let mut yubikey = self.device()?;
+        verify_pin(&mut yubikey, self.pin.as_bytes())?;
+        authenticate(&mut yubikey, self.management_key()?)?;
+        migrate_legacy_record(&mut yubikey, metadata.slot)?;
+        yubikey
+            .write_certificate(metadata.slot, certificate)
+            .map_err(device_error)?;
+        // An empty object deletes the chain of a previous certificate.
+        yubikey
+            .save_object(chain_object(metadata.slot), &mut chain_data)
+            .map_err(device_error)
+    }
+
+    /// Reads the certificate stored for the created or loaded key.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the DER encoded certificate on success, or a `SecurityModuleError`
+    /// if no certificate is stored in the slot.
+    #[instrument]
+    pub fn certificate(&self) -> Result<Vec<u8>, SecurityModuleError> {
+        let slot = self.slot_id.ok_or_else(|| {
+            SecurityModuleError::Hsm(HsmError::DeviceSpecific("No key loaded".to_string()))
+        })?;
+        self.device()?.read_certificate(slot).map_err(|_| {
+            SecurityModuleError::Hsm(HsmError::DeviceSpecific(format!(
+                "No certificate stored in slot {:?}",
+                slot
+            )))
+        })
+    }
+
+    /// Reads the certificate of the key followed by the stored chain.
+    pub(super) fn read_certificate_chain(&self) -> Result<Vec<Vec<u8>>, SecurityModuleError> {
+        let mut certificates = vec![self.certificate()?];
+        let slot = self
+            .slot_id
+            .expect("The certificate was read from the slot");
+        let Ok(data) = self.device()?.fetch_object(chain_object(slot)) else {
+            return Ok(certificates);
+        };
+
+        let mut reader = SliceReader::new(&data).map_err(|e| invalid(&e.to_string()))?;
+        while !reader.is_finished() {
+            let issuer = x509_cert::Certificate::decode(&mut reader)
+                .and_then(|issuer| issuer.to_der())
+                .map_err(|e| invalid(&format!("Invalid certificate chain: {}", e)))?;
+            certificates.push(issuer);
+        }
+        Ok(certificates)
+    }
+}
+
+/// Writes a self-signed certificate for a new key, so that PIV tools recognize the slot as
+/// populated. Requires the PIN and a 3DES management key, as the `yubikey` crate writes it.
+pub(super) fn write_placeholder_certificate(
+    yubikey: &mut Box<dyn PivDevice>,
+    metadata: &KeyMetadata,
+) -> Result<(), SecurityModuleError> {
+    let created = metadata
+        .created
+        .unwrap_or_else(SystemTime::now)
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_secs());
+    let serial = SerialNumber::new(&created.to_be_bytes()).map_err(|e| invalid(&e.to_string()))?;
+    let validity = Validity::from_now(PLACEHOLDER_VALIDITY).map_err(|e| invalid(&e.to_string()))?;
+    let subject = Name::from_str(&format!("CN={}", escape(&metadata.key_id)))
+        .map_err(|e| invalid(&e.to_string()))?;
+    let public_key = SubjectPublicKeyInfoOwned::from_der(&metadata.public_key)
+        .map_err(|e| invalid(&e.to_string()))?;
+
+    migrate_legacy_record(yubikey, metadata.slot)?;
+    // The `yubikey` crate signs the certificate, so algorithms it does not know have none.
+    let algorithm = metadata
+        .algorithm
+        .and_then(PivAlgorithm::algorithm_id)
+        .ok_or_else(|| {
+            SecurityModuleError::Hsm(HsmError::UnsupportedFeature(
+                "Placeholder certificates are not supported for the key algorithm".to_string(),
+            ))
+        })?;
+    yubikey
+        .write_self_signed_certificate(
+            metadata.slot,
+            algorithm,
+            serial,
+            validity,
+            subject,
+            public_key,
+        )
+        .map_err(|e| match e {
+            ::yubikey::Error::NotSupported => {
+                SecurityModuleError::Hsm(HsmError::UnsupportedFeature(
+                    "Placeholder certificates require a 3DES management key".to_string(),
+                ))
+            }
+            e => device_error(e),
+        })
+}
+
+/// Escapes a key id for use as an attribute value of a distinguished name (RFC 4514).
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        let special = matches!(c, ',' | '+' | '"' | '\\' | '<' | '>' | ';' | '=')
+            || (i == 0 && matches!(c, '#' | ' '))
+            || (i == value.chars().count() - 1 && c == ' ');
+        if special {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn device_error(err: ::yubikey::Error) -> SecurityModuleError {
+    SecurityModuleError::Hsm(HsmError::DeviceSpecific(err.to_string()))
+}
+
+fn invalid(message: &str) -> SecurityModuleError {
+    SecurityModuleError::Hsm(HsmError::DeviceSpecific(message.to_string()))
+}
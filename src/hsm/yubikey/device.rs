@@ -0,0 +1,408 @@
+use super::{apdu, metadata::PivAlgorithm, pin::ManagementKeyMode};
+use ::yubikey::{
+    certificate::{
+        yubikey_signer::{Rsa1024, Rsa2048, YubiRsa},
+        CertInfo,
+    },
+    piv::{self, AlgorithmId, ManagementSlotId, SlotId},
+    Certificate, Error, MgmKey, PinPolicy, Result, TouchPolicy, Version, YubiKey,
+};
+use std::{ffi::CString, fmt::Debug};
+use x509_cert::{
+    der::Encode, name::Name, serial_number::SerialNumber, spki::SubjectPublicKeyInfoOwned,
+    time::Validity,
+};
+use zeroize::Zeroizing;
+
+/// The operations of a PIV device the YubiKey provider performs.
+///
+/// Implemented for attached YubiKeys by `YubiKeyCard`, and by an in-memory simulator in tests.
+/// The methods follow the `yubikey` crate and report its errors, so that they are mapped the
+/// same way for both.
+pub(crate) trait PivDevice: Send + Debug {
+    /// The firmware version of the device.
+    fn version(&self) -> Version;
+
+    fn verify_pin(&mut self, pin: &[u8]) -> Result<()>;
+
+    /// The PIN attempts left. Resets the PIN verification.
+    fn get_pin_retries(&mut self) -> Result<u8>;
+
+    /// The PUK attempts left, if the device reports them.
+    fn get_puk_retries(&mut self) -> Result<Option<u8>>;
+
+    fn change_pin(&mut self, current_pin: &[u8], new_pin: &[u8]) -> Result<()>;
+
+    fn change_puk(&mut self, current_puk: &[u8], new_puk: &[u8]) -> Result<()>;
+
+    fn unblock_pin(&mut self, puk: &[u8], new_pin: &[u8]) -> Result<()>;
+
+    /// Authenticates with a 3DES or AES management key.
+    fn authenticate(&mut self, management_key: &[u8]) -> Result<()>;
+
+    /// The management key stored on the device, protected by the PIN.
+    fn protected_management_key(&mut self) -> Result<MgmKey>;
+
+    /// Replaces the management key. Requires authentication with the current one, devices
+    /// that can not store the key in `mode` fail with `Error::NotSupported`.
+    fn set_management_key(
+        &mut self,
+        management_key: &MgmKey,
+        mode: ManagementKeyMode,
+    ) -> Result<()>;
+
+    fn fetch_object(&mut self, object_id: u32) -> Result<Vec<u8>>;
+
+    /// Stores a data object, deleting it if `data` is empty.
+    fn save_object(&mut self, object_id: u32, data: &mut [u8]) -> Result<()>;
+
+    /// Generates a key in `slot` and returns its DER encoded `SubjectPublicKeyInfo`.
+    fn generate(
+        &mut self,
+        slot: SlotId,
+        algorithm: PivAlgorithm,
+        pin_policy: PinPolicy,
+        touch_policy: TouchPolicy,
+    ) -> Result<Vec<u8>>;
+
+    /// Whether `slot` holds a key, read from the slot metadata. Requires firmware 5.3 or
+    /// later, older devices fail with `Error::NotSupported`.
+    fn has_key(&mut self, slot: SlotId) -> Result<bool>;
+
+    /// Applies the raw private key operation of `slot` to the padded digest for RSA keys,
+    /// signs the digest with ECDSA for EC keys and signs the message itself for Ed25519 keys.
+    fn sign_data(&mut self, input: &[u8], algorithm: PivAlgorithm, slot: SlotId)
+        -> Result<Vec<u8>>;
+
+    /// Applies the raw private key operation of `slot` for RSA keys, and derives the shared
+    /// secret with the given point for EC keys or public key for X25519 keys.
+    fn decrypt_data(
+        &mut self,
+        input: &[u8],
+        algorithm: PivAlgorithm,
+        slot: SlotId,
+    ) -> Result<Vec<u8>>;
+
+    /// Returns the DER encoded attestation certificate of the key in `slot`.
+    fn attest(&mut self, slot: SlotId) -> Result<Vec<u8>>;
+
+    /// Returns the DER encoded certificate stored in the certificate object of `slot`.
+    fn read_certificate(&mut self, slot: SlotId) -> Result<Vec<u8>>;
+
+    /// Stores a DER encoded certificate in the certificate object of `slot`.
+    fn write_certificate(&mut self, slot: SlotId, certificate: &[u8]) -> Result<()>;
+
+    /// Stores a certificate for the key in `slot` signed with the key itself.
+    #[allow(clippy::too_many_arguments)]
+    fn write_self_signed_certificate(
+        &mut self,
+        slot: SlotId,
+        algorithm: AlgorithmId,
+        serial: SerialNumber,
+        validity: Validity,
+        subject: Name,
+        public_key: SubjectPublicKeyInfoOwned,
+    ) -> Result<()>;
+}
+
+/// An attached YubiKey.
+///
+/// Operations go through the `yubikey` crate, except for the algorithms added by firmware 5.7,
+/// which the crate does not know. Those are sent as raw PIV commands on a connection of their
+/// own, which has to verify the PIN and authenticate with the management key again. The
+/// credentials the crate accepted last are kept for that.
+///
+/// The crate only authenticates with 3DES management keys. With an AES key, the default from
+/// firmware 5.7, the commands that require the management key are sent raw as well.
+pub(crate) struct YubiKeyCard {
+    yubikey: YubiKey,
+    pin: Option<Zeroizing<Vec<u8>>>,
+    management_key: Option<Zeroizing<Vec<u8>>>,
+    aes_management_key: bool,
+}
+
+impl YubiKeyCard {
+    pub(crate) fn new(yubikey: YubiKey) -> Self {
+        Self {
+            yubikey,
+            pin: None,
+            management_key: None,
+            aes_management_key: false,
+        }
+    }
+
+    /// Runs `operation` on a new connection to the PIV application of the device, after
+    /// verifying the PIN and, if `authenticate` is set, the management key.
+    fn raw<T>(
+        &mut self,
+        authenticate: bool,
+        operation: impl FnOnce(&mut dyn apdu::Transport) -> Result<T>,
+    ) -> Result<T> {
+        let context = pcsc::Context::establish(pcsc::Scope::User)
+            .map_err(|e| Error::PcscError { inner: Some(e) })?;
+        let reader = CString::new(self.yubikey.name()).map_err(|_| Error::InvalidObject)?;
+        let mut card = context
+            .connect(&reader, pcsc::ShareMode::Shared, pcsc::Protocols::T1)
+            .map_err(|e| Error::PcscError { inner: Some(e) })?;
+        let mut transaction = card
+            .transaction()
+            .map_err(|e| Error::PcscError { inner: Some(e) })?;
+
+        apdu::select(&mut transaction)?;
+        let pin = self.pin.as_ref().ok_or(Error::AuthenticationError)?;
+        apdu::verify_pin(&mut transaction, pin)?;
+        if authenticate {
+            let management_key = self
+                .management_key
+                .as_ref()
+                .ok_or(Error::AuthenticationError)?;
+            apdu::authenticate(&mut transaction, management_key)?;
+        }
+        operation(&mut transaction)
+    }
+}
+
+impl Debug for YubiKeyCard {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("YubiKeyCard")
+            .field("yubikey", &self.yubikey)
+            .finish_non_exhaustive()
+    }
+}
+
+impl PivDevice for YubiKeyCard {
+    fn version(&self) -> Version {
+        self.yubikey.version()
+    }
+
+    fn verify_pin(&mut self, pin: &[u8]) -> Result<()> {
+        self.yubikey.verify_pin(pin)?;
+        self.pin = Some(Zeroizing::new(pin.to_vec()));
+        Ok(())
+    }
+
+    fn get_pin_retries(&mut self) -> Result<u8> {
+        self.yubikey.get_pin_retries()
+    }
+
+    fn get_puk_retries(&mut self) -> Result<Option<u8>> {
+        // Requires firmware 5.3 or later, which reports the retries in the PUK metadata.
+        piv::metadata(&mut self.yubikey, SlotId::Management(ManagementSlotId::Puk))
+            .map(|metadata| metadata.retries.map(|retries| retries.remaining_count))
+    }
+
+    fn change_pin(&mut self, current_pin: &[u8], new_pin: &[u8]) -> Result<()> {
+        self.yubikey.change_pin(current_pin, new_pin)?;
+        self.pin = Some(Zeroizing::new(new_pin.to_vec()));
+        Ok(())
+    }
+
+    fn change_puk(&mut self, current_puk: &[u8], new_puk: &[u8]) -> Result<()> {
+        self.yubikey.change_puk(current_puk, new_puk)
+    }
+
+    fn unblock_pin(&mut self, puk: &[u8], new_pin: &[u8]) -> Result<()> {
+        self.yubikey.unblock_pin(puk, new_pin)?;
+        self.pin = Some(Zeroizing::new(new_pin.to_vec()));
+        Ok(())
+    }
+
+    fn authenticate(&mut self, management_key: &[u8]) -> Result<()> {
+        // AES management keys were added by firmware 5.4, older devices only have 3DES keys.
+        let version = self.yubikey.version();
+        let algorithm = if (version.major, version.minor) >= (5, 4) {
+            self.raw(false, apdu::management_key_algorithm)?
+        } else {
+            apdu::TDES
+        };
+        if algorithm == apdu::TDES {
+            let key =
+                <[u8; 24]>::try_from(management_key).map_err(|_| Error::AuthenticationError)?;
+            self.yubikey.authenticate(MgmKey::new(key)?)?;
+        } else {
+            self.raw(false, |card| apdu::authenticate(card, management_key))?;
+        }
+        self.management_key = Some(Zeroizing::new(management_key.to_vec()));
+        self.aes_management_key = algorithm != apdu::TDES;
+        Ok(())
+    }
+
+    fn protected_management_key(&mut self) -> Result<MgmKey> {
+        MgmKey::get_protected(&mut self.yubikey)
+    }
+
+    fn set_management_key(
+        &mut self,
+        management_key: &MgmKey,
+        mode: ManagementKeyMode,
+    ) -> Result<()> {
+        if self.aes_management_key {
+            // Storing the key in the PIN-protected objects needs the crate authenticated.
+            match mode {
+                ManagementKeyMode::Manual { require_touch } => self.raw(true, |card| {
+                    apdu::set_management_key(card, management_key.as_ref(), require_touch)
+                }),
+                ManagementKeyMode::PinProtected => Err(Error::NotSupported),
+            }
+        } else {
+            match mode {
+                ManagementKeyMode::Manual { require_touch } => {
+                    management_key.set_manual(&mut self.yubikey, require_touch)
+                }
+                ManagementKeyMode::PinProtected => management_key.set_protected(&mut self.yubikey),
+            }
+        }?;
+        self.management_key = Some(Zeroizing::new(management_key.as_ref().to_vec()));
+        Ok(())
+    }
+
+    fn fetch_object(&mut self, object_id: u32) -> Result<Vec<u8>> {
+        self.yubikey
+            .fetch_object(object_id)
+            .map(|data| data.to_vec())
+    }
+
+    fn save_object(&mut self, object_id: u32, data: &mut [u8]) -> Result<()> {
+        if self.aes_management_key {
+            self.raw(true, |card| apdu::put_data(card, object_id, data))
+        } else {
+            self.yubikey.save_object(object_id, data)
+        }
+    }
+
+    fn generate(
+        &mut self,
+        slot: SlotId,
+        algorithm: PivAlgorithm,
+        pin_policy: PinPolicy,
+        touch_policy: TouchPolicy,
+    ) -> Result<Vec<u8>> {
+        match algorithm.algorithm_id() {
+            Some(algorithm) if !self.aes_management_key => {
+                piv::generate(&mut self.yubikey, slot, algorithm, pin_policy, touch_policy)
+                    .and_then(|public_key| public_key.to_der().map_err(|_| Error::ParseError))
+            }
+            _ => self.raw(true, |card| {
+                apdu::generate(card, slot, algorithm, pin_policy, touch_policy)
+            }),
+        }
+    }
+
+    fn has_key(&mut self, slot: SlotId) -> Result<bool> {
+        // The `yubikey` crate reports the "reference data not found" status of empty slots as
+        // a generic error.
+        match piv::metadata(&mut self.yubikey, slot) {
+            Ok(_) => Ok(true),
+            Err(Error::GenericError) => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    fn sign_data(
+        &mut self,
+        input: &[u8],
+        algorithm: PivAlgorithm,
+        slot: SlotId,
+    ) -> Result<Vec<u8>> {
+        match algorithm.algorithm_id() {
+            Some(algorithm) => piv::sign_data(&mut self.yubikey, input, algorithm, slot)
+                .map(|signature| signature.to_vec()),
+            None => self.raw(false, |card| {
+                apdu::general_authenticate(card, algorithm, slot, input)
+            }),
+        }
+    }
+
+    fn decrypt_data(
+        &mut self,
+        input: &[u8],
+        algorithm: PivAlgorithm,
+        slot: SlotId,
+    ) -> Result<Vec<u8>> {
+        match algorithm.algorithm_id() {
+            Some(algorithm) => piv::decrypt_data(&mut self.yubikey, input, algorithm, slot)
+                .map(|data| data.to_vec()),
+            None => self.raw(false, |card| {
+                apdu::general_authenticate(card, algorithm, slot, input)
+            }),
+        }
+    }
+
+    fn attest(&mut self, slot: SlotId) -> Result<Vec<u8>> {
+        piv::attest(&mut self.yubikey, slot).map(|certificate| certificate.to_vec())
+    }
+
+    fn read_certificate(&mut self, slot: SlotId) -> Result<Vec<u8>> {
+        Certificate::read(&mut self.yubikey, slot)?
+            .cert
+            .to_der()
+            .map_err(|_| Error::ParseError)
+    }
+
+    fn write_certificate(&mut self, slot: SlotId, certificate: &[u8]) -> Result<()> {
+        if self.aes_management_key {
+            let object_id = apdu::certificate_object(slot)?;
+            let data = apdu::encode_certificate(certificate);
+            return self.raw(true, |card| apdu::put_data(card, object_id, &data));
+        }
+        Certificate::from_bytes(certificate.to_vec())?.write(
+            &mut self.yubikey,
+            slot,
+            CertInfo::Uncompressed,
+        )
+    }
+
+    fn write_self_signed_certificate(
+        &mut self,
+        slot: SlotId,
+        algorithm: AlgorithmId,
+        serial: SerialNumber,
+        validity: Validity,
+        subject: Name,
+        public_key: SubjectPublicKeyInfoOwned,
+    ) -> Result<()> {
+        // The crate writes the certificate itself, which requires it to be authenticated.
+        if self.aes_management_key {
+            return Err(Error::NotSupported);
+        }
+        match algorithm {
+            AlgorithmId::Rsa1024 => Certificate::generate_self_signed::<_, YubiRsa<Rsa1024>>(
+                &mut self.yubikey,
+                slot,
+                serial,
+                validity,
+                subject,
+                public_key,
+                |_| Ok(()),
+            ),
+            AlgorithmId::Rsa2048 => Certificate::generate_self_signed::<_, YubiRsa<Rsa2048>>(
+                &mut self.yubikey,
+                slot,
+                serial,
+                validity,
+                subject,
+                public_key,
+                |_| Ok(()),
+            ),
+            AlgorithmId::EccP256 => Certificate::generate_self_signed::<_, p256::NistP256>(
+                &mut self.yubikey,
+                slot,
+                serial,
+                validity,
+                subject,
+                public_key,
+                |_| Ok(()),
+            ),
+            AlgorithmId::EccP384 => Certificate::generate_self_signed::<_, p384::NistP384>(
+                &mut self.yubikey,
+                slot,
+                serial,
+                validity,
+                subject,
+                public_key,
+                |_| Ok(()),
+            ),
+        }
+        .map(|_| ())
+    }
+}
@@ -1,40 +1,41 @@
-use super::YubiKeyProvider;
+use super::{
+    metadata::{algorithm_id, PivAlgorithm},
+    pin::verify_pin,
+    YubiKeyProvider,
+};
 use crate::{
     common::{
         crypto::algorithms::{
             encryption::{AsymmetricEncryption, EccCurves, EccSchemeAlgorithm},
+            hashes::{Hash, Sha2Bits},
             KeyBits,
         },
         error::SecurityModuleError,
         traits::key_handle::KeyHandle,
     },
-    hsm::core::error::HsmError,
+    hsm::{core::error::HsmError, RsaSignaturePadding},
 };
 
-use ::yubikey::piv;
-use ::yubikey::{
-    piv::{AlgorithmId, SlotId},
-    MgmKey,
-};
-use base64::{engine::general_purpose, Engine};
 use openssl::{
-    ec::EcKey,
-    hash::MessageDigest,
-    pkey::PKey,
+    bn::BigNumContext,
+    ec::{EcKey, PointConversionForm},
+    hash::{hash, MessageDigest},
+    nid::Nid,
+    pkey::{Id, PKey},
+    rand::rand_bytes,
     rsa::{Padding, Rsa},
-    sign::Verifier,
+    sign::{RsaPssSaltlen, Verifier},
 };
-use rsa::sha2::Digest;
-use sha2::Sha256;
 use tracing::instrument;
-use x509_cert::der::zeroize::Zeroizing;
 
 /// Provides cryptographic operations for asymmetric keys on a YubiKey,
 /// such as signing, encryption, decryption, and signature verification.
 
 /// Signs data using the cryptographic key on a YubiKey.
 ///
-/// This method hashes the input data using SHA-256 and then signs the hash.
+/// This method hashes the input data with the configured hash, SHA-256 by default, and then
+/// signs the hash. RSA digests are padded with the configured padding. Ed25519 keys sign the
+/// data itself.
 ///
 /// # Arguments
 ///
@@ -48,77 +49,43 @@ use x509_cert::der::zeroize::Zeroizing;
 impl KeyHandle for YubiKeyProvider {
     #[instrument]
     fn sign_data(&self, data: &[u8]) -> Result<Vec<u8>, SecurityModuleError> {
-        let yubikey = self.yubikey.as_ref().unwrap();
-        let mut yubikey = yubikey.lock().unwrap();
-        let data = data.to_vec();
-        let key_algo = self.key_algo.unwrap();
-
-        // Input gets hashed with SHA-256
-        let mut hasher = Sha256::new();
-        hasher.update(data);
-        let data = hasher.finalize();
-        let mut data: &[u8] = &data;
-
-        //TODO After PIN input implementation in App, insert code for re-authentication
-        let verify = yubikey.verify_pin(self.pin.as_ref());
-        if !verify.is_ok() {
-            return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
-                "PIN verification failed".to_string(),
-            )));
-        }
-        let auth = yubikey.authenticate(MgmKey::new(self.management_key.unwrap()).unwrap());
-        if !auth.is_ok() {
-            return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
-                "Authentication  failed".to_string(),
-            )));
-        }
-
-        let signature: Result<Zeroizing<Vec<u8>>, yubikey::Error>;
-        let mut vec_data: Vec<u8> = create_digest_info(data).unwrap();
-        let algorithm_id: AlgorithmId;
+        self.confirm_touch("sign")?;
+        let algorithm_id = self.key_algo.and_then(algorithm_id).ok_or_else(|| {
+            SecurityModuleError::Hsm(HsmError::DeviceSpecific(
+                "Key Algorithm not supported".to_string(),
+            ))
+        })?;
+        let digest = signature_digest(self.hash)?;
+        let hashed =
+            hash(digest, data).map_err(|e| SecurityModuleError::SigningError(e.to_string()))?;
 
-        match key_algo {
-            AsymmetricEncryption::Rsa(KeyBits::Bits1024) => {
-                algorithm_id = AlgorithmId::Rsa1024;
-                vec_data = apply_pkcs1v15_padding(&vec_data, 128);
-                data = &vec_data.as_slice();
+        let input = match algorithm_id {
+            PivAlgorithm::Rsa1024
+            | PivAlgorithm::Rsa2048
+            | PivAlgorithm::Rsa3072
+            | PivAlgorithm::Rsa4096 => {
+                let key_len = algorithm_id.rsa_len().unwrap();
+                match self.rsa_padding {
+                    RsaSignaturePadding::Pkcs1v15 => pkcs1v15_encode(digest, &hashed, key_len)?,
+                    RsaSignaturePadding::Pss => pss_encode(digest, &hashed, key_len)?,
+                }
             }
-            AsymmetricEncryption::Rsa(KeyBits::Bits2048) => {
-                algorithm_id = AlgorithmId::Rsa2048;
-                vec_data = apply_pkcs1v15_padding(&vec_data, 256);
-                data = vec_data.as_slice();
+            // ECDSA signs the leftmost bits of digests longer than the curve order.
+            PivAlgorithm::EccP256 => hashed[..hashed.len().min(32)].to_vec(),
+            PivAlgorithm::EccP384 => hashed[..hashed.len().min(48)].to_vec(),
+            PivAlgorithm::Ed25519 => data.to_vec(),
+            PivAlgorithm::X25519 => {
+                return Err(SecurityModuleError::Hsm(HsmError::UnsupportedFeature(
+                    "X25519 keys can not sign, use derive_shared_secret".to_string(),
+                )))
             }
+        };
 
-            AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256)) => {
-                algorithm_id = AlgorithmId::EccP256;
-            }
-            AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P384)) => {
-                algorithm_id = AlgorithmId::EccP384;
-            }
-            _ => {
-                return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
-                    "Key Algorithm not supported".to_string(),
-                )));
-            }
-        }
-        signature = piv::sign_data(
-            &mut yubikey,
-            data,
-            algorithm_id,
-            SlotId::Retired(self.slot_id.unwrap()),
-        );
-        match signature {
-            Ok(buffer) => {
-                let signature = general_purpose::STANDARD.encode(&buffer);
-                let signature = general_purpose::STANDARD
-                    .decode(signature)
-                    .expect("Failed to decode signature");
-                Ok(signature)
-            }
-            Err(err) => Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
-                err.to_string(),
-            ))),
-        }
+        let mut yubikey = self.device()?;
+        verify_pin(&mut yubikey, self.pin.as_bytes())?;
+        yubikey
+            .sign_data(&input, algorithm_id, self.slot_id.unwrap())
+            .map_err(|err| SecurityModuleError::Hsm(HsmError::DeviceSpecific(err.to_string())))
     }
 
     /// Decrypts data encrypted with the corresponding public key on a YubiKey.
@@ -134,30 +101,18 @@ impl KeyHandle for YubiKeyProvider {
     /// A `Result` containing the decrypted data as a `Vec<u8>` on success, or a `yubikey::Error` on failure.
     #[instrument]
     fn decrypt_data(&self, encrypted_data: &[u8]) -> Result<Vec<u8>, SecurityModuleError> {
-        let yubikey = self.yubikey.as_ref().unwrap();
-        let mut yubikey = yubikey.lock().unwrap();
+        self.confirm_touch("decrypt")?;
+        let mut yubikey = self.device()?;
+        verify_pin(&mut yubikey, self.pin.as_bytes())?;
 
-        let decrypted: Result<Zeroizing<Vec<u8>>, &str>;
+        let decrypted: Result<Vec<u8>, &str>;
         let key_algo = self.key_algo.unwrap();
 
-        match key_algo {
-            AsymmetricEncryption::Rsa(KeyBits::Bits1024) => {
-                decrypted = piv::decrypt_data(
-                    &mut yubikey,
-                    encrypted_data,
-                    piv::AlgorithmId::Rsa1024,
-                    piv::SlotId::Retired(self.slot_id.unwrap()),
-                )
-                .map_err(|_| "Failed to decrypt data");
-            }
-            AsymmetricEncryption::Rsa(KeyBits::Bits2048) => {
-                decrypted = piv::decrypt_data(
-                    &mut yubikey,
-                    encrypted_data,
-                    piv::AlgorithmId::Rsa2048,
-                    piv::SlotId::Retired(self.slot_id.unwrap()),
-                )
-                .map_err(|_| "Failed to decrypt data");
+        match algorithm_id(key_algo) {
+            Some(algorithm) if algorithm.is_rsa() => {
+                decrypted = yubikey
+                    .decrypt_data(encrypted_data, algorithm, self.slot_id.unwrap())
+                    .map_err(|_| "Failed to decrypt data");
             }
             // The Yubikey do not support decryption with ECC, see:
             // https://docs.yubico.com/yesdk/users-manual/application-piv/apdu/auth-decrypt.html
@@ -221,7 +176,9 @@ impl KeyHandle for YubiKeyProvider {
     fn encrypt_data(&self, data: &[u8]) -> Result<Vec<u8>, SecurityModuleError> {
         match self.key_algo.unwrap() {
             AsymmetricEncryption::Rsa(KeyBits::Bits1024)
-            | AsymmetricEncryption::Rsa(KeyBits::Bits2048) => {
+            | AsymmetricEncryption::Rsa(KeyBits::Bits2048)
+            | AsymmetricEncryption::Rsa(KeyBits::Bits3072)
+            | AsymmetricEncryption::Rsa(KeyBits::Bits4096) => {
                 let rsa = Rsa::public_key_from_pem(self.pkey.trim().as_bytes())
                     .map_err(|_| "failed to create RSA from public key PEM");
                 let mut encrypted_data = vec![0; rsa.clone().unwrap().size() as usize];
@@ -242,7 +199,8 @@ impl KeyHandle for YubiKeyProvider {
 
     /// Verifies a signature against the provided data using the YubiKey.
     ///
-    /// This method hashes the input data using SHA-256 and then verifies the signature.
+    /// This method hashes the input data with the configured hash, SHA-256 by default, and then
+    /// verifies the signature with the configured RSA padding.
     ///
     /// # Arguments
     ///
@@ -257,13 +215,25 @@ impl KeyHandle for YubiKeyProvider {
     fn verify_signature(&self, data: &[u8], signature: &[u8]) -> Result<bool, SecurityModuleError> {
         match self.key_algo.unwrap() {
             AsymmetricEncryption::Rsa(KeyBits::Bits1024)
-            | AsymmetricEncryption::Rsa(KeyBits::Bits2048) => {
+            | AsymmetricEncryption::Rsa(KeyBits::Bits2048)
+            | AsymmetricEncryption::Rsa(KeyBits::Bits3072)
+            | AsymmetricEncryption::Rsa(KeyBits::Bits4096) => {
                 let rsa = Rsa::public_key_from_pem(self.pkey.trim().as_bytes())
                     .expect("failed to create RSA from public key PEM");
                 let key_pkey = PKey::from_rsa(rsa).unwrap();
 
-                let mut verifier = Verifier::new(MessageDigest::sha256(), &key_pkey)
-                    .expect("failed to create verifier");
+                let digest = signature_digest(self.hash)?;
+                let mut verifier =
+                    Verifier::new(digest, &key_pkey).expect("failed to create verifier");
+                if self.rsa_padding == RsaSignaturePadding::Pss {
+                    verifier
+                        .set_rsa_padding(Padding::PKCS1_PSS)
+                        .and_then(|_| verifier.set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH))
+                        .and_then(|_| verifier.set_rsa_mgf1_md(digest))
+                        .map_err(|e| {
+                            SecurityModuleError::SignatureVerificationError(e.to_string())
+                        })?;
+                }
                 verifier
                     .update(data)
                     .map_err(|_| "failed to update verifier")
@@ -286,7 +256,7 @@ impl KeyHandle for YubiKeyProvider {
                     .expect("failed to create ECC from public key PEM");
                 let ecc = PKey::from_ec_key(ecc).expect("failed to create PKey from ECC");
 
-                let mut verifier = Verifier::new(MessageDigest::sha256(), &ecc)
+                let mut verifier = Verifier::new(signature_digest(self.hash)?, &ecc)
                     .expect("failed to create verifier");
                 verifier
                     .update(data)
@@ -303,6 +273,21 @@ impl KeyHandle for YubiKeyProvider {
                     )));
                 }
             }
+
+            AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::Curve25519)) => {
+                let key = PKey::public_key_from_pem(self.pkey.trim().as_bytes())
+                    .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
+                let verified = Verifier::new_without_digest(&key)
+                    .and_then(|mut verifier| verifier.verify_oneshot(signature, data))
+                    .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
+                if verified {
+                    Ok(true)
+                } else {
+                    Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
+                        "Signature verification failed".to_string(),
+                    )))
+                }
+            }
             _ => {
                 return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
                     "Key Algorithm not supported".to_string(),
@@ -310,39 +295,184 @@ impl KeyHandle for YubiKeyProvider {
             }
         }
     }
+
+    /// Returns the attestation certificate of the key followed by the certificate of the
+    /// attestation key in slot f9, PEM encoded. Relying parties parse it with
+    /// `Attestation::from_pem` and verify it against the Yubico PIV attestation CAs.
+    ///
+    /// The certificate states where the key was generated and can not include a nonce, so
+    /// only an empty `nonce` is accepted. Relying parties that need freshness have to ask for
+    /// a signature over their nonce in addition.
+    #[instrument(skip(nonce))]
+    fn attest_key(&self, nonce: &[u8]) -> Result<Vec<u8>, SecurityModuleError> {
+        if !nonce.is_empty() {
+            return Err(SecurityModuleError::Hsm(HsmError::UnsupportedFeature(
+                "YubiKey attestations can not include a nonce".to_string(),
+            )));
+        }
+        Ok(self.read_attestation()?.to_pem())
+    }
+
+    /// Returns the certificate stored in the slot of the key, followed by the chain stored
+    /// with `store_certificate`.
+    #[instrument]
+    fn certificate_chain(&self) -> Result<Vec<Vec<u8>>, SecurityModuleError> {
+        self.read_certificate_chain()
+    }
+}
+
+impl YubiKeyProvider {
+    /// Derives the shared secret of the key with a peer's public key.
+    ///
+    /// # Arguments
+    ///
+    /// * `public_key` - The DER encoded `SubjectPublicKeyInfo` of the peer, an X25519 key for
+    ///   X25519 keys or a key on the same curve for ECC keys.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the shared secret on success, or a `SecurityModuleError` on
+    /// failure.
+    #[instrument(skip(public_key))]
+    pub fn derive_shared_secret(&self, public_key: &[u8]) -> Result<Vec<u8>, SecurityModuleError> {
+        let algorithm = self.key_algo.and_then(algorithm_id).ok_or_else(|| {
+            SecurityModuleError::Hsm(HsmError::DeviceSpecific(
+                "Key Algorithm not supported".to_string(),
+            ))
+        })?;
+        let peer = PKey::public_key_from_der(public_key)
+            .map_err(|e| SecurityModuleError::Hsm(HsmError::DeviceSpecific(e.to_string())))?;
+        // The device takes the raw X25519 public key and the uncompressed point of ECC keys.
+        let input = match (algorithm, peer.id()) {
+            (PivAlgorithm::X25519, Id::X25519) => peer.raw_public_key(),
+            (PivAlgorithm::EccP256 | PivAlgorithm::EccP384, Id::EC) => {
+                peer.ec_key().and_then(|ec| {
+                    let mut context = BigNumContext::new()?;
+                    ec.public_key().to_bytes(
+                        ec.group(),
+                        PointConversionForm::UNCOMPRESSED,
+                        &mut context,
+                    )
+                })
+            }
+            _ => {
+                return Err(SecurityModuleError::Hsm(HsmError::UnsupportedFeature(
+                    format!(
+                        "Shared secrets can not be derived with {:?} keys and this public key",
+                        algorithm
+                    ),
+                )))
+            }
+        }
+        .map_err(|e| SecurityModuleError::Hsm(HsmError::DeviceSpecific(e.to_string())))?;
+
+        self.confirm_touch("derive a shared secret")?;
+        let mut yubikey = self.device()?;
+        verify_pin(&mut yubikey, self.pin.as_bytes())?;
+        yubikey
+            .decrypt_data(&input, algorithm, self.slot_id.unwrap())
+            .map_err(|err| SecurityModuleError::Hsm(HsmError::DeviceSpecific(err.to_string())))
+    }
 }
 
-#[instrument]
-fn create_digest_info(digest: &[u8]) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-    let oid_sha256: [u8; 9] = [0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01]; // OID für SHA-256
-    let mut digest_info = vec![];
+/// Returns the digest signatures are created with, SHA-256 if no hash is configured.
+pub(super) fn signature_digest(hash: Option<Hash>) -> Result<MessageDigest, SecurityModuleError> {
+    match hash {
+        None | Some(Hash::Sha2(Sha2Bits::Sha256)) => Ok(MessageDigest::sha256()),
+        Some(Hash::Sha2(Sha2Bits::Sha384)) => Ok(MessageDigest::sha384()),
+        Some(Hash::Sha2(Sha2Bits::Sha512)) => Ok(MessageDigest::sha512()),
+        Some(hash) => Err(SecurityModuleError::Hsm(HsmError::UnsupportedFeature(
+            format!(
+                "Signing with {:?} is not supported, use SHA-256, SHA-384 or SHA-512",
+                hash
+            ),
+        ))),
+    }
+}
 
-    // ASN.1 SEQUENCE Header
-    digest_info.extend_from_slice(&[
-        0x30, 0x31, // SEQUENCE, Länge 49
-        0x30, 0x0d, // SEQUENCE, Länge 13
-        0x06, 0x09, // OID Header
-    ]);
-    digest_info.extend_from_slice(&oid_sha256); // OID SHA-256
-    digest_info.extend_from_slice(&[
-        0x05, 0x00, // NULL
-        0x04, 0x20, // OCTET STRING, Länge 32
-    ]);
-    digest_info.extend_from_slice(digest); // SHA-256 Hash-Wert
+/// Encodes a digest as RSASSA-PKCS1-v1_5 signature input (EMSA-PKCS1-v1_5, RFC 8017,
+/// section 9.2) for a key of `key_len` bytes.
+pub(crate) fn pkcs1v15_encode(
+    digest: MessageDigest,
+    hashed: &[u8],
+    key_len: usize,
+) -> Result<Vec<u8>, SecurityModuleError> {
+    // The DER encoded DigestInfo up to the digest, see note 1 of section 9.2.
+    let prefix: &[u8] = match digest.type_() {
+        Nid::SHA256 => &[
+            0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+            0x01, 0x05, 0x00, 0x04, 0x20,
+        ],
+        Nid::SHA384 => &[
+            0x30, 0x41, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+            0x02, 0x05, 0x00, 0x04, 0x30,
+        ],
+        Nid::SHA512 => &[
+            0x30, 0x51, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02,
+            0x03, 0x05, 0x00, 0x04, 0x40,
+        ],
+        _ => return Err(signing_error("Unsupported digest")),
+    };
+    let digest_info_len = prefix.len() + hashed.len();
+    if digest_info_len + 11 > key_len {
+        return Err(signing_error("The key is too short for the digest"));
+    }
 
-    Ok(digest_info)
+    let mut encoded = Vec::with_capacity(key_len);
+    encoded.extend_from_slice(&[0x00, 0x01]);
+    encoded.resize(key_len - digest_info_len - 1, 0xff);
+    encoded.push(0x00);
+    encoded.extend_from_slice(prefix);
+    encoded.extend_from_slice(hashed);
+    Ok(encoded)
 }
 
-#[instrument]
-fn apply_pkcs1v15_padding(data: &[u8], block_size: usize) -> Vec<u8> {
-    let padding_length = block_size - data.len() - 3;
-    let mut padded_data = Vec::with_capacity(block_size);
-    padded_data.push(0x00);
-    padded_data.push(0x01);
-    for _ in 0..padding_length {
-        padded_data.push(0xFF);
+/// Encodes a digest as RSASSA-PSS signature input (EMSA-PSS, RFC 8017, section 9.1) for a key
+/// of `key_len` bytes, using MGF1 with the same digest and a random salt of the digest length.
+pub(crate) fn pss_encode(
+    digest: MessageDigest,
+    hashed: &[u8],
+    key_len: usize,
+) -> Result<Vec<u8>, SecurityModuleError> {
+    let hash_len = hashed.len();
+    if key_len < 2 * hash_len + 2 {
+        return Err(signing_error(
+            "The key is too short for PSS with the digest",
+        ));
     }
-    padded_data.push(0x00);
-    padded_data.extend_from_slice(data);
-    padded_data
+    let mut salt = vec![0; hash_len];
+    rand_bytes(&mut salt).map_err(|e| signing_error(&e.to_string()))?;
+
+    let mut message = vec![0; 8];
+    message.extend_from_slice(hashed);
+    message.extend_from_slice(&salt);
+    let h = hash(digest, &message).map_err(|e| signing_error(&e.to_string()))?;
+
+    // DB = PS || 0x01 || salt, masked with MGF1(H).
+    let db_len = key_len - hash_len - 1;
+    let mut db = vec![0; db_len - hash_len - 1];
+    db.push(0x01);
+    db.extend_from_slice(&salt);
+    let mut counter: u32 = 0;
+    let mut offset = 0;
+    while offset < db_len {
+        let mut seed = h.to_vec();
+        seed.extend_from_slice(&counter.to_be_bytes());
+        let mask = hash(digest, &seed).map_err(|e| signing_error(&e.to_string()))?;
+        for (byte, mask) in db[offset..].iter_mut().zip(mask.iter()) {
+            *byte ^= mask;
+        }
+        offset += mask.len();
+        counter += 1;
+    }
+    // The encoded message has one bit less than the modulus, whose length is a multiple of 8.
+    db[0] &= 0x7f;
+
+    db.extend_from_slice(&h);
+    db.push(0xbc);
+    Ok(db)
+}
+
+fn signing_error(message: &str) -> SecurityModuleError {
+    SecurityModuleError::SigningError(message.to_string())
 }
@@ -0,0 +1,422 @@
+use crate::{
+    common::{
+        crypto::{
+            algorithms::{
+                encryption::{AsymmetricEncryption, EccCurves, EccSchemeAlgorithm},
+                KeyBits,
+            },
+            KeyUsage,
+        },
+        error::SecurityModuleError,
+    },
+    hsm::{self, core::error::HsmError},
+};
+use ::yubikey::{
+    piv::{AlgorithmId, RetiredSlotId, SlotId},
+    PinPolicy, TouchPolicy,
+};
+use base64::{engine::general_purpose, Engine};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Identifies a key metadata record and its format version.
+const RECORD_MAGIC: &[u8; 4] = b"CLKM";
+const RECORD_VERSION: u8 = 1;
+
+/// Tags of the fields of a metadata record.
+const TAG_KEY_ID: u8 = 0x01;
+const TAG_SLOT: u8 = 0x02;
+const TAG_ALGORITHM: u8 = 0x03;
+const TAG_USAGES: u8 = 0x04;
+const TAG_CREATED: u8 = 0x05;
+const TAG_PIN_POLICY: u8 = 0x06;
+const TAG_TOUCH_POLICY: u8 = 0x07;
+const TAG_PUBLIC_KEY: u8 = 0x08;
+
+/// The largest data object a YubiKey can store.
+pub const MAX_OBJECT_SIZE: usize = 3063;
+
+/// Object id of the first retired slot certificate object, `5FC10D`.
+const RETIRED_OBJECT_BASE: u32 = 0x005f_c10d;
+
+/// Describes a key stored on a YubiKey.
+///
+/// The serialized record starts with a magic and version, followed by fields encoded as a
+/// one byte tag, a big endian `u16` length and the value. Readers skip unknown tags, so fields
+/// can be added without a new version. Records written before this format, which separate the
+/// key id, object id and PEM public key by NUL bytes, can still be read.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyMetadata {
+    /// The id the key was created with.
+    pub key_id: String,
+    /// The PIV slot holding the private key.
+    pub slot: SlotId,
+    /// The algorithm of the key, unknown for old records.
+    pub algorithm: Option<PivAlgorithm>,
+    /// The usages the key was created for.
+    pub key_usages: Vec<KeyUsage>,
+    /// When the key was created, unknown for old records.
+    pub created: Option<SystemTime>,
+    /// Whether the PIN has to be verified to use the key.
+    pub pin_policy: PinPolicy,
+    /// Whether the key has to be touched to use the key.
+    pub touch_policy: TouchPolicy,
+    /// The DER encoded `SubjectPublicKeyInfo` of the key.
+    pub public_key: Vec<u8>,
+}
+
+impl KeyMetadata {
+    /// Returns the public key PEM encoded.
+    pub fn public_key_pem(&self) -> String {
+        format!(
+            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----",
+            general_purpose::STANDARD.encode(&self.public_key)
+        )
+    }
+
+    /// Serializes the record, failing if it does not fit into a data object.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SecurityModuleError> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(RECORD_MAGIC);
+        buffer.push(RECORD_VERSION);
+        put(&mut buffer, TAG_KEY_ID, self.key_id.as_bytes())?;
+        put(&mut buffer, TAG_SLOT, &[self.slot.into()])?;
+        if let Some(algorithm) = self.algorithm {
+            put(&mut buffer, TAG_ALGORITHM, &[algorithm.into()])?;
+        }
+        let usages: Vec<u8> = self
+            .key_usages
+            .iter()
+            .map(|usage| usage_id(*usage))
+            .collect();
+        put(&mut buffer, TAG_USAGES, &usages)?;
+        if let Some(created) = self.created {
+            let seconds = created
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |duration| duration.as_secs());
+            put(&mut buffer, TAG_CREATED, &seconds.to_be_bytes())?;
+        }
+        put(&mut buffer, TAG_PIN_POLICY, &[self.pin_policy.into()])?;
+        put(&mut buffer, TAG_TOUCH_POLICY, &[self.touch_policy.into()])?;
+        put(&mut buffer, TAG_PUBLIC_KEY, &self.public_key)?;
+
+        if buffer.len() > MAX_OBJECT_SIZE {
+            return Err(invalid(&format!(
+                "Record of {} bytes exceeds the object size limit of {} bytes",
+                buffer.len(),
+                MAX_OBJECT_SIZE
+            )));
+        }
+        Ok(buffer)
+    }
+
+    /// Parses a record produced by [`KeyMetadata::to_bytes`] or by earlier versions of this
+    /// crate.
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SecurityModuleError> {
+        match data.strip_prefix(RECORD_MAGIC) {
+            Some(record) => Self::parse(record),
+            None => Self::parse_legacy(data),
+        }
+    }
+
+    fn parse(record: &[u8]) -> Result<Self, SecurityModuleError> {
+        let (&version, mut fields) = record
+            .split_first()
+            .ok_or_else(|| invalid("Record is truncated"))?;
+        if version != RECORD_VERSION {
+            return Err(invalid(&format!("Unsupported record version {}", version)));
+        }
+
+        let mut key_id = None;
+        let mut slot = None;
+        let mut algorithm = None;
+        let mut key_usages = Vec::new();
+        let mut created = None;
+        let mut pin_policy = PinPolicy::Default;
+        let mut touch_policy = TouchPolicy::Default;
+        let mut public_key = None;
+        while !fields.is_empty() {
+            let (tag, value, rest) = take(fields)?;
+            fields = rest;
+            match tag {
+                TAG_KEY_ID => {
+                    key_id = Some(
+                        String::from_utf8(value.to_vec())
+                            .map_err(|_| invalid("Key id is not valid UTF-8"))?,
+                    )
+                }
+                TAG_SLOT => {
+                    slot =
+                        Some(SlotId::try_from(byte(value)?).map_err(|_| invalid("Invalid slot"))?)
+                }
+                TAG_ALGORITHM => {
+                    algorithm = Some(
+                        PivAlgorithm::try_from(byte(value)?)
+                            .map_err(|_| invalid("Unsupported algorithm"))?,
+                    )
+                }
+                TAG_USAGES => {
+                    key_usages = value
+                        .iter()
+                        .map(|id| usage(*id))
+                        .collect::<Result<_, _>>()?
+                }
+                TAG_CREATED => {
+                    let seconds = u64::from_be_bytes(
+                        value
+                            .try_into()
+                            .map_err(|_| invalid("Invalid creation time"))?,
+                    );
+                    created = Some(UNIX_EPOCH + Duration::from_secs(seconds));
+                }
+                TAG_PIN_POLICY => {
+                    pin_policy = PinPolicy::try_from(byte(value)?)
+                        .map_err(|_| invalid("Invalid PIN policy"))?
+                }
+                TAG_TOUCH_POLICY => {
+                    touch_policy = TouchPolicy::try_from(byte(value)?)
+                        .map_err(|_| invalid("Invalid touch policy"))?
+                }
+                TAG_PUBLIC_KEY => public_key = Some(value.to_vec()),
+                // Fields added by later versions.
+                _ => {}
+            }
+        }
+
+        Ok(Self {
+            key_id: key_id.ok_or_else(|| invalid("Record has no key id"))?,
+            slot: slot.ok_or_else(|| invalid("Record has no slot"))?,
+            algorithm,
+            key_usages,
+            created,
+            pin_policy,
+            touch_policy,
+            public_key: public_key.ok_or_else(|| invalid("Record has no public key"))?,
+        })
+    }
+
+    /// Parses a record of the form `key_id NUL object_id NUL pem NUL`, where `object_id` is
+    /// the retired slot certificate object the record is stored in, ten slots after the
+    /// slot of the key.
+    fn parse_legacy(data: &[u8]) -> Result<Self, SecurityModuleError> {
+        let parts: Vec<&[u8]> = data.split(|&x| x == 0).collect();
+        if parts.len() < 4 || parts[..3].iter().any(|part| part.is_empty()) {
+            return Err(invalid("Not a key metadata record"));
+        }
+        let text = |part: &[u8]| {
+            std::str::from_utf8(part)
+                .map(str::to_string)
+                .map_err(|_| invalid("Legacy record is not valid UTF-8"))
+        };
+
+        let object_id: u32 = text(parts[1])?
+            .parse()
+            .map_err(|_| invalid("Legacy record has an invalid object id"))?;
+        let slot = object_id
+            .checked_sub(RETIRED_OBJECT_BASE + 10)
+            .filter(|index| *index < 10)
+            .and_then(|index| RetiredSlotId::try_from(0x82 + index as u8).ok())
+            .ok_or_else(|| invalid("Legacy record has an invalid object id"))?;
+
+        let pem = text(parts[2])?;
+        let body: String = pem
+            .lines()
+            .filter(|line| !line.starts_with("-----"))
+            .collect();
+        let public_key = general_purpose::STANDARD
+            .decode(body.trim())
+            .map_err(|_| invalid("Legacy record has an invalid public key"))?;
+
+        Ok(Self {
+            key_id: text(parts[0])?,
+            slot: SlotId::Retired(slot),
+            algorithm: None,
+            key_usages: Vec::new(),
+            created: None,
+            pin_policy: PinPolicy::Default,
+            touch_policy: TouchPolicy::Default,
+            public_key,
+        })
+    }
+}
+
+/// The algorithm of a PIV key.
+///
+/// Extends `AlgorithmId` of the `yubikey` crate with the algorithms added by firmware 5.7,
+/// which the crate does not know yet. The ids are those of GENERATE ASYMMETRIC KEY PAIR.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum PivAlgorithm {
+    Rsa1024,
+    Rsa2048,
+    /// Requires firmware 5.7 or later.
+    Rsa3072,
+    /// Requires firmware 5.7 or later.
+    Rsa4096,
+    EccP256,
+    EccP384,
+    /// Requires firmware 5.7 or later.
+    Ed25519,
+    /// Requires firmware 5.7 or later.
+    X25519,
+}
+
+impl PivAlgorithm {
+    /// Returns the algorithm as known to the `yubikey` crate, if it is.
+    pub fn algorithm_id(self) -> Option<AlgorithmId> {
+        match self {
+            Self::Rsa1024 => Some(AlgorithmId::Rsa1024),
+            Self::Rsa2048 => Some(AlgorithmId::Rsa2048),
+            Self::EccP256 => Some(AlgorithmId::EccP256),
+            Self::EccP384 => Some(AlgorithmId::EccP384),
+            Self::Rsa3072 | Self::Rsa4096 | Self::Ed25519 | Self::X25519 => None,
+        }
+    }
+
+    /// Returns the length of the modulus in bytes for RSA keys.
+    pub fn rsa_len(self) -> Option<usize> {
+        match self {
+            Self::Rsa1024 => Some(128),
+            Self::Rsa2048 => Some(256),
+            Self::Rsa3072 => Some(384),
+            Self::Rsa4096 => Some(512),
+            _ => None,
+        }
+    }
+
+    pub fn is_rsa(self) -> bool {
+        self.rsa_len().is_some()
+    }
+}
+
+impl From<AlgorithmId> for PivAlgorithm {
+    fn from(algorithm: AlgorithmId) -> Self {
+        match algorithm {
+            AlgorithmId::Rsa1024 => Self::Rsa1024,
+            AlgorithmId::Rsa2048 => Self::Rsa2048,
+            AlgorithmId::EccP256 => Self::EccP256,
+            AlgorithmId::EccP384 => Self::EccP384,
+        }
+    }
+}
+
+impl TryFrom<u8> for PivAlgorithm {
+    type Error = ::yubikey::Error;
+
+    fn try_from(id: u8) -> Result<Self, Self::Error> {
+        match id {
+            0x05 => Ok(Self::Rsa3072),
+            0x16 => Ok(Self::Rsa4096),
+            0xe0 => Ok(Self::Ed25519),
+            0xe1 => Ok(Self::X25519),
+            id => AlgorithmId::try_from(id).map(Self::from),
+        }
+    }
+}
+
+impl From<PivAlgorithm> for u8 {
+    fn from(algorithm: PivAlgorithm) -> u8 {
+        match algorithm {
+            PivAlgorithm::Rsa3072 => 0x05,
+            PivAlgorithm::Rsa4096 => 0x16,
+            PivAlgorithm::Ed25519 => 0xe0,
+            PivAlgorithm::X25519 => 0xe1,
+            algorithm => algorithm.algorithm_id().unwrap().into(),
+        }
+    }
+}
+
+/// Returns the PIV algorithm of a key algorithm, if YubiKeys support it.
+pub(super) fn algorithm_id(algorithm: AsymmetricEncryption) -> Option<PivAlgorithm> {
+    match algorithm {
+        AsymmetricEncryption::Rsa(KeyBits::Bits1024) => Some(PivAlgorithm::Rsa1024),
+        AsymmetricEncryption::Rsa(KeyBits::Bits2048) => Some(PivAlgorithm::Rsa2048),
+        AsymmetricEncryption::Rsa(KeyBits::Bits3072) => Some(PivAlgorithm::Rsa3072),
+        AsymmetricEncryption::Rsa(KeyBits::Bits4096) => Some(PivAlgorithm::Rsa4096),
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256)) => {
+            Some(PivAlgorithm::EccP256)
+        }
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P384)) => {
+            Some(PivAlgorithm::EccP384)
+        }
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::Curve25519)) => {
+            Some(PivAlgorithm::Ed25519)
+        }
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDh(EccCurves::Curve25519)) => {
+            Some(PivAlgorithm::X25519)
+        }
+        _ => None,
+    }
+}
+
+/// Returns the YubiKey PIN policy of a configured policy.
+pub(super) fn pin_policy(policy: hsm::PinPolicy) -> PinPolicy {
+    match policy {
+        hsm::PinPolicy::Default => PinPolicy::Default,
+        hsm::PinPolicy::Never => PinPolicy::Never,
+        hsm::PinPolicy::Once => PinPolicy::Once,
+        hsm::PinPolicy::Always => PinPolicy::Always,
+    }
+}
+
+/// Returns the YubiKey touch policy of a configured policy.
+pub(super) fn touch_policy(policy: hsm::TouchPolicy) -> TouchPolicy {
+    match policy {
+        hsm::TouchPolicy::Default => TouchPolicy::Default,
+        hsm::TouchPolicy::Never => TouchPolicy::Never,
+        hsm::TouchPolicy::Always => TouchPolicy::Always,
+        hsm::TouchPolicy::Cached => TouchPolicy::Cached,
+    }
+}
+
+fn usage_id(usage: KeyUsage) -> u8 {
+    match usage {
+        KeyUsage::ClientAuth => 0,
+        KeyUsage::Decrypt => 1,
+        KeyUsage::SignEncrypt => 2,
+        KeyUsage::CreateX509 => 3,
+    }
+}
+
+fn usage(id: u8) -> Result<KeyUsage, SecurityModuleError> {
+    match id {
+        0 => Ok(KeyUsage::ClientAuth),
+        1 => Ok(KeyUsage::Decrypt),
+        2 => Ok(KeyUsage::SignEncrypt),
+        3 => Ok(KeyUsage::CreateX509),
+        _ => Err(invalid("Unknown key usage")),
+    }
+}
+
+fn put(buffer: &mut Vec<u8>, tag: u8, value: &[u8]) -> Result<(), SecurityModuleError> {
+    let len = u16::try_from(value.len()).map_err(|_| invalid("Field is too long"))?;
+    buffer.push(tag);
+    buffer.extend_from_slice(&len.to_be_bytes());
+    buffer.extend_from_slice(value);
+    Ok(())
+}
+
+/// Splits off the next field, returning its tag, value and the remaining fields.
+fn take(fields: &[u8]) -> Result<(u8, &[u8], &[u8]), SecurityModuleError> {
+    if fields.len() < 3 {
+        return Err(invalid("Record is truncated"));
+    }
+    let len = u16::from_be_bytes([fields[1], fields[2]]) as usize;
+    let rest = &fields[3..];
+    if rest.len() < len {
+        return Err(invalid("Record is truncated"));
+    }
+    Ok((fields[0], &rest[..len], &rest[len..]))
+}
+
+fn byte(value: &[u8]) -> Result<u8, SecurityModuleError> {
+    match value {
+        [byte] => Ok(*byte),
+        _ => Err(invalid("Field has an invalid length")),
+    }
+}
+
+fn invalid(message: &str) -> SecurityModuleError {
+    SecurityModuleError::Hsm(HsmError::DeviceSpecific(format!(
+        "Invalid key metadata: {}",
+        message
+    )))
+}
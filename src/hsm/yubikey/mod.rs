@@ -1,10 +1,37 @@
-use crate::common::crypto::algorithms::encryption::AsymmetricEncryption;
-use ::yubikey::{piv::RetiredSlotId, YubiKey};
+use crate::common::{
+    crypto::algorithms::{encryption::AsymmetricEncryption, hashes::Hash},
+    error::SecurityModuleError,
+    traits::credential_provider::CredentialProvider,
+};
+use crate::hsm::RsaSignaturePadding;
+use ::yubikey::{piv::SlotId, Serial, TouchPolicy, Version};
+use device::PivDevice;
+use metadata::KeyMetadata;
 use std::sync::{Arc, Mutex};
 use tracing::instrument;
+use zeroize::Zeroizing;
 
+pub(crate) mod apdu;
+pub mod attestation;
+pub mod certificate;
+pub(crate) mod device;
 pub mod key_handle;
+pub mod metadata;
+pub mod pin;
 pub mod provider;
+#[cfg(test)]
+pub(crate) mod simulator;
+
+/// A YubiKey attached to the system.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct YubiKeyDevice {
+    /// The serial number selecting the device in [`YubiKeyProvider::with_serial`].
+    pub serial: Serial,
+    /// The firmware version of the device.
+    pub version: Version,
+    /// The name of the smart card reader of the device.
+    pub reader: String,
+}
 
 /// A YubiKey-based cryptographic provider for managing cryptographic keys and performing
 /// cryptographic operations.
@@ -12,17 +39,35 @@ pub mod provider;
 /// This provider leverages the YubiKey API to interact with a YubiKey device for operations
 /// like signing, encryption, and decryption. It provides a secure and hardware-backed solution
 /// for managing cryptographic keys and performing cryptographic operations.
+///
+/// Keys can be RSA-1024, RSA-2048, ECDSA P-256 or ECDSA P-384 keys. YubiKeys with firmware
+/// 5.7 or later also support RSA-3072, RSA-4096, Ed25519 and X25519 keys, which are used
+/// through raw PIV commands as the `yubikey` crate does not know them yet. On older devices
+/// `create_key` rejects them with `HsmError::UnsupportedFeature`. X25519 keys can not sign,
+/// they derive shared secrets with `derive_shared_secret`.
 
 // #[derive(cloe, Debug)]???
 #[derive(Debug)]
 pub struct YubiKeyProvider {
     /// A unique identifier for the cryptographic key managed by this provider.
     pub(super) pkey: String,
-    pub(super) slot_id: Option<RetiredSlotId>,
+    pub(super) slot_id: Option<SlotId>,
+    /// The serial number of the YubiKey to open, if several may be attached.
+    pub(super) serial: Option<Serial>,
     pub(super) key_algo: Option<AsymmetricEncryption>,
-    pub(super) yubikey: Option<Arc<Mutex<YubiKey>>>,
-    pub(super) pin: String,
-    pub(super) management_key: Option<[u8; 24]>,
+    /// The hash signatures are created with, SHA-256 if not set.
+    pub(super) hash: Option<Hash>,
+    /// The padding of RSA signatures.
+    pub(super) rsa_padding: RsaSignaturePadding,
+    /// The device opened by `initialize_module`.
+    pub(super) yubikey: Option<Arc<Mutex<Box<dyn PivDevice>>>>,
+    pub(super) pin: Zeroizing<String>,
+    /// A 3DES or AES-128, AES-192 or AES-256 management key.
+    pub(super) management_key: Option<Zeroizing<Vec<u8>>>,
+    /// The metadata record of the created or loaded key.
+    pub(super) metadata: Option<KeyMetadata>,
+    /// Asked for the PIN and management key when the module is initialized.
+    pub(super) credentials: Option<Arc<dyn CredentialProvider>>,
 }
 
 impl YubiKeyProvider {
@@ -40,10 +85,88 @@ impl YubiKeyProvider {
         Self {
             pkey: String::new(),
             slot_id: None,
+            serial: None,
             key_algo: None,
+            hash: None,
+            rsa_padding: RsaSignaturePadding::default(),
             yubikey: None,
-            pin: String::new(),
+            pin: Zeroizing::new(String::new()),
             management_key: None,
+            metadata: None,
+            credentials: None,
+        }
+    }
+
+    /// Constructs a new `YubiKeyProvider` for the YubiKey with the given serial number.
+    ///
+    /// # Arguments
+    ///
+    /// * `key_id` - A string identifier for the cryptographic key to be managed by this provider.
+    /// * `serial` - The serial number of the YubiKey, as returned by
+    ///   [`YubiKeyProvider::list_devices`].
+    #[instrument]
+    pub fn with_serial(key_id: String, serial: Serial) -> Self {
+        Self {
+            serial: Some(serial),
+            ..Self::new(key_id)
+        }
+    }
+
+    /// Constructs a new `YubiKeyProvider` using the given device instead of opening a YubiKey
+    /// in `initialize_module`.
+    #[cfg(test)]
+    pub(crate) fn with_device(key_id: String, device: Box<dyn PivDevice>) -> Self {
+        Self {
+            yubikey: Some(Arc::new(Mutex::new(device))),
+            ..Self::new(key_id)
         }
     }
+
+    /// Returns the serial number of the YubiKey the provider was constructed for, if any.
+    pub fn serial(&self) -> Option<Serial> {
+        self.serial
+    }
+
+    /// Returns the metadata of the created or loaded key, if any.
+    pub fn metadata(&self) -> Option<&KeyMetadata> {
+        self.metadata.as_ref()
+    }
+
+    /// Asks the user to touch the YubiKey if the key requires a touch for every operation.
+    /// The prompt is shown even if a cached touch is still valid, as the device does not
+    /// report it.
+    ///
+    /// The prompt is advisory, as the device enforces the touch itself. It is skipped if the
+    /// credential provider cannot prompt, and only a user declining it cancels the operation.
+    ///
+    /// # Arguments
+    ///
+    /// * `operation` - The operation about to be performed, e.g. "sign".
+    pub(super) fn confirm_touch(&self, operation: &str) -> Result<(), SecurityModuleError> {
+        let (Some(metadata), Some(credentials)) = (&self.metadata, &self.credentials) else {
+            return Ok(());
+        };
+        if !credentials.can_confirm_presence() {
+            return Ok(());
+        }
+        match metadata.touch_policy {
+            TouchPolicy::Always | TouchPolicy::Cached => credentials.confirm_presence(&format!(
+                "Touch the YubiKey to {} with the key {}",
+                operation, metadata.key_id
+            )),
+            _ => Ok(()),
+        }
+    }
+
+    /// Returns the management key obtained from the credential provider.
+    pub(super) fn management_key(&self) -> Result<&[u8], SecurityModuleError> {
+        self.management_key
+            .as_ref()
+            .map(|key| key.as_slice())
+            .ok_or_else(|| {
+                SecurityModuleError::InitializationError(
+                    "No management key available from the credential provider".to_string(),
+                )
+            })
+    }
 }
@@ -0,0 +1,211 @@
+use super::{device::PivDevice, YubiKeyProvider};
+use crate::{
+    common::{
+        error::SecurityModuleError,
+        traits::credential_provider::{Credential, Secret},
+    },
+    hsm::core::error::HsmError,
+};
+use ::yubikey::{Error, MgmKey};
+use std::sync::MutexGuard;
+use tracing::instrument;
+use zeroize::Zeroizing;
+
+/// How a rotated management key is stored.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ManagementKeyMode {
+    /// The key has to be provided by the caller, optionally requiring a touch to use it.
+    Manual { require_touch: bool },
+    /// The key is stored in the PIV printed object, which can only be read after the PIN
+    /// has been verified.
+    PinProtected,
+}
+
+impl YubiKeyProvider {
+    /// Returns the number of PIN attempts left before the PIN is blocked.
+    ///
+    /// Querying the retries resets the PIN verification of the device, so the PIN is
+    /// verified again afterwards.
+    #[instrument]
+    pub fn pin_retries(&self) -> Result<u8, SecurityModuleError> {
+        let mut yubikey = self.device()?;
+        let retries = yubikey.get_pin_retries().map_err(device_error)?;
+        if retries > 0 {
+            verify_pin(&mut yubikey, self.pin.as_bytes())?;
+        }
+        Ok(retries)
+    }
+
+    /// Returns the number of PUK attempts left before the PUK is blocked.
+    ///
+    /// Requires firmware 5.3 or later, which reports the retries in the PUK metadata.
+    #[instrument]
+    pub fn puk_retries(&self) -> Result<u8, SecurityModuleError> {
+        let mut yubikey = self.device()?;
+        let retries = yubikey.get_puk_retries().map_err(|e| {
+            SecurityModuleError::Hsm(HsmError::UnsupportedFeature(format!(
+                "PUK metadata is not available: {}",
+                e
+            )))
+        })?;
+        retries.ok_or_else(|| {
+            SecurityModuleError::Hsm(HsmError::DeviceSpecific(
+                "The PUK metadata contains no retry count".to_string(),
+            ))
+        })
+    }
+
+    /// Changes the PIN from the one the module was initialized with to `new_pin`.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_pin` - The new PIN, 6 to 8 characters.
+    #[instrument(skip(new_pin))]
+    pub fn change_pin(&mut self, new_pin: &str) -> Result<(), SecurityModuleError> {
+        self.device()?
+            .change_pin(self.pin.as_bytes(), new_pin.as_ref())
+            .map_err(|e| pin_error(e, "PIN"))?;
+        self.pin = Zeroizing::new(new_pin.to_string());
+        Ok(())
+    }
+
+    /// Changes the PUK to `new_puk`. The current PUK is requested from the credential provider.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_puk` - The new PUK, 6 to 8 characters.
+    #[instrument(skip(new_puk))]
+    pub fn change_puk(&mut self, new_puk: &str) -> Result<(), SecurityModuleError> {
+        let puk = self.puk()?;
+        self.device()?
+            .change_puk(puk.expose(), new_puk.as_ref())
+            .map_err(|e| pin_error(e, "PUK"))
+    }
+
+    /// Unblocks the PIN after too many failed attempts and sets it to `new_pin`. The PUK is
+    /// requested from the credential provider.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_pin` - The new PIN, 6 to 8 characters.
+    #[instrument(skip(new_pin))]
+    pub fn unblock_pin(&mut self, new_pin: &str) -> Result<(), SecurityModuleError> {
+        let puk = self.puk()?;
+        let mut yubikey = self.device()?;
+        yubikey
+            .unblock_pin(puk.expose(), new_pin.as_ref())
+            .map_err(|e| pin_error(e, "PUK"))?;
+        verify_pin(&mut yubikey, new_pin.as_ref())?;
+        drop(yubikey);
+        self.pin = Zeroizing::new(new_pin.to_string());
+        Ok(())
+    }
+
+    /// Replaces the management key with a random 3DES key.
+    ///
+    /// The `yubikey` crate stores PIN-protected keys, which it can only do when the current key
+    /// is a 3DES key as well. Devices with an AES key, the default from firmware 5.7, fail with
+    /// `HsmError::UnsupportedFeature` for `ManagementKeyMode::PinProtected`.
+    ///
+    /// # Arguments
+    ///
+    /// * `mode` - Whether the new key is returned to the caller only or also stored on the
+    ///   device, protected by the PIN.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the new management key on success, or a `SecurityModuleError` on failure.
+    /// Unless it is PIN-protected, the key has to be stored by the caller, as it is needed to
+    /// create keys.
+    #[instrument]
+    pub fn rotate_management_key(
+        &mut self,
+        mode: ManagementKeyMode,
+    ) -> Result<[u8; 24], SecurityModuleError> {
+        let current = self.management_key()?;
+        let new = MgmKey::generate();
+
+        let mut yubikey = self.device()?;
+        verify_pin(&mut yubikey, self.pin.as_bytes())?;
+        authenticate(&mut yubikey, current)?;
+        yubikey
+            .set_management_key(&new, mode)
+            .map_err(|e| match e {
+                Error::NotSupported => SecurityModuleError::Hsm(HsmError::UnsupportedFeature(
+                    "PIN-protected management keys require a 3DES management key".to_string(),
+                )),
+                e => device_error(e),
+            })?;
+        drop(yubikey);
+
+        self.management_key = Some(Zeroizing::new(new.as_ref().to_vec()));
+        Ok(*new.as_ref())
+    }
+
+    /// Locks the YubiKey opened by `initialize_module`.
+    pub(super) fn device(&self) -> Result<MutexGuard<'_, Box<dyn PivDevice>>, SecurityModuleError> {
+        self.yubikey
+            .as_ref()
+            .ok_or_else(|| {
+                SecurityModuleError::InitializationError(
+                    "The module is not initialized".to_string(),
+                )
+            })?
+            .lock()
+            .map_err(|e| SecurityModuleError::Hsm(HsmError::DeviceSpecific(e.to_string())))
+    }
+
+    fn puk(&self) -> Result<Secret, SecurityModuleError> {
+        self.credentials
+            .as_ref()
+            .map(|credentials| credentials.credential(&Credential::Puk))
+            .transpose()?
+            .flatten()
+            .ok_or_else(|| {
+                SecurityModuleError::InitializationError(
+                    "No PUK available from the credential provider".to_string(),
+                )
+            })
+    }
+}
+
+/// Verifies the PIN, reporting a wrong PIN with the number of attempts left.
+pub(super) fn verify_pin(
+    yubikey: &mut Box<dyn PivDevice>,
+    pin: &[u8],
+) -> Result<(), SecurityModuleError> {
+    yubikey.verify_pin(pin).map_err(|e| pin_error(e, "PIN"))
+}
+
+/// Authenticates with the management key.
+pub(super) fn authenticate(
+    yubikey: &mut Box<dyn PivDevice>,
+    management_key: &[u8],
+) -> Result<(), SecurityModuleError> {
+    yubikey.authenticate(management_key).map_err(|e| match e {
+        Error::AuthenticationError => SecurityModuleError::AuthenticationFailed {
+            reason: "Wrong management key".to_string(),
+            retries: None,
+        },
+        e => device_error(e),
+    })
+}
+
+/// Maps the errors of PIN and PUK operations, which report the attempts left.
+fn pin_error(err: Error, credential: &str) -> SecurityModuleError {
+    match err {
+        Error::WrongPin { tries } => SecurityModuleError::AuthenticationFailed {
+            reason: format!("Wrong {}", credential),
+            retries: Some(tries),
+        },
+        Error::PinLocked => SecurityModuleError::AuthenticationFailed {
+            reason: format!("The {} is blocked", credential),
+            retries: Some(0),
+        },
+        e => device_error(e),
+    }
+}
+
+fn device_error(err: Error) -> SecurityModuleError {
+    SecurityModuleError::Hsm(HsmError::DeviceSpecific(err.to_string()))
+}
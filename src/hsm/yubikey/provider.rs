@@ -1,71 +1,72 @@
-use super::YubiKeyProvider;
+use super::{
+    certificate::write_placeholder_certificate,
+    device::{PivDevice, YubiKeyCard},
+    key_handle::signature_digest,
+    metadata::{algorithm_id, pin_policy, touch_policy, KeyMetadata, PivAlgorithm},
+    pin::{authenticate, verify_pin},
+    YubiKeyDevice, YubiKeyProvider,
+};
 use crate::common::{
-    crypto::algorithms::{
-        encryption::{AsymmetricEncryption, EccCurves, EccSchemeAlgorithm},
-        KeyBits,
-    },
+    crypto::algorithms::encryption::AsymmetricEncryption,
     error::SecurityModuleError,
-    traits::module_provider::Provider,
+    traits::{
+        credential_provider::{Credential, CredentialProvider, EnvCredentials},
+        module_provider::Provider,
+    },
 };
-use crate::hsm::{core::error::HsmError, HsmProviderConfig};
+use crate::hsm::{core::error::HsmError, HsmProviderConfig, PivSlot};
 use ::yubikey::{
-    piv::{self, AlgorithmId, RetiredSlotId, SlotId},
-    Error, YubiKey,
+    piv::{RetiredSlotId, SlotId},
+    reader::Context,
+    Error, Serial, Version, YubiKey,
 };
-use base64::{engine::general_purpose, Engine};
 use std::any::Any;
 use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use tracing::instrument;
-use x509_cert::der::Encode;
-use yubikey::MgmKey;
-
-const SLOTS: [RetiredSlotId; 20] = [
-    RetiredSlotId::R1,
-    RetiredSlotId::R2,
-    RetiredSlotId::R3,
-    RetiredSlotId::R4,
-    RetiredSlotId::R5,
-    RetiredSlotId::R6,
-    RetiredSlotId::R7,
-    RetiredSlotId::R8,
-    RetiredSlotId::R9,
-    RetiredSlotId::R10,
-    RetiredSlotId::R11,
-    RetiredSlotId::R12,
-    RetiredSlotId::R13,
-    RetiredSlotId::R14,
-    RetiredSlotId::R15,
-    RetiredSlotId::R16,
-    RetiredSlotId::R17,
-    RetiredSlotId::R18,
-    RetiredSlotId::R19,
-    RetiredSlotId::R20,
+use zeroize::Zeroizing;
+
+/// The slots keys can be created in, the standard slots followed by the retired slots.
+const SLOTS: [SlotId; 24] = [
+    SlotId::Authentication,
+    SlotId::Signature,
+    SlotId::KeyManagement,
+    SlotId::CardAuthentication,
+    SlotId::Retired(RetiredSlotId::R1),
+    SlotId::Retired(RetiredSlotId::R2),
+    SlotId::Retired(RetiredSlotId::R3),
+    SlotId::Retired(RetiredSlotId::R4),
+    SlotId::Retired(RetiredSlotId::R5),
+    SlotId::Retired(RetiredSlotId::R6),
+    SlotId::Retired(RetiredSlotId::R7),
+    SlotId::Retired(RetiredSlotId::R8),
+    SlotId::Retired(RetiredSlotId::R9),
+    SlotId::Retired(RetiredSlotId::R10),
+    SlotId::Retired(RetiredSlotId::R11),
+    SlotId::Retired(RetiredSlotId::R12),
+    SlotId::Retired(RetiredSlotId::R13),
+    SlotId::Retired(RetiredSlotId::R14),
+    SlotId::Retired(RetiredSlotId::R15),
+    SlotId::Retired(RetiredSlotId::R16),
+    SlotId::Retired(RetiredSlotId::R17),
+    SlotId::Retired(RetiredSlotId::R18),
+    SlotId::Retired(RetiredSlotId::R19),
+    SlotId::Retired(RetiredSlotId::R20),
 ];
 
-/// IDs/addresses for read/write objects operations;
+/// Object id of the metadata record of the key in `SLOTS[0]`, followed by those of the other
+/// slots. The range is not used by the PIV standard or YubiKey tools, so the certificate
+/// objects of all slots stay available for certificates.
+const METADATA_OBJECT_BASE: u32 = 0x005f_ff20;
+
+/// Object id of the certificate chain of the key in `SLOTS[0]`, followed by those of the other
+/// slots.
+const CHAIN_OBJECT_BASE: u32 = 0x005f_ff40;
+
+/// IDs/addresses of the certificate objects of retired slots 11 to 20, which held the metadata
+/// records of the keys in retired slots 1 to 10 before the records got objects of their own;
 /// see https://developers.yubico.com/yubico-piv-tool/Actions/read_write_objects.html
-const SLOTSU32: [u32; 20] = [
-    0x005f_c10d,
-    0x005f_c10e,
-    0x005f_c10f,
-    0x005f_c110,
-    0x005f_c111,
-    0x005f_c112,
-    0x005f_c113,
-    0x005f_c114,
-    0x005f_c115,
-    0x005f_c116,
-    0x005f_c117,
-    0x005f_c118,
-    0x005f_c119,
-    0x005f_c11a,
-    0x005f_c11b,
-    0x005f_c11c,
-    0x005f_c11d,
-    0x005f_c11e,
-    0x005f_c11f,
-    0x005f_c120,
-];
+const LEGACY_METADATA_OBJECTS: std::ops::RangeInclusive<u32> = 0x005f_c117..=0x005f_c120;
 
 /// Implements the `Provider` trait, providing cryptographic operations utilizing a YubiKey.
 ///
@@ -90,8 +91,9 @@ impl Provider for YubiKeyProvider {
     /// On failure, it returns a `yubikey::Error`.
     ///
     /// # Errors
-    /// Stick throws Error, if all Slots are used. We have coded a method to get all stored keys,
-    /// so that the user can see which slots are used.
+    /// Stick throws Error, if all Slots are used, or if the slot set in the configuration holds
+    /// another key. `list_keys` returns all stored keys, so that the user can see which slots
+    /// are used.
     /// We also coded a method, which can remove any stored key from the Yubikey.
 
     #[instrument]
@@ -101,107 +103,78 @@ impl Provider for YubiKeyProvider {
         config: Box<dyn Any>,
     ) -> Result<(), SecurityModuleError> {
         if let Some(hsm_config) = config.downcast_ref::<HsmProviderConfig>() {
+            signature_digest(hsm_config.hash)?;
             self.key_algo = Some(hsm_config.key_algorithm);
-            let key_algo = self.key_algo.clone().expect("No Key Algortihm found");
-
-            let slot: u32;
-            let slot_id;
-            let algorithm: AlgorithmId;
-
-            if !(self.load_key(key_id, config).is_ok()) {
-                let mut yubikey = self.yubikey.as_ref().unwrap().lock().unwrap();
-                let _ = yubikey.verify_pin(self.pin.as_ref());
-                let _ = yubikey.authenticate(MgmKey::new(self.management_key.unwrap()).unwrap());
-                match get_free_slot(&mut yubikey) {
-                    Ok(free) => {
-                        slot_id = free;
-                    }
-                    Err(err) => {
-                        return Err(SecurityModuleError::InitializationError(err.to_string()));
-                    }
-                }
-            } else {
-                slot_id = self.slot_id.unwrap();
-            }
-
-            fn generate_key(
-                mut yubikey: &mut YubiKey,
-                algorithm: AlgorithmId,
-                slot_id: RetiredSlotId,
-            ) -> Result<(RetiredSlotId, String), SecurityModuleError> {
-                let pkey: String;
-
-                let gen_key = piv::generate(
-                    &mut yubikey,
-                    SlotId::Retired(slot_id),
-                    algorithm,
-                    yubikey::PinPolicy::Default,
-                    yubikey::TouchPolicy::Default,
-                );
-                match gen_key {
-                    Ok(_) => {
-                        let gen_key = gen_key.as_ref().unwrap().to_der().unwrap();
-                        let gen_key = general_purpose::STANDARD.encode(&gen_key);
-                        let gen_key = format!(
-                            "-----BEGIN PUBLIC KEY-----\n{}\n-----END PUBLIC KEY-----",
-                            gen_key.trim()
-                        );
-                        pkey = gen_key;
-                    }
-                    Err(err) => {
-                        return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
-                            err.to_string(),
-                        )))
-                    }
-                }
-                Ok((slot_id, pkey))
-            }
-
-            match key_algo {
-                AsymmetricEncryption::Rsa(curve) => match curve {
-                    KeyBits::Bits1024 => algorithm = AlgorithmId::Rsa1024,
-                    KeyBits::Bits2048 => algorithm = AlgorithmId::Rsa2048,
-                    _ => {
-                        return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
-                            "Key Algorithm not supported".to_string(),
-                        )));
-                    }
-                },
-                AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(curve)) => match curve {
-                    EccCurves::P256 => algorithm = AlgorithmId::EccP256,
-                    EccCurves::P384 => algorithm = AlgorithmId::EccP384,
-                    _ => {
-                        return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
-                            "Key Algorithm not supported".to_string(),
-                        )));
-                    }
-                },
-                _ => {
-                    return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
-                        "Key Algorithm not supported".to_string(),
+            self.hash = hsm_config.hash;
+            self.rsa_padding = hsm_config.rsa_padding;
+            let key_algo = self.key_algo.expect("No Key Algortihm found");
+            let pinned = hsm_config.slot.map(slot_id).transpose()?;
+            let pin_policy = pin_policy(hsm_config.pin_policy);
+            let touch_policy = touch_policy(hsm_config.touch_policy);
+
+            let metadata = {
+                let mut yubikey = self.device()?;
+                verify_pin(&mut yubikey, self.pin.as_bytes())?;
+                let algorithm = supported_algorithm(key_algo, yubikey.version())?;
+                if hsm_config.placeholder_certificate && algorithm.algorithm_id().is_none() {
+                    return Err(SecurityModuleError::Hsm(HsmError::UnsupportedFeature(
+                        format!(
+                            "Placeholder certificates are not supported for {:?} keys",
+                            algorithm
+                        ),
                     )));
                 }
-            }
-
-            let mut yubikey = self.yubikey.as_ref().unwrap().lock().unwrap();
-            let (slot_id, pkey) = generate_key(&mut yubikey, algorithm, slot_id).unwrap();
-            self.slot_id = Some(slot_id);
-            self.pkey = pkey;
-
-            let pkey = self.pkey.clone();
-            slot = get_reference_u32slot(self.slot_id.unwrap());
-
-            let _ = yubikey.verify_pin(self.pin.as_ref());
-            let _ = yubikey.authenticate(MgmKey::new(self.management_key.unwrap()).unwrap());
-
-            match save_key_object(&mut yubikey, key_id, slot, &pkey) {
-                Ok(_) => Ok(()),
-                Err(err) => {
-                    return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
-                        err.to_string(),
-                    )))
+                authenticate(&mut yubikey, self.management_key()?)?;
+
+                let records = read_records(&mut yubikey);
+                let slot = select_slot(&records, key_id, pinned, hsm_config.replace_existing_key)?;
+                if pinned.is_some()
+                    && !hsm_config.replace_existing_key
+                    && !records.iter().any(|metadata| metadata.slot == slot)
+                    && holds_foreign_key(&mut yubikey, slot)
+                {
+                    return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(format!(
+                        "Slot {:?} already holds a key that was not created by this provider, \
+                         set replace_existing_key to replace it",
+                        slot
+                    ))));
                 }
+                let public_key = yubikey
+                    .generate(slot, algorithm, pin_policy, touch_policy)
+                    .map_err(|err| {
+                        SecurityModuleError::Hsm(HsmError::DeviceSpecific(err.to_string()))
+                    })?;
+                let metadata = KeyMetadata {
+                    key_id: key_id.to_string(),
+                    slot,
+                    algorithm: Some(algorithm),
+                    key_usages: hsm_config.key_usages.clone(),
+                    created: Some(SystemTime::now()),
+                    pin_policy,
+                    touch_policy,
+                    public_key,
+                };
+                let mut record = metadata.to_bytes()?;
+                yubikey
+                    .save_object(metadata_object(slot), &mut record)
+                    .map_err(|err| {
+                        SecurityModuleError::Hsm(HsmError::DeviceSpecific(err.to_string()))
+                    })?;
+                metadata
+            };
+            self.slot_id = Some(metadata.slot);
+            self.pkey = metadata.public_key_pem();
+            self.metadata = Some(metadata);
+
+            if hsm_config.placeholder_certificate {
+                // The certificate is signed with the new key.
+                self.confirm_touch("sign a placeholder certificate")?;
+                let mut yubikey = self.device()?;
+                verify_pin(&mut yubikey, self.pin.as_bytes())?;
+                authenticate(&mut yubikey, self.management_key()?)?;
+                write_placeholder_certificate(&mut yubikey, self.metadata.as_ref().unwrap())?;
             }
+            Ok(())
         } else {
             Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
                 "Failed to get the Configurations".to_string(),
@@ -227,46 +200,37 @@ impl Provider for YubiKeyProvider {
     #[instrument]
     fn load_key(&mut self, key_id: &str, config: Box<dyn Any>) -> Result<(), SecurityModuleError> {
         if let Some(hsm_config) = config.downcast_ref::<HsmProviderConfig>() {
-            self.key_algo = Some(hsm_config.key_algorithm);
-            let mut yubikey = self.yubikey.as_ref().unwrap().lock().unwrap();
-            let mut found = false;
-            for i in 10..20 {
-                let _ = yubikey.verify_pin(self.pin.as_ref());
-                let _ = yubikey.authenticate(MgmKey::new(self.management_key.unwrap()).unwrap());
-                let data = yubikey.fetch_object(SLOTSU32[i]);
-                let output: Vec<u8>;
-                match data {
-                    Ok(data) => {
-                        output = data.to_vec();
-                    }
-                    Err(_) => {
-                        continue;
-                    }
+            signature_digest(hsm_config.hash)?;
+            let metadata = {
+                let mut yubikey = self.device()?;
+                // Verify the PIN once, as every failed attempt counts towards blocking it.
+                verify_pin(&mut yubikey, self.pin.as_bytes())?;
+                read_records(&mut yubikey)
+                    .into_iter()
+                    .find(|metadata| metadata.key_id == key_id)
+                    .ok_or_else(|| {
+                        SecurityModuleError::Hsm(HsmError::DeviceSpecific(
+                            "Key not found".to_string(),
+                        ))
+                    })?
+            };
+            // The key is used with the algorithm it was created with. Records written before
+            // the algorithm was stored leave it to the configuration.
+            if let Some(algorithm) = metadata.algorithm {
+                if algorithm_id(hsm_config.key_algorithm) != Some(algorithm) {
+                    return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(format!(
+                        "Key was created with {:?}, not the configured algorithm",
+                        algorithm
+                    ))));
                 }
-
-                let data = output;
-                match parse_slot_data(&data) {
-                    Ok((key_name, _, public_key)) => {
-                        if key_name == key_id.to_string() {
-                            self.slot_id = Some(SLOTS[i - 10]);
-                            self.pkey = public_key;
-                            found = true;
-                            break;
-                        }
-                    }
-                    Err(_) => {
-                        continue;
-                    }
-                }
-            }
-
-            if !found {
-                return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
-                    "Key not found".to_string(),
-                )));
-            } else {
-                Ok(())
             }
+            self.key_algo = Some(hsm_config.key_algorithm);
+            self.hash = hsm_config.hash;
+            self.rsa_padding = hsm_config.rsa_padding;
+            self.slot_id = Some(metadata.slot);
+            self.pkey = metadata.public_key_pem();
+            self.metadata = Some(metadata);
+            Ok(())
         } else {
             Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
                 "Failed to get the Configurations".to_string(),
@@ -290,229 +254,317 @@ impl Provider for YubiKeyProvider {
     /// On failure, it returns a Yubikey based `Error`.
     #[instrument]
     fn initialize_module(&mut self) -> Result<(), SecurityModuleError> {
-        let yubi = YubiKey::open().map_err(|_| Error::NotFound);
-        let mut yubikey: YubiKey;
-        match yubi {
-            Ok(yubi) => {
-                yubikey = yubi;
-            }
-            Err(err) => {
-                return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
-                    err.to_string(),
-                )));
-            }
+        // A device opened before, or given to the provider, is kept.
+        let device = match &self.yubikey {
+            Some(device) => device.clone(),
+            None => Arc::new(Mutex::new(
+                Box::new(YubiKeyCard::new(open_device(self.serial)?)) as Box<dyn PivDevice>,
+            )),
+        };
+        let mut yubikey = device
+            .lock()
+            .map_err(|e| SecurityModuleError::Hsm(HsmError::DeviceSpecific(e.to_string())))?;
+        // Never fall back to the default PIN and management key of the device.
+        let credentials = self
+            .credentials
+            .clone()
+            .unwrap_or_else(|| Arc::new(EnvCredentials::default()));
+        let pin = credentials.credential(&Credential::Pin)?.ok_or_else(|| {
+            SecurityModuleError::InitializationError(
+                "No PIN available from the credential provider".to_string(),
+            )
+        })?;
+        self.pin = String::from_utf8(pin.expose().to_vec())
+            .map(Zeroizing::new)
+            .map_err(|_| {
+                SecurityModuleError::InitializationError("The PIN is not valid UTF-8".to_string())
+            })?;
+        self.management_key = credentials
+            .credential(&Credential::ManagementKey)?
+            .map(|key| match key.expose().len() {
+                16 | 24 | 32 => Ok(Zeroizing::new(key.expose().to_vec())),
+                _ => Err(SecurityModuleError::InitializationError(
+                    "The management key must be 16, 24 or 32 bytes long".to_string(),
+                )),
+            })
+            .transpose()?;
+        self.credentials = Some(credentials);
+
+        verify_pin(&mut yubikey, self.pin.as_bytes())?;
+        // Without a management key from the credential provider, use the PIN-protected one
+        // stored on the device, if there is one.
+        if self.management_key.is_none() {
+            self.management_key = yubikey
+                .protected_management_key()
+                .ok()
+                .map(|key| Zeroizing::new(key.as_ref().to_vec()));
         }
-        // Hier muesste die Pin Eingabe und die Managementkey Eingabe implementiert werden. Ist aktuell hardcoded.
-        self.pin = "123456".to_string();
-        self.management_key = Some(*MgmKey::default().as_ref());
+        drop(yubikey);
+        self.yubikey = Some(device);
 
-        let verify = yubikey.verify_pin(self.pin.as_ref());
-        match verify {
-            Ok(_) => {
-                self.yubikey = Some(Arc::new(Mutex::new(yubikey)));
+        Ok(())
+    }
 
-                Ok(())
-            }
-            Err(err) => {
-                return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
-                    err.to_string(),
-                )));
-            }
-        }
+    /// Sets the credential provider asked for the PIN and management key by
+    /// `initialize_module`. Without one, they are read from the `CRYPTO_LAYER_PIN` and
+    /// `CRYPTO_LAYER_MANAGEMENT_KEY` environment variables.
+    ///
+    /// # Arguments
+    ///
+    /// * `credentials` - The `CredentialProvider` to ask for credentials.
+    fn set_credential_provider(&mut self, credentials: Arc<dyn CredentialProvider>) {
+        self.credentials = Some(credentials);
     }
 }
 
-/// Saves the key object to the YubiKey device.
-///
-/// This method saves a object to the YubiKey device. The object is stored in a slot and represents
-/// information about the key, such as the key name, slot, key and public key. This information
-/// belongs to a private key which is stored in a other Slot.
-///
-/// # Arguments
-/// * 'key_id' - A string slice that uniquely identifies the key for later usage.
-/// * 'slot_id' - An address where an object will be stored must be given.
-/// * 'pkey' - The public key which is intended to be stored.
-///
-/// # Returns
-///
-/// The saved Object will be stored in the Yubikey on a free Retired slot as Object with futher information
-/// A `Result` that, on success, contains `Ok()`.
-/// On failure, it returns a `yubikey::Error`.
-fn save_key_object(
-    yubikey: &mut YubiKey,
-    key_id: &str,
-    slot_id: u32,
-    pkey: &str,
-) -> Result<(), yubikey::Error> {
-    let key_name = key_id;
-    let slot = slot_id.to_string();
-    let public_key = pkey;
-
-    let total_length = key_name.len() + 1 + slot.len() + 1 + 1 + public_key.len();
-    let mut data = vec![0u8; total_length];
-    let data_slice: &mut [u8] = &mut data;
-
-    let mut offset = 0;
-    data_slice[offset..offset + key_name.len()].copy_from_slice(key_name.as_bytes());
-    offset += key_name.len();
-    data_slice[offset] = 0;
-    offset += 1;
-
-    data_slice[offset..offset + slot.len()].copy_from_slice(slot.as_bytes());
-    offset += slot.len();
-    data_slice[offset] = 0;
-    offset += 1;
-
-    data_slice[offset..offset + public_key.len()].copy_from_slice(public_key.as_bytes());
-
-    let saved = yubikey.save_object(slot_id, data_slice);
-    match saved {
-        Ok(()) => Ok(()),
-        Err(err) => Err(err),
+impl YubiKeyProvider {
+    /// Lists the YubiKeys attached to the system. Devices used by another application without
+    /// sharing the connection are skipped.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` that, on success, contains the attached devices, ordered by serial number.
+    /// On failure, it returns a `SecurityModuleError` if the smart card service is unavailable.
+    #[instrument]
+    pub fn list_devices() -> Result<Vec<YubiKeyDevice>, SecurityModuleError> {
+        let mut context = Context::open()
+            .map_err(|e| SecurityModuleError::Hsm(HsmError::DeviceSpecific(e.to_string())))?;
+        let readers = context
+            .iter()
+            .map_err(|e| SecurityModuleError::Hsm(HsmError::DeviceSpecific(e.to_string())))?;
+        let mut devices: Vec<YubiKeyDevice> = readers
+            .filter_map(|reader| {
+                let yubikey = reader.open().ok()?;
+                Some(YubiKeyDevice {
+                    serial: yubikey.serial(),
+                    version: yubikey.version(),
+                    reader: reader.name().into_owned(),
+                })
+            })
+            .collect();
+        devices.sort_by_key(|device| device.serial.0);
+        Ok(devices)
+    }
+
+    /// Lists the keys created on the YubiKey.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` that, on success, contains the metadata of the keys, ordered by slot.
+    /// On failure, it returns a `SecurityModuleError`.
+    #[instrument]
+    pub fn list_keys(&self) -> Result<Vec<KeyMetadata>, SecurityModuleError> {
+        let mut yubikey = self.device()?;
+        verify_pin(&mut yubikey, self.pin.as_bytes())?;
+        Ok(read_records(&mut yubikey))
     }
 }
 
-/// parses the u8 Data to different Key-Information Strings
-///
-/// This method creates a persisted cryptographic key using the specified algorithm
-/// and identifier, making it retrievable for future operations. The key is created
-/// and stored in the YubiKey.
-///
-/// # Arguments
+/// Returns the PIV algorithm of a key algorithm the device supports.
 ///
-///* 'data' - This array reference contains important information, it provides: the key name, the slot where it is stored and the public key itself
-///
-/// # Returns
-///
-/// A `Result` that, on success, contains `Ok(key_name, slot, public_key)` where the individual information is given.
-/// On failure, it returns a `Utf8Error`.
-fn parse_slot_data(data: &[u8]) -> Result<(String, String, String), SecurityModuleError> {
-    let parts: Vec<&[u8]> = data.split(|&x| x == 0).collect();
-    if !(parts.len() < 4 || parts[0].is_empty() || parts[1].is_empty() || parts[2].is_empty()) {
-        let key_name = std::str::from_utf8(parts[0]).unwrap();
-        let slot = std::str::from_utf8(parts[1]).unwrap();
-        let public_key = std::str::from_utf8(parts[2]).unwrap();
-
-        Ok((
-            key_name.to_string(),
-            slot.to_string(),
-            public_key.to_string(),
+/// Firmware 5.7 added RSA-3072, RSA-4096, Ed25519 and X25519 keys. Older devices report them
+/// as unsupported along with the reason.
+fn supported_algorithm(
+    algorithm: AsymmetricEncryption,
+    version: Version,
+) -> Result<PivAlgorithm, SecurityModuleError> {
+    let piv_algorithm = algorithm_id(algorithm).ok_or_else(|| {
+        SecurityModuleError::Hsm(HsmError::DeviceSpecific(
+            "Key Algorithm not supported".to_string(),
         ))
-    } else {
-        return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
-            "Failed to verify PIN, retries: {}".to_string(),
-        )));
+    })?;
+    if piv_algorithm.algorithm_id().is_some() || (version.major, version.minor) >= (5, 7) {
+        return Ok(piv_algorithm);
     }
+    let name = match piv_algorithm {
+        PivAlgorithm::Rsa3072 => "RSA-3072",
+        PivAlgorithm::Rsa4096 => "RSA-4096",
+        PivAlgorithm::Ed25519 => "Ed25519",
+        _ => "X25519",
+    };
+    Err(SecurityModuleError::Hsm(HsmError::UnsupportedFeature(
+        format!(
+            "{} keys require YubiKey firmware 5.7 or later, the device has firmware {}",
+            name, version
+        ),
+    )))
 }
 
-/// Gets a free slot for storing a key object.
-///
-/// This method goes through the available slots on the YubiKey and returns the first free slot
-///
-/// # Arguments
-/// The method takes a Yubikey device as an input
-///
-/// # Returns
+/// Opens the YubiKey with the given serial number, or the only attached one.
+fn open_device(serial: Option<Serial>) -> Result<YubiKey, SecurityModuleError> {
+    if let Some(serial) = serial {
+        return YubiKey::open_by_serial(serial).map_err(|e| {
+            SecurityModuleError::Hsm(HsmError::DeviceSpecific(match e {
+                Error::NotFound => format!("No YubiKey with serial number {} attached", serial),
+                e => e.to_string(),
+            }))
+        });
+    }
+
+    // `YubiKey::open` reports several attached devices as a generic PC/SC error.
+    YubiKey::open().map_err(|e| {
+        let devices = YubiKeyProvider::list_devices().unwrap_or_default();
+        SecurityModuleError::Hsm(HsmError::DeviceSpecific(match devices.len() {
+            0 => Error::NotFound.to_string(),
+            1 => e.to_string(),
+            _ => format!(
+                "Several YubiKeys are attached ({}), select one by serial number",
+                devices
+                    .iter()
+                    .map(|device| device.serial.to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ),
+        }))
+    })
+}
+
+/// Reads the metadata records of all slots.
 ///
-/// A `Result` that, on failure, returns the first free slot.
-/// On Success, it returns that no more free slots are available.
-fn get_free_slot(yubikey: &mut YubiKey) -> Result<RetiredSlotId, SecurityModuleError> {
-    let mut end = false;
-    let mut slot_id: RetiredSlotId = SLOTS[0];
-    let mut counter = 0;
-    for i in 10..20 {
-        let data = yubikey.fetch_object(SLOTSU32[i]);
-        let output: Vec<u8>;
-        match data {
-            Ok(data) => {
-                output = data.to_vec();
-            }
-            Err(_) => {
-                continue;
+/// Slots without a record of their own are looked up in the objects earlier versions stored
+/// the records in. Objects that do not hold a record are skipped.
+fn read_records(yubikey: &mut Box<dyn PivDevice>) -> Vec<KeyMetadata> {
+    let mut records: Vec<KeyMetadata> = SLOTS
+        .iter()
+        .filter_map(|slot| read_record(yubikey, metadata_object(*slot)))
+        .collect();
+    for object_id in LEGACY_METADATA_OBJECTS {
+        if let Some(legacy) = read_record(yubikey, object_id) {
+            if !records.iter().any(|metadata| metadata.slot == legacy.slot) {
+                records.push(legacy);
             }
         }
-        let data = output;
-        let parsed = parse_slot_data(&data);
-        if !parsed.is_ok() {
-            slot_id = SLOTS[i - 10];
-            end = true;
-        }
-        // match parsed {
-        //     Ok(_) => {
-        //         continue;
-        //     }
-        //     Err(_) => {
-
-        // }
-        if end {
-            break;
-        }
-        counter += 1;
-    }
-    if counter <= 9 {
-        Ok(slot_id)
-    } else {
-        let _ = list_all_slots(yubikey);
-
-        return Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
-            "No more free slots available".to_string(),
-        )));
     }
+    records.sort_by_key(|metadata| slot_index(metadata.slot));
+    records
 }
 
-/// Converts a `RetiredSlotId` to its corresponding u32 value.
-///
-/// This method converts a `RetiredSlotId` to its corresponding u32 value,
-/// which is required for slot operations on the YubiKey device.
-///
-/// # Arguments
-///
-/// * `slot` - The `RetiredSlotId` to be converted.
-///
-/// # Returns
+pub(super) fn read_record(yubikey: &mut Box<dyn PivDevice>, object_id: u32) -> Option<KeyMetadata> {
+    let data = yubikey.fetch_object(object_id).ok()?;
+    KeyMetadata::from_bytes(&data).ok()
+}
+
+/// Selects the slot to create a key in.
 ///
-/// The corresponding u32 value of the `RetiredSlotId`.
-fn get_reference_u32slot(slot: RetiredSlotId) -> u32 {
-    let mut output: u32 = SLOTSU32[0];
-    for i in 0..20 {
-        if SLOTS[i] == slot {
-            output = SLOTSU32[i + 10];
-            break;
-        } else {
-            continue;
+/// A key is only recreated in the slot it already occupies if `replace` is set, and never
+/// replaces a different key in a pinned slot. Keys that are not pinned get the first retired
+/// slot without a key, as the standard slots are reserved for their purpose.
+fn select_slot(
+    records: &[KeyMetadata],
+    key_id: &str,
+    pinned: Option<SlotId>,
+    replace: bool,
+) -> Result<SlotId, SecurityModuleError> {
+    let existing = records.iter().find(|metadata| metadata.key_id == key_id);
+    match (pinned, existing) {
+        (Some(slot), Some(existing)) if existing.slot != slot => {
+            Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(format!(
+                "The key {} already exists in slot {:?}",
+                key_id, existing.slot
+            ))))
         }
+        (Some(slot), _) => match records.iter().find(|metadata| metadata.slot == slot) {
+            Some(occupant) if occupant.key_id != key_id => {
+                Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(format!(
+                    "Slot {:?} already holds the key {}",
+                    slot, occupant.key_id
+                ))))
+            }
+            Some(_) if !replace => Err(already_exists(key_id, slot)),
+            _ => Ok(slot),
+        },
+        (None, Some(existing)) if !replace => Err(already_exists(key_id, existing.slot)),
+        (None, Some(existing)) => Ok(existing.slot),
+        (None, None) => SLOTS
+            .iter()
+            .filter(|slot| matches!(slot, SlotId::Retired(_)))
+            .find(|slot| !records.iter().any(|metadata| metadata.slot == **slot))
+            .copied()
+            .ok_or_else(|| {
+                SecurityModuleError::Hsm(HsmError::DeviceSpecific(
+                    "No more free slots available".to_string(),
+                ))
+            }),
     }
-    output
 }
 
-fn list_all_slots(yubikey: &mut YubiKey) -> Result<Vec<String>, SecurityModuleError> {
-    let mut output: Vec<String> = Vec::new();
-    for i in 10..20 {
-        let data = yubikey.fetch_object(SLOTSU32[i]);
-        let temp_vec: Vec<u8>;
-        match data {
-            Ok(data) => {
-                temp_vec = data.to_vec();
-            }
-            Err(_) => {
-                continue;
-            }
-        }
-        let data = temp_vec;
-        match parse_slot_data(&data) {
-            Ok((key_name, slot, pkey)) => {
-                let output_string = format!(
-                    "Key Name: {}, Slot: {}, Public-Key: {}\n",
-                    key_name, slot, pkey
-                );
-                output.push(output_string);
-            }
-            Err(_) => {
-                continue;
-            }
+fn already_exists(key_id: &str, slot: SlotId) -> SecurityModuleError {
+    SecurityModuleError::Hsm(HsmError::DeviceSpecific(format!(
+        "The key {} already exists in slot {:?}, set replace_existing_key to replace it",
+        key_id, slot
+    )))
+}
+
+/// Whether `slot`, which has no record of this provider, holds a key anyway.
+///
+/// Firmware 5.3 and later report this in the slot metadata. Older devices only reveal keys
+/// that have a certificate, as PIV tools store one with every key they enroll.
+fn holds_foreign_key(yubikey: &mut Box<dyn PivDevice>, slot: SlotId) -> bool {
+    let version = yubikey.version();
+    if (version.major, version.minor) >= (5, 3) {
+        if let Ok(has_key) = yubikey.has_key(slot) {
+            return has_key;
         }
     }
-    Ok(output)
+    yubikey.read_certificate(slot).is_ok()
+}
+
+/// Converts a slot of the configuration to the `SlotId` of the YubiKey.
+fn slot_id(slot: PivSlot) -> Result<SlotId, SecurityModuleError> {
+    match slot {
+        PivSlot::Authentication => Ok(SlotId::Authentication),
+        PivSlot::Signature => Ok(SlotId::Signature),
+        PivSlot::KeyManagement => Ok(SlotId::KeyManagement),
+        PivSlot::CardAuthentication => Ok(SlotId::CardAuthentication),
+        PivSlot::Retired(number @ 1..=20) => RetiredSlotId::try_from(0x81 + number)
+            .map(SlotId::Retired)
+            .map_err(|e| SecurityModuleError::Hsm(HsmError::DeviceSpecific(e.to_string()))),
+        PivSlot::Retired(number) => Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(
+            format!("There is no retired slot {}", number),
+        ))),
+    }
+}
+
+fn slot_index(slot: SlotId) -> usize {
+    SLOTS
+        .iter()
+        .position(|candidate| *candidate == slot)
+        .unwrap_or(SLOTS.len())
+}
+
+/// Returns the object id of the metadata record of the key in `slot`.
+pub(super) fn metadata_object(slot: SlotId) -> u32 {
+    METADATA_OBJECT_BASE + slot_index(slot) as u32
+}
+
+/// Returns the object id of the certificate chain of the key in `slot`.
+pub(super) fn chain_object(slot: SlotId) -> u32 {
+    CHAIN_OBJECT_BASE + slot_index(slot) as u32
+}
+
+/// Moves a metadata record written by earlier versions out of the certificate object of
+/// `slot`, before a certificate is written to it. Requires the management key.
+pub(super) fn migrate_legacy_record(
+    yubikey: &mut Box<dyn PivDevice>,
+    slot: SlotId,
+) -> Result<(), SecurityModuleError> {
+    let SlotId::Retired(retired) = slot else {
+        return Ok(());
+    };
+    // The certificate objects of the retired slots follow each other, starting at 5FC10D.
+    let object_id = 0x005f_c10d + (u8::from(retired) - 0x82) as u32;
+    if !LEGACY_METADATA_OBJECTS.contains(&object_id) {
+        return Ok(());
+    }
+    let Some(legacy) = read_record(yubikey, object_id) else {
+        return Ok(());
+    };
+    if read_record(yubikey, metadata_object(legacy.slot)).is_none() {
+        yubikey
+            .save_object(metadata_object(legacy.slot), &mut legacy.to_bytes()?)
+            .map_err(|e| SecurityModuleError::Hsm(HsmError::DeviceSpecific(e.to_string())))?;
+    }
+    Ok(())
 }
 
 /*
@@ -0,0 +1,613 @@
+use super::{
+    apdu::{certificate_object, encode_certificate},
+    device::PivDevice,
+    metadata::{PivAlgorithm, MAX_OBJECT_SIZE},
+    pin::ManagementKeyMode,
+};
+use ::yubikey::{
+    piv::{AlgorithmId, SlotId},
+    Error, MgmKey, PinPolicy, Result, TouchPolicy, Version,
+};
+use openssl::{
+    asn1::{Asn1Object, Asn1OctetString, Asn1Time},
+    bn::{BigNum, BigNumContext},
+    derive::Deriver,
+    ec::{EcGroup, EcKey, EcPoint},
+    ecdsa::EcdsaSig,
+    error::ErrorStack,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{HasPublic, Id, PKey, Private},
+    rsa::{Padding, Rsa},
+    sign::Signer,
+    x509::{extension::BasicConstraints, X509Extension, X509Name, X509},
+};
+use std::{
+    collections::BTreeMap,
+    sync::{Arc, Mutex, MutexGuard},
+};
+use x509_cert::{
+    der::Encode, name::Name, serial_number::SerialNumber, spki::SubjectPublicKeyInfoOwned,
+    time::Validity,
+};
+
+/// The credentials of a new device.
+pub(crate) const DEFAULT_PIN: &str = "123456";
+pub(crate) const DEFAULT_PUK: &str = "12345678";
+
+/// The serial number of simulated devices.
+pub(crate) const SERIAL: u32 = 12345678;
+
+const MAX_RETRIES: u8 = 3;
+
+/// An in-memory PIV device with software keys, for testing the YubiKey provider without a
+/// YubiKey.
+///
+/// It enforces the PIN and management key like a YubiKey with default credentials, but does
+/// not require touches. Like a YubiKey, it supports RSA-3072, RSA-4096, Ed25519 and X25519
+/// keys from firmware 5.7. Clones share the same device, like several connections to one key.
+#[derive(Debug, Clone)]
+pub(crate) struct SimulatedPivDevice {
+    state: Arc<Mutex<State>>,
+}
+
+#[derive(Debug)]
+struct State {
+    version: Version,
+    pin: Vec<u8>,
+    puk: Vec<u8>,
+    pin_retries: u8,
+    puk_retries: u8,
+    pin_verified: bool,
+    management_key: Vec<u8>,
+    management_key_protected: bool,
+    authenticated: bool,
+    objects: BTreeMap<u32, Vec<u8>>,
+    keys: BTreeMap<u8, SlotKey>,
+    attestation_key: PKey<Private>,
+}
+
+#[derive(Debug)]
+struct SlotKey {
+    algorithm: PivAlgorithm,
+    key: PKey<Private>,
+    pin_policy: PinPolicy,
+    touch_policy: TouchPolicy,
+}
+
+impl SimulatedPivDevice {
+    /// Creates a device with firmware 5.4.3 and the default credentials.
+    pub(crate) fn new() -> Self {
+        Self::with_version(Version::new([5, 4, 3]))
+    }
+
+    /// Creates a device with the given firmware version and the default credentials.
+    pub(crate) fn with_version(version: Version) -> Self {
+        let attestation_key = ec_key(Nid::X9_62_PRIME256V1).expect("Failed to generate key");
+        let attestation_certificate = attestation_ca("Simulated PIV Attestation", &attestation_key)
+            .expect("Failed to create the attestation certificate");
+        let mut objects = BTreeMap::new();
+        objects.insert(
+            certificate_object(SlotId::Attestation).unwrap(),
+            encode_certificate(&attestation_certificate),
+        );
+
+        Self {
+            state: Arc::new(Mutex::new(State {
+                version,
+                pin: DEFAULT_PIN.as_bytes().to_vec(),
+                puk: DEFAULT_PUK.as_bytes().to_vec(),
+                pin_retries: MAX_RETRIES,
+                puk_retries: MAX_RETRIES,
+                pin_verified: false,
+                management_key: MgmKey::default().as_ref().to_vec(),
+                management_key_protected: false,
+                authenticated: false,
+                objects,
+                keys: BTreeMap::new(),
+                attestation_key,
+            })),
+        }
+    }
+
+    /// Creates a device with the given firmware version and management key, e.g. an AES key
+    /// as supported from firmware 5.4, and the default PIN and PUK.
+    pub(crate) fn with_management_key(version: Version, key: &[u8]) -> Self {
+        let device = Self::with_version(version);
+        {
+            let mut state = device.lock();
+            state.management_key = key.to_vec();
+        }
+        device
+    }
+
+    /// Returns the PEM encoded certificate of the attestation key, which attestations of the
+    /// device chain to.
+    pub(crate) fn attestation_root(&self) -> Vec<u8> {
+        let mut state = self.lock();
+        let certificate = state
+            .read_certificate(SlotId::Attestation)
+            .expect("The attestation certificate was removed");
+        X509::from_der(&certificate).unwrap().to_pem().unwrap()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+}
+
+impl Default for SimulatedPivDevice {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PivDevice for SimulatedPivDevice {
+    fn version(&self) -> Version {
+        self.lock().version
+    }
+
+    fn verify_pin(&mut self, pin: &[u8]) -> Result<()> {
+        let mut state = self.lock();
+        let result = check(&state.pin.clone(), &mut state.pin_retries, pin);
+        state.pin_verified = result.is_ok();
+        result
+    }
+
+    fn get_pin_retries(&mut self) -> Result<u8> {
+        let mut state = self.lock();
+        state.pin_verified = false;
+        Ok(state.pin_retries)
+    }
+
+    fn get_puk_retries(&mut self) -> Result<Option<u8>> {
+        Ok(Some(self.lock().puk_retries))
+    }
+
+    fn change_pin(&mut self, current_pin: &[u8], new_pin: &[u8]) -> Result<()> {
+        let mut state = self.lock();
+        check(&state.pin.clone(), &mut state.pin_retries, current_pin)?;
+        state.pin = valid_pin(new_pin)?;
+        Ok(())
+    }
+
+    fn change_puk(&mut self, current_puk: &[u8], new_puk: &[u8]) -> Result<()> {
+        let mut state = self.lock();
+        check(&state.puk.clone(), &mut state.puk_retries, current_puk)?;
+        state.puk = valid_pin(new_puk)?;
+        Ok(())
+    }
+
+    fn unblock_pin(&mut self, puk: &[u8], new_pin: &[u8]) -> Result<()> {
+        let mut state = self.lock();
+        check(&state.puk.clone(), &mut state.puk_retries, puk)?;
+        state.pin = valid_pin(new_pin)?;
+        state.pin_retries = MAX_RETRIES;
+        Ok(())
+    }
+
+    fn authenticate(&mut self, management_key: &[u8]) -> Result<()> {
+        let mut state = self.lock();
+        state.authenticated = management_key == state.management_key;
+        if state.authenticated {
+            Ok(())
+        } else {
+            Err(Error::AuthenticationError)
+        }
+    }
+
+    fn protected_management_key(&mut self) -> Result<MgmKey> {
+        let state = self.lock();
+        if !state.management_key_protected {
+            return Err(Error::NotFound);
+        }
+        if !state.pin_verified {
+            return Err(Error::AuthenticationError);
+        }
+        let key =
+            <[u8; 24]>::try_from(state.management_key.as_slice()).map_err(|_| Error::KeyError)?;
+        MgmKey::new(key)
+    }
+
+    fn set_management_key(
+        &mut self,
+        management_key: &MgmKey,
+        mode: ManagementKeyMode,
+    ) -> Result<()> {
+        let mut state = self.lock();
+        state.require_authentication()?;
+        state.management_key = management_key.as_ref().to_vec();
+        state.management_key_protected = mode == ManagementKeyMode::PinProtected;
+        Ok(())
+    }
+
+    fn fetch_object(&mut self, object_id: u32) -> Result<Vec<u8>> {
+        self.lock()
+            .objects
+            .get(&object_id)
+            .cloned()
+            .ok_or(Error::NotFound)
+    }
+
+    fn save_object(&mut self, object_id: u32, data: &mut [u8]) -> Result<()> {
+        self.lock().save_object(object_id, data.to_vec())
+    }
+
+    fn generate(
+        &mut self,
+        slot: SlotId,
+        algorithm: PivAlgorithm,
+        pin_policy: PinPolicy,
+        touch_policy: TouchPolicy,
+    ) -> Result<Vec<u8>> {
+        let mut state = self.lock();
+        state.require_authentication()?;
+        if algorithm.algorithm_id().is_none() && (state.version.major, state.version.minor) < (5, 7)
+        {
+            return Err(Error::NotSupported);
+        }
+        let key = match algorithm {
+            PivAlgorithm::Rsa1024 => rsa_key(1024),
+            PivAlgorithm::Rsa2048 => rsa_key(2048),
+            PivAlgorithm::Rsa3072 => rsa_key(3072),
+            PivAlgorithm::Rsa4096 => rsa_key(4096),
+            PivAlgorithm::EccP256 => ec_key(Nid::X9_62_PRIME256V1),
+            PivAlgorithm::EccP384 => ec_key(Nid::SECP384R1),
+            PivAlgorithm::Ed25519 => PKey::generate_ed25519(),
+            PivAlgorithm::X25519 => PKey::generate_x25519(),
+        }
+        .map_err(openssl_error)?;
+        let public_key = key.public_key_to_der().map_err(openssl_error)?;
+        state.keys.insert(
+            slot.into(),
+            SlotKey {
+                algorithm,
+                key,
+                pin_policy,
+                touch_policy,
+            },
+        );
+        Ok(public_key)
+    }
+
+    fn has_key(&mut self, slot: SlotId) -> Result<bool> {
+        let state = self.lock();
+        if (state.version.major, state.version.minor) < (5, 3) {
+            return Err(Error::NotSupported);
+        }
+        Ok(state.keys.contains_key(&slot.into()))
+    }
+
+    fn sign_data(
+        &mut self,
+        input: &[u8],
+        algorithm: PivAlgorithm,
+        slot: SlotId,
+    ) -> Result<Vec<u8>> {
+        let mut state = self.lock();
+        let key = state.use_key(slot, algorithm)?;
+        match algorithm {
+            PivAlgorithm::Rsa1024
+            | PivAlgorithm::Rsa2048
+            | PivAlgorithm::Rsa3072
+            | PivAlgorithm::Rsa4096 => raw_rsa(&key, input),
+            PivAlgorithm::EccP256 | PivAlgorithm::EccP384 => {
+                let ec = key.ec_key().map_err(openssl_error)?;
+                if input.len() > field_len(&ec) {
+                    return Err(Error::SizeError);
+                }
+                EcdsaSig::sign(input, &ec)
+                    .and_then(|signature| signature.to_der())
+                    .map_err(openssl_error)
+            }
+            PivAlgorithm::Ed25519 => Signer::new_without_digest(&key)
+                .and_then(|mut signer| signer.sign_oneshot_to_vec(input))
+                .map_err(openssl_error),
+            PivAlgorithm::X25519 => Err(Error::AlgorithmError),
+        }
+    }
+
+    fn decrypt_data(
+        &mut self,
+        input: &[u8],
+        algorithm: PivAlgorithm,
+        slot: SlotId,
+    ) -> Result<Vec<u8>> {
+        let mut state = self.lock();
+        let key = state.use_key(slot, algorithm)?;
+        match algorithm {
+            PivAlgorithm::Rsa1024
+            | PivAlgorithm::Rsa2048
+            | PivAlgorithm::Rsa3072
+            | PivAlgorithm::Rsa4096 => raw_rsa(&key, input),
+            PivAlgorithm::EccP256 | PivAlgorithm::EccP384 => {
+                let ec = key.ec_key().map_err(openssl_error)?;
+                if input.len() != 2 * field_len(&ec) + 1 {
+                    return Err(Error::SizeError);
+                }
+                ecdh(&key, ec.group(), input).map_err(openssl_error)
+            }
+            PivAlgorithm::X25519 => {
+                if input.len() != 32 {
+                    return Err(Error::SizeError);
+                }
+                PKey::public_key_from_raw_bytes(input, Id::X25519)
+                    .and_then(|peer| derive(&key, &peer))
+                    .map_err(openssl_error)
+            }
+            PivAlgorithm::Ed25519 => Err(Error::AlgorithmError),
+        }
+    }
+
+    fn attest(&mut self, slot: SlotId) -> Result<Vec<u8>> {
+        let mut state = self.lock();
+        let intermediate =
+            X509::from_der(&state.read_certificate(SlotId::Attestation)?).map_err(openssl_error)?;
+        let version = state.version;
+        let key = state.keys.get(&slot.into()).ok_or(Error::NotFound)?;
+        let policy = [
+            effective_pin_policy(slot, key.pin_policy).into(),
+            match key.touch_policy {
+                TouchPolicy::Default => TouchPolicy::Never,
+                policy => policy,
+            }
+            .into(),
+        ];
+        let serial = SERIAL.to_der().map_err(|_| Error::ParseError)?;
+        let extensions = [
+            (
+                "1.3.6.1.4.1.41482.3.3",
+                vec![version.major, version.minor, version.patch],
+            ),
+            ("1.3.6.1.4.1.41482.3.7", serial),
+            ("1.3.6.1.4.1.41482.3.8", policy.to_vec()),
+            ("1.3.6.1.4.1.41482.3.9", vec![0x01]),
+        ];
+
+        let build = || -> std::result::Result<Vec<u8>, ErrorStack> {
+            let mut builder = X509::builder()?;
+            builder.set_version(2)?;
+            let serial = BigNum::from_u32(u8::from(slot).into())?.to_asn1_integer()?;
+            builder.set_serial_number(&serial)?;
+            let subject = name(&format!("YubiKey PIV Attestation {:02x}", u8::from(slot)))?;
+            builder.set_subject_name(&subject)?;
+            builder.set_issuer_name(intermediate.subject_name())?;
+            builder.set_pubkey(&key.key)?;
+            builder.set_not_before(intermediate.not_before())?;
+            builder.set_not_after(intermediate.not_after())?;
+            for (oid, value) in &extensions {
+                let oid = Asn1Object::from_str(oid)?;
+                let value = Asn1OctetString::new_from_bytes(value)?;
+                builder.append_extension(X509Extension::new_from_der(&oid, false, &value)?)?;
+            }
+            builder.sign(&state.attestation_key, MessageDigest::sha256())?;
+            builder.build().to_der()
+        };
+        build().map_err(openssl_error)
+    }
+
+    fn read_certificate(&mut self, slot: SlotId) -> Result<Vec<u8>> {
+        self.lock().read_certificate(slot)
+    }
+
+    fn write_certificate(&mut self, slot: SlotId, certificate: &[u8]) -> Result<()> {
+        self.lock()
+            .save_object(certificate_object(slot)?, encode_certificate(certificate))
+    }
+
+    fn write_self_signed_certificate(
+        &mut self,
+        slot: SlotId,
+        algorithm: AlgorithmId,
+        serial: SerialNumber,
+        validity: Validity,
+        subject: Name,
+        public_key: SubjectPublicKeyInfoOwned,
+    ) -> Result<()> {
+        let mut state = self.lock();
+        state.require_authentication()?;
+        let key = state.use_key(slot, algorithm.into())?;
+        if public_key.to_der().ok() != key.public_key_to_der().ok() {
+            return Err(Error::KeyError);
+        }
+        let subject = subject.to_der().map_err(|_| Error::ParseError)?;
+        let digest = match algorithm {
+            AlgorithmId::EccP384 => MessageDigest::sha384(),
+            _ => MessageDigest::sha256(),
+        };
+
+        let build = || -> std::result::Result<Vec<u8>, ErrorStack> {
+            let mut builder = X509::builder()?;
+            builder.set_version(2)?;
+            let serial = BigNum::from_slice(serial.as_bytes())?.to_asn1_integer()?;
+            builder.set_serial_number(&serial)?;
+            let subject = X509Name::from_der(&subject)?;
+            builder.set_subject_name(&subject)?;
+            builder.set_issuer_name(&subject)?;
+            builder.set_pubkey(&key)?;
+            let (not_before, not_after) = (
+                unix_time(validity.not_before)?,
+                unix_time(validity.not_after)?,
+            );
+            builder.set_not_before(&not_before)?;
+            builder.set_not_after(&not_after)?;
+            builder.sign(&key, digest)?;
+            builder.build().to_der()
+        };
+        let certificate = build().map_err(openssl_error)?;
+        state.save_object(certificate_object(slot)?, encode_certificate(&certificate))
+    }
+}
+
+impl State {
+    fn require_authentication(&self) -> Result<()> {
+        if self.authenticated {
+            Ok(())
+        } else {
+            Err(Error::AuthenticationError)
+        }
+    }
+
+    /// Returns the key in `slot` if the PIN policy allows using it.
+    fn use_key(&mut self, slot: SlotId, algorithm: PivAlgorithm) -> Result<PKey<Private>> {
+        let key = self.keys.get(&slot.into()).ok_or(Error::NotFound)?;
+        if key.algorithm != algorithm {
+            return Err(Error::AlgorithmError);
+        }
+        let (key, policy) = (key.key.clone(), effective_pin_policy(slot, key.pin_policy));
+        match policy {
+            PinPolicy::Never => {}
+            _ if !self.pin_verified => return Err(Error::AuthenticationError),
+            // The PIN has to be verified again for the next operation.
+            PinPolicy::Always => self.pin_verified = false,
+            _ => {}
+        }
+        Ok(key)
+    }
+
+    fn save_object(&mut self, object_id: u32, data: Vec<u8>) -> Result<()> {
+        self.require_authentication()?;
+        if data.len() > MAX_OBJECT_SIZE {
+            return Err(Error::SizeError);
+        }
+        if data.is_empty() {
+            self.objects.remove(&object_id);
+        } else {
+            self.objects.insert(object_id, data);
+        }
+        Ok(())
+    }
+
+    fn read_certificate(&mut self, slot: SlotId) -> Result<Vec<u8>> {
+        let data = self
+            .objects
+            .get(&certificate_object(slot)?)
+            .ok_or(Error::NotFound)?;
+        decode_certificate(data)
+    }
+}
+
+/// Checks a PIN or PUK against its retry counter, like the device does.
+fn check(expected: &[u8], retries: &mut u8, given: &[u8]) -> Result<()> {
+    if *retries == 0 {
+        return Err(Error::PinLocked);
+    }
+    if given == expected {
+        *retries = MAX_RETRIES;
+        Ok(())
+    } else {
+        *retries -= 1;
+        Err(Error::WrongPin { tries: *retries })
+    }
+}
+
+fn valid_pin(pin: &[u8]) -> Result<Vec<u8>> {
+    match pin.len() {
+        6..=8 => Ok(pin.to_vec()),
+        _ => Err(Error::SizeError),
+    }
+}
+
+/// Resolves the default PIN policy of a slot.
+fn effective_pin_policy(slot: SlotId, policy: PinPolicy) -> PinPolicy {
+    match (policy, slot) {
+        (PinPolicy::Default, SlotId::Signature) => PinPolicy::Always,
+        (PinPolicy::Default, SlotId::CardAuthentication) => PinPolicy::Never,
+        (PinPolicy::Default, _) => PinPolicy::Once,
+        (policy, _) => policy,
+    }
+}
+
+fn decode_certificate(data: &[u8]) -> Result<Vec<u8>> {
+    let (len, offset) = match data {
+        [0x70, len @ 0..=0x7f, ..] => (*len as usize, 2),
+        [0x70, 0x81, len, ..] => (*len as usize, 3),
+        [0x70, 0x82, high, low, ..] => (u16::from_be_bytes([*high, *low]) as usize, 4),
+        _ => return Err(Error::InvalidObject),
+    };
+    data.get(offset..offset + len)
+        .map(<[u8]>::to_vec)
+        .ok_or(Error::InvalidObject)
+}
+
+fn rsa_key(bits: u32) -> std::result::Result<PKey<Private>, ErrorStack> {
+    PKey::from_rsa(Rsa::generate(bits)?)
+}
+
+fn ec_key(curve: Nid) -> std::result::Result<PKey<Private>, ErrorStack> {
+    let group = EcGroup::from_curve_name(curve)?;
+    PKey::from_ec_key(EcKey::generate(&group)?)
+}
+
+/// Applies the private key operation without padding, as the device does for RSA keys.
+fn raw_rsa(key: &PKey<Private>, input: &[u8]) -> Result<Vec<u8>> {
+    let rsa = key.rsa().map_err(openssl_error)?;
+    if input.len() != rsa.size() as usize {
+        return Err(Error::SizeError);
+    }
+    let mut output = vec![0; rsa.size() as usize];
+    let len = rsa
+        .private_encrypt(input, &mut output, Padding::NONE)
+        .map_err(openssl_error)?;
+    output.truncate(len);
+    Ok(output)
+}
+
+fn ecdh(
+    key: &PKey<Private>,
+    group: &openssl::ec::EcGroupRef,
+    point: &[u8],
+) -> std::result::Result<Vec<u8>, ErrorStack> {
+    let mut context = BigNumContext::new()?;
+    let point = EcPoint::from_bytes(group, point, &mut context)?;
+    let peer = PKey::from_ec_key(EcKey::from_public_key(group, &point)?)?;
+    derive(key, &peer)
+}
+
+fn derive<T: HasPublic>(
+    key: &PKey<Private>,
+    peer: &PKey<T>,
+) -> std::result::Result<Vec<u8>, ErrorStack> {
+    let mut deriver = Deriver::new(key)?;
+    deriver.set_peer(peer)?;
+    deriver.derive_to_vec()
+}
+
+fn field_len(key: &EcKey<Private>) -> usize {
+    (key.group().degree() as usize).div_ceil(8)
+}
+
+/// Creates the self-signed certificate of the attestation key in slot f9.
+fn attestation_ca(
+    common_name: &str,
+    key: &PKey<Private>,
+) -> std::result::Result<Vec<u8>, ErrorStack> {
+    let mut builder = X509::builder()?;
+    builder.set_version(2)?;
+    let serial = BigNum::from_u32(1)?.to_asn1_integer()?;
+    builder.set_serial_number(&serial)?;
+    let name = name(common_name)?;
+    builder.set_subject_name(&name)?;
+    builder.set_issuer_name(&name)?;
+    builder.set_pubkey(key)?;
+    let (not_before, not_after) = (Asn1Time::days_from_now(0)?, Asn1Time::days_from_now(365)?);
+    builder.set_not_before(&not_before)?;
+    builder.set_not_after(&not_after)?;
+    builder.append_extension(BasicConstraints::new().critical().ca().build()?)?;
+    builder.sign(key, MessageDigest::sha256())?;
+    builder.build().to_der()
+}
+
+fn name(common_name: &str) -> std::result::Result<X509Name, ErrorStack> {
+    let mut name = X509Name::builder()?;
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)?;
+    Ok(name.build())
+}
+
+fn unix_time(time: x509_cert::time::Time) -> std::result::Result<Asn1Time, ErrorStack> {
+    Asn1Time::from_unix(time.to_unix_duration().as_secs() as i64)
+}
+
+fn openssl_error(_: ErrorStack) -> Error {
+    Error::GenericError
+}
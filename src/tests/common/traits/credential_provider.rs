@@ -0,0 +1,89 @@
+use crate::common::traits::credential_provider::{
+    Credential, CredentialProvider, EnvCredentials, StaticCredentials,
+};
+use std::env;
+
+#[test]
+fn test_static_credentials() {
+    let credentials = StaticCredentials::new()
+        .with(Credential::Pin, "654321")
+        .with(Credential::AuthValue("signing".to_string()), b"auth".as_slice());
+
+    let pin = credentials
+        .credential(&Credential::Pin)
+        .expect("Failed to get PIN")
+        .expect("PIN is missing");
+    assert_eq!(pin.expose(), b"654321");
+    let auth = credentials
+        .credential(&Credential::AuthValue("signing".to_string()))
+        .expect("Failed to get auth value")
+        .expect("Auth value is missing");
+    assert_eq!(auth.expose(), b"auth");
+    assert!(credentials
+        .credential(&Credential::ManagementKey)
+        .expect("Failed to get management key")
+        .is_none());
+}
+
+#[test]
+fn test_static_credentials_presence() {
+    assert!(StaticCredentials::new().confirm_presence("Touch").is_ok());
+    assert!(StaticCredentials::new()
+        .with_presence(false)
+        .confirm_presence("Touch")
+        .is_err());
+}
+
+#[test]
+fn test_secret_debug_is_redacted() {
+    let credentials = StaticCredentials::new().with(Credential::Pin, "654321");
+
+    assert!(!format!("{:?}", credentials).contains("654321"));
+}
+
+#[test]
+fn test_env_credentials() {
+    let credentials = EnvCredentials::new("CRYPTO_LAYER_TEST_ENV");
+    assert_eq!(
+        credentials.variable(&Credential::AuthValue("my-key.1".to_string())),
+        "CRYPTO_LAYER_TEST_ENV_AUTH_MY_2DKEY_2E1"
+    );
+    assert_ne!(
+        credentials.variable(&Credential::AuthValue("a-b".to_string())),
+        credentials.variable(&Credential::AuthValue("a_b".to_string()))
+    );
+    assert_ne!(
+        credentials.variable(&Credential::AuthValue("key".to_string())),
+        credentials.variable(&Credential::AuthValue("KEY".to_string()))
+    );
+    assert!(credentials.confirm_presence("Touch").is_err());
+
+    env::set_var("CRYPTO_LAYER_TEST_ENV_PIN", "112233");
+    env::set_var(
+        "CRYPTO_LAYER_TEST_ENV_MANAGEMENT_KEY",
+        "010203040506070801020304050607080102030405060708",
+    );
+
+    let pin = credentials
+        .credential(&Credential::Pin)
+        .expect("Failed to get PIN")
+        .expect("PIN is missing");
+    assert_eq!(pin.expose(), b"112233");
+    let management_key = credentials
+        .credential(&Credential::ManagementKey)
+        .expect("Failed to get management key")
+        .expect("Management key is missing");
+    assert_eq!(management_key.expose().len(), 24);
+    assert!(credentials
+        .credential(&Credential::Puk)
+        .expect("Failed to get PUK")
+        .is_none());
+}
+
+#[test]
+fn test_env_credentials_reject_invalid_management_key() {
+    let credentials = EnvCredentials::new("CRYPTO_LAYER_TEST_INVALID");
+    env::set_var("CRYPTO_LAYER_TEST_INVALID_MANAGEMENT_KEY", "not hex");
+
+    assert!(credentials.credential(&Credential::ManagementKey).is_err());
+}
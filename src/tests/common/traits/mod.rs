@@ -1,3 +1,5 @@
+mod credential_provider;
+
 // use tracing::Level;
 // use tracing_appender::rolling;
 // use tracing_subscriber::FmtSubscriber;
@@ -0,0 +1,309 @@
+/// Tests for the raw PIV commands used for the algorithms added by firmware 5.7.
+///
+/// The commands run against a fake card, which records the command APDUs and answers them
+/// like a YubiKey, so the encoding of commands, command chaining and the collection of long
+/// responses are covered without a device.
+use crate::hsm::yubikey::{
+    apdu::{self, find, tlv, Transport},
+    metadata::PivAlgorithm,
+};
+use openssl::{
+    bn::BigNumContext,
+    ec::{EcGroup, EcKey, PointConversionForm},
+    nid::Nid,
+    pkey::PKey,
+    rsa::Rsa,
+    symm::{encrypt, Cipher},
+};
+use yubikey::{
+    piv::{RetiredSlotId, SlotId},
+    Error, MgmKey, PinPolicy, Result, TouchPolicy,
+};
+
+const OK: [u8; 2] = [0x90, 0x00];
+
+struct FakeCard<F> {
+    respond: F,
+    commands: Vec<Vec<u8>>,
+}
+
+impl<F: FnMut(&[u8]) -> Vec<u8>> Transport for FakeCard<F> {
+    fn transmit(&mut self, command: &[u8]) -> Result<Vec<u8>> {
+        self.commands.push(command.to_vec());
+        Ok((self.respond)(command))
+    }
+}
+
+fn card<F: FnMut(&[u8]) -> Vec<u8>>(respond: F) -> FakeCard<F> {
+    FakeCard {
+        respond,
+        commands: Vec::new(),
+    }
+}
+
+/// Returns a card that answers with `responses` in order.
+fn scripted(responses: Vec<Vec<u8>>) -> FakeCard<impl FnMut(&[u8]) -> Vec<u8>> {
+    let mut responses = responses.into_iter();
+    card(move |_| responses.next().expect("Unexpected command"))
+}
+
+fn response(data: &[u8], sw: [u8; 2]) -> Vec<u8> {
+    let mut response = data.to_vec();
+    response.extend_from_slice(&sw);
+    response
+}
+
+fn management_key_cipher(algorithm: u8) -> Cipher {
+    match algorithm {
+        apdu::AES128 => Cipher::aes_128_ecb(),
+        apdu::AES192 => Cipher::aes_192_ecb(),
+        apdu::AES256 => Cipher::aes_256_ecb(),
+        _ => Cipher::des_ede3_ecb(),
+    }
+}
+
+fn ecb(cipher: Cipher, key: &[u8], block: &[u8]) -> Vec<u8> {
+    encrypt(cipher, key, None, block).unwrap()[..cipher.block_size()].to_vec()
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_generate_ed25519() {
+    let key = PKey::generate_ed25519().unwrap();
+    let template = tlv(&[0x7f, 0x49], &tlv(&[0x86], &key.raw_public_key().unwrap()));
+    let mut card = scripted(vec![response(&template, OK)]);
+
+    let public_key = apdu::generate(
+        &mut card,
+        SlotId::Retired(RetiredSlotId::R1),
+        PivAlgorithm::Ed25519,
+        PinPolicy::Once,
+        TouchPolicy::Always,
+    )
+    .expect("Failed to generate key");
+    assert_eq!(public_key, key.public_key_to_der().unwrap());
+    assert_eq!(
+        card.commands,
+        vec![vec![
+            0x00, 0x47, 0x00, 0x82, 0x0b, 0xac, 0x09, 0x80, 0x01, 0xe0, 0xaa, 0x01, 0x02, 0xab,
+            0x01, 0x02
+        ]]
+    );
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_generate_p256() {
+    let key = EcKey::generate(&EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap()).unwrap();
+    let mut context = BigNumContext::new().unwrap();
+    let point = key
+        .public_key()
+        .to_bytes(key.group(), PointConversionForm::UNCOMPRESSED, &mut context)
+        .unwrap();
+    let template = tlv(&[0x7f, 0x49], &tlv(&[0x86], &point));
+    let mut card = scripted(vec![response(&template, OK)]);
+
+    let public_key = apdu::generate(
+        &mut card,
+        SlotId::Retired(RetiredSlotId::R1),
+        PivAlgorithm::EccP256,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .expect("Failed to generate key");
+    assert_eq!(public_key, key.public_key_to_der().unwrap());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_generate_rsa_4096_collects_response() {
+    let key = Rsa::generate(4096).unwrap();
+    let mut template = tlv(&[0x81], &key.n().to_vec());
+    template.extend(tlv(&[0x82], &key.e().to_vec()));
+    let template = tlv(&[0x7f, 0x49], &template);
+    // The card returns long responses in parts, announcing the rest with SW1 0x61.
+    let (first, rest) = template.split_at(256);
+    let mut card = scripted(vec![response(first, [0x61, 0x00]), response(rest, OK)]);
+
+    let public_key = apdu::generate(
+        &mut card,
+        SlotId::KeyManagement,
+        PivAlgorithm::Rsa4096,
+        PinPolicy::Default,
+        TouchPolicy::Default,
+    )
+    .expect("Failed to generate key");
+    assert_eq!(
+        public_key,
+        PKey::from_rsa(key).unwrap().public_key_to_der().unwrap()
+    );
+    assert_eq!(
+        card.commands[0],
+        vec![0x00, 0x47, 0x00, 0x9d, 0x05, 0xac, 0x03, 0x80, 0x01, 0x16]
+    );
+    assert_eq!(card.commands[1], vec![0x00, 0xc0, 0x00, 0x00, 0x00]);
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_general_authenticate_chains_long_input() {
+    let input = vec![0x5a; 512];
+    let signature = vec![0xa5; 512];
+    let output = tlv(&[0x7c], &tlv(&[0x82], &signature));
+    let (first, rest) = output.split_at(256);
+    let mut card = scripted(vec![
+        response(&[], OK),
+        response(&[], OK),
+        response(first, [0x61, 0x00]),
+        response(rest, OK),
+    ]);
+
+    let result =
+        apdu::general_authenticate(&mut card, PivAlgorithm::Rsa4096, SlotId::Signature, &input)
+            .expect("Failed to sign");
+    assert_eq!(result, signature);
+
+    // 522 bytes of data are sent in chunks of 255 bytes, all but the last chained.
+    let mut template = tlv(&[0x82], &[]);
+    template.extend(tlv(&[0x81], &input));
+    let data = tlv(&[0x7c], &template);
+    let mut sent = Vec::new();
+    for (command, (cla, len)) in
+        card.commands[..3]
+            .iter()
+            .zip([(0x10, 255), (0x10, 255), (0x00, 12)])
+    {
+        assert_eq!(command[..5], [cla, 0x87, 0x16, 0x9c, len]);
+        sent.extend_from_slice(&command[5..]);
+    }
+    assert_eq!(sent, data);
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_x25519_exchange() {
+    let point = [0x42; 32];
+    let secret = [0x24; 32];
+    let mut card = scripted(vec![response(&tlv(&[0x7c], &tlv(&[0x82], &secret)), OK)]);
+
+    let result = apdu::general_authenticate(
+        &mut card,
+        PivAlgorithm::X25519,
+        SlotId::KeyManagement,
+        &point,
+    )
+    .expect("Failed to derive shared secret");
+    assert_eq!(result, secret);
+
+    let mut command = vec![
+        0x00, 0x87, 0xe1, 0x9d, 0x26, 0x7c, 0x24, 0x82, 0x00, 0x85, 0x20,
+    ];
+    command.extend_from_slice(&point);
+    assert_eq!(card.commands, vec![command]);
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_verify_pin() {
+    let mut card = scripted(vec![
+        response(&[], [0x63, 0xc2]),
+        response(&[], [0x69, 0x83]),
+        response(&[], OK),
+    ]);
+
+    assert!(matches!(
+        apdu::verify_pin(&mut card, b"654321"),
+        Err(Error::WrongPin { tries: 2 })
+    ));
+    assert!(matches!(
+        apdu::verify_pin(&mut card, b"654321"),
+        Err(Error::PinLocked)
+    ));
+    apdu::verify_pin(&mut card, b"123456").expect("Failed to verify PIN");
+    assert_eq!(
+        card.commands[2],
+        b"\x00\x20\x00\x80\x08123456\xff\xff".to_vec()
+    );
+    assert!(matches!(
+        apdu::verify_pin(&mut card, b"123456789"),
+        Err(Error::SizeError)
+    ));
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_authenticate_management_key() {
+    // A card that knows `key` of type `algorithm`, answering the metadata of the key and the
+    // witness and challenge of mutual authentication.
+    let device = |algorithm: u8, key: Vec<u8>| {
+        let cipher = management_key_cipher(algorithm);
+        let witness = vec![0x11; cipher.block_size()];
+        card(move |command| {
+            if command[1] == 0xf7 {
+                return response(&tlv(&[0x01], &[algorithm]), OK);
+            }
+            let template = find(&command[5..], &[0x7c]).unwrap();
+            match find(template, &[0x81]) {
+                None => response(
+                    &tlv(&[0x7c], &tlv(&[0x80], &ecb(cipher, &key, &witness))),
+                    OK,
+                ),
+                Some(_) if find(template, &[0x80]) != Some(&witness[..]) => {
+                    response(&[], [0x69, 0x82])
+                }
+                Some(challenge) => response(
+                    &tlv(&[0x7c], &tlv(&[0x82], &ecb(cipher, &key, challenge))),
+                    OK,
+                ),
+            }
+        })
+    };
+
+    let mut card = device(apdu::TDES, MgmKey::default().as_ref().to_vec());
+    apdu::authenticate(&mut card, MgmKey::default().as_ref()).expect("Failed to authenticate");
+    assert_eq!(card.commands.len(), 3);
+    assert_eq!(card.commands[0], [0x00, 0xf7, 0x00, 0x9b]);
+    assert_eq!(card.commands[1][..4], [0x00, 0x87, 0x03, 0x9b]);
+
+    let mut card = device(apdu::TDES, vec![0x42; 24]);
+    assert!(matches!(
+        apdu::authenticate(&mut card, MgmKey::default().as_ref()),
+        Err(Error::AuthenticationError)
+    ));
+
+    for (algorithm, len) in [(apdu::AES128, 16), (apdu::AES192, 24), (apdu::AES256, 32)] {
+        let key = vec![0x42; len];
+        let mut card = device(algorithm, key.clone());
+        apdu::authenticate(&mut card, &key).expect("Failed to authenticate");
+        assert_eq!(card.commands[1][..4], [0x00, 0x87, algorithm, 0x9b]);
+        // The challenge for the card is an AES block.
+        let template = find(&card.commands[2][5..], &[0x7c]).unwrap();
+        assert_eq!(find(template, &[0x81]).unwrap().len(), 16);
+
+        let mut card = device(algorithm, key);
+        assert!(matches!(
+            apdu::authenticate(&mut card, &[0x42; 8]),
+            Err(Error::AuthenticationError)
+        ));
+    }
+
+    // Firmware before 5.3 has no metadata, and only 3DES keys.
+    let mut card = scripted(vec![response(&[], [0x6d, 0x00])]);
+    assert_eq!(
+        apdu::management_key_algorithm(&mut card).unwrap(),
+        apdu::TDES
+    );
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_put_data() {
+    let mut card = scripted(vec![response(&[], OK)]);
+    apdu::put_data(&mut card, 0x005f_c10d, &[0x01, 0x02]).expect("Failed to store object");
+    assert_eq!(
+        card.commands,
+        vec![vec![
+            0x00, 0xdb, 0x3f, 0xff, 0x09, 0x5c, 0x03, 0x5f, 0xc1, 0x0d, 0x53, 0x02, 0x01, 0x02
+        ]]
+    );
+}
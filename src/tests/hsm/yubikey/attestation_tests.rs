@@ -0,0 +1,171 @@
+use crate::common::error::SecurityModuleError;
+use crate::hsm::yubikey::attestation::{Attestation, FormFactor, YUBICO_PIV_ROOT_CA};
+use ::yubikey::{PinPolicy, Serial, TouchPolicy};
+use openssl::{
+    asn1::{Asn1Object, Asn1OctetString, Asn1Time},
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private},
+    x509::{extension::BasicConstraints, X509Extension, X509Name, X509},
+};
+
+fn key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+fn name(common_name: &str) -> X509Name {
+    let mut name = X509Name::builder().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+        .unwrap();
+    name.build()
+}
+
+/// Issues a certificate for `key`, self-signed if no issuer is given.
+fn certificate(
+    subject: &str,
+    key: &PKey<Private>,
+    issuer: Option<(&X509, &PKey<Private>)>,
+    extensions: Vec<X509Extension>,
+) -> X509 {
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder
+        .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_subject_name(&name(subject)).unwrap();
+    let (issuer_name, issuer_key) = match issuer {
+        Some((certificate, key)) => (certificate.subject_name().to_owned().unwrap(), key),
+        None => (name(subject), key),
+    };
+    builder.set_issuer_name(&issuer_name).unwrap();
+    builder.set_pubkey(key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    for extension in extensions {
+        builder.append_extension(extension).unwrap();
+    }
+    builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+    builder.build()
+}
+
+fn yubico_extension(oid: &str, value: &[u8]) -> X509Extension {
+    X509Extension::new_from_der(
+        &Asn1Object::from_str(oid).unwrap(),
+        false,
+        &Asn1OctetString::new_from_bytes(value).unwrap(),
+    )
+    .unwrap()
+}
+
+fn ca() -> BasicConstraints {
+    let mut constraints = BasicConstraints::new();
+    constraints.critical().ca();
+    constraints
+}
+
+/// Returns the PEM encoded root and an attestation signed via an f9 intermediate.
+fn attestation() -> (Vec<u8>, Attestation) {
+    let root_key = key();
+    let root = certificate(
+        "Test PIV Root CA",
+        &root_key,
+        None,
+        vec![ca().build().unwrap()],
+    );
+    let intermediate_key = key();
+    let intermediate = certificate(
+        "Test PIV Attestation",
+        &intermediate_key,
+        Some((&root, &root_key)),
+        vec![ca().build().unwrap()],
+    );
+    let leaf = certificate(
+        "YubiKey PIV Attestation 9a",
+        &key(),
+        Some((&intermediate, &intermediate_key)),
+        vec![
+            yubico_extension("1.3.6.1.4.1.41482.3.3", &[5, 4, 3]),
+            yubico_extension(
+                "1.3.6.1.4.1.41482.3.7",
+                &[0x02, 0x04, 0x00, 0xbc, 0x61, 0x4e],
+            ),
+            yubico_extension("1.3.6.1.4.1.41482.3.8", &[3, 2]),
+            yubico_extension("1.3.6.1.4.1.41482.3.9", &[0x03]),
+        ],
+    );
+
+    let attestation = Attestation::from_der(leaf.to_der().unwrap(), intermediate.to_der().unwrap())
+        .expect("Failed to parse attestation");
+    (root.to_pem().unwrap(), attestation)
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_parse_extensions() {
+    let (_, attestation) = attestation();
+
+    let version = attestation.firmware_version.expect("No firmware version");
+    assert_eq!((version.major, version.minor, version.patch), (5, 4, 3));
+    assert_eq!(attestation.serial, Some(Serial(12345678)));
+    assert_eq!(attestation.pin_policy, Some(PinPolicy::Always));
+    assert_eq!(attestation.touch_policy, Some(TouchPolicy::Always));
+    assert_eq!(attestation.form_factor, Some(FormFactor::UsbCKeychain));
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_verify_chain() {
+    let (root, attestation) = attestation();
+    attestation
+        .verify(Some(&root))
+        .expect("Failed to verify attestation");
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_verify_chain_with_other_root() {
+    let (_, attestation) = attestation();
+    let (other_root, _) = self::attestation();
+
+    match attestation.verify(Some(&other_root)) {
+        Err(SecurityModuleError::SignatureVerificationError(_)) => {}
+        other => panic!("Expected a verification error, got {:?}", other),
+    }
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_verify_chain_with_bundled_root() {
+    let root = X509::from_pem(YUBICO_PIV_ROOT_CA).expect("Failed to parse bundled root");
+    assert!(root.verify(&root.public_key().unwrap()).unwrap());
+    assert_eq!(
+        hex::encode(root.digest(MessageDigest::sha256()).unwrap()),
+        "63ece914e54dd87915f34033c85af4c0696ba1512f8add66ced738331207b546"
+    );
+
+    // The test attestation is not issued by Yubico.
+    let (_, attestation) = attestation();
+    match attestation.verify(None) {
+        Err(SecurityModuleError::SignatureVerificationError(_)) => {}
+        other => panic!("Expected a verification error, got {:?}", other),
+    }
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_pem_round_trip() {
+    let (root, attestation) = attestation();
+
+    let parsed = Attestation::from_pem(&attestation.to_pem()).expect("Failed to parse attestation");
+    assert_eq!(parsed, attestation);
+    parsed
+        .verify(Some(&root))
+        .expect("Failed to verify attestation");
+}
@@ -0,0 +1,147 @@
+/// Tests for storing certificates in YubiKey PIV slots.
+///
+/// Except for `test_store_certificate_without_key`, these tests need a YubiKey with the PIN and
+/// management key in the `CRYPTO_LAYER_PIN` and `CRYPTO_LAYER_MANAGEMENT_KEY` environment
+/// variables. Run them with **cargo test --features yubi -- --test-threads=1**.
+use crate::common::{
+    crypto::algorithms::encryption::{AsymmetricEncryption, EccCurves, EccSchemeAlgorithm},
+    traits::{key_handle::KeyHandle, module_provider::Provider},
+};
+use crate::hsm::{yubikey::YubiKeyProvider, HsmProviderConfig};
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    ec::{EcGroup, EcKey},
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Private, Public},
+    x509::{X509Name, X509},
+};
+
+fn config(placeholder_certificate: bool) -> Box<HsmProviderConfig> {
+    Box::new(HsmProviderConfig {
+        key_algorithm: AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256)),
+        placeholder_certificate,
+        ..Default::default()
+    })
+}
+
+fn name(common_name: &str) -> X509Name {
+    let mut name = X509Name::builder().unwrap();
+    name.append_entry_by_nid(Nid::COMMONNAME, common_name)
+        .unwrap();
+    name.build()
+}
+
+/// Issues a certificate for `subject_key`, self-signed if no issuer is given.
+fn issue(
+    subject: &str,
+    subject_key: &PKey<impl openssl::pkey::HasPublic>,
+    issuer: &str,
+    issuer_key: &PKey<Private>,
+) -> X509 {
+    let mut builder = X509::builder().unwrap();
+    builder.set_version(2).unwrap();
+    builder
+        .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+        .unwrap();
+    builder.set_subject_name(&name(subject)).unwrap();
+    builder.set_issuer_name(&name(issuer)).unwrap();
+    builder.set_pubkey(subject_key).unwrap();
+    builder
+        .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+        .unwrap();
+    builder
+        .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+        .unwrap();
+    builder.sign(issuer_key, MessageDigest::sha256()).unwrap();
+    builder.build()
+}
+
+fn ca_key() -> PKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap()
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_placeholder_certificate() {
+    let key_id = "test_placeholder_certificate";
+    let mut provider = YubiKeyProvider::new(key_id.to_string());
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+        .create_key(key_id, config(true))
+        .expect("Failed to create key");
+
+    let chain = provider
+        .certificate_chain()
+        .expect("Failed to read certificate chain");
+    assert_eq!(chain.len(), 1);
+    let certificate = X509::from_der(&chain[0]).unwrap();
+    let public_key = certificate.public_key().unwrap();
+    assert_eq!(
+        public_key.public_key_to_der().unwrap(),
+        provider.metadata().unwrap().public_key
+    );
+    assert!(certificate.verify(&public_key).unwrap());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_store_certificate_chain() {
+    let key_id = "test_store_certificate";
+    let mut provider = YubiKeyProvider::new(key_id.to_string());
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+        .create_key(key_id, config(false))
+        .expect("Failed to create key");
+
+    let ca_key = ca_key();
+    let ca = issue("Test CA", &ca_key, "Test CA", &ca_key);
+    let public_key: PKey<Public> =
+        PKey::public_key_from_der(&provider.metadata().unwrap().public_key).unwrap();
+    let certificate = issue(key_id, &public_key, "Test CA", &ca_key);
+
+    provider
+        .store_certificate(&certificate.to_der().unwrap(), &[ca.to_der().unwrap()])
+        .expect("Failed to store certificate");
+    assert_eq!(
+        provider.certificate_chain().unwrap(),
+        vec![certificate.to_der().unwrap(), ca.to_der().unwrap()]
+    );
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_store_certificate_of_other_key() {
+    let key_id = "test_store_certificate";
+    let mut provider = YubiKeyProvider::new(key_id.to_string());
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+        .create_key(key_id, config(false))
+        .expect("Failed to create key");
+
+    let ca_key = ca_key();
+    let certificate = issue(key_id, &ca_key, key_id, &ca_key);
+    assert!(provider
+        .store_certificate(&certificate.to_der().unwrap(), &[])
+        .is_err());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_store_certificate_without_key() {
+    let provider = YubiKeyProvider::new("test_store_certificate".to_string());
+
+    let ca_key = ca_key();
+    let certificate = issue("Test", &ca_key, "Test", &ca_key);
+    assert!(provider
+        .store_certificate(&certificate.to_der().unwrap(), &[])
+        .is_err());
+}
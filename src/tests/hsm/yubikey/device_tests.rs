@@ -0,0 +1,77 @@
+/// Tests for selecting one of several attached YubiKeys by serial number.
+///
+/// `test_list_devices` and `test_open_by_serial` need at least one attached YubiKey with the PIN
+/// in the `CRYPTO_LAYER_PIN` environment variable. Run them with
+/// **cargo test --features yubi -- --test-threads=1**.
+use crate::common::{factory::SecurityModule, traits::module_provider::Provider};
+use crate::hsm::{
+    core::instance::{HsmInstance, HsmType},
+    yubikey::YubiKeyProvider,
+};
+use ::yubikey::Serial;
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_parse_hsm_type() {
+    assert_eq!(
+        HsmType::try_from("YubiKey").unwrap(),
+        HsmType::YubiKey(None)
+    );
+    assert_eq!(
+        HsmType::try_from("YubiKey:12345678").unwrap(),
+        HsmType::YubiKey(Some(12345678))
+    );
+    assert_ne!(
+        HsmType::try_from("YubiKey:12345678").unwrap(),
+        HsmType::try_from("YubiKey:87654321").unwrap()
+    );
+    assert_eq!(
+        SecurityModule::try_from("HSM:YubiKey:12345678").unwrap(),
+        SecurityModule::Hsm(HsmType::YubiKey(Some(12345678)))
+    );
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_parse_invalid_hsm_type() {
+    assert!(HsmType::try_from("YubiKey:primary").is_err());
+    assert!(HsmType::try_from("SoloKey").is_err());
+    assert!(SecurityModule::try_from("HSM:YubiKey:primary").is_err());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_create_unsupported_instance() {
+    assert!(HsmInstance::create_instance("key".to_string(), &HsmType::NitroKey).is_err());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_list_devices() {
+    let devices = YubiKeyProvider::list_devices().expect("Failed to list devices");
+    assert!(!devices.is_empty(), "No YubiKey attached");
+    assert!(devices
+        .windows(2)
+        .all(|pair| pair[0].serial.0 < pair[1].serial.0));
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_open_by_serial() {
+    for device in YubiKeyProvider::list_devices().expect("Failed to list devices") {
+        let mut provider =
+            YubiKeyProvider::with_serial("test_open_by_serial".to_string(), device.serial);
+        provider
+            .initialize_module()
+            .unwrap_or_else(|e| panic!("Failed to open YubiKey {}: {:?}", device.serial, e));
+        assert_eq!(provider.serial(), Some(device.serial));
+        provider.list_keys().expect("Failed to list keys");
+    }
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_open_unknown_serial() {
+    let mut provider = YubiKeyProvider::with_serial("test_open_by_serial".to_string(), Serial(0));
+    assert!(provider.initialize_module().is_err());
+}
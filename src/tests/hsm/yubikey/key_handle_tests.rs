@@ -11,6 +11,10 @@
 /// - `test_sign_and_verify_rsa_2048`: Tests signing and verifying data with a 2048-bit RSA key.
 /// - `test_sign_and_verify_ecc_256`: Tests signing and verifying data with a 256-bit ECC key.
 /// - `test_sign_and_verify_ecc_384`: Tests signing and verifying data with a 384-bit ECC key.
+/// - `test_sign_and_verify_rsa_pss_sha384`: Tests RSA-PSS signatures over SHA-384 digests.
+/// - `test_sign_and_verify_ecc_256_sha512`: Tests ECDSA signatures over truncated SHA-512 digests.
+/// - `test_create_ed25519_key`: Tests Ed25519 signatures on firmware 5.7 or later, and that
+///   older devices report Ed25519 keys as unsupported.
 ///
 /// ## Test Procedures
 ///
@@ -45,6 +49,9 @@
 /// failures and will be reported accordingly.
 ///
 /// Please use **cargo test --features yubi -- --test-threads=1** for successful testing due to parallelization issues.
+///
+/// The PIN and management key are read from the `CRYPTO_LAYER_PIN` and
+/// `CRYPTO_LAYER_MANAGEMENT_KEY` (hex encoded) environment variables.
 #[cfg(test)]
 #[allow(unused_imports)]
 use crate::common::{
@@ -60,7 +67,21 @@ use crate::common::{
 };
 
 // Import YubiKeyProvider and HsmProviderConfig for HSM operations
-use crate::hsm::{yubikey::YubiKeyProvider, HsmProviderConfig};
+use crate::hsm::{yubikey::YubiKeyProvider, HsmProviderConfig, RsaSignaturePadding};
+use crate::{
+    common::{crypto::algorithms::hashes::Sha2Bits, error::SecurityModuleError},
+    hsm::core::error::HsmError,
+};
+use std::any::Any;
+
+/// Creates a configuration that replaces the key an earlier run left on the device.
+fn replacing_config(key_algorithm: AsymmetricEncryption) -> Box<dyn Any> {
+    Box::new(HsmProviderConfig {
+        key_algorithm,
+        replace_existing_key: true,
+        ..Default::default()
+    })
+}
 // The following tests cover different cryptographic scenarios, ensuring the robustness and
 // compatibility of the system across various configurations and key sizes.
 
@@ -72,7 +93,7 @@ fn test_sign_and_verify_rsa_1024() {
     // omitted for brevity; please refer to the individual test implementations
     let mut provider = YubiKeyProvider::new("test_sv_1024".to_string());
 
-    let config = HsmProviderConfig::new(AsymmetricEncryption::Rsa(KeyBits::Bits1024));
+    let config = replacing_config(AsymmetricEncryption::Rsa(KeyBits::Bits1024));
 
     provider
         .initialize_module()
@@ -93,7 +114,7 @@ fn test_sign_and_verify_rsa_1024() {
 fn test_sign_and_verify_rsa_2048() {
     let mut provider = YubiKeyProvider::new("test_sv_2048".to_string());
 
-    let config = HsmProviderConfig::new(AsymmetricEncryption::Rsa(KeyBits::Bits2048));
+    let config = replacing_config(AsymmetricEncryption::Rsa(KeyBits::Bits2048));
 
     provider
         .initialize_module()
@@ -114,7 +135,7 @@ fn test_sign_and_verify_rsa_2048() {
 fn test_sign_and_verify_ecc_256() {
     let mut provider = YubiKeyProvider::new("test_ecc_256".to_string());
 
-    let config = HsmProviderConfig::new(AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(
+    let config = replacing_config(AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(
         EccCurves::P256,
     )));
 
@@ -137,7 +158,7 @@ fn test_sign_and_verify_ecc_256() {
 fn test_sign_and_verify_ecc_384() {
     let mut provider = YubiKeyProvider::new("test_ecc_384".to_string());
 
-    let config = HsmProviderConfig::new(AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(
+    let config = replacing_config(AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(
         EccCurves::P384,
     )));
 
@@ -158,7 +179,7 @@ fn test_sign_and_verify_ecc_384() {
 #[test]
 fn test_encrypt_and_decrypt_rsa_1024() {
     let mut provider = YubiKeyProvider::new("test_enc_dec_1024".to_string());
-    let config = HsmProviderConfig::new(AsymmetricEncryption::Rsa(KeyBits::Bits1024));
+    let config = replacing_config(AsymmetricEncryption::Rsa(KeyBits::Bits1024));
 
     provider
         .initialize_module()
@@ -180,7 +201,7 @@ fn test_encrypt_and_decrypt_rsa_1024() {
 #[test]
 fn test_encrypt_and_decrypt_rsa_2048() {
     let mut provider = YubiKeyProvider::new("test_enc_dec_2048".to_string());
-    let config = HsmProviderConfig::new(AsymmetricEncryption::Rsa(KeyBits::Bits2048));
+    let config = replacing_config(AsymmetricEncryption::Rsa(KeyBits::Bits2048));
 
     provider
         .initialize_module()
@@ -198,6 +219,79 @@ fn test_encrypt_and_decrypt_rsa_2048() {
     assert_eq!(data, decrypted_data.as_slice());
 }
 
+#[cfg(feature = "yubi")]
+#[test]
+fn test_sign_and_verify_rsa_pss_sha384() {
+    let mut provider = YubiKeyProvider::new("test_sv_pss".to_string());
+    let config = Box::new(HsmProviderConfig {
+        key_algorithm: AsymmetricEncryption::Rsa(KeyBits::Bits2048),
+        hash: Some(Hash::Sha2(Sha2Bits::Sha384)),
+        rsa_padding: RsaSignaturePadding::Pss,
+        replace_existing_key: true,
+        ..Default::default()
+    });
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+        .create_key("test_rsa_key_pss", config)
+        .expect("Failed to create RSA key");
+
+    let data = b"Hello, World!";
+    let signature = provider.sign_data(data).expect("Failed to sign data");
+
+    assert!(provider.verify_signature(data, &signature).unwrap());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_sign_and_verify_ecc_256_sha512() {
+    let mut provider = YubiKeyProvider::new("test_sv_256_sha512".to_string());
+    let config = Box::new(HsmProviderConfig {
+        key_algorithm: AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256)),
+        hash: Some(Hash::Sha2(Sha2Bits::Sha512)),
+        replace_existing_key: true,
+        ..Default::default()
+    });
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+        .create_key("test_ecc_key_256_sha512", config)
+        .expect("Failed to create ECC key");
+
+    let data = b"Hello, World!";
+    let signature = provider.sign_data(data).expect("Failed to sign data");
+
+    assert!(provider.verify_signature(data, &signature).unwrap());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_create_ed25519_key() {
+    let mut provider = YubiKeyProvider::new("test_ed25519".to_string());
+    let config = replacing_config(AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(
+        EccCurves::Curve25519,
+    )));
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    match provider.create_key("test_ed25519_key", config) {
+        Ok(()) => {
+            let data = b"Hello, World!";
+            let signature = provider.sign_data(data).expect("Failed to sign data");
+            assert!(provider.verify_signature(data, &signature).unwrap());
+        }
+        Err(SecurityModuleError::Hsm(HsmError::UnsupportedFeature(message))) => {
+            assert!(message.contains("firmware 5.7"), "{}", message)
+        }
+        other => panic!("Expected an unsupported feature error, got {:?}", other),
+    }
+}
+
 /*
 /// The following tests, `test_encrypt_and_decrypt_rsa` and `test_encrypt_and_decrypt_ecdh`,
 /// are currently commented out as they are placeholders for future implementations
@@ -0,0 +1,112 @@
+use crate::common::{crypto::KeyUsage, error::SecurityModuleError};
+use crate::hsm::{
+    core::error::HsmError,
+    yubikey::metadata::{KeyMetadata, PivAlgorithm, MAX_OBJECT_SIZE},
+};
+use ::yubikey::{
+    piv::{AlgorithmId, RetiredSlotId, SlotId},
+    PinPolicy, TouchPolicy,
+};
+use std::time::{Duration, UNIX_EPOCH};
+
+fn metadata() -> KeyMetadata {
+    KeyMetadata {
+        key_id: "test\0key".to_string(),
+        slot: SlotId::Retired(RetiredSlotId::R3),
+        algorithm: Some(PivAlgorithm::EccP256),
+        key_usages: vec![KeyUsage::SignEncrypt, KeyUsage::ClientAuth],
+        created: Some(UNIX_EPOCH + Duration::from_secs(1_700_000_000)),
+        pin_policy: PinPolicy::Once,
+        touch_policy: TouchPolicy::Always,
+        public_key: vec![0x30, 0x59, 0x30, 0x13],
+    }
+}
+
+fn assert_invalid<T: std::fmt::Debug>(result: Result<T, SecurityModuleError>) {
+    match result {
+        Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(message))) => {
+            assert!(message.starts_with("Invalid key metadata"))
+        }
+        other => panic!("Expected invalid key metadata, got {:?}", other),
+    }
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_round_trip() {
+    let metadata = metadata();
+    let record = metadata.to_bytes().expect("Failed to encode metadata");
+    assert_eq!(KeyMetadata::from_bytes(&record).unwrap(), metadata);
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_algorithms_of_firmware_5_7() {
+    // The ids are those of GENERATE ASYMMETRIC KEY PAIR, shared with `AlgorithmId`.
+    for (algorithm, id) in [
+        (PivAlgorithm::Rsa1024, 0x06),
+        (PivAlgorithm::Rsa2048, 0x07),
+        (PivAlgorithm::Rsa3072, 0x05),
+        (PivAlgorithm::Rsa4096, 0x16),
+        (PivAlgorithm::EccP256, 0x11),
+        (PivAlgorithm::EccP384, 0x14),
+        (PivAlgorithm::Ed25519, 0xe0),
+        (PivAlgorithm::X25519, 0xe1),
+    ] {
+        assert_eq!(u8::from(algorithm), id);
+        assert_eq!(PivAlgorithm::try_from(id).unwrap(), algorithm);
+        if let Some(algorithm_id) = algorithm.algorithm_id() {
+            assert_eq!(u8::from(algorithm_id), id);
+        }
+    }
+    assert_eq!(
+        PivAlgorithm::from(AlgorithmId::Rsa2048),
+        PivAlgorithm::Rsa2048
+    );
+    assert!(PivAlgorithm::try_from(0x42).is_err());
+
+    let mut metadata = metadata();
+    metadata.algorithm = Some(PivAlgorithm::Ed25519);
+    let record = metadata.to_bytes().unwrap();
+    assert_eq!(KeyMetadata::from_bytes(&record).unwrap(), metadata);
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_unknown_fields_are_skipped() {
+    let mut record = metadata().to_bytes().unwrap();
+    record.extend_from_slice(&[0x7f, 0x00, 0x02, 0xaa, 0xbb]);
+    assert_eq!(KeyMetadata::from_bytes(&record).unwrap(), metadata());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_legacy_record() {
+    // Key "legacy" in R2, described in the object of R12.
+    let record =
+        b"legacy\x006275352\x00-----BEGIN PUBLIC KEY-----\nMFkwEw==\n-----END PUBLIC KEY-----\x00";
+    let metadata = KeyMetadata::from_bytes(record).expect("Failed to parse legacy record");
+    assert_eq!(metadata.key_id, "legacy");
+    assert_eq!(metadata.slot, SlotId::Retired(RetiredSlotId::R2));
+    assert_eq!(metadata.public_key, vec![0x30, 0x59, 0x30, 0x13]);
+    assert_eq!(metadata.algorithm, None);
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_invalid_records() {
+    assert_invalid(KeyMetadata::from_bytes(&[]));
+    assert_invalid(KeyMetadata::from_bytes(b"no separators"));
+    assert_invalid(KeyMetadata::from_bytes(b"CLKM\x02"));
+
+    let record = metadata().to_bytes().unwrap();
+    assert_invalid(KeyMetadata::from_bytes(&record[..record.len() - 1]));
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_record_size_limit() {
+    let mut metadata = metadata();
+    metadata.public_key = vec![0; MAX_OBJECT_SIZE];
+    assert_invalid(metadata.to_bytes());
+}
@@ -1,2 +1,10 @@
+mod apdu_tests;
+mod attestation_tests;
+mod certificate_tests;
+mod device_tests;
 mod key_handle_tests;
-mod provider_handle_tests;
\ No newline at end of file
+mod metadata_tests;
+mod padding_tests;
+mod pin_tests;
+mod provider_handle_tests;
+mod simulator_tests;
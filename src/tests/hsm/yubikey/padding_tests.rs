@@ -0,0 +1,94 @@
+/// Tests for the RSA signature padding applied before the YubiKey signs with the raw key.
+///
+/// The padded digests are signed with a software key without padding and checked with OpenSSL,
+/// so these tests do not need a YubiKey.
+use crate::common::error::SecurityModuleError;
+use crate::hsm::yubikey::key_handle::{pkcs1v15_encode, pss_encode};
+use openssl::{
+    hash::{hash, MessageDigest},
+    pkey::PKey,
+    rsa::{Padding, Rsa},
+    sign::{RsaPssSaltlen, Verifier},
+};
+
+const DATA: &[u8] = b"Hello, World!";
+
+/// Signs the encoded digest with the raw RSA operation, as the YubiKey does.
+fn raw_sign(rsa: &Rsa<openssl::pkey::Private>, encoded: &[u8]) -> Vec<u8> {
+    let mut signature = vec![0; rsa.size() as usize];
+    let len = rsa
+        .private_encrypt(encoded, &mut signature, Padding::NONE)
+        .expect("Failed to sign");
+    signature.truncate(len);
+    signature
+}
+
+fn digests() -> [MessageDigest; 3] {
+    [
+        MessageDigest::sha256(),
+        MessageDigest::sha384(),
+        MessageDigest::sha512(),
+    ]
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_pkcs1v15_padding() {
+    for bits in [1024, 2048] {
+        let rsa = Rsa::generate(bits).unwrap();
+        let key = PKey::from_rsa(rsa.clone()).unwrap();
+        for digest in digests() {
+            let hashed = hash(digest, DATA).unwrap();
+            let encoded = pkcs1v15_encode(digest, &hashed, bits as usize / 8)
+                .expect("Failed to encode digest");
+            let signature = raw_sign(&rsa, &encoded);
+
+            let mut verifier = Verifier::new(digest, &key).unwrap();
+            verifier.update(DATA).unwrap();
+            assert!(verifier.verify(&signature).unwrap());
+        }
+    }
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_pss_padding() {
+    let rsa = Rsa::generate(2048).unwrap();
+    let key = PKey::from_rsa(rsa.clone()).unwrap();
+    for digest in digests() {
+        let hashed = hash(digest, DATA).unwrap();
+        let encoded = pss_encode(digest, &hashed, 256).expect("Failed to encode digest");
+        let signature = raw_sign(&rsa, &encoded);
+
+        let mut verifier = Verifier::new(digest, &key).unwrap();
+        verifier.set_rsa_padding(Padding::PKCS1_PSS).unwrap();
+        verifier
+            .set_rsa_pss_saltlen(RsaPssSaltlen::DIGEST_LENGTH)
+            .unwrap();
+        verifier.set_rsa_mgf1_md(digest).unwrap();
+        verifier.update(DATA).unwrap();
+        assert!(verifier.verify(&signature).unwrap());
+    }
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_pss_padding_is_randomized() {
+    let digest = MessageDigest::sha256();
+    let hashed = hash(digest, DATA).unwrap();
+    assert_ne!(
+        pss_encode(digest, &hashed, 256).unwrap(),
+        pss_encode(digest, &hashed, 256).unwrap()
+    );
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_pss_padding_key_too_short() {
+    let digest = MessageDigest::sha512();
+    let hashed = hash(digest, DATA).unwrap();
+    match pss_encode(digest, &hashed, 128) {
+        Err(SecurityModuleError::SigningError(_)) => {}
+        other => panic!("Expected a signing error, got {:?}", other),
+    }
+}
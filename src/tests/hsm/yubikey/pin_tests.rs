@@ -0,0 +1,70 @@
+use crate::common::{
+    error::SecurityModuleError,
+    traits::{
+        credential_provider::{Credential, CredentialProvider, EnvCredentials, StaticCredentials},
+        module_provider::Provider,
+    },
+};
+use crate::hsm::yubikey::YubiKeyProvider;
+use std::sync::Arc;
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_pin_retries() {
+    let mut provider = YubiKeyProvider::new("test_pin_retries".to_string());
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+
+    let retries = provider.pin_retries().expect("Failed to get PIN retries");
+    assert!(retries > 0);
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_wrong_pin_reports_retries() {
+    let mut provider = YubiKeyProvider::new("test_wrong_pin".to_string());
+    provider.set_credential_provider(Arc::new(
+        StaticCredentials::new().with(Credential::Pin, "000000"),
+    ));
+
+    match provider.initialize_module() {
+        Err(SecurityModuleError::AuthenticationFailed { retries, .. }) => {
+            assert!(retries.is_some())
+        }
+        other => panic!("Expected AuthenticationFailed, got {:?}", other),
+    }
+
+    // Verifying the correct PIN resets the retry counter.
+    let pin = EnvCredentials::default()
+        .credential(&Credential::Pin)
+        .expect("Failed to read PIN")
+        .expect("CRYPTO_LAYER_PIN is not set");
+    let mut provider = YubiKeyProvider::new("test_wrong_pin".to_string());
+    provider.set_credential_provider(Arc::new(
+        StaticCredentials::new().with(Credential::Pin, pin),
+    ));
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_change_pin() {
+    let mut provider = YubiKeyProvider::new("test_change_pin".to_string());
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+
+    let pin = EnvCredentials::default()
+        .credential(&Credential::Pin)
+        .expect("Failed to read PIN")
+        .expect("CRYPTO_LAYER_PIN is not set");
+    let pin = String::from_utf8(pin.expose().to_vec()).expect("PIN is not UTF-8");
+
+    provider
+        .change_pin("24681357")
+        .expect("Failed to change PIN");
+    provider.change_pin(&pin).expect("Failed to restore PIN");
+}
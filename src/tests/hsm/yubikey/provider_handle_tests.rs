@@ -12,6 +12,9 @@
 /// - `test_create_ecc_key_384`: Tests the creation of a 384-bit ECC key pair on the YubiKey device.
 /// - `test_load_rsa_key`: Tests the loading of an RSA key pair from the YubiKey device.
 /// - `test_load_ecc_key`: Tests the loading of an ECC key pair from the YubiKey device.
+/// - `test_create_key_in_pinned_slot`: Tests creating a key in the slot set in the configuration.
+/// - `test_invalid_retired_slot`: Tests that slots beyond retired slot 20 are rejected.
+/// - `test_create_key_with_policies`: Tests that PIN and touch policies are recorded with the key.
 ///
 /// ## Test Procedures
 ///
@@ -45,6 +48,9 @@
 /// failures and will be reported accordingly.
 ///
 /// Please use **cargo test --features yubi -- --test-threads=1** for successful testing due to parallelization issues
+///
+/// The PIN and management key are read from the `CRYPTO_LAYER_PIN` and
+/// `CRYPTO_LAYER_MANAGEMENT_KEY` (hex encoded) environment variables.
 #[allow(unused_imports)]
 use crate::common::{
     crypto::{
@@ -58,7 +64,17 @@ use crate::common::{
     traits::module_provider::Provider,
 };
 use crate::hsm::yubikey::YubiKeyProvider;
-use crate::hsm::HsmProviderConfig;
+use crate::hsm::{HsmProviderConfig, PinPolicy, PivSlot, TouchPolicy};
+use std::any::Any;
+
+/// Creates a configuration that replaces the key an earlier run left on the device.
+fn replacing_config(key_algorithm: AsymmetricEncryption) -> Box<dyn Any> {
+    Box::new(HsmProviderConfig {
+        key_algorithm,
+        replace_existing_key: true,
+        ..Default::default()
+    })
+}
 
 // Tests for creating 1024-bit RSA keys
 #[cfg(feature = "yubi")]
@@ -66,7 +82,7 @@ use crate::hsm::HsmProviderConfig;
 fn test_create_rsa_key_1024() {
     let key_id = "test_rsa_key_1024";
     let mut provider = YubiKeyProvider::new(key_id.to_string());
-    let config = HsmProviderConfig::new(AsymmetricEncryption::Rsa(KeyBits::Bits1024));
+    let config = replacing_config(AsymmetricEncryption::Rsa(KeyBits::Bits1024));
 
     //initialize HSM-module
     provider
@@ -86,7 +102,7 @@ fn test_create_rsa_key_2048() {
     let key_id = "test_rsa_key_2048";
     let mut provider: YubiKeyProvider = YubiKeyProvider::new(key_id.to_string());
 
-    let config = HsmProviderConfig::new(AsymmetricEncryption::Rsa(KeyBits::Bits2048));
+    let config = replacing_config(AsymmetricEncryption::Rsa(KeyBits::Bits2048));
 
     // initialize HSM-module
     provider
@@ -107,7 +123,7 @@ fn test_create_ecc_key_256() {
 
     let mut provider = YubiKeyProvider::new(key_id.to_string());
 
-    let config = HsmProviderConfig::new(AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(
+    let config = replacing_config(AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(
         EccCurves::P256,
     )));
 
@@ -130,7 +146,7 @@ fn test_create_ecc_key_384() {
 
     let mut provider = YubiKeyProvider::new(key_id.to_string());
 
-    let config = HsmProviderConfig::new(AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(
+    let config = replacing_config(AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(
         EccCurves::P384,
     )));
 
@@ -152,7 +168,7 @@ fn test_load_rsa_key_1024() {
     let key_id = "test_rsa_key_1024";
     let mut provider = YubiKeyProvider::new(key_id.to_string());
 
-    let config = HsmProviderConfig::new(AsymmetricEncryption::Rsa(KeyBits::Bits1024));
+    let config = replacing_config(AsymmetricEncryption::Rsa(KeyBits::Bits1024));
 
     // initialize HSM-module
     provider
@@ -172,7 +188,7 @@ fn test_load_rsa_key_2048() {
     let key_id = "test_rsa_key_2048";
     let mut provider = YubiKeyProvider::new(key_id.to_string());
 
-    let config = HsmProviderConfig::new(AsymmetricEncryption::Rsa(KeyBits::Bits1024));
+    let config = replacing_config(AsymmetricEncryption::Rsa(KeyBits::Bits1024));
 
     // initialize HSM-module
     provider
@@ -193,7 +209,7 @@ fn test_load_ecc_key_256() {
 
     let mut provider = YubiKeyProvider::new(key_id.to_string());
 
-    let config = HsmProviderConfig::new(AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(
+    let config = replacing_config(AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(
         EccCurves::P256,
     )));
 
@@ -216,7 +232,7 @@ fn test_load_ecc_key_384() {
 
     let mut provider = YubiKeyProvider::new(key_id.to_string());
 
-    let config = HsmProviderConfig::new(AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(
+    let config = replacing_config(AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(
         EccCurves::P256,
     )));
 
@@ -230,3 +246,58 @@ fn test_load_ecc_key_384() {
         .load_key(key_id, config)
         .expect("Failed to load ECC key");
 }
+
+// Test that retired slots are numbered from 1 to 20
+#[cfg(feature = "yubi")]
+#[test]
+fn test_invalid_retired_slot() {
+    let key_id = "test_invalid_slot";
+    let mut provider = YubiKeyProvider::new(key_id.to_string());
+
+    let config = Box::new(HsmProviderConfig {
+        key_algorithm: AsymmetricEncryption::Rsa(KeyBits::Bits2048),
+        slot: Some(PivSlot::Retired(21)),
+        ..Default::default()
+    });
+
+    assert!(provider.create_key(key_id, config).is_err());
+}
+
+// Test that the PIN and touch policies are applied and recorded with the key
+#[cfg(feature = "yubi")]
+#[test]
+fn test_create_key_with_policies() {
+    let key_id = "test_policy_key";
+    let mut provider = YubiKeyProvider::new(key_id.to_string());
+
+    let config = Box::new(HsmProviderConfig {
+        key_algorithm: AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256)),
+        key_usages: vec![KeyUsage::SignEncrypt],
+        pin_policy: PinPolicy::Always,
+        touch_policy: TouchPolicy::Cached,
+        replace_existing_key: true,
+        ..Default::default()
+    });
+
+    // initialize HSM-module
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+
+    provider
+        .create_key(key_id, config)
+        .expect("Failed to create key");
+
+    // The policies are read back from the key metadata
+    provider
+        .load_key(
+            key_id,
+            HsmProviderConfig::new(AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(
+                EccCurves::P256,
+            ))),
+        )
+        .expect("Failed to load key");
+    let metadata = provider.metadata().expect("No metadata for the key");
+    assert_eq!(metadata.pin_policy, ::yubikey::PinPolicy::Always);
+    assert_eq!(metadata.touch_policy, ::yubikey::TouchPolicy::Cached);
+}
@@ -0,0 +1,851 @@
+/// Tests for the YubiKey provider running against a simulated PIV device.
+///
+/// Unlike the other YubiKey tests, these tests need no device and run in parallel. The
+/// simulator keeps its keys in memory and enforces the PIN, PUK and management key like a
+/// YubiKey with firmware 5.4.3, or 5.7.1 for the algorithms added by firmware 5.7, so they
+/// cover slot selection, metadata records, signature padding, attestation and certificates
+/// in CI.
+use crate::common::{
+    crypto::{
+        algorithms::{
+            encryption::{AsymmetricEncryption, EccCurves, EccSchemeAlgorithm},
+            hashes::{Hash, Sha2Bits},
+            KeyBits,
+        },
+        KeyUsage,
+    },
+    error::SecurityModuleError,
+    traits::{
+        credential_provider::{Credential, EnvCredentials, StaticCredentials},
+        key_handle::KeyHandle,
+        module_provider::Provider,
+    },
+};
+use crate::hsm::{
+    core::error::HsmError,
+    yubikey::{
+        attestation::Attestation,
+        device::PivDevice,
+        metadata::PivAlgorithm,
+        pin::ManagementKeyMode,
+        simulator::{SimulatedPivDevice, DEFAULT_PIN, DEFAULT_PUK, SERIAL},
+        YubiKeyProvider,
+    },
+    HsmProviderConfig, PivSlot, RsaSignaturePadding,
+};
+use openssl::{
+    asn1::Asn1Time,
+    bn::BigNum,
+    derive::Deriver,
+    hash::MessageDigest,
+    nid::Nid,
+    pkey::{PKey, Public},
+    x509::{X509Name, X509},
+};
+use std::{env, sync::Arc};
+use yubikey::{
+    piv::{RetiredSlotId, SlotId},
+    MgmKey, PinPolicy, Serial, TouchPolicy, Version,
+};
+
+const DATA: &[u8] = b"Hello, World!";
+
+fn credentials(pin: &str, management_key: &[u8]) -> Arc<StaticCredentials> {
+    Arc::new(
+        StaticCredentials::new()
+            .with(Credential::Pin, pin)
+            .with(Credential::Puk, DEFAULT_PUK)
+            .with(Credential::ManagementKey, management_key.to_vec()),
+    )
+}
+
+/// Returns an initialized provider for `key_id` using `device`, with the default PIN and
+/// management key.
+fn provider(device: &SimulatedPivDevice, key_id: &str) -> YubiKeyProvider {
+    let mut provider = YubiKeyProvider::with_device(key_id.to_string(), Box::new(device.clone()));
+    provider.set_credential_provider(credentials(DEFAULT_PIN, MgmKey::default().as_ref()));
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+}
+
+fn config(key_algorithm: AsymmetricEncryption) -> HsmProviderConfig {
+    HsmProviderConfig {
+        key_algorithm,
+        key_usages: vec![KeyUsage::SignEncrypt],
+        ..Default::default()
+    }
+}
+
+fn p256() -> AsymmetricEncryption {
+    AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256))
+}
+
+fn ed25519() -> AsymmetricEncryption {
+    AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::Curve25519))
+}
+
+fn x25519() -> AsymmetricEncryption {
+    AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDh(EccCurves::Curve25519))
+}
+
+fn retired(number: u8) -> SlotId {
+    SlotId::Retired(RetiredSlotId::try_from(0x81 + number).unwrap())
+}
+
+fn assert_device_error(result: Result<(), SecurityModuleError>, message: &str) {
+    match result {
+        Err(SecurityModuleError::Hsm(HsmError::DeviceSpecific(error))) => {
+            assert!(error.contains(message), "Unexpected error: {}", error)
+        }
+        other => panic!("Expected a device error, got {:?}", other),
+    }
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_keys_fill_retired_slots() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = provider(&device, "key");
+
+    for number in 1..=20 {
+        provider
+            .create_key(&format!("key_{}", number), Box::new(config(p256())))
+            .expect("Failed to create key");
+        assert_eq!(provider.metadata().unwrap().slot, retired(number));
+    }
+    assert_device_error(
+        provider.create_key("key_21", Box::new(config(p256()))),
+        "No more free slots available",
+    );
+    assert_eq!(provider.list_keys().unwrap().len(), 20);
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_recreate_key_in_same_slot() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = provider(&device, "key");
+
+    provider
+        .create_key("first", Box::new(config(p256())))
+        .unwrap();
+    let first = provider.metadata().unwrap().clone();
+    provider
+        .create_key("second", Box::new(config(p256())))
+        .unwrap();
+    assert_device_error(
+        provider.create_key("first", Box::new(config(p256()))),
+        "replace_existing_key",
+    );
+    assert_eq!(provider.metadata().unwrap().key_id, "second");
+    provider
+        .create_key(
+            "first",
+            Box::new(HsmProviderConfig {
+                replace_existing_key: true,
+                ..config(p256())
+            }),
+        )
+        .unwrap();
+
+    let recreated = provider.metadata().unwrap();
+    assert_eq!(recreated.slot, first.slot);
+    assert_ne!(recreated.public_key, first.public_key);
+    assert_eq!(provider.list_keys().unwrap().len(), 2);
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_pinned_slot_holds_one_key() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = provider(&device, "key");
+    let pinned = HsmProviderConfig {
+        slot: Some(PivSlot::Signature),
+        ..config(p256())
+    };
+
+    provider
+        .create_key("signing", Box::new(pinned.clone()))
+        .expect("Failed to create key");
+    assert_eq!(provider.metadata().unwrap().slot, SlotId::Signature);
+    assert_device_error(
+        provider.create_key("other", Box::new(pinned)),
+        "already holds the key signing",
+    );
+    assert_device_error(
+        provider.create_key(
+            "signing",
+            Box::new(HsmProviderConfig {
+                slot: Some(PivSlot::Authentication),
+                ..config(p256())
+            }),
+        ),
+        "already exists",
+    );
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_create_key_in_pinned_slot() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = provider(&device, "pinned");
+    let pinned = HsmProviderConfig {
+        key_usages: vec![KeyUsage::ClientAuth],
+        slot: Some(PivSlot::Authentication),
+        ..config(p256())
+    };
+
+    provider
+        .create_key("pinned", Box::new(pinned))
+        .expect("Failed to create key in slot 9a");
+    let metadata = provider.metadata().expect("No metadata for the key");
+    assert_eq!(metadata.slot, SlotId::Authentication);
+    assert_eq!(metadata.key_usages, vec![KeyUsage::ClientAuth]);
+    assert!(provider
+        .list_keys()
+        .expect("Failed to list keys")
+        .iter()
+        .any(|metadata| metadata.key_id == "pinned" && metadata.slot == SlotId::Authentication));
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_pinned_slot_keeps_foreign_key() {
+    // Firmware 5.3 reports the key in the slot metadata, older devices only reveal it by the
+    // certificate PIV tools store with it.
+    for (version, certificate) in [
+        (Version::new([5, 4, 3]), false),
+        (Version::new([5, 2, 7]), true),
+    ] {
+        let device = SimulatedPivDevice::with_version(version);
+        let mut enrolled: Box<dyn PivDevice> = Box::new(device.clone());
+        enrolled.authenticate(MgmKey::default().as_ref()).unwrap();
+        enrolled
+            .generate(
+                SlotId::Authentication,
+                PivAlgorithm::EccP256,
+                PinPolicy::Default,
+                TouchPolicy::Default,
+            )
+            .unwrap();
+        if certificate {
+            let certificate = X509::from_pem(&device.attestation_root()).unwrap();
+            enrolled
+                .write_certificate(SlotId::Authentication, &certificate.to_der().unwrap())
+                .unwrap();
+        }
+
+        let mut provider = provider(&device, "pinned");
+        let pinned = HsmProviderConfig {
+            slot: Some(PivSlot::Authentication),
+            ..config(p256())
+        };
+        assert_device_error(
+            provider.create_key("pinned", Box::new(pinned.clone())),
+            "replace_existing_key",
+        );
+        provider
+            .create_key(
+                "pinned",
+                Box::new(HsmProviderConfig {
+                    replace_existing_key: true,
+                    ..pinned
+                }),
+            )
+            .expect("Failed to replace the key");
+        assert_eq!(provider.metadata().unwrap().slot, SlotId::Authentication);
+    }
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_load_key_from_other_provider() {
+    let device = SimulatedPivDevice::new();
+    let rsa = config(AsymmetricEncryption::Rsa(KeyBits::Bits1024));
+
+    let mut creator = provider(&device, "loaded");
+    creator
+        .create_key("loaded", Box::new(rsa.clone()))
+        .expect("Failed to create key");
+
+    let mut loader = provider(&device, "loaded");
+    loader
+        .load_key("loaded", Box::new(rsa))
+        .expect("Failed to load key");
+    // The record stores the creation time in seconds.
+    assert_eq!(loader.metadata(), creator.list_keys().unwrap().first());
+    assert_eq!(
+        loader.metadata().unwrap().key_usages,
+        vec![KeyUsage::SignEncrypt]
+    );
+
+    let signature = loader.sign_data(DATA).expect("Failed to sign data");
+    assert!(creator.verify_signature(DATA, &signature).unwrap());
+
+    assert_device_error(
+        loader.load_key("missing", Box::new(config(p256()))),
+        "Key not found",
+    );
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_load_key_with_other_algorithm_fails() {
+    let device = SimulatedPivDevice::new();
+
+    let mut creator = provider(&device, "mismatch");
+    creator
+        .create_key("mismatch", Box::new(config(p256())))
+        .expect("Failed to create key");
+
+    let mut loader = provider(&device, "mismatch");
+    assert_device_error(
+        loader.load_key(
+            "mismatch",
+            Box::new(config(AsymmetricEncryption::Rsa(KeyBits::Bits2048))),
+        ),
+        "Key was created with EccP256, not the configured algorithm",
+    );
+    assert!(loader.metadata().is_none());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_sign_and_verify_with_each_hash_and_padding() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = provider(&device, "signing");
+    let hashes = [
+        None,
+        Some(Hash::Sha2(Sha2Bits::Sha384)),
+        Some(Hash::Sha2(Sha2Bits::Sha512)),
+    ];
+
+    for key_algorithm in [
+        AsymmetricEncryption::Rsa(KeyBits::Bits2048),
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256)),
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P384)),
+    ] {
+        let replacing = HsmProviderConfig {
+            replace_existing_key: true,
+            ..config(key_algorithm)
+        };
+        provider
+            .create_key("signing", Box::new(replacing))
+            .expect("Failed to create key");
+        for hash in hashes {
+            for rsa_padding in [RsaSignaturePadding::Pkcs1v15, RsaSignaturePadding::Pss] {
+                let config = HsmProviderConfig {
+                    hash,
+                    rsa_padding,
+                    ..config(key_algorithm)
+                };
+                provider
+                    .load_key("signing", Box::new(config))
+                    .expect("Failed to load key");
+
+                let signature = provider.sign_data(DATA).expect("Failed to sign data");
+                assert!(
+                    provider.verify_signature(DATA, &signature).unwrap(),
+                    "{:?} {:?} {:?}",
+                    key_algorithm,
+                    hash,
+                    rsa_padding
+                );
+                assert!(provider
+                    .verify_signature(b"other data", &signature)
+                    .is_err());
+            }
+        }
+    }
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_pss_needs_room_for_digest() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = provider(&device, "pss");
+    let config = HsmProviderConfig {
+        hash: Some(Hash::Sha2(Sha2Bits::Sha512)),
+        rsa_padding: RsaSignaturePadding::Pss,
+        ..config(AsymmetricEncryption::Rsa(KeyBits::Bits1024))
+    };
+
+    // PSS over SHA-512 needs at least 130 bytes of a 128 byte RSA-1024 key.
+    provider
+        .create_key("pss", Box::new(config))
+        .expect("Failed to create key");
+    assert!(matches!(
+        provider.sign_data(DATA),
+        Err(SecurityModuleError::SigningError(_))
+    ));
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_sign_with_touch_key_without_prompt() {
+    env::set_var("CRYPTO_LAYER_TEST_TOUCH_PIN", DEFAULT_PIN);
+    env::set_var(
+        "CRYPTO_LAYER_TEST_TOUCH_MANAGEMENT_KEY",
+        hex::encode(MgmKey::default().as_ref()),
+    );
+    let device = SimulatedPivDevice::new();
+    let mut provider = YubiKeyProvider::with_device("touch".to_string(), Box::new(device.clone()));
+    provider.set_credential_provider(Arc::new(EnvCredentials::new("CRYPTO_LAYER_TEST_TOUCH")));
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    let touch = HsmProviderConfig {
+        touch_policy: TouchPolicy::Always,
+        ..config(p256())
+    };
+
+    // Environment variables cannot prompt, so the device waits for the touch on its own.
+    provider
+        .create_key("touch", Box::new(touch))
+        .expect("Failed to create key");
+    let signature = provider.sign_data(DATA).expect("Failed to sign data");
+    assert!(provider.verify_signature(DATA, &signature).unwrap());
+
+    // A user declining the prompt still cancels the operation.
+    provider.set_credential_provider(Arc::new(StaticCredentials::new().with_presence(false)));
+    assert!(provider.sign_data(DATA).is_err());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_encrypt_and_decrypt_rsa() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = provider(&device, "encryption");
+    provider
+        .create_key(
+            "encryption",
+            Box::new(config(AsymmetricEncryption::Rsa(KeyBits::Bits2048))),
+        )
+        .expect("Failed to create key");
+
+    let encrypted = provider.encrypt_data(DATA).expect("Failed to encrypt data");
+    assert_ne!(encrypted, DATA);
+    let decrypted = provider
+        .decrypt_data(&encrypted)
+        .expect("Failed to decrypt data");
+    assert_eq!(decrypted, DATA);
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_wrong_pin_blocks_and_unblocks() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = YubiKeyProvider::with_device("pin".to_string(), Box::new(device.clone()));
+    provider.set_credential_provider(credentials("000000", MgmKey::default().as_ref()));
+
+    for expected in [2, 1, 0] {
+        match provider.initialize_module() {
+            Err(SecurityModuleError::AuthenticationFailed { retries, .. }) => {
+                assert_eq!(retries, Some(expected))
+            }
+            other => panic!("Expected AuthenticationFailed, got {:?}", other),
+        }
+    }
+    // A blocked PIN is rejected even if it is correct.
+    let mut correct = YubiKeyProvider::with_device("pin".to_string(), Box::new(device.clone()));
+    correct.set_credential_provider(credentials(DEFAULT_PIN, MgmKey::default().as_ref()));
+    assert!(correct.initialize_module().is_err());
+
+    provider
+        .unblock_pin("24681357")
+        .expect("Failed to unblock PIN");
+    let mut unblocked = YubiKeyProvider::with_device("pin".to_string(), Box::new(device));
+    unblocked.set_credential_provider(credentials("24681357", MgmKey::default().as_ref()));
+    unblocked
+        .initialize_module()
+        .expect("Failed to initialize module");
+    assert_eq!(unblocked.pin_retries().unwrap(), 3);
+    assert_eq!(unblocked.puk_retries().unwrap(), 3);
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_change_pin() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = provider(&device, "pin");
+    provider
+        .change_pin("24681357")
+        .expect("Failed to change PIN");
+
+    let mut old_pin = YubiKeyProvider::with_device("pin".to_string(), Box::new(device.clone()));
+    old_pin.set_credential_provider(credentials(DEFAULT_PIN, MgmKey::default().as_ref()));
+    assert!(matches!(
+        old_pin.initialize_module(),
+        Err(SecurityModuleError::AuthenticationFailed { .. })
+    ));
+
+    let mut new_pin = YubiKeyProvider::with_device("pin".to_string(), Box::new(device));
+    new_pin.set_credential_provider(credentials("24681357", MgmKey::default().as_ref()));
+    new_pin
+        .initialize_module()
+        .expect("Failed to initialize module");
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_wrong_management_key() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = YubiKeyProvider::with_device("mgm".to_string(), Box::new(device));
+    provider.set_credential_provider(credentials(DEFAULT_PIN, &[0x42; 24]));
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+
+    assert!(matches!(
+        provider.create_key("mgm", Box::new(config(p256()))),
+        Err(SecurityModuleError::AuthenticationFailed { .. })
+    ));
+    assert!(provider.list_keys().unwrap().is_empty());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_pin_protected_management_key() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = provider(&device, "mgm");
+    let new_key = provider
+        .rotate_management_key(ManagementKeyMode::PinProtected)
+        .expect("Failed to rotate management key");
+    assert_ne!(new_key, *MgmKey::default().as_ref());
+
+    // Without a management key from the credential provider, the protected one is used.
+    let mut protected = YubiKeyProvider::with_device("mgm".to_string(), Box::new(device));
+    protected.set_credential_provider(Arc::new(
+        StaticCredentials::new().with(Credential::Pin, DEFAULT_PIN),
+    ));
+    protected
+        .initialize_module()
+        .expect("Failed to initialize module");
+    protected
+        .create_key("mgm", Box::new(config(p256())))
+        .expect("Failed to create key");
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_aes_management_key() {
+    // Firmware 5.7 ships with an AES-192 key with the bytes of the default 3DES key.
+    for key in [
+        vec![0x42; 16],
+        MgmKey::default().as_ref().to_vec(),
+        vec![0x42; 32],
+    ] {
+        let device = SimulatedPivDevice::with_management_key(Version::new([5, 7, 1]), &key);
+        let mut provider = YubiKeyProvider::with_device("aes".to_string(), Box::new(device));
+        provider.set_credential_provider(credentials(DEFAULT_PIN, &key));
+        provider
+            .initialize_module()
+            .expect("Failed to initialize module");
+        provider
+            .create_key("aes", Box::new(config(ed25519())))
+            .expect("Failed to create key");
+        let signature = provider.sign_data(DATA).expect("Failed to sign data");
+        assert!(provider.verify_signature(DATA, &signature).unwrap());
+    }
+
+    let device = SimulatedPivDevice::with_version(Version::new([5, 7, 1]));
+    let mut provider = YubiKeyProvider::with_device("aes".to_string(), Box::new(device));
+    provider.set_credential_provider(credentials(DEFAULT_PIN, &[0x42; 20]));
+    assert!(matches!(
+        provider.initialize_module(),
+        Err(SecurityModuleError::InitializationError(_))
+    ));
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_extended_algorithms_require_firmware_5_7() {
+    let mut provider = provider(&SimulatedPivDevice::new(), "ed25519");
+    match provider.create_key("ed25519", Box::new(config(ed25519()))) {
+        Err(SecurityModuleError::Hsm(HsmError::UnsupportedFeature(message))) => {
+            assert!(message.contains("firmware 5.7"), "{}", message)
+        }
+        other => panic!("Expected UnsupportedFeature, got {:?}", other),
+    }
+    assert!(provider.list_keys().unwrap().is_empty());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_sign_and_verify_with_extended_algorithms() {
+    let device = SimulatedPivDevice::with_version(Version::new([5, 7, 1]));
+    let mut provider = provider(&device, "extended");
+
+    for (key_algorithm, algorithm) in [
+        (
+            AsymmetricEncryption::Rsa(KeyBits::Bits3072),
+            PivAlgorithm::Rsa3072,
+        ),
+        (
+            AsymmetricEncryption::Rsa(KeyBits::Bits4096),
+            PivAlgorithm::Rsa4096,
+        ),
+        (ed25519(), PivAlgorithm::Ed25519),
+    ] {
+        let key_id = format!("{:?}", algorithm);
+        provider
+            .create_key(&key_id, Box::new(config(key_algorithm)))
+            .expect("Failed to create key");
+        assert_eq!(provider.metadata().unwrap().algorithm, Some(algorithm));
+
+        let signature = provider.sign_data(DATA).expect("Failed to sign data");
+        assert!(provider.verify_signature(DATA, &signature).unwrap());
+        assert!(provider
+            .verify_signature(b"other data", &signature)
+            .is_err());
+
+        // The key is found and used again by a provider that did not create it.
+        let mut loader = self::provider(&device, &key_id);
+        loader
+            .load_key(&key_id, Box::new(config(key_algorithm)))
+            .expect("Failed to load key");
+        let signature = loader.sign_data(DATA).expect("Failed to sign data");
+        assert!(provider.verify_signature(DATA, &signature).unwrap());
+    }
+
+    // Ed25519 signatures are deterministic and can be checked with the public key alone.
+    let public_key = PKey::public_key_from_der(&provider.metadata().unwrap().public_key).unwrap();
+    let signature = provider.sign_data(DATA).unwrap();
+    assert!(openssl::sign::Verifier::new_without_digest(&public_key)
+        .unwrap()
+        .verify_oneshot(&signature, DATA)
+        .unwrap());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_encrypt_and_decrypt_rsa_4096() {
+    let device = SimulatedPivDevice::with_version(Version::new([5, 7, 1]));
+    let mut provider = provider(&device, "encryption");
+    provider
+        .create_key(
+            "encryption",
+            Box::new(config(AsymmetricEncryption::Rsa(KeyBits::Bits4096))),
+        )
+        .expect("Failed to create key");
+
+    let encrypted = provider.encrypt_data(DATA).expect("Failed to encrypt data");
+    assert_eq!(encrypted.len(), 512);
+    let decrypted = provider
+        .decrypt_data(&encrypted)
+        .expect("Failed to decrypt data");
+    assert_eq!(decrypted, DATA);
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_derive_x25519_shared_secret() {
+    let device = SimulatedPivDevice::with_version(Version::new([5, 7, 1]));
+    let mut provider = provider(&device, "x25519");
+    provider
+        .create_key("x25519", Box::new(config(x25519())))
+        .expect("Failed to create key");
+
+    let peer = PKey::generate_x25519().unwrap();
+    let secret = provider
+        .derive_shared_secret(&peer.public_key_to_der().unwrap())
+        .expect("Failed to derive shared secret");
+
+    let public_key = PKey::public_key_from_der(&provider.metadata().unwrap().public_key).unwrap();
+    let mut deriver = Deriver::new(&peer).unwrap();
+    deriver.set_peer(&public_key).unwrap();
+    assert_eq!(secret, deriver.derive_to_vec().unwrap());
+
+    // X25519 keys can not sign, and only take X25519 public keys.
+    assert!(matches!(
+        provider.sign_data(DATA),
+        Err(SecurityModuleError::Hsm(HsmError::UnsupportedFeature(_)))
+    ));
+    let ec_peer = PKey::from_ec_key(
+        openssl::ec::EcKey::generate(
+            &openssl::ec::EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    assert!(provider
+        .derive_shared_secret(&ec_peer.public_key_to_der().unwrap())
+        .is_err());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_placeholder_certificate_needs_yubikey_crate_algorithm() {
+    let device = SimulatedPivDevice::with_version(Version::new([5, 7, 1]));
+    let mut provider = provider(&device, "ed25519");
+    let config = HsmProviderConfig {
+        placeholder_certificate: true,
+        ..config(ed25519())
+    };
+    assert!(matches!(
+        provider.create_key("ed25519", Box::new(config)),
+        Err(SecurityModuleError::Hsm(HsmError::UnsupportedFeature(_)))
+    ));
+    assert!(provider.list_keys().unwrap().is_empty());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_list_legacy_record() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = provider(&device, "legacy");
+    let public_key = PKey::from_ec_key(
+        openssl::ec::EcKey::generate(
+            &openssl::ec::EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap()
+    .public_key_to_pem()
+    .unwrap();
+
+    // Earlier versions stored the record of the key in R1 in the certificate object of R11.
+    let mut record = b"legacy\x006275351\x00".to_vec();
+    record.extend_from_slice(&public_key);
+    record.push(0);
+    {
+        let mut yubikey = device.clone();
+        yubikey.authenticate(MgmKey::default().as_ref()).unwrap();
+        yubikey.save_object(0x005f_c117, &mut record).unwrap();
+    }
+
+    let keys = provider.list_keys().expect("Failed to list keys");
+    assert_eq!(keys.len(), 1);
+    assert_eq!(keys[0].key_id, "legacy");
+    assert_eq!(keys[0].slot, retired(1));
+
+    // The slot stays taken for new keys.
+    provider
+        .create_key("new", Box::new(config(p256())))
+        .expect("Failed to create key");
+    assert_eq!(provider.metadata().unwrap().slot, retired(2));
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_attestation() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = provider(&device, "attested");
+    provider
+        .create_key("attested", Box::new(config(p256())))
+        .expect("Failed to create key");
+
+    let attestation = provider
+        .attestation(Some(&device.attestation_root()))
+        .expect("Failed to attest key");
+    assert_eq!(attestation.serial, Some(Serial(SERIAL)));
+    let version = attestation.firmware_version.expect("No firmware version");
+    assert_eq!((version.major, version.minor, version.patch), (5, 4, 3));
+    assert_eq!(
+        attestation.public_key().unwrap(),
+        provider.metadata().unwrap().public_key
+    );
+
+    let other = SimulatedPivDevice::new();
+    assert!(provider
+        .attestation(Some(&other.attestation_root()))
+        .is_err());
+
+    // The attestation certificate can not include the nonce of a relying party.
+    let pem = provider.attest_key(&[]).expect("Failed to attest key");
+    Attestation::from_pem(&pem)
+        .and_then(|attestation| attestation.verify(Some(&device.attestation_root())))
+        .expect("Failed to verify attestation");
+    assert!(provider.attest_key(&[0x42; 32]).is_err());
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_placeholder_certificate() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = provider(&device, "placeholder");
+    for key_algorithm in [p256(), AsymmetricEncryption::Rsa(KeyBits::Bits1024)] {
+        provider
+            .create_key(
+                "placeholder",
+                Box::new(HsmProviderConfig {
+                    placeholder_certificate: true,
+                    replace_existing_key: true,
+                    ..config(key_algorithm)
+                }),
+            )
+            .expect("Failed to create key");
+
+        let chain = provider
+            .certificate_chain()
+            .expect("Failed to read certificate chain");
+        assert_eq!(chain.len(), 1);
+        let certificate = X509::from_der(&chain[0]).unwrap();
+        let public_key = certificate.public_key().unwrap();
+        assert_eq!(
+            public_key.public_key_to_der().unwrap(),
+            provider.metadata().unwrap().public_key
+        );
+        assert!(certificate.verify(&public_key).unwrap());
+    }
+}
+
+#[cfg(feature = "yubi")]
+#[test]
+fn test_store_certificate_chain() {
+    let device = SimulatedPivDevice::new();
+    let mut provider = provider(&device, "certified");
+    provider
+        .create_key("certified", Box::new(config(p256())))
+        .expect("Failed to create key");
+
+    let ca_key = PKey::from_ec_key(
+        openssl::ec::EcKey::generate(
+            &openssl::ec::EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap(),
+        )
+        .unwrap(),
+    )
+    .unwrap();
+    let public_key: PKey<Public> =
+        PKey::public_key_from_der(&provider.metadata().unwrap().public_key).unwrap();
+    let issue = |subject: &str, key: &PKey<Public>| {
+        let mut name = X509Name::builder().unwrap();
+        name.append_entry_by_nid(Nid::COMMONNAME, subject).unwrap();
+        let name = name.build();
+        let mut issuer = X509Name::builder().unwrap();
+        issuer
+            .append_entry_by_nid(Nid::COMMONNAME, "Test CA")
+            .unwrap();
+        let issuer = issuer.build();
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder
+            .set_serial_number(&BigNum::from_u32(1).unwrap().to_asn1_integer().unwrap())
+            .unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&issuer).unwrap();
+        builder.set_pubkey(key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&ca_key, MessageDigest::sha256()).unwrap();
+        builder.build().to_der().unwrap()
+    };
+    let ca_public_key = PKey::public_key_from_der(&ca_key.public_key_to_der().unwrap()).unwrap();
+    let ca = issue("Test CA", &ca_public_key);
+    let certificate = issue("certified", &public_key);
+
+    // A certificate for another key is rejected.
+    assert!(provider.store_certificate(&ca, &[]).is_err());
+
+    provider
+        .store_certificate(&certificate, std::slice::from_ref(&ca))
+        .expect("Failed to store certificate");
+    assert_eq!(provider.certificate().unwrap(), certificate);
+    assert_eq!(provider.certificate_chain().unwrap(), vec![certificate, ca]);
+}
@@ -0,0 +1,71 @@
+use crate::{
+    common::traits::module_provider::Provider,
+    tpm::linux::{
+        nv::{NvAuthorization, NvIndexKind},
+        TpmProvider,
+    },
+};
+use tss_esapi::{
+    constants::{AlgorithmIdentifier, EccCurveIdentifier},
+    interface_types::algorithm::HashingAlgorithm,
+};
+
+fn provider() -> TpmProvider {
+    let mut provider = TpmProvider::new("test_device".to_string());
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+}
+
+#[test]
+fn test_device_info() {
+    let info = provider()
+        .device_info()
+        .expect("Failed to read device info");
+
+    assert_eq!(info.family, "2.0");
+    assert!(!info.manufacturer.is_empty());
+    assert!(info.revision >= 116);
+    assert!(info.algorithms.contains(&AlgorithmIdentifier::Rsa));
+    assert!(info.algorithms.contains(&AlgorithmIdentifier::Sha256));
+    assert!(info.ecc_curves.contains(&EccCurveIdentifier::NistP256));
+    assert!(info.pcr_banks.contains(&HashingAlgorithm::Sha256));
+}
+
+#[test]
+fn test_health() {
+    let health = provider().health().expect("Failed to read health");
+
+    assert!(health.self_test_passed);
+    assert!(!health.lockout.in_lockout);
+    assert!(health.lockout.counter < health.lockout.max_tries);
+}
+
+#[test]
+fn test_reset_lockout() {
+    let provider = provider();
+    let index = 0x0150_0005;
+    let auth = NvAuthorization::Index(b"1234".to_vec());
+    provider
+        .nv_define(index, NvIndexKind::Ordinary(8), &auth)
+        .expect("Failed to define NV index");
+    provider.nv_write(index, &[0; 8], &auth).unwrap();
+
+    let before = provider.health().unwrap().lockout.counter;
+    assert!(provider
+        .nv_read(index, &NvAuthorization::Index(b"4321".to_vec()))
+        .is_err());
+    let after_failure = provider.health().unwrap().lockout.counter;
+    provider
+        .reset_lockout(b"")
+        .expect("Failed to reset lockout");
+    let after_reset = provider.health().unwrap().lockout;
+    provider
+        .nv_undefine(index)
+        .expect("Failed to undefine NV index");
+
+    assert_eq!(after_failure, before + 1);
+    assert_eq!(after_reset.counter, 0);
+    assert!(!after_reset.in_lockout);
+}
@@ -0,0 +1,164 @@
+use crate::{
+    common::{
+        crypto::{
+            algorithms::{
+                encryption::{
+                    AsymmetricEncryption, BlockCiphers, EccCurves, EccSchemeAlgorithm,
+                    SymmetricMode,
+                },
+                hashes::{Hash, Sha2Bits},
+                KeyBits,
+            },
+            KeyUsage,
+        },
+        traits::{
+            key_handle::KeyHandle, module_provider::Provider,
+            module_provider_config::ProviderConfig,
+        },
+    },
+    tpm::{
+        linux::{
+            duplication::{DuplicatedKey, WrappedKey},
+            policy::KeyPolicy,
+            TpmProvider,
+        },
+        TpmConfig,
+    },
+};
+use tss_esapi::{interface_types::algorithm::AsymmetricAlgorithm, structures::Public};
+
+fn config() -> Box<dyn ProviderConfig> {
+    TpmConfig::new(
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256)),
+        BlockCiphers::Aes(SymmetricMode::Cfb, KeyBits::Bits128),
+        Hash::Sha2(Sha2Bits::Sha256),
+        vec![KeyUsage::SignEncrypt],
+    )
+}
+
+/// Permits using the key with its auth value, or duplicating it to `new_parent`.
+fn policy(new_parent: &Public) -> KeyPolicy {
+    KeyPolicy::new().or(vec![
+        KeyPolicy::new().auth_value(),
+        KeyPolicy::new().duplication(new_parent.clone()),
+    ])
+}
+
+fn provider() -> TpmProvider {
+    let mut provider = TpmProvider::new("test_duplication".to_string());
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+}
+
+#[test]
+fn test_duplicate_and_import() {
+    let mut source = provider();
+    // The target is the same TPM, standing in for the storage root of a replacement board.
+    let new_parent = source.storage_root_public().unwrap();
+    source.set_key_policy(Some(policy(&new_parent)));
+    source
+        .create_duplicable_key(config())
+        .expect("Failed to create duplicable key");
+    let signature = source.sign_data(b"Hello, World!").unwrap();
+
+    let duplicated = source
+        .duplicate_key(&new_parent)
+        .expect("Failed to duplicate key");
+    let duplicated = DuplicatedKey::from_bytes(&duplicated.to_bytes().unwrap()).unwrap();
+
+    let mut target = provider();
+    target.set_key_policy(Some(policy(&new_parent)));
+    let imported = target
+        .import_key(&duplicated)
+        .expect("Failed to import key");
+    let imported = WrappedKey::from_bytes(&imported.to_bytes().unwrap()).unwrap();
+    target
+        .load_external_key(imported.public.clone(), config())
+        .unwrap();
+    assert!(target
+        .verify_signature(b"Hello, World!", &signature)
+        .unwrap());
+
+    target.load_wrapped_key(&imported).unwrap();
+    let signature = target.sign_data(b"Hello, World!").unwrap();
+    assert!(source
+        .verify_signature(b"Hello, World!", &signature)
+        .unwrap());
+}
+
+#[test]
+fn test_duplicate_with_duplication_policy() {
+    let mut source = provider();
+    let new_parent = source.storage_root_public().unwrap();
+    // Without an OR, the key can only be duplicated, never used.
+    source.set_key_policy(Some(KeyPolicy::new().duplication(new_parent.clone())));
+    source.create_duplicable_key(config()).unwrap();
+    assert!(source.sign_data(b"Hello, World!").is_err());
+
+    let duplicated = source
+        .duplicate_key(&new_parent)
+        .expect("Failed to duplicate key");
+
+    let mut target = provider();
+    let imported = target
+        .import_key(&duplicated)
+        .expect("Failed to import key");
+    assert_eq!(imported.public, duplicated.public);
+    target.set_key_policy(Some(KeyPolicy::new().duplication(new_parent.clone())));
+    target
+        .load_wrapped_key(&imported)
+        .expect("Failed to load imported key");
+}
+
+#[test]
+fn test_duplicate_to_other_parent_fails() {
+    let mut source = provider();
+    let new_parent = source.storage_root_public().unwrap();
+    source.set_key_policy(Some(policy(&new_parent)));
+    source.create_duplicable_key(config()).unwrap();
+
+    let other_parent = source.endorsement_key(AsymmetricAlgorithm::Rsa).unwrap();
+
+    assert!(source.duplicate_key(&other_parent).is_err());
+}
+
+#[test]
+fn test_duplicate_fixed_key_fails() {
+    let mut provider = provider();
+    provider
+        .create_key("test_duplication", config())
+        .expect("Failed to create key");
+    let new_parent = provider.storage_root_public().unwrap();
+
+    assert!(provider.duplicate_key(&new_parent).is_err());
+}
+
+#[test]
+fn test_duplicable_key_requires_policy() {
+    assert!(provider().create_duplicable_key(config()).is_err());
+}
+
+#[test]
+fn test_pinned_storage_root() {
+    let mut provider = provider();
+    let storage_root = provider.storage_root_public().unwrap();
+
+    let mut pinned = self::provider();
+    pinned.set_storage_root(&storage_root).unwrap();
+    pinned
+        .create_key("test_duplication", config())
+        .expect("Failed to create key with the pinned storage root");
+    pinned.sign_data(b"Hello, World!").unwrap();
+}
+
+#[test]
+fn test_mismatching_storage_root_fails() {
+    let mut provider = provider();
+    let other_key = provider.endorsement_key(AsymmetricAlgorithm::Rsa).unwrap();
+    provider.set_storage_root(&other_key).unwrap();
+
+    assert!(provider.storage_root_public().is_err());
+    assert!(provider.create_key("test_duplication", config()).is_err());
+}
@@ -0,0 +1,159 @@
+//! The fixtures are logs in the `binary_bios_measurements` layout of a UEFI boot that loads
+//! shim and GRUB. They only differ in the measurement of the GRUB image.
+
+use crate::{
+    common::traits::module_provider::Provider,
+    tpm::linux::{
+        event_log::{EventLog, EventType, BIOS_MEASUREMENTS_PATH},
+        TpmProvider,
+    },
+};
+use std::{fs, io::ErrorKind};
+use tss_esapi::interface_types::algorithm::HashingAlgorithm;
+
+const REFERENCE_LOG: &[u8] = include_bytes!("fixtures/event_log_reference.bin");
+const UPDATED_LOG: &[u8] = include_bytes!("fixtures/event_log_updated.bin");
+
+fn hex(value: &str) -> Vec<u8> {
+    (0..value.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&value[i..i + 2], 16).unwrap())
+        .collect()
+}
+
+#[test]
+fn test_parse_event_log() {
+    let log = EventLog::parse(REFERENCE_LOG).expect("Failed to parse event log");
+
+    assert_eq!(
+        log.algorithms,
+        vec![(HashingAlgorithm::Sha1, 20), (HashingAlgorithm::Sha256, 32)]
+    );
+    assert_eq!(log.startup_locality, 3);
+    assert_eq!(log.events.len(), 15);
+    assert_eq!(log.events[0].event_type, EventType::NoAction);
+    assert_eq!(log.events[1].event_type, EventType::SCrtmVersion);
+    assert_eq!(log.events[1].description().as_deref(), Some("1.0.42"));
+    assert_eq!(log.events[3].pcr_index, 7);
+    assert_eq!(log.events[3].description().as_deref(), Some("SecureBoot"));
+    assert_eq!(
+        log.events[5].description().as_deref(),
+        Some("Calling EFI Application from Boot Option")
+    );
+    assert_eq!(
+        log.events[14].event_type,
+        EventType::EfiBootServicesApplication
+    );
+    assert_eq!(
+        log.events[14]
+            .digest(HashingAlgorithm::Sha256)
+            .map(<[u8]>::len),
+        Some(32)
+    );
+}
+
+#[test]
+fn test_replay_event_log() {
+    let pcrs = EventLog::parse(REFERENCE_LOG).unwrap().replay().unwrap();
+
+    assert_eq!(
+        pcrs.get(HashingAlgorithm::Sha256, 0),
+        Some(hex("3bc24da3970d20305b84cad515d6aa87623107fd301254809f867f5830fe66f4").as_slice())
+    );
+    assert_eq!(
+        pcrs.get(HashingAlgorithm::Sha256, 4),
+        Some(hex("96dac81f9e98ddc8208f57486625c12264154d3cfc96417a159391f5b2e3b8cf").as_slice())
+    );
+    assert_eq!(
+        pcrs.get(HashingAlgorithm::Sha1, 7),
+        Some(hex("3a73fc9d29ebdbc866f1ad32f75fbafc0cc48807").as_slice())
+    );
+    assert_eq!(pcrs.get(HashingAlgorithm::Sha256, 8), None);
+}
+
+#[test]
+fn test_verify_event_log() {
+    let reference = EventLog::parse(REFERENCE_LOG).unwrap();
+    let updated = EventLog::parse(UPDATED_LOG).unwrap();
+
+    assert!(reference
+        .verify(&reference.replay().unwrap())
+        .unwrap()
+        .is_empty());
+
+    let mismatches = reference.verify(&updated.replay().unwrap()).unwrap();
+    assert_eq!(mismatches.len(), 2);
+    assert!(mismatches.iter().all(|mismatch| mismatch.index == 4));
+    let sha256 = mismatches
+        .iter()
+        .find(|mismatch| mismatch.bank == HashingAlgorithm::Sha256)
+        .unwrap();
+    assert_eq!(
+        sha256.reported,
+        Some(hex(
+            "80dfe1d66980e17f546fa35d594121d23a87b5a3eeb489dfbae6f60daeb4d624"
+        ))
+    );
+}
+
+#[test]
+fn test_diff_event_log() {
+    let reference = EventLog::parse(REFERENCE_LOG).unwrap();
+    let updated = EventLog::parse(UPDATED_LOG).unwrap();
+
+    assert!(reference.diff(&reference).is_empty());
+
+    let diffs = updated.diff(&reference);
+    assert_eq!(diffs.len(), 1);
+    assert_eq!(diffs[0].pcr_index, 4);
+    assert_eq!(diffs[0].position, 3);
+    assert_eq!(diffs[0].actual.as_ref(), updated.events.last());
+    assert_eq!(diffs[0].reference.as_ref(), reference.events.last());
+}
+
+#[test]
+fn test_parse_invalid_event_log() {
+    assert!(EventLog::parse(&REFERENCE_LOG[..REFERENCE_LOG.len() - 1]).is_err());
+    // Replace the spec ID signature of the header.
+    let mut legacy = REFERENCE_LOG.to_vec();
+    legacy[32..48].copy_from_slice(b"Spec ID Event02\0");
+    assert!(EventLog::parse(&legacy).is_err());
+
+    // Move the first event after the header to PCR 32, which no PCR selection can address.
+    let header_size = u32::from_le_bytes(REFERENCE_LOG[28..32].try_into().unwrap()) as usize;
+    let mut out_of_range = REFERENCE_LOG.to_vec();
+    out_of_range[32 + header_size..36 + header_size].copy_from_slice(&32u32.to_le_bytes());
+    assert!(EventLog::parse(&out_of_range).is_err());
+}
+
+#[test]
+fn test_verify_event_log_against_tpm() {
+    let mut provider = TpmProvider::new("test_event_log".to_string());
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+
+    // The fixture does not describe the boot of the test TPM, so its PCRs cannot match.
+    let log = EventLog::parse(REFERENCE_LOG).unwrap();
+    let mismatches = provider
+        .verify_event_log(&log)
+        .expect("Failed to verify event log");
+    assert!(mismatches
+        .iter()
+        .any(|mismatch| mismatch.index == 4 && mismatch.reported.is_some()));
+}
+
+#[test]
+fn test_parse_event_log_of_this_machine() {
+    // Only machines booted with a TPM expose their log, and reading it usually requires root.
+    // The PCRs are not compared, as the tests may run against a simulator.
+    let data = match fs::read(BIOS_MEASUREMENTS_PATH) {
+        Ok(data) => data,
+        Err(e) if matches!(e.kind(), ErrorKind::NotFound | ErrorKind::PermissionDenied) => return,
+        Err(e) => panic!("Failed to read event log: {}", e),
+    };
+    let log = EventLog::parse(&data).expect("Failed to parse event log of this machine");
+
+    assert!(log.replay().is_ok());
+    assert!(log.diff(&log).is_empty());
+}
@@ -0,0 +1,198 @@
+use crate::{
+    common::{
+        crypto::{
+            algorithms::{
+                encryption::{
+                    AsymmetricEncryption, BlockCiphers, EccCurves, EccSchemeAlgorithm,
+                    SymmetricMode,
+                },
+                hashes::{Hash, Sha2Bits},
+                KeyBits,
+            },
+            KeyUsage,
+        },
+        traits::{key_handle::KeyHandle, module_provider::Provider},
+    },
+    tpm::{
+        linux::{
+            attestation::{KeyAttestation, KeyCreation},
+            identity::{make_credential, AttestationKey},
+            verifier::verify_key_attestation,
+            TpmProvider,
+        },
+        TpmConfig,
+    },
+};
+use openssl::{
+    md::Md,
+    pkey::PKey,
+    pkey_ctx::PkeyCtx,
+    rsa::{Padding, Rsa},
+};
+use tss_esapi::{
+    abstraction::ek::create_ek_public_from_default_template,
+    interface_types::algorithm::{AsymmetricAlgorithm, HashingAlgorithm, SignatureSchemeAlgorithm},
+    structures::{Public, PublicKeyRsa},
+    traits::Marshall,
+};
+
+fn provider() -> TpmProvider {
+    let mut provider = TpmProvider::new("test_identity".to_string());
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+}
+
+fn activate(ek_algorithm: AsymmetricAlgorithm, scheme: SignatureSchemeAlgorithm) {
+    let provider = provider();
+    let ek_public = provider
+        .endorsement_key(ek_algorithm)
+        .expect("Failed to read endorsement key");
+    let ak = provider
+        .create_attestation_key(ek_algorithm, scheme, HashingAlgorithm::Sha256)
+        .expect("Failed to create attestation key");
+    let ak = AttestationKey::from_bytes(&ak.to_bytes().unwrap()).unwrap();
+
+    let credential = make_credential(&ek_public, &ak.name().unwrap(), b"enrollment secret")
+        .expect("Failed to make credential");
+
+    assert_eq!(
+        provider.activate_credential(&ak, &credential).unwrap(),
+        b"enrollment secret"
+    );
+}
+
+#[test]
+fn test_activate_credential_rsa_ek() {
+    activate(AsymmetricAlgorithm::Rsa, SignatureSchemeAlgorithm::RsaSsa);
+}
+
+#[test]
+fn test_activate_credential_ecc_ek() {
+    activate(AsymmetricAlgorithm::Ecc, SignatureSchemeAlgorithm::EcDsa);
+}
+
+#[test]
+fn test_make_credential_layout() {
+    let rsa = Rsa::generate(2048).unwrap();
+    let ek_public =
+        match create_ek_public_from_default_template(AsymmetricAlgorithm::Rsa, None).unwrap() {
+            Public::Rsa {
+                object_attributes,
+                name_hashing_algorithm,
+                auth_policy,
+                parameters,
+                ..
+            } => Public::Rsa {
+                object_attributes,
+                name_hashing_algorithm,
+                auth_policy,
+                parameters,
+                unique: PublicKeyRsa::try_from(rsa.n().to_vec()).unwrap(),
+            },
+            _ => unreachable!(),
+        };
+    let name = [0u8; 34];
+
+    let credential = make_credential(&ek_public, &name, b"secret").unwrap();
+
+    // u16 HMAC size, SHA-256 HMAC, encrypted u16 size and credential.
+    assert_eq!(&credential.credential_blob[..2], &[0, 32]);
+    assert_eq!(credential.credential_blob.len(), 2 + 32 + 2 + 6);
+
+    let pkey = PKey::from_rsa(rsa).unwrap();
+    let mut ctx = PkeyCtx::new(&pkey).unwrap();
+    ctx.decrypt_init().unwrap();
+    ctx.set_rsa_padding(Padding::PKCS1_OAEP).unwrap();
+    ctx.set_rsa_oaep_md(Md::sha256()).unwrap();
+    ctx.set_rsa_mgf1_md(Md::sha256()).unwrap();
+    ctx.set_rsa_oaep_label(b"IDENTITY\0").unwrap();
+    let mut seed = Vec::new();
+    ctx.decrypt_to_vec(&credential.secret, &mut seed).unwrap();
+    assert_eq!(seed.len(), 32);
+}
+
+#[test]
+fn test_make_credential_rejects_oversized_credential() {
+    let ek_public = create_ek_public_from_default_template(AsymmetricAlgorithm::Rsa, None).unwrap();
+
+    assert!(make_credential(&ek_public, &[0; 34], &[0; 33]).is_err());
+}
+
+#[test]
+fn test_attest_key() {
+    let mut provider = provider();
+    let ak = provider
+        .create_attestation_key(
+            AsymmetricAlgorithm::Rsa,
+            SignatureSchemeAlgorithm::RsaSsa,
+            HashingAlgorithm::Sha256,
+        )
+        .expect("Failed to create attestation key");
+    let ak_public = ak.public.marshall().unwrap();
+    provider.set_attestation_key(Some(ak));
+    provider
+        .create_key(
+            "test_identity",
+            TpmConfig::new(
+                AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256)),
+                BlockCiphers::Aes(SymmetricMode::Cfb, KeyBits::Bits128),
+                Hash::Sha2(Sha2Bits::Sha256),
+                vec![KeyUsage::SignEncrypt, KeyUsage::ClientAuth],
+            ),
+        )
+        .expect("Failed to create key");
+
+    let nonce = [0x42; 32];
+    let attestation = KeyAttestation::from_bytes(&provider.attest_key(&nonce).unwrap()).unwrap();
+    let verdict = verify_key_attestation(&attestation, &ak_public, &nonce).unwrap();
+    let replayed = verify_key_attestation(&attestation, &ak_public, &[0x24; 32]).unwrap();
+
+    assert!(verdict.is_hardware_bound());
+    assert!(!replayed.nonce_valid);
+    assert!(!replayed.is_hardware_bound());
+}
+
+#[test]
+fn test_attest_key_with_stored_creation_data() {
+    let mut provider = provider();
+    let ak = provider
+        .create_attestation_key(
+            AsymmetricAlgorithm::Ecc,
+            SignatureSchemeAlgorithm::EcDsa,
+            HashingAlgorithm::Sha256,
+        )
+        .expect("Failed to create attestation key");
+    let ak_public = ak.public.marshall().unwrap();
+    provider.set_attestation_key(Some(ak));
+    provider
+        .create_key(
+            "test_identity",
+            TpmConfig::new(
+                AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256)),
+                BlockCiphers::Aes(SymmetricMode::Cfb, KeyBits::Bits128),
+                Hash::Sha2(Sha2Bits::Sha256),
+                vec![KeyUsage::SignEncrypt, KeyUsage::ClientAuth],
+            ),
+        )
+        .expect("Failed to create key");
+    let stored = provider.key_creation().unwrap().to_bytes();
+
+    // Without the creation data, e.g. after loading the key again, it cannot be certified.
+    provider.set_key_creation(None);
+    assert!(provider.attest_key(&[0x42; 32]).is_err());
+
+    provider.set_key_creation(Some(KeyCreation::from_bytes(&stored).unwrap()));
+    let attestation =
+        KeyAttestation::from_bytes(&provider.attest_key(&[0x42; 32]).unwrap()).unwrap();
+    let verdict = verify_key_attestation(&attestation, &ak_public, &[0x42; 32]).unwrap();
+
+    assert!(verdict.creation_valid);
+    assert!(verdict.is_hardware_bound());
+}
+
+#[test]
+fn test_attest_key_without_attestation_key() {
+    assert!(provider().attest_key(&[0x42; 32]).is_err());
+}
@@ -1,6 +1,10 @@
 use crate::{
-    common::crypto::algorithms::{encryption::SymmetricMode, hashes::Sha2Bits, KeyBits},
-    tpm::TpmConfig,
+    common::{
+        crypto::algorithms::{encryption::SymmetricMode, hashes::Sha2Bits, KeyBits},
+        error::SecurityModuleError,
+        traits::module_provider_config::ProviderConfig,
+    },
+    tpm::{core::error::TpmError, linux::duplication::DuplicatedKey, TpmConfig},
 };
 #[allow(unused_imports)]
 use crate::{
@@ -16,6 +20,22 @@ use crate::{
     },
     tpm::linux::TpmProvider,
 };
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey},
+    nid::Nid,
+};
+use tss_esapi::{
+    attributes::ObjectAttributesBuilder,
+    interface_types::{
+        algorithm::{HashingAlgorithm, PublicAlgorithm},
+        ecc::EccCurve,
+    },
+    structures::{
+        EccParameter, EccPoint, EccScheme, EncryptedSecret, HashScheme, Private, PublicBuilder,
+        PublicEccParameters,
+    },
+};
 
 #[test]
 fn test_sign_and_verify_rsa() {
@@ -102,7 +122,7 @@ fn test_encrypt_and_decrypt_ecdh() {
 
     let config = TpmConfig::new(
         AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDh(EccCurves::Curve25519)),
-        BlockCiphers::Aes(SymmetricMode::Gcm, KeyBits::Bits512),
+        BlockCiphers::Aes(SymmetricMode::Cfb, KeyBits::Bits256),
         Hash::Sha2(Sha2Bits::Sha256),
         vec![
             KeyUsage::SignEncrypt,
@@ -126,3 +146,234 @@ fn test_encrypt_and_decrypt_ecdh() {
 
     assert_eq!(data, decrypted_data.as_slice());
 }
+
+#[test]
+fn test_encrypt_and_decrypt_ecdh_symmetric_key() {
+    let mut provider = TpmProvider::new("test_ecdh_sym_key".to_string());
+
+    let config = TpmConfig::new(
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDh(EccCurves::P256)),
+        BlockCiphers::Aes(SymmetricMode::Cbc, KeyBits::Bits128),
+        Hash::Sha2(Sha2Bits::Sha256),
+        vec![KeyUsage::ClientAuth, KeyUsage::Decrypt],
+    );
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+        .create_key("test_ecdh_sym_key", config)
+        .expect("Failed to create ECDH key");
+
+    let data = b"Hello, World!";
+    let first = provider.encrypt_data(data).expect("Failed to encrypt data");
+    let second = provider.encrypt_data(data).expect("Failed to encrypt data");
+    let decrypted_data = provider
+        .decrypt_data(&first)
+        .expect("Failed to decrypt data");
+
+    // Every encryption uses a fresh IV.
+    assert_ne!(first, second);
+    assert_eq!(data, decrypted_data.as_slice());
+}
+
+#[test]
+fn test_encrypt_with_gcm_unsupported() {
+    let mut provider = TpmProvider::new("test_ecdsa_gcm_key".to_string());
+
+    let config = TpmConfig::new(
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256)),
+        BlockCiphers::Aes(SymmetricMode::Gcm, KeyBits::Bits256),
+        Hash::Sha2(Sha2Bits::Sha256),
+        vec![KeyUsage::SignEncrypt, KeyUsage::ClientAuth],
+    );
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    // The block cipher is only needed for bulk encryption, not to create or use the key.
+    provider
+        .create_key("test_ecdsa_gcm_key", config)
+        .expect("Failed to create ECDSA key");
+    let data = b"Hello, World!";
+    let signature = provider.sign_data(data).expect("Failed to sign data");
+    assert!(provider.verify_signature(data, &signature).unwrap());
+
+    assert!(matches!(
+        provider.encrypt_data(data),
+        Err(SecurityModuleError::Tpm(TpmError::UnsupportedOperation(_)))
+    ));
+}
+
+#[test]
+fn test_decrypt_with_loaded_key() {
+    let config = || {
+        TpmConfig::new(
+            AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDh(EccCurves::P256)),
+            BlockCiphers::Aes(SymmetricMode::Ctr, KeyBits::Bits128),
+            Hash::Sha2(Sha2Bits::Sha256),
+            vec![KeyUsage::ClientAuth, KeyUsage::Decrypt],
+        )
+    };
+
+    let mut provider = TpmProvider::new("test_ecdh_loaded_key".to_string());
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+        .create_key("test_ecdh_loaded_key", config())
+        .expect("Failed to create ECDH key");
+    let data = b"Hello, World!";
+    let encrypted_data = provider.encrypt_data(data).expect("Failed to encrypt data");
+
+    // The symmetric keys are loaded along with the key.
+    let mut loaded = TpmProvider::new("test_ecdh_loaded_key".to_string());
+    loaded
+        .initialize_module()
+        .expect("Failed to initialize module");
+    loaded
+        .load_key("test_ecdh_loaded_key", config())
+        .expect("Failed to load ECDH key");
+    let decrypted_data = loaded
+        .decrypt_data(&encrypted_data)
+        .expect("Failed to decrypt data");
+
+    assert_eq!(data, decrypted_data.as_slice());
+}
+
+#[test]
+fn test_decrypt_tampered_data_fails() {
+    let mut provider = TpmProvider::new("test_ecdh_tamper_key".to_string());
+
+    let config = TpmConfig::new(
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDh(EccCurves::P256)),
+        BlockCiphers::Aes(SymmetricMode::Cfb, KeyBits::Bits128),
+        Hash::Sha2(Sha2Bits::Sha256),
+        vec![KeyUsage::ClientAuth, KeyUsage::Decrypt],
+    );
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+        .create_key("test_ecdh_tamper_key", config)
+        .expect("Failed to create ECDH key");
+
+    let mut encrypted_data = provider
+        .encrypt_data(b"Hello, World!")
+        .expect("Failed to encrypt data");
+    encrypted_data[16] ^= 0x01;
+
+    assert!(provider.decrypt_data(&encrypted_data).is_err());
+}
+
+#[test]
+fn test_sign_and_verify_large_message() {
+    let mut provider = TpmProvider::new("test_large_message_key".to_string());
+
+    let config = TpmConfig::new(
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256)),
+        BlockCiphers::Aes(SymmetricMode::Cfb, KeyBits::Bits128),
+        Hash::Sha2(Sha2Bits::Sha256),
+        vec![KeyUsage::SignEncrypt, KeyUsage::ClientAuth],
+    );
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+        .create_key("test_large_message_key", config)
+        .expect("Failed to create ECDSA key");
+
+    // Larger than the TPM's input buffer, so the message is hashed in software.
+    let data = vec![0x5a; 512 * 1024];
+    let signature = provider.sign_data(&data).expect("Failed to sign data");
+
+    assert!(provider.verify_signature(&data, &signature).unwrap());
+    assert!(!provider.verify_signature(&data[1..], &signature).unwrap());
+}
+
+/// Imports a software generated restricted ECDSA P-256 signing key into `provider`.
+fn import_restricted_key(provider: &mut TpmProvider, config: Box<dyn ProviderConfig>) {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    let key = EcKey::generate(&group).unwrap();
+    let mut ctx = BigNumContext::new().unwrap();
+    let mut x = BigNum::new().unwrap();
+    let mut y = BigNum::new().unwrap();
+    key.public_key()
+        .affine_coordinates_gfp(&group, &mut x, &mut y, &mut ctx)
+        .unwrap();
+
+    let public = PublicBuilder::new()
+        .with_public_algorithm(PublicAlgorithm::Ecc)
+        .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+        .with_object_attributes(
+            ObjectAttributesBuilder::new()
+                .with_user_with_auth(true)
+                .with_sign_encrypt(true)
+                .with_restricted(true)
+                .build()
+                .unwrap(),
+        )
+        .with_ecc_parameters(
+            PublicEccParameters::builder()
+                .with_ecc_scheme(EccScheme::EcDsa(HashScheme::new(HashingAlgorithm::Sha256)))
+                .with_curve(EccCurve::NistP256)
+                .with_is_signing_key(true)
+                .with_restricted(true)
+                .build()
+                .unwrap(),
+        )
+        .with_ecc_unique_identifier(EccPoint::new(
+            EccParameter::try_from(x.to_vec_padded(32).unwrap()).unwrap(),
+            EccParameter::try_from(y.to_vec_padded(32).unwrap()).unwrap(),
+        ))
+        .build()
+        .unwrap();
+
+    // Without seed and inner wrapper, the duplicate is the plain TPM2B_SENSITIVE.
+    let mut sensitive = 0x0023u16.to_be_bytes().to_vec();
+    sensitive.extend_from_slice(&0u16.to_be_bytes());
+    sensitive.extend_from_slice(&32u16.to_be_bytes());
+    sensitive.extend_from_slice(&[0x11; 32]);
+    sensitive.extend_from_slice(&32u16.to_be_bytes());
+    sensitive.extend_from_slice(&key.private_key().to_vec_padded(32).unwrap());
+    let mut duplicate = (sensitive.len() as u16).to_be_bytes().to_vec();
+    duplicate.extend_from_slice(&sensitive);
+
+    let imported = provider
+        .import_key(&DuplicatedKey {
+            public: public.clone(),
+            duplicate: Private::try_from(duplicate).unwrap(),
+            seed: EncryptedSecret::default(),
+        })
+        .expect("Failed to import key");
+    provider.load_external_key(public, config).unwrap();
+    provider
+        .load_wrapped_key(&imported)
+        .expect("Failed to load imported key");
+}
+
+#[test]
+fn test_sign_large_message_with_restricted_key() {
+    let mut provider = TpmProvider::new("test_restricted_key".to_string());
+    let config = TpmConfig::new(
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256)),
+        BlockCiphers::Aes(SymmetricMode::Cfb, KeyBits::Bits128),
+        Hash::Sha2(Sha2Bits::Sha256),
+        vec![KeyUsage::SignEncrypt, KeyUsage::ClientAuth],
+    );
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    import_restricted_key(&mut provider, config);
+
+    // Short messages are hashed with TPM2_Hash, longer ones in a hash sequence.
+    for data in [vec![0x5a; 100], vec![0x5a; 64 * 1024 + 1]] {
+        let signature = provider.sign_data(&data).expect("Failed to sign data");
+
+        assert!(provider.verify_signature(&data, &signature).unwrap());
+        assert!(!provider.verify_signature(&data[1..], &signature).unwrap());
+    }
+}
@@ -1,2 +1,12 @@
+mod device_tests;
+mod duplication_tests;
+mod event_log_tests;
+mod identity_tests;
 mod key_handle_tests;
+mod nv_tests;
+mod policy_tests;
 mod provider_handle_tests;
+mod resources_tests;
+mod seal_tests;
+mod systemd_creds_tests;
+mod verifier_tests;
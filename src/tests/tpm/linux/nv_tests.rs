@@ -0,0 +1,148 @@
+use crate::{
+    common::traits::module_provider::Provider,
+    tpm::linux::{
+        nv::{NvAuthorization, NvIndexKind},
+        TpmProvider,
+    },
+};
+
+#[test]
+fn test_nv_write_and_read_owner_auth() {
+    let mut provider = TpmProvider::new("test_nv_owner".to_string());
+    let index = 0x0150_0001;
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+
+    let data = b"firmware update secret";
+    provider
+        .nv_define(
+            index,
+            NvIndexKind::Ordinary(data.len()),
+            &NvAuthorization::Owner,
+        )
+        .expect("Failed to define NV index");
+    provider
+        .nv_write(index, data, &NvAuthorization::Owner)
+        .expect("Failed to write NV index");
+    let read = provider
+        .nv_read(index, &NvAuthorization::Owner)
+        .expect("Failed to read NV index");
+    provider
+        .nv_undefine(index)
+        .expect("Failed to undefine NV index");
+
+    assert_eq!(data, read.as_slice());
+}
+
+#[test]
+fn test_nv_index_auth() {
+    let mut provider = TpmProvider::new("test_nv_index_auth".to_string());
+    let index = 0x0150_0002;
+    let auth = NvAuthorization::Index(b"1234".to_vec());
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+
+    let data = [0x5a; 32];
+    provider
+        .nv_define(index, NvIndexKind::Ordinary(data.len()), &auth)
+        .expect("Failed to define NV index");
+    provider
+        .nv_write(index, &data, &auth)
+        .expect("Failed to write NV index");
+    let wrong_auth = provider.nv_read(index, &NvAuthorization::Index(b"4321".to_vec()));
+    let read = provider
+        .nv_read(index, &auth)
+        .expect("Failed to read NV index");
+    provider
+        .nv_undefine(index)
+        .expect("Failed to undefine NV index");
+
+    assert!(wrong_auth.is_err());
+    assert_eq!(data, read.as_slice());
+}
+
+#[test]
+fn test_nv_counter() {
+    let mut provider = TpmProvider::new("test_nv_counter".to_string());
+    let index = 0x0150_0003;
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+
+    provider
+        .nv_define(index, NvIndexKind::Counter, &NvAuthorization::Owner)
+        .expect("Failed to define NV counter");
+    let first = provider
+        .nv_increment(index, &NvAuthorization::Owner)
+        .expect("Failed to increment NV counter");
+    let second = provider
+        .nv_increment(index, &NvAuthorization::Owner)
+        .expect("Failed to increment NV counter");
+    let read = provider
+        .nv_read_counter(index, &NvAuthorization::Owner)
+        .expect("Failed to read NV counter");
+    provider
+        .nv_undefine(index)
+        .expect("Failed to undefine NV counter");
+
+    assert_eq!(second, first + 1);
+    assert_eq!(read, second);
+}
+
+#[test]
+fn test_nv_bits() {
+    let mut provider = TpmProvider::new("test_nv_bits".to_string());
+    let index = 0x0150_0004;
+    let auth = NvAuthorization::Index(b"bits".to_vec());
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+
+    provider
+        .nv_define(index, NvIndexKind::Bits, &auth)
+        .expect("Failed to define NV bit field");
+    let first = provider
+        .nv_set_bits(index, 0b0101, &auth)
+        .expect("Failed to set NV bits");
+    let second = provider
+        .nv_set_bits(index, 0b0011 << 62, &auth)
+        .expect("Failed to set NV bits");
+    let read = provider
+        .nv_read_bits(index, &auth)
+        .expect("Failed to read NV bit field");
+    provider
+        .nv_undefine(index)
+        .expect("Failed to undefine NV bit field");
+
+    assert_eq!(first, 0b0101);
+    assert_eq!(second, 0b0101 | 0b0011 << 62);
+    assert_eq!(read, second);
+}
+
+#[test]
+fn test_nv_bits_owner_auth() {
+    let mut provider = TpmProvider::new("test_nv_bits_owner".to_string());
+    let index = 0x0150_0005;
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+
+    provider
+        .nv_define(index, NvIndexKind::Bits, &NvAuthorization::Owner)
+        .expect("Failed to define NV bit field");
+    let bits = provider
+        .nv_set_bits(index, 1, &NvAuthorization::Owner)
+        .expect("Failed to set NV bits");
+    provider
+        .nv_undefine(index)
+        .expect("Failed to undefine NV bit field");
+
+    assert_eq!(bits, 1);
+}
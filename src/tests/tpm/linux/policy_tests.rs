@@ -0,0 +1,270 @@
+use crate::{
+    common::{
+        crypto::{
+            algorithms::{
+                encryption::{
+                    AsymmetricEncryption, BlockCiphers, EccCurves, EccSchemeAlgorithm,
+                    SymmetricMode,
+                },
+                hashes::{Hash, Sha2Bits},
+                KeyBits,
+            },
+            KeyUsage,
+        },
+        traits::{
+            credential_provider::{Credential, StaticCredentials},
+            key_handle::KeyHandle,
+            module_provider::Provider,
+            module_provider_config::ProviderConfig,
+        },
+    },
+    tpm::{
+        linux::{
+            policy::{KeyPolicy, PolicyApproval},
+            TpmProvider,
+        },
+        TpmConfig,
+    },
+};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    nid::Nid,
+    pkey::Private,
+};
+use std::sync::Arc;
+use tss_esapi::{
+    attributes::ObjectAttributesBuilder,
+    interface_types::{
+        algorithm::{HashingAlgorithm, PublicAlgorithm},
+        ecc::EccCurve,
+    },
+    structures::{
+        Digest, EccParameter, EccPoint, EccScheme, EccSignature, HashScheme, PcrSelectionList,
+        PcrSlot, Public, PublicBuilder, PublicEccParameters, Signature,
+    },
+};
+
+fn config() -> Box<dyn ProviderConfig> {
+    TpmConfig::new(
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256)),
+        BlockCiphers::Aes(SymmetricMode::Cfb, KeyBits::Bits128),
+        Hash::Sha2(Sha2Bits::Sha256),
+        vec![KeyUsage::SignEncrypt, KeyUsage::ClientAuth],
+    )
+}
+
+fn pcr_selection() -> PcrSelectionList {
+    PcrSelectionList::builder()
+        .with_selection(HashingAlgorithm::Sha256, &[PcrSlot::Slot0, PcrSlot::Slot7])
+        .build()
+        .expect("Failed to build PCR selection")
+}
+
+/// Builds the public area of a P-384 authority key with a SHA-384 name algorithm.
+fn authority_public(key: &EcKey<Private>) -> Public {
+    let mut ctx = BigNumContext::new().unwrap();
+    let mut x = BigNum::new().unwrap();
+    let mut y = BigNum::new().unwrap();
+    key.public_key()
+        .affine_coordinates_gfp(key.group(), &mut x, &mut y, &mut ctx)
+        .unwrap();
+
+    PublicBuilder::new()
+        .with_public_algorithm(PublicAlgorithm::Ecc)
+        .with_name_hashing_algorithm(HashingAlgorithm::Sha384)
+        .with_object_attributes(
+            ObjectAttributesBuilder::new()
+                .with_user_with_auth(true)
+                .with_sign_encrypt(true)
+                .build()
+                .unwrap(),
+        )
+        .with_ecc_parameters(
+            PublicEccParameters::builder()
+                .with_ecc_scheme(EccScheme::EcDsa(HashScheme::new(HashingAlgorithm::Sha384)))
+                .with_curve(EccCurve::NistP384)
+                .with_is_signing_key(true)
+                .build()
+                .unwrap(),
+        )
+        .with_ecc_unique_identifier(EccPoint::new(
+            EccParameter::try_from(x.to_vec()).unwrap(),
+            EccParameter::try_from(y.to_vec()).unwrap(),
+        ))
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn test_sign_with_key_auth() {
+    let mut provider = TpmProvider::new("test_auth_key".to_string());
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+        .set_key_auth(Some(b"1234"))
+        .expect("Failed to set auth value");
+    provider
+        .create_key("test_auth_key", config())
+        .expect("Failed to create key");
+
+    assert!(provider.sign_data(b"Hello, World!").is_ok());
+
+    provider
+        .set_key_auth(Some(b"4321"))
+        .expect("Failed to set auth value");
+    assert!(provider.sign_data(b"Hello, World!").is_err());
+}
+
+#[test]
+fn test_key_auth_from_credential_provider_per_key() {
+    let mut provider = TpmProvider::new("test_credential_key_a".to_string());
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider.set_credential_provider(Arc::new(
+        StaticCredentials::new()
+            .with(
+                Credential::AuthValue("test_credential_key_a".to_string()),
+                "1234",
+            )
+            .with(
+                Credential::AuthValue("test_credential_key_b".to_string()),
+                "4321",
+            ),
+    ));
+    provider
+        .create_key("test_credential_key_a", config())
+        .expect("Failed to create key");
+    provider
+        .create_key("test_credential_key_b", config())
+        .expect("Failed to create key");
+
+    assert!(provider.sign_data(b"Hello, World!").is_ok());
+
+    // The second key must not have been created with the auth value of the first.
+    provider
+        .set_key_auth(Some(b"1234"))
+        .expect("Failed to set auth value");
+    assert!(provider.sign_data(b"Hello, World!").is_err());
+}
+
+#[test]
+fn test_sign_with_pcr_and_auth_policy() {
+    let mut provider = TpmProvider::new("test_policy_key".to_string());
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    let digest = provider
+        .pcr_digest(&pcr_selection(), HashingAlgorithm::Sha256)
+        .expect("Failed to read PCRs");
+    provider.set_key_policy(Some(
+        KeyPolicy::new().pcr(pcr_selection(), digest).auth_value(),
+    ));
+    provider
+        .set_key_auth(Some(b"1234"))
+        .expect("Failed to set auth value");
+    provider
+        .create_key("test_policy_key", config())
+        .expect("Failed to create key");
+
+    assert!(provider.sign_data(b"Hello, World!").is_ok());
+
+    provider
+        .set_key_auth(Some(b"4321"))
+        .expect("Failed to set auth value");
+    assert!(provider.sign_data(b"Hello, World!").is_err());
+}
+
+#[test]
+fn test_sign_with_unexpected_boot_state_fails() {
+    let mut provider = TpmProvider::new("test_pcr_mismatch_key".to_string());
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    let expected = Digest::try_from(vec![0x42; 32]).unwrap();
+    provider.set_key_policy(Some(KeyPolicy::new().pcr(pcr_selection(), expected)));
+    provider
+        .create_key("test_pcr_mismatch_key", config())
+        .expect("Failed to create key");
+
+    assert!(provider.sign_data(b"Hello, World!").is_err());
+}
+
+#[test]
+fn test_sign_with_or_policy() {
+    let mut provider = TpmProvider::new("test_or_policy_key".to_string());
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    let current = provider
+        .pcr_digest(&pcr_selection(), HashingAlgorithm::Sha256)
+        .expect("Failed to read PCRs");
+    let previous = Digest::try_from(vec![0x42; 32]).unwrap();
+    provider.set_key_policy(Some(KeyPolicy::new().or(vec![
+        KeyPolicy::new().pcr(pcr_selection(), previous),
+        KeyPolicy::new().pcr(pcr_selection(), current),
+    ])));
+    provider
+        .create_key("test_or_policy_key", config())
+        .expect("Failed to create key");
+
+    assert!(provider.sign_data(b"Hello, World!").is_ok());
+}
+
+#[test]
+fn test_sign_with_policy_approved_by_authority_with_other_hash() {
+    let mut provider = TpmProvider::new("test_authorize_policy_key".to_string());
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    let group = EcGroup::from_curve_name(Nid::SECP384R1).unwrap();
+    let authority_key = EcKey::generate(&group).unwrap();
+    let authority = authority_public(&authority_key);
+    provider.set_key_policy(Some(KeyPolicy::new().authorize(
+        authority.clone(),
+        b"boot",
+        None,
+    )));
+    provider
+        .create_key("test_authorize_policy_key", config())
+        .expect("Failed to create key");
+
+    // The approved policy is digested with SHA-256 like the key's policy, the approval with
+    // the SHA-384 name algorithm of the authority.
+    let current = provider
+        .pcr_digest(&pcr_selection(), HashingAlgorithm::Sha256)
+        .expect("Failed to read PCRs");
+    let approved = KeyPolicy::new().pcr(pcr_selection(), current);
+    let digest = provider
+        .approval_digest(&approved, &authority, b"boot")
+        .expect("Failed to compute approval digest");
+    assert_eq!(digest.value().len(), 48);
+    let signature = EcdsaSig::sign(digest.value(), &authority_key).unwrap();
+    let signature = Signature::EcDsa(
+        EccSignature::create(
+            HashingAlgorithm::Sha384,
+            EccParameter::try_from(signature.r().to_vec()).unwrap(),
+            EccParameter::try_from(signature.s().to_vec()).unwrap(),
+        )
+        .unwrap(),
+    );
+    provider.set_key_policy(Some(KeyPolicy::new().authorize(
+        authority,
+        b"boot",
+        Some(PolicyApproval {
+            policy: approved,
+            signature,
+        }),
+    )));
+
+    assert!(provider.sign_data(b"Hello, World!").is_ok());
+}
@@ -1,6 +1,9 @@
 use crate::{
-    common::crypto::algorithms::{encryption::SymmetricMode, hashes::Sha2Bits, KeyBits},
-    tpm::TpmConfig,
+    common::{
+        crypto::algorithms::{encryption::SymmetricMode, hashes::Sha2Bits, KeyBits},
+        error::SecurityModuleError,
+    },
+    tpm::{core::error::TpmError, TpmConfig},
 };
 #[allow(unused_imports)]
 use crate::{
@@ -83,6 +86,26 @@ fn test_create_ecdh_key() {
         .expect("Failed to create ECDH key");
 }
 
+#[test]
+fn test_create_ecdaa_key_unsupported() {
+    let mut provider = TpmProvider::new("test_key".to_string());
+
+    let config = TpmConfig::new(
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDaa(EccCurves::P256)),
+        BlockCiphers::Aes(SymmetricMode::Cfb, KeyBits::Bits256),
+        Hash::Sha2(Sha2Bits::Sha256),
+        vec![KeyUsage::SignEncrypt, KeyUsage::ClientAuth],
+    );
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    assert!(matches!(
+        provider.create_key("test_ecdaa_key", config),
+        Err(SecurityModuleError::Tpm(TpmError::UnsupportedOperation(_)))
+    ));
+}
+
 #[test]
 fn test_load_rsa_key() {
     let mut provider = TpmProvider::new("test_key".to_string());
@@ -0,0 +1,80 @@
+use crate::{
+    common::{
+        crypto::{
+            algorithms::{
+                encryption::{
+                    AsymmetricEncryption, BlockCiphers, EccCurves, EccSchemeAlgorithm,
+                    SymmetricMode,
+                },
+                hashes::{Hash, Sha2Bits},
+                KeyBits,
+            },
+            KeyUsage,
+        },
+        traits::{
+            key_handle::KeyHandle, module_provider::Provider,
+            module_provider_config::ProviderConfig,
+        },
+    },
+    tpm::{linux::TpmProvider, TpmConfig},
+};
+
+fn config() -> Box<dyn ProviderConfig> {
+    TpmConfig::new(
+        AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDsa(EccCurves::P256)),
+        BlockCiphers::Aes(SymmetricMode::Cfb, KeyBits::Bits128),
+        Hash::Sha2(Sha2Bits::Sha256),
+        vec![KeyUsage::SignEncrypt, KeyUsage::ClientAuth],
+    )
+}
+
+fn create_provider(key_id: &str) -> TpmProvider {
+    let mut provider = TpmProvider::new(key_id.to_string());
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+        .create_key(key_id, config())
+        .expect("Failed to create key");
+    provider
+}
+
+#[test]
+fn test_use_key_after_swap_out() {
+    let provider = create_provider("test_swap_out_key");
+
+    provider
+        .swap_out_objects()
+        .expect("Failed to swap out objects");
+    let signature = provider.sign_data(b"Hello, World!").unwrap();
+    provider.swap_out_objects().unwrap();
+
+    assert!(provider
+        .verify_signature(b"Hello, World!", &signature)
+        .unwrap());
+    let encrypted = provider.encrypt_data(b"Hello, World!").unwrap();
+    provider.swap_out_objects().unwrap();
+    assert_eq!(provider.decrypt_data(&encrypted).unwrap(), b"Hello, World!");
+}
+
+#[test]
+fn test_more_keys_than_object_slots() {
+    // Each provider holds three objects, far more than the TPM has slots for.
+    let mut provider = create_provider("test_resources_key_0");
+    for i in 1..8 {
+        let key_id = format!("test_resources_key_{}", i);
+        provider
+            .create_key(&key_id, config())
+            .expect("Failed to create key");
+        assert!(provider.sign_data(key_id.as_bytes()).is_ok());
+        provider.swap_out_objects().unwrap();
+    }
+}
+
+#[test]
+fn test_dropped_providers_release_objects() {
+    for i in 0..16 {
+        let provider = create_provider(&format!("test_dropped_key_{}", i));
+        assert!(provider.sign_data(b"Hello, World!").is_ok());
+    }
+}
@@ -0,0 +1,62 @@
+use crate::{
+    common::traits::module_provider::Provider,
+    tpm::linux::{seal::SealedBlob, TpmProvider},
+};
+use tss_esapi::{
+    interface_types::algorithm::HashingAlgorithm,
+    structures::{PcrSelectionList, PcrSlot},
+};
+
+fn pcr_selection() -> PcrSelectionList {
+    PcrSelectionList::builder()
+        .with_selection(HashingAlgorithm::Sha256, &[PcrSlot::Slot0, PcrSlot::Slot7])
+        .build()
+        .expect("Failed to build PCR selection")
+}
+
+#[test]
+fn test_seal_and_unseal() {
+    let mut provider = TpmProvider::new("test_seal".to_string());
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+
+    let secret = b"database password";
+    let sealed = provider
+        .seal(secret, pcr_selection(), None)
+        .expect("Failed to seal data");
+    let unsealed = provider
+        .unseal(&sealed, None)
+        .expect("Failed to unseal data");
+
+    assert_eq!(secret, unsealed.as_slice());
+}
+
+#[test]
+fn test_seal_and_unseal_with_auth() {
+    let mut provider = TpmProvider::new("test_seal_auth".to_string());
+
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+
+    let secret = b"disk encryption key";
+    let sealed = provider
+        .seal(secret, pcr_selection(), Some(b"1234"))
+        .expect("Failed to seal data");
+
+    assert!(provider.unseal(&sealed, None).is_err());
+    assert!(provider.unseal(&sealed, Some(b"4321")).is_err());
+
+    let unsealed = provider
+        .unseal(&sealed, Some(b"1234"))
+        .expect("Failed to unseal data");
+    assert_eq!(secret, unsealed.as_slice());
+}
+
+#[test]
+fn test_sealed_blob_rejects_garbage() {
+    assert!(SealedBlob::from_bytes(b"not a sealed blob").is_err());
+    assert!(SealedBlob::from_bytes(b"TPMS\x01").is_err());
+}
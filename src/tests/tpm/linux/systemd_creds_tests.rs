@@ -0,0 +1,191 @@
+use crate::{common::traits::module_provider::Provider, tpm::linux::TpmProvider};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use std::{
+    env,
+    io::{ErrorKind, Write},
+    process::{Command, Output, Stdio},
+    time::{Duration, SystemTime},
+};
+
+fn provider(key_id: &str) -> TpmProvider {
+    let mut provider = TpmProvider::new(key_id.to_string());
+    provider
+        .initialize_module()
+        .expect("Failed to initialize module");
+    provider
+}
+
+/// Runs `systemd-creds` with `input` on stdin against the TPM of the tests, or returns `None`
+/// if it is not installed.
+fn systemd_creds(args: &[&str], input: &[u8]) -> Option<Output> {
+    let mut command = Command::new("systemd-creds");
+    command
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    // systemd takes TCTIs in the `driver:configuration` form of the TCTI environment variable.
+    if let Ok(tcti) = env::var("TCTI") {
+        command.arg(format!("--tpm2-device={}", tcti));
+    }
+    let mut child = match command.spawn() {
+        Ok(child) => child,
+        Err(e) if e.kind() == ErrorKind::NotFound => return None,
+        Err(e) => panic!("Failed to run systemd-creds: {}", e),
+    };
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input)
+        .expect("Failed to write to systemd-creds");
+    let output = child
+        .wait_with_output()
+        .expect("Failed to run systemd-creds");
+    assert!(
+        output.status.success(),
+        "systemd-creds failed: {}",
+        String::from_utf8_lossy(&output.stderr)
+    );
+    Some(output)
+}
+
+#[test]
+fn test_encrypt_and_decrypt_credential() {
+    let provider = provider("test_systemd_creds");
+
+    let encrypted = provider
+        .encrypt_credential("db-password", b"hunter2", 0, None)
+        .expect("Failed to encrypt credential");
+    let credential = provider
+        .decrypt_credential(Some("db-password"), encrypted.as_bytes())
+        .expect("Failed to decrypt credential");
+
+    assert_eq!(credential.name, "db-password");
+    assert_eq!(credential.data, b"hunter2");
+    assert_eq!(credential.not_after, None);
+}
+
+#[test]
+fn test_encrypt_and_decrypt_credential_bound_to_pcr() {
+    let provider = provider("test_systemd_creds_pcr");
+
+    let encrypted = provider
+        .encrypt_credential("token", b"secret", 1 << 7, None)
+        .expect("Failed to encrypt credential");
+    let raw = STANDARD
+        .decode(&encrypted)
+        .expect("Credential is not valid Base64");
+    let credential = provider
+        .decrypt_credential(None, &raw)
+        .expect("Failed to decrypt credential");
+
+    assert_eq!(credential.data, b"secret");
+}
+
+#[test]
+fn test_credential_header_layout() {
+    let provider = provider("test_systemd_creds_header");
+
+    let encrypted = provider
+        .encrypt_credential("layout", b"data", 1 << 7, None)
+        .expect("Failed to encrypt credential");
+    let raw = STANDARD
+        .decode(&encrypted)
+        .expect("Credential is not valid Base64");
+
+    // CRED_AES256_GCM_BY_TPM2_HMAC followed by the AES-256-GCM parameters.
+    assert_eq!(hex::encode(&raw[..16]), "0c7cc07b117645919c4b0bea08bc20fe");
+    assert_eq!(&raw[16..20], &32u32.to_le_bytes());
+    assert_eq!(&raw[20..24], &1u32.to_le_bytes());
+    assert_eq!(&raw[24..28], &12u32.to_le_bytes());
+    assert_eq!(&raw[28..32], &16u32.to_le_bytes());
+    // The TPM2 header starts 8 byte aligned after the IV, with the PCR mask and SHA-256 bank.
+    assert_eq!(&raw[48..56], &(1u64 << 7).to_le_bytes());
+    assert_eq!(&raw[56..58], &0x000bu16.to_le_bytes());
+}
+
+#[test]
+fn test_decrypt_credential_rejects_wrong_name() {
+    let provider = provider("test_systemd_creds_name");
+
+    let encrypted = provider
+        .encrypt_credential("expected", b"secret", 0, None)
+        .expect("Failed to encrypt credential");
+
+    assert!(provider
+        .decrypt_credential(Some("other"), encrypted.as_bytes())
+        .is_err());
+}
+
+#[test]
+fn test_decrypt_credential_rejects_expired() {
+    let provider = provider("test_systemd_creds_expired");
+
+    let not_after = SystemTime::now() - Duration::from_secs(60);
+    let encrypted = provider
+        .encrypt_credential("expired", b"secret", 0, Some(not_after))
+        .expect("Failed to encrypt credential");
+
+    assert!(provider
+        .decrypt_credential(None, encrypted.as_bytes())
+        .is_err());
+}
+
+#[test]
+fn test_decrypt_credential_rejects_tampering() {
+    let provider = provider("test_systemd_creds_tampered");
+
+    let encrypted = provider
+        .encrypt_credential("tampered", b"secret", 0, None)
+        .expect("Failed to encrypt credential");
+    let mut raw = STANDARD
+        .decode(&encrypted)
+        .expect("Credential is not valid Base64");
+    let last = raw.len() - 1;
+    raw[last] ^= 1;
+
+    assert!(provider.decrypt_credential(None, &raw).is_err());
+}
+
+#[test]
+fn test_decrypt_credential_from_systemd_creds() {
+    let provider = provider("test_systemd_creds_interop_decrypt");
+
+    let Some(output) = systemd_creds(
+        &[
+            "encrypt",
+            "--with-key=tpm2",
+            "--tpm2-pcrs=7",
+            "--name=db-password",
+            "-",
+            "-",
+        ],
+        b"hunter2",
+    ) else {
+        return;
+    };
+    let credential = provider
+        .decrypt_credential(Some("db-password"), &output.stdout)
+        .expect("Failed to decrypt credential of systemd-creds");
+
+    assert_eq!(credential.name, "db-password");
+    assert_eq!(credential.data, b"hunter2");
+}
+
+#[test]
+fn test_systemd_creds_decrypts_credential() {
+    let provider = provider("test_systemd_creds_interop_encrypt");
+
+    let encrypted = provider
+        .encrypt_credential("db-password", b"hunter2", 1 << 7, None)
+        .expect("Failed to encrypt credential");
+    let Some(output) = systemd_creds(
+        &["decrypt", "--name=db-password", "-", "-"],
+        encrypted.as_bytes(),
+    ) else {
+        return;
+    };
+
+    assert_eq!(output.stdout, b"hunter2");
+}
@@ -0,0 +1,364 @@
+use crate::tpm::linux::{
+    attestation::KeyAttestation,
+    verifier::{verify_key_attestation, verify_quote, PcrValues},
+};
+use openssl::{
+    bn::BigNumContext,
+    ec::{EcGroup, EcKey},
+    ecdsa::EcdsaSig,
+    hash::{hash, MessageDigest},
+    nid::Nid,
+    pkey::Private,
+};
+use tss_esapi::{
+    attributes::ObjectAttributesBuilder,
+    interface_types::{
+        algorithm::{HashingAlgorithm, PublicAlgorithm},
+        ecc::EccCurve,
+    },
+    structures::{
+        EccParameter, EccPoint, EccScheme, EccSignature, HashScheme, PublicBuilder,
+        PublicEccParameters, Signature,
+    },
+    traits::Marshall,
+};
+
+const NONCE: &[u8] = b"verifier-nonce-0123456789";
+
+/// A `TPMS_CREATION_DATA` of a primary key in the owner hierarchy, without PCRs.
+const CREATION_DATA: &[u8] = &[
+    0, 0, 0, 0, // pcrSelect
+    0, 0, // pcrDigest
+    0, // locality
+    0x00, 0x10, // parentNameAlg
+    0, 4, 0x40, 0, 0, 1, // parentName
+    0, 4, 0x40, 0, 0, 1, // parentQualifiedName
+    0, 0, // outsideInfo
+];
+
+fn pcr_values() -> PcrValues {
+    let mut pcrs = PcrValues::new();
+    pcrs.insert(HashingAlgorithm::Sha256, 0, vec![0x11; 32]);
+    pcrs.insert(HashingAlgorithm::Sha256, 7, vec![0x77; 32]);
+    pcrs
+}
+
+/// Marshals a `TPMS_ATTEST` quote over PCR 0 and 7 of the SHA-256 bank.
+fn marshal_quote(nonce: &[u8], pcrs: &PcrValues) -> Vec<u8> {
+    let mut concatenated = pcrs.get(HashingAlgorithm::Sha256, 0).unwrap().to_vec();
+    concatenated.extend_from_slice(pcrs.get(HashingAlgorithm::Sha256, 7).unwrap());
+    let pcr_digest = hash(MessageDigest::sha256(), &concatenated).unwrap();
+
+    let mut quote = Vec::new();
+    quote.extend_from_slice(&0xff54_4347u32.to_be_bytes()); // TPM_GENERATED_VALUE
+    quote.extend_from_slice(&0x8018u16.to_be_bytes()); // TPM_ST_ATTEST_QUOTE
+    quote.extend_from_slice(&0u16.to_be_bytes()); // qualifiedSigner
+    quote.extend_from_slice(&(nonce.len() as u16).to_be_bytes());
+    quote.extend_from_slice(nonce);
+    quote.extend_from_slice(&1234u64.to_be_bytes()); // clock
+    quote.extend_from_slice(&3u32.to_be_bytes()); // resetCount
+    quote.extend_from_slice(&0u32.to_be_bytes()); // restartCount
+    quote.push(1); // safe
+    quote.extend_from_slice(&0x2000_0001u64.to_be_bytes()); // firmwareVersion
+    quote.extend_from_slice(&1u32.to_be_bytes()); // TPML_PCR_SELECTION count
+    quote.extend_from_slice(&0x000bu16.to_be_bytes()); // TPM_ALG_SHA256
+    quote.extend_from_slice(&[3, 0x81, 0x00, 0x00]); // PCR 0 and 7
+    quote.extend_from_slice(&(pcr_digest.len() as u16).to_be_bytes());
+    quote.extend_from_slice(&pcr_digest);
+    quote
+}
+
+/// Marshals a `TPMS_ATTEST` creation certification of the key with the given public area.
+fn marshal_certify(nonce: &[u8], key_public: &[u8]) -> Vec<u8> {
+    let mut name = 0x000bu16.to_be_bytes().to_vec(); // TPM_ALG_SHA256
+    name.extend_from_slice(&hash(MessageDigest::sha256(), key_public).unwrap());
+    let creation_hash = hash(MessageDigest::sha256(), CREATION_DATA).unwrap();
+
+    let mut certify = Vec::new();
+    certify.extend_from_slice(&0xff54_4347u32.to_be_bytes()); // TPM_GENERATED_VALUE
+    certify.extend_from_slice(&0x801au16.to_be_bytes()); // TPM_ST_ATTEST_CREATION
+    certify.extend_from_slice(&0u16.to_be_bytes()); // qualifiedSigner
+    certify.extend_from_slice(&(nonce.len() as u16).to_be_bytes());
+    certify.extend_from_slice(nonce);
+    certify.extend_from_slice(&1234u64.to_be_bytes()); // clock
+    certify.extend_from_slice(&3u32.to_be_bytes()); // resetCount
+    certify.extend_from_slice(&0u32.to_be_bytes()); // restartCount
+    certify.push(1); // safe
+    certify.extend_from_slice(&0x2000_0001u64.to_be_bytes()); // firmwareVersion
+    certify.extend_from_slice(&(name.len() as u16).to_be_bytes());
+    certify.extend_from_slice(&name);
+    certify.extend_from_slice(&(creation_hash.len() as u16).to_be_bytes());
+    certify.extend_from_slice(&creation_hash);
+    certify
+}
+
+fn sign(key: &EcKey<Private>, message: &[u8]) -> Vec<u8> {
+    let digest = hash(MessageDigest::sha256(), message).unwrap();
+    let signature = EcdsaSig::sign(&digest, key).unwrap();
+    Signature::EcDsa(
+        EccSignature::create(
+            HashingAlgorithm::Sha256,
+            EccParameter::try_from(signature.r().to_vec()).unwrap(),
+            EccParameter::try_from(signature.s().to_vec()).unwrap(),
+        )
+        .unwrap(),
+    )
+    .marshall()
+    .unwrap()
+}
+
+fn ak_public(key: &EcKey<Private>) -> Vec<u8> {
+    signing_key_public(key, true)
+}
+
+fn signing_key_public(key: &EcKey<Private>, restricted: bool) -> Vec<u8> {
+    let mut ctx = BigNumContext::new().unwrap();
+    let mut x = openssl::bn::BigNum::new().unwrap();
+    let mut y = openssl::bn::BigNum::new().unwrap();
+    key.public_key()
+        .affine_coordinates_gfp(key.group(), &mut x, &mut y, &mut ctx)
+        .unwrap();
+
+    PublicBuilder::new()
+        .with_public_algorithm(PublicAlgorithm::Ecc)
+        .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+        .with_object_attributes(
+            ObjectAttributesBuilder::new()
+                .with_fixed_tpm(true)
+                .with_fixed_parent(true)
+                .with_sensitive_data_origin(true)
+                .with_user_with_auth(true)
+                .with_sign_encrypt(true)
+                .with_restricted(restricted)
+                .build()
+                .unwrap(),
+        )
+        .with_ecc_parameters(
+            PublicEccParameters::builder()
+                .with_ecc_scheme(EccScheme::EcDsa(HashScheme::new(HashingAlgorithm::Sha256)))
+                .with_curve(EccCurve::NistP256)
+                .with_is_signing_key(true)
+                .with_restricted(restricted)
+                .build()
+                .unwrap(),
+        )
+        .with_ecc_unique_identifier(EccPoint::new(
+            EccParameter::try_from(x.to_vec()).unwrap(),
+            EccParameter::try_from(y.to_vec()).unwrap(),
+        ))
+        .build()
+        .unwrap()
+        .marshall()
+        .unwrap()
+}
+
+fn generate_ak() -> EcKey<Private> {
+    let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+    EcKey::generate(&group).unwrap()
+}
+
+#[test]
+fn test_verify_valid_quote() {
+    let ak = generate_ak();
+    let quote = marshal_quote(NONCE, &pcr_values());
+
+    let verdict = verify_quote(
+        &quote,
+        &sign(&ak, &quote),
+        &ak_public(&ak),
+        NONCE,
+        &pcr_values(),
+        &pcr_values(),
+    )
+    .expect("Failed to verify quote");
+
+    assert!(verdict.is_trusted());
+    assert_eq!(verdict.clock, 1234);
+    assert_eq!(verdict.reset_count, 3);
+}
+
+#[test]
+fn test_verify_quote_wrong_nonce() {
+    let ak = generate_ak();
+    let quote = marshal_quote(b"replayed-nonce", &pcr_values());
+
+    let verdict = verify_quote(
+        &quote,
+        &sign(&ak, &quote),
+        &ak_public(&ak),
+        NONCE,
+        &pcr_values(),
+        &pcr_values(),
+    )
+    .expect("Failed to verify quote");
+
+    assert!(verdict.signature_valid);
+    assert!(!verdict.nonce_valid);
+    assert!(!verdict.is_trusted());
+}
+
+#[test]
+fn test_verify_quote_foreign_signature() {
+    let ak = generate_ak();
+    let quote = marshal_quote(NONCE, &pcr_values());
+
+    let verdict = verify_quote(
+        &quote,
+        &sign(&generate_ak(), &quote),
+        &ak_public(&ak),
+        NONCE,
+        &pcr_values(),
+        &pcr_values(),
+    )
+    .expect("Failed to verify quote");
+
+    assert!(!verdict.signature_valid);
+    assert!(!verdict.is_trusted());
+}
+
+#[test]
+fn test_verify_quote_unrestricted_key() {
+    let key = generate_ak();
+    let quote = marshal_quote(NONCE, &pcr_values());
+
+    // An ordinary signing key can sign a forged quote.
+    let verdict = verify_quote(
+        &quote,
+        &sign(&key, &quote),
+        &signing_key_public(&key, false),
+        NONCE,
+        &pcr_values(),
+        &pcr_values(),
+    )
+    .expect("Failed to verify quote");
+
+    assert!(verdict.signature_valid);
+    assert!(!verdict.attestation_key_valid);
+    assert!(!verdict.is_trusted());
+}
+
+#[test]
+fn test_verify_quote_pcr_mismatch() {
+    let ak = generate_ak();
+    let mut booted = pcr_values();
+    booted.insert(HashingAlgorithm::Sha256, 7, vec![0x42; 32]);
+    let quote = marshal_quote(NONCE, &booted);
+
+    let verdict = verify_quote(
+        &quote,
+        &sign(&ak, &quote),
+        &ak_public(&ak),
+        NONCE,
+        &booted,
+        &pcr_values(),
+    )
+    .expect("Failed to verify quote");
+
+    assert!(verdict.signature_valid);
+    assert!(verdict.pcr_digest_valid);
+    assert_eq!(verdict.pcr_mismatches.len(), 1);
+    assert_eq!(verdict.pcr_mismatches[0].index, 7);
+    assert_eq!(verdict.pcr_mismatches[0].reported, Some(vec![0x42; 32]));
+    assert!(!verdict.is_trusted());
+}
+
+#[test]
+fn test_verify_quote_tampered_pcr_report() {
+    let ak = generate_ak();
+    let mut booted = pcr_values();
+    booted.insert(HashingAlgorithm::Sha256, 7, vec![0x42; 32]);
+    let quote = marshal_quote(NONCE, &booted);
+
+    // The device claims the expected values, but the quote was taken over different ones.
+    let verdict = verify_quote(
+        &quote,
+        &sign(&ak, &quote),
+        &ak_public(&ak),
+        NONCE,
+        &pcr_values(),
+        &pcr_values(),
+    )
+    .expect("Failed to verify quote");
+
+    assert!(!verdict.pcr_digest_valid);
+    assert!(!verdict.is_trusted());
+}
+
+#[test]
+fn test_verify_valid_key_attestation() {
+    let ak = generate_ak();
+    let key_public = ak_public(&generate_ak());
+    let attest = marshal_certify(NONCE, &key_public);
+    let attestation = KeyAttestation {
+        signature: sign(&ak, &attest),
+        attest,
+        key_public,
+        creation_data: CREATION_DATA.to_vec(),
+    };
+    let attestation = KeyAttestation::from_bytes(&attestation.to_bytes()).unwrap();
+
+    let verdict = verify_key_attestation(&attestation, &ak_public(&ak), NONCE)
+        .expect("Failed to verify key attestation");
+
+    assert!(verdict.is_hardware_bound());
+}
+
+#[test]
+fn test_verify_key_attestation_substituted_key() {
+    let ak = generate_ak();
+    let attest = marshal_certify(NONCE, &ak_public(&generate_ak()));
+
+    // The device reports a different key than the one the TPM certified.
+    let attestation = KeyAttestation {
+        signature: sign(&ak, &attest),
+        attest,
+        key_public: ak_public(&generate_ak()),
+        creation_data: CREATION_DATA.to_vec(),
+    };
+
+    let verdict = verify_key_attestation(&attestation, &ak_public(&ak), NONCE)
+        .expect("Failed to verify key attestation");
+
+    assert!(verdict.signature_valid);
+    assert!(!verdict.name_valid);
+    assert!(!verdict.is_hardware_bound());
+}
+
+#[test]
+fn test_verify_key_attestation_tampered_creation_data() {
+    let ak = generate_ak();
+    let key_public = ak_public(&generate_ak());
+    let attest = marshal_certify(NONCE, &key_public);
+
+    // The device claims the key was created with a different locality.
+    let mut creation_data = CREATION_DATA.to_vec();
+    creation_data[6] = 0x04;
+    let attestation = KeyAttestation {
+        signature: sign(&ak, &attest),
+        attest,
+        key_public,
+        creation_data,
+    };
+
+    let verdict = verify_key_attestation(&attestation, &ak_public(&ak), NONCE)
+        .expect("Failed to verify key attestation");
+
+    assert!(verdict.signature_valid);
+    assert!(verdict.name_valid);
+    assert!(!verdict.creation_valid);
+    assert!(!verdict.is_hardware_bound());
+}
+
+#[test]
+fn test_verify_key_attestation_rejects_quote() {
+    let ak = generate_ak();
+    let quote = marshal_quote(NONCE, &pcr_values());
+    let attestation = KeyAttestation {
+        signature: sign(&ak, &quote),
+        attest: quote,
+        key_public: ak_public(&generate_ak()),
+        creation_data: CREATION_DATA.to_vec(),
+    };
+
+    assert!(verify_key_attestation(&attestation, &ak_public(&ak), NONCE).is_err());
+}
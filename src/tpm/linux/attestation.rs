@@ -0,0 +1,250 @@
+use super::{
+    identity::AttestationKey,
+    utils::{flush, invalid_data, marshal_creation_data, marshal_tpm2b, tpm_error, Reader},
+    TpmProvider,
+};
+use crate::common::error::SecurityModuleError;
+use tracing::instrument;
+use tss_esapi::{
+    interface_types::session_handles::AuthSession,
+    structures::{CreationData, CreationTicket, Data, Digest, SignatureScheme},
+    traits::Marshall,
+    tss2_esys::{TPM2B_DIGEST, TPMT_TK_CREATION},
+};
+
+/// Identifies a serialized key attestation and its format version.
+const KEY_ATTESTATION_MAGIC: &[u8; 4] = b"TPKA";
+const KEY_ATTESTATION_VERSION: u8 = 2;
+
+/// Identifies serialized key creation data and its format version.
+const KEY_CREATION_MAGIC: &[u8; 4] = b"TPKC";
+const KEY_CREATION_VERSION: u8 = 1;
+
+/// Evidence that a key was created inside a TPM, produced by [`TpmProvider::certify_key`].
+///
+/// The attestation key signs the name of the certified key, which commits to its public area
+/// and therefore to attributes such as `fixed_tpm` and `sensitive_data_origin`, together with
+/// the digest of the creation data the TPM recorded when it created the key. See
+/// [`verify_key_attestation`](super::verifier::verify_key_attestation) for the relying party side.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyAttestation {
+    /// The marshalled `TPMS_ATTEST` structure returned by `TPM2_CertifyCreation`.
+    pub attest: Vec<u8>,
+    /// The marshalled `TPMT_SIGNATURE` over `attest`.
+    pub signature: Vec<u8>,
+    /// The marshalled `TPMT_PUBLIC` area of the certified key.
+    pub key_public: Vec<u8>,
+    /// The marshalled `TPMS_CREATION_DATA` of the key, whose digest is attested.
+    pub creation_data: Vec<u8>,
+}
+
+impl KeyAttestation {
+    /// Serializes the attestation into its portable binary form.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(KEY_ATTESTATION_MAGIC);
+        buffer.push(KEY_ATTESTATION_VERSION);
+        marshal_tpm2b(&self.attest, &mut buffer);
+        marshal_tpm2b(&self.signature, &mut buffer);
+        marshal_tpm2b(&self.key_public, &mut buffer);
+        marshal_tpm2b(&self.creation_data, &mut buffer);
+        buffer
+    }
+
+    /// Parses an attestation previously produced by [`KeyAttestation::to_bytes`].
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SecurityModuleError> {
+        let mut reader = Reader::new(data);
+        if reader.read_bytes(KEY_ATTESTATION_MAGIC.len())? != KEY_ATTESTATION_MAGIC
+            || reader.read_u8()? != KEY_ATTESTATION_VERSION
+        {
+            return Err(invalid_data("Not a key attestation or unsupported version").into());
+        }
+
+        Ok(Self {
+            attest: reader.read_tpm2b()?.to_vec(),
+            signature: reader.read_tpm2b()?.to_vec(),
+            key_public: reader.read_tpm2b()?.to_vec(),
+            creation_data: reader.read_tpm2b()?.to_vec(),
+        })
+    }
+}
+
+/// What the TPM reported when it created a key, which is needed to certify the key with
+/// `TPM2_CertifyCreation`.
+///
+/// The TPM only returns it from the command creating the key, so it has to be stored alongside
+/// the key, see [`TpmProvider::key_creation`] and [`TpmProvider::set_key_creation`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyCreation {
+    /// The marshalled `TPMS_CREATION_DATA` describing the environment the key was created in.
+    pub creation_data: Vec<u8>,
+    /// The digest of `creation_data` computed by the TPM.
+    pub creation_hash: Vec<u8>,
+    /// The marshalled `TPMT_TK_CREATION` binding `creation_hash` to the key.
+    pub creation_ticket: Vec<u8>,
+}
+
+impl KeyCreation {
+    /// Collects the creation data returned by `TPM2_Create` or `TPM2_CreatePrimary`.
+    pub(super) fn new(
+        creation_data: CreationData,
+        creation_hash: Digest,
+        creation_ticket: CreationTicket,
+    ) -> Result<Self, SecurityModuleError> {
+        let ticket = TPMT_TK_CREATION::from(creation_ticket);
+        let mut marshalled_ticket = Vec::new();
+        marshalled_ticket.extend_from_slice(&ticket.tag.to_be_bytes());
+        marshalled_ticket.extend_from_slice(&ticket.hierarchy.to_be_bytes());
+        marshal_tpm2b(
+            &ticket.digest.buffer[..ticket.digest.size as usize],
+            &mut marshalled_ticket,
+        );
+
+        Ok(Self {
+            creation_data: marshal_creation_data(creation_data)?,
+            creation_hash: creation_hash.value().to_vec(),
+            creation_ticket: marshalled_ticket,
+        })
+    }
+
+    /// Serializes the creation data into its portable binary form.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(KEY_CREATION_MAGIC);
+        buffer.push(KEY_CREATION_VERSION);
+        marshal_tpm2b(&self.creation_data, &mut buffer);
+        marshal_tpm2b(&self.creation_hash, &mut buffer);
+        marshal_tpm2b(&self.creation_ticket, &mut buffer);
+        buffer
+    }
+
+    /// Parses creation data previously produced by [`KeyCreation::to_bytes`].
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SecurityModuleError> {
+        let mut reader = Reader::new(data);
+        if reader.read_bytes(KEY_CREATION_MAGIC.len())? != KEY_CREATION_MAGIC
+            || reader.read_u8()? != KEY_CREATION_VERSION
+        {
+            return Err(invalid_data("Not key creation data or unsupported version").into());
+        }
+
+        Ok(Self {
+            creation_data: reader.read_tpm2b()?.to_vec(),
+            creation_hash: reader.read_tpm2b()?.to_vec(),
+            creation_ticket: reader.read_tpm2b()?.to_vec(),
+        })
+    }
+
+    /// Parses the creation ticket into the structure expected by ESAPI.
+    fn ticket(&self) -> Result<CreationTicket, SecurityModuleError> {
+        let mut reader = Reader::new(&self.creation_ticket);
+        let tag = reader.read_u16()?;
+        let hierarchy = reader.read_u32()?;
+        let digest = reader.read_tpm2b()?;
+        let mut ticket = TPMT_TK_CREATION {
+            tag,
+            hierarchy,
+            digest: TPM2B_DIGEST {
+                size: digest.len() as u16,
+                buffer: [0; 64],
+            },
+        };
+        ticket
+            .digest
+            .buffer
+            .get_mut(..digest.len())
+            .ok_or_else(|| invalid_data("The creation ticket digest is too long"))?
+            .copy_from_slice(digest);
+
+        CreationTicket::try_from(ticket).map_err(tpm_error)
+    }
+}
+
+impl TpmProvider {
+    /// Sets the attestation key used by [`TpmProvider::certify_key`] and `attest_key`.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - An attestation key created with [`TpmProvider::create_attestation_key`], or
+    ///   `None` to disable key attestation.
+    pub fn set_attestation_key(&mut self, key: Option<AttestationKey>) {
+        self.attestation_key = key;
+    }
+
+    /// Returns the creation data of the key managed by this provider, if it was created by
+    /// this provider or set with [`TpmProvider::set_key_creation`].
+    ///
+    /// It should be stored with the key, as the key can only be certified with it.
+    pub fn key_creation(&self) -> Option<&KeyCreation> {
+        self.key_creation.as_ref()
+    }
+
+    /// Sets the creation data of the key loaded into this provider, as returned by
+    /// [`TpmProvider::key_creation`] when the key was created.
+    ///
+    /// # Arguments
+    ///
+    /// * `creation` - The stored creation data, or `None` if it is not known.
+    pub fn set_key_creation(&mut self, creation: Option<KeyCreation>) {
+        self.key_creation = creation;
+    }
+
+    /// Certifies with `TPM2_CertifyCreation` that the TPM created the key managed by this
+    /// provider, signed by the configured attestation key.
+    ///
+    /// This needs the creation data of the key, see [`TpmProvider::key_creation`].
+    ///
+    /// # Arguments
+    ///
+    /// * `qualifying_data` - A nonce chosen by the relying party, reported as `extraData`.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the [`KeyAttestation`] on success, or a `SecurityModuleError` on failure.
+    #[instrument(skip(qualifying_data))]
+    pub fn certify_key(
+        &self,
+        qualifying_data: &[u8],
+    ) -> Result<KeyAttestation, SecurityModuleError> {
+        let attestation_key = self.attestation_key.as_ref().ok_or_else(|| {
+            SecurityModuleError::InitializationError("No attestation key set".to_string())
+        })?;
+        let creation = self.key_creation.as_ref().ok_or_else(|| {
+            SecurityModuleError::InitializationError(
+                "The creation data of the key is not known".to_string(),
+            )
+        })?;
+        let ticket = creation.ticket()?;
+
+        let qualifying_data = Data::try_from(qualifying_data).map_err(tpm_error)?;
+        let creation_hash =
+            Digest::try_from(creation.creation_hash.as_slice()).map_err(tpm_error)?;
+
+        let mut context = self.context()?;
+        let key_handle = self.loaded_key(&mut context)?;
+        let key_public = context.read_public(key_handle).map_err(tpm_error)?.0;
+        let (ak, ek) = self.load_attestation_key(&mut context, attestation_key)?;
+        // The attestation key has an empty auth value, the certified key needs no
+        // authorization. The scheme of the restricted attestation key is used.
+        let result = context.execute_with_session(Some(AuthSession::Password), |ctx| {
+            ctx.certify_creation(
+                ak,
+                key_handle.into(),
+                qualifying_data,
+                creation_hash,
+                SignatureScheme::Null,
+                ticket,
+            )
+        });
+        flush(&mut context, ak.into());
+        flush(&mut context, ek.into());
+        let (attest, signature) = result.map_err(tpm_error)?;
+
+        let marshall_error = |e: tss_esapi::Error| SecurityModuleError::SigningError(e.to_string());
+        Ok(KeyAttestation {
+            attest: attest.marshall().map_err(marshall_error)?,
+            signature: signature.marshall().map_err(marshall_error)?,
+            key_public: key_public.marshall().map_err(marshall_error)?,
+            creation_data: creation.creation_data.clone(),
+        })
+    }
+}
@@ -0,0 +1,243 @@
+use super::{policy::ParameterEncryption, utils::tpm_error, TpmProvider};
+use crate::common::error::SecurityModuleError;
+use tracing::instrument;
+use tss_esapi::{
+    constants::{AlgorithmIdentifier, CapabilityType, EccCurveIdentifier, PropertyTag},
+    handles::ObjectHandle,
+    interface_types::algorithm::HashingAlgorithm,
+    structures::{Auth, CapabilityData},
+    Context,
+};
+
+/// The `inLockout` bit of `TPM2_PT_PERMANENT`.
+const PERMANENT_IN_LOCKOUT: u32 = 1 << 9;
+
+/// Upper bound for the number of entries requested per `TPM2_GetCapability` call.
+const CAPABILITY_COUNT: u32 = 64;
+
+/// Static information about the TPM, as reported by `TPM2_GetCapability`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TpmInfo {
+    /// The vendor ID assigned by the TCG, e.g. `IFX` or `INTC`.
+    pub manufacturer: String,
+    /// The concatenated vendor strings, e.g. `SLB9670`.
+    pub vendor: String,
+    /// The vendor-defined firmware version, with `TPM2_PT_FIRMWARE_VERSION_1` in the upper
+    /// and `TPM2_PT_FIRMWARE_VERSION_2` in the lower 32 bits.
+    pub firmware_version: u64,
+    /// The specification family, e.g. `2.0`.
+    pub family: String,
+    /// The specification level.
+    pub level: u32,
+    /// The specification revision times 100, e.g. `138` for revision 1.38.
+    pub revision: u32,
+    /// The year and day of year of the specification version.
+    pub spec_date: (u32, u32),
+    /// The algorithms implemented by the TPM.
+    pub algorithms: Vec<AlgorithmIdentifier>,
+    /// The ECC curves implemented by the TPM.
+    pub ecc_curves: Vec<EccCurveIdentifier>,
+    /// The PCR banks that currently have PCRs allocated.
+    pub pcr_banks: Vec<HashingAlgorithm>,
+}
+
+/// The dictionary attack protection state of the TPM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LockoutState {
+    /// Whether the TPM is in lockout and refuses DA protected authorizations.
+    pub in_lockout: bool,
+    /// The current number of authorization failures.
+    pub counter: u32,
+    /// The number of authorization failures before the TPM enters lockout.
+    pub max_tries: u32,
+    /// The number of seconds after which the counter is decremented by one.
+    pub recovery_time: u32,
+    /// The number of seconds to wait after a failed lockout authorization.
+    pub lockout_recovery: u32,
+}
+
+/// The runtime state of the TPM.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TpmHealth {
+    /// Whether the last self test passed.
+    pub self_test_passed: bool,
+    /// The dictionary attack protection state.
+    pub lockout: LockoutState,
+}
+
+impl TpmProvider {
+    /// Reads the manufacturer, firmware and specification version and the supported
+    /// algorithms, curves and PCR banks of the TPM.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the [`TpmInfo`] on success, or a `SecurityModuleError` on failure.
+    #[instrument]
+    pub fn device_info(&self) -> Result<TpmInfo, SecurityModuleError> {
+        let mut context = self.context()?;
+
+        let vendor = [
+            PropertyTag::VendorString1,
+            PropertyTag::VendorString2,
+            PropertyTag::VendorString3,
+            PropertyTag::VendorString4,
+        ]
+        .into_iter()
+        .map(|tag| property(&mut context, tag))
+        .collect::<Result<Vec<_>, _>>()?;
+        let firmware_version = (u64::from(property(&mut context, PropertyTag::FirmwareVersion1)?)
+            << 32)
+            | u64::from(property(&mut context, PropertyTag::FirmwareVersion2)?);
+
+        let mut algorithms = Vec::new();
+        let mut ecc_curves = Vec::new();
+        let mut pcr_banks = Vec::new();
+        for capability in [
+            CapabilityType::Algorithms,
+            CapabilityType::EccCurves,
+            CapabilityType::AssignedPcr,
+        ] {
+            for data in capabilities(&mut context, capability)? {
+                match data {
+                    CapabilityData::Algorithms(list) => {
+                        algorithms.extend(list.iter().map(|alg| alg.algorithm_identifier()))
+                    }
+                    CapabilityData::EccCurves(list) => ecc_curves.extend(list.iter().copied()),
+                    CapabilityData::AssignedPcr(list) => pcr_banks.extend(
+                        list.get_selections()
+                            .iter()
+                            .filter(|selection| !selection.is_empty())
+                            .map(|selection| selection.hashing_algorithm()),
+                    ),
+                    _ => {}
+                }
+            }
+        }
+
+        Ok(TpmInfo {
+            manufacturer: ascii(&[property(&mut context, PropertyTag::Manufacturer)?]),
+            vendor: ascii(&vendor),
+            firmware_version,
+            family: ascii(&[property(&mut context, PropertyTag::FamilyIndicator)?]),
+            level: property(&mut context, PropertyTag::Level)?,
+            revision: property(&mut context, PropertyTag::Revision)?,
+            spec_date: (
+                property(&mut context, PropertyTag::Year)?,
+                property(&mut context, PropertyTag::DayOfYear)?,
+            ),
+            algorithms,
+            ecc_curves,
+            pcr_banks,
+        })
+    }
+
+    /// Reads the self test result and the dictionary attack lockout state of the TPM.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the [`TpmHealth`] on success, or a `SecurityModuleError` on failure.
+    #[instrument]
+    pub fn health(&self) -> Result<TpmHealth, SecurityModuleError> {
+        let mut context = self.context()?;
+
+        let (_, self_test) = context.get_test_result().map_err(tpm_error)?;
+        let lockout = LockoutState {
+            in_lockout: property(&mut context, PropertyTag::Permanent)? & PERMANENT_IN_LOCKOUT != 0,
+            counter: property(&mut context, PropertyTag::LockoutCounter)?,
+            max_tries: property(&mut context, PropertyTag::MaxAuthFail)?,
+            recovery_time: property(&mut context, PropertyTag::LockoutInterval)?,
+            lockout_recovery: property(&mut context, PropertyTag::LockoutRecovery)?,
+        };
+
+        Ok(TpmHealth {
+            self_test_passed: self_test.is_ok(),
+            lockout,
+        })
+    }
+
+    /// Resets the dictionary attack counter and leaves lockout, authorized by the lockout
+    /// hierarchy.
+    ///
+    /// A failed authorization of the lockout hierarchy locks it for
+    /// [`LockoutState::lockout_recovery`] seconds, so `lockout_auth` should be correct.
+    ///
+    /// # Arguments
+    ///
+    /// * `lockout_auth` - The auth value of the lockout hierarchy.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` that, on success, contains `Ok(())`. On failure, it returns a `SecurityModuleError`.
+    #[instrument(skip(lockout_auth))]
+    pub fn reset_lockout(&self, lockout_auth: &[u8]) -> Result<(), SecurityModuleError> {
+        let lockout_auth = Auth::try_from(lockout_auth).map_err(tpm_error)?;
+        let mut context = self.context()?;
+        context
+            .tr_set_auth(ObjectHandle::Lockout, lockout_auth)
+            .map_err(tpm_error)?;
+        // The auth value is only used for the HMAC of the session, never sent in the clear.
+        let result = self
+            .encryption_session(&mut context, ParameterEncryption::None)
+            .and_then(|session| {
+                let result = context
+                    .execute_with_session(Some(session), |ctx| ctx.dictionary_attack_lock_reset());
+                self.resources.flush_session(&mut context, session);
+                result.map_err(tpm_error)
+            });
+        if let Err(e) = context.tr_set_auth(ObjectHandle::Lockout, Auth::default()) {
+            tracing::warn!("Failed to clear the lockout auth value: {}", e);
+        }
+
+        result
+    }
+}
+
+/// Reads a single TPM property, reporting properties the TPM does not know as zero.
+fn property(context: &mut Context, tag: PropertyTag) -> Result<u32, SecurityModuleError> {
+    Ok(context
+        .get_tpm_property(tag)
+        .map_err(tpm_error)?
+        .unwrap_or(0))
+}
+
+/// Reads all entries of a capability, which may take more than one call.
+fn capabilities(
+    context: &mut Context,
+    capability: CapabilityType,
+) -> Result<Vec<CapabilityData>, SecurityModuleError> {
+    let mut data = Vec::new();
+    let mut next = 0;
+    loop {
+        let (chunk, more) = context
+            .get_capability(capability, next, CAPABILITY_COUNT)
+            .map_err(tpm_error)?;
+        // Continue after the last entry returned, which is only needed for lists with
+        // more entries than fit in a single response.
+        let last = match &chunk {
+            CapabilityData::Algorithms(list) => list
+                .last()
+                .map(|alg| u32::from(u16::from(alg.algorithm_identifier()))),
+            CapabilityData::EccCurves(list) => {
+                list.last().map(|&curve| u32::from(u16::from(curve)))
+            }
+            _ => None,
+        };
+        data.push(chunk);
+        match last {
+            Some(last) if more => next = last + 1,
+            _ => return Ok(data),
+        }
+    }
+}
+
+/// Decodes properties holding up to four ASCII characters each, dropping padding.
+fn ascii(values: &[u32]) -> String {
+    values
+        .iter()
+        .flat_map(|value| value.to_be_bytes())
+        .filter(|byte| byte.is_ascii_graphic() || *byte == b' ')
+        .map(char::from)
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
@@ -0,0 +1,352 @@
+use super::{
+    attestation::KeyCreation,
+    policy::{KeyAuth, ParameterEncryption},
+    resources::StorageRoot,
+    utils::{flush, invalid_data, marshal_tpm2b, public_name, tpm_error, Reader},
+    TpmProvider,
+};
+use crate::{common::error::SecurityModuleError, tpm::core::error::TpmError, tpm::TpmConfig};
+use std::{any::Any, sync::Arc};
+use tracing::instrument;
+use tss_esapi::{
+    interface_types::resource_handles::Hierarchy,
+    structures::{EncryptedSecret, Private, Public, SymmetricDefinitionObject},
+    traits::{Marshall, UnMarshall},
+};
+
+/// Identifies a serialized wrapped key and its format version.
+const WRAPPED_KEY_MAGIC: &[u8; 4] = b"TPWK";
+const WRAPPED_KEY_VERSION: u8 = 1;
+
+/// Identifies a serialized duplicated key and its format version.
+const DUPLICATED_KEY_MAGIC: &[u8; 4] = b"TPDK";
+const DUPLICATED_KEY_VERSION: u8 = 1;
+
+/// A key stored outside the TPM, with its private area encrypted by the storage root.
+///
+/// Wrapped keys are returned by [`TpmProvider::create_duplicable_key`] and
+/// [`TpmProvider::import_key`] and can only be loaded on the TPM holding the storage root.
+#[derive(Debug, Clone)]
+pub struct WrappedKey {
+    /// The public area of the key.
+    pub public: Public,
+    /// The private area of the key, encrypted by the storage root.
+    pub private: Private,
+}
+
+impl WrappedKey {
+    /// Serializes the key into its portable binary form.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SecurityModuleError> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(WRAPPED_KEY_MAGIC);
+        buffer.push(WRAPPED_KEY_VERSION);
+        marshal_tpm2b(&self.public.marshall().map_err(tpm_error)?, &mut buffer);
+        marshal_tpm2b(self.private.value(), &mut buffer);
+        Ok(buffer)
+    }
+
+    /// Parses a key previously produced by [`WrappedKey::to_bytes`].
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SecurityModuleError> {
+        let mut reader = Reader::new(data);
+        check_header(&mut reader, WRAPPED_KEY_MAGIC, WRAPPED_KEY_VERSION)?;
+
+        Ok(Self {
+            public: Public::unmarshall(reader.read_tpm2b()?).map_err(tpm_error)?,
+            private: Private::try_from(reader.read_tpm2b()?).map_err(tpm_error)?,
+        })
+    }
+}
+
+/// A key wrapped for the storage root of another TPM by [`TpmProvider::duplicate_key`].
+#[derive(Debug, Clone)]
+pub struct DuplicatedKey {
+    /// The public area of the key.
+    pub public: Public,
+    /// The private area of the key, protected by the seed.
+    pub duplicate: Private,
+    /// The seed protecting the private area, encrypted to the new parent.
+    pub seed: EncryptedSecret,
+}
+
+impl DuplicatedKey {
+    /// Serializes the key into its portable binary form.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SecurityModuleError> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(DUPLICATED_KEY_MAGIC);
+        buffer.push(DUPLICATED_KEY_VERSION);
+        marshal_tpm2b(&self.public.marshall().map_err(tpm_error)?, &mut buffer);
+        marshal_tpm2b(self.duplicate.value(), &mut buffer);
+        marshal_tpm2b(self.seed.value(), &mut buffer);
+        Ok(buffer)
+    }
+
+    /// Parses a key previously produced by [`DuplicatedKey::to_bytes`].
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SecurityModuleError> {
+        let mut reader = Reader::new(data);
+        check_header(&mut reader, DUPLICATED_KEY_MAGIC, DUPLICATED_KEY_VERSION)?;
+
+        Ok(Self {
+            public: Public::unmarshall(reader.read_tpm2b()?).map_err(tpm_error)?,
+            duplicate: Private::try_from(reader.read_tpm2b()?).map_err(tpm_error)?,
+            seed: EncryptedSecret::try_from(reader.read_tpm2b()?).map_err(tpm_error)?,
+        })
+    }
+}
+
+impl TpmProvider {
+    /// Returns the public area of the storage root, the parent of wrapped and imported keys.
+    ///
+    /// This is the `new_parent` that keys are duplicated to when moving them to this TPM.
+    #[instrument]
+    pub fn storage_root_public(&self) -> Result<Public, SecurityModuleError> {
+        let mut context = self.context()?;
+        let storage_root = self.storage_root(&mut context)?;
+        let (public, _, _) = context.read_public(storage_root).map_err(tpm_error)?;
+
+        Ok(public)
+    }
+
+    /// Pins the storage root to `public`, e.g. as returned by
+    /// [`TpmProvider::storage_root_public`] when the device was enrolled.
+    ///
+    /// The storage root salts the sessions that protect auth values and parameters, so
+    /// operations fail if the TPM presents a different one. Without a pin, the storage root
+    /// seen first is pinned for the lifetime of the provider and its clones.
+    ///
+    /// # Arguments
+    ///
+    /// * `public` - The public area of the expected storage root.
+    #[instrument(skip(public))]
+    pub fn set_storage_root(&mut self, public: &Public) -> Result<(), SecurityModuleError> {
+        let name = public_name(public)?;
+        *self.storage_root.lock().unwrap() = StorageRoot {
+            object: None,
+            name: Some(name),
+        };
+
+        Ok(())
+    }
+
+    /// Creates a key that can be duplicated to another TPM and loads it into the provider.
+    ///
+    /// The key is created under the storage root with `fixed_tpm` and `fixed_parent` cleared.
+    /// It uses the key policy and auth value set on the provider, and the policy has to
+    /// contain a [`PolicyStep::Duplication`](super::policy::PolicyStep::Duplication) step
+    /// naming the TPM the key may be moved to.
+    ///
+    /// # Arguments
+    ///
+    /// * `config` - The `TpmConfig` of the key, as for `create_key`.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the [`WrappedKey`] to store on success, or a `SecurityModuleError` on failure.
+    #[instrument]
+    pub fn create_duplicable_key(
+        &mut self,
+        config: Box<dyn Any>,
+    ) -> Result<WrappedKey, SecurityModuleError> {
+        if self.key_policy.is_none() {
+            return Err(TpmError::UnsupportedOperation(
+                "Duplicable keys require a key policy".to_string(),
+            )
+            .into());
+        }
+        self.configure(config)?;
+        let public = self.key_template(false)?;
+        let auth = self.key_auth.as_ref().map(|KeyAuth(auth)| auth.clone());
+
+        let (key, creation) = {
+            let mut context = self.context()?;
+            let storage_root = self.storage_root(&mut context)?;
+            // The auth value of the key is sent encrypted.
+            let session = self.encryption_session(&mut context, ParameterEncryption::Command)?;
+            let result = context.execute_with_session(Some(session), |ctx| {
+                ctx.create(storage_root, public, auth, None, None, None)
+            });
+            self.resources.flush_session(&mut context, session);
+            let result = result.map_err(tpm_error)?;
+            let creation = KeyCreation::new(
+                result.creation_data,
+                result.creation_hash,
+                result.creation_ticket,
+            )?;
+            (
+                WrappedKey {
+                    public: result.out_public,
+                    private: result.out_private,
+                },
+                creation,
+            )
+        };
+        self.load_wrapped_key(&key)?;
+        self.key_creation = Some(creation);
+
+        Ok(key)
+    }
+
+    /// Loads a wrapped key into the provider, replacing the current key.
+    ///
+    /// The algorithms of the provider have to be configured for the key, e.g. by
+    /// [`TpmProvider::create_duplicable_key`] or [`TpmProvider::load_external_key`]. The
+    /// symmetric keys paired with ECC keys are bound to the TPM and not part of the wrapped
+    /// key, they are the ones stored for the key id of the provider.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - A key created or imported on this TPM.
+    #[instrument]
+    pub fn load_wrapped_key(&mut self, key: &WrappedKey) -> Result<(), SecurityModuleError> {
+        let key_handle = {
+            let mut context = self.context()?;
+            let storage_root = self.storage_root(&mut context)?;
+            let key_handle = self.resources.retry(&mut context, |ctx| {
+                ctx.execute_with_nullauth_session(|ctx| {
+                    ctx.load(storage_root, key.private.clone(), key.public.clone())
+                })
+            });
+            key_handle.map_err(tpm_error)?
+        };
+        // The previous key is flushed once the context is unlocked.
+        self.key_handle = Some(self.track(key_handle)?);
+        self.key_creation = None;
+        self.symmetric_keys = Arc::default();
+
+        Ok(())
+    }
+
+    /// Loads the public area of a foreign key into the provider, so that signatures can be
+    /// verified with it. The private operations of the provider are not available for
+    /// such keys.
+    ///
+    /// # Arguments
+    ///
+    /// * `public` - The public area of the key.
+    /// * `config` - The `TpmConfig` of the key, as for `load_key`.
+    #[instrument]
+    pub fn load_external_key(
+        &mut self,
+        public: Public,
+        config: Box<dyn Any>,
+    ) -> Result<(), SecurityModuleError> {
+        self.configure(config)?;
+        let key_handle = self
+            .resources
+            .retry(&mut *self.context()?, |ctx| {
+                ctx.load_external_public(public.clone(), Hierarchy::Owner)
+            })
+            .map_err(tpm_error)?;
+        self.key_handle = Some(self.track(key_handle)?);
+        self.key_creation = None;
+
+        Ok(())
+    }
+
+    /// Wraps the current key for the storage root of another TPM.
+    ///
+    /// Only keys whose template allows it, i.e. with `fixed_tpm` and `fixed_parent` cleared,
+    /// can be duplicated, and the key policy has to permit duplication to `new_parent`.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_parent` - The storage root of the target TPM, see
+    ///   [`TpmProvider::storage_root_public`].
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the [`DuplicatedKey`] on success, or a `SecurityModuleError` on failure.
+    #[instrument(skip(new_parent))]
+    pub fn duplicate_key(&self, new_parent: &Public) -> Result<DuplicatedKey, SecurityModuleError> {
+        let mut context = self.context()?;
+        let key_handle = self.loaded_key(&mut context)?;
+
+        let (public, key_name, _) = context.read_public(key_handle).map_err(tpm_error)?;
+        let attributes = public.object_attributes();
+        if attributes.fixed_tpm() || attributes.fixed_parent() {
+            return Err(TpmError::UnsupportedOperation(
+                "The key template does not allow duplication".to_string(),
+            )
+            .into());
+        }
+
+        let new_parent = self
+            .resources
+            .retry(&mut context, |ctx| {
+                ctx.load_external_public(new_parent.clone(), Hierarchy::Owner)
+            })
+            .map_err(tpm_error)?;
+        let result = self
+            .duplication_session(&mut context, key_handle, &key_name)
+            .and_then(|session| {
+                let result = context.execute_with_session(Some(session), |ctx| {
+                    ctx.duplicate(
+                        key_handle.into(),
+                        new_parent.into(),
+                        None,
+                        SymmetricDefinitionObject::Null,
+                    )
+                });
+                self.resources.flush_session(&mut context, session);
+                result.map_err(tpm_error)
+            });
+        flush(&mut context, new_parent.into());
+        let (_, duplicate, seed) = result?;
+
+        Ok(DuplicatedKey {
+            public,
+            duplicate,
+            seed,
+        })
+    }
+
+    /// Imports a key duplicated to the storage root of this TPM.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The key produced by [`TpmProvider::duplicate_key`] on the source TPM.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the [`WrappedKey`] to store on success, or a `SecurityModuleError` on failure.
+    #[instrument(skip(key))]
+    pub fn import_key(&self, key: &DuplicatedKey) -> Result<WrappedKey, SecurityModuleError> {
+        let mut context = self.context()?;
+        let storage_root = self.storage_root(&mut context)?;
+        let private = context.execute_with_nullauth_session(|ctx| {
+            ctx.import(
+                storage_root.into(),
+                None,
+                key.public.clone(),
+                key.duplicate.clone(),
+                key.seed.clone(),
+                SymmetricDefinitionObject::Null,
+            )
+        });
+
+        Ok(WrappedKey {
+            public: key.public.clone(),
+            private: private.map_err(tpm_error)?,
+        })
+    }
+
+    /// Applies the algorithms and usages of a `TpmConfig` to the provider.
+    pub(super) fn configure(&mut self, config: Box<dyn Any>) -> Result<(), SecurityModuleError> {
+        let config = config.downcast_ref::<TpmConfig>().ok_or_else(|| {
+            SecurityModuleError::InitializationError("Expected a TpmConfig".to_string())
+        })?;
+
+        self.key_algorithm = Some(config.key_algorithm);
+        self.sym_algorithm = Some(config.sym_algorithm);
+        self.hash = Some(config.hash);
+        self.key_usages = Some(config.key_usages.clone());
+
+        Ok(())
+    }
+}
+
+fn check_header(reader: &mut Reader, magic: &[u8], version: u8) -> Result<(), SecurityModuleError> {
+    if reader.read_bytes(magic.len())? != magic || reader.read_u8()? != version {
+        return Err(invalid_data("Unknown key format or unsupported version").into());
+    }
+    Ok(())
+}
@@ -0,0 +1,451 @@
+use super::{
+    utils::{invalid_data, tpm_error, Reader},
+    verifier::{message_digest, PcrMismatch, PcrValues},
+    TpmProvider,
+};
+use crate::{common::error::SecurityModuleError, tpm::core::error::TpmError};
+use openssl::hash::hash;
+use std::{fs, path::Path};
+use tracing::instrument;
+use tss_esapi::{
+    abstraction::pcr::read_all,
+    interface_types::algorithm::HashingAlgorithm,
+    structures::{PcrSelectionList, PcrSlot},
+};
+
+/// Where the Linux kernel exposes the event log of the firmware.
+pub const BIOS_MEASUREMENTS_PATH: &str = "/sys/kernel/security/tpm0/binary_bios_measurements";
+
+/// Signature of the `TCG_EfiSpecIDEvent` that starts a crypto agile log.
+const SPEC_ID_SIGNATURE: &[u8; 16] = b"Spec ID Event03\0";
+
+/// Signature of the `TCG_EfiStartupLocalityEvent`, which sets the initial value of PCR 0.
+const STARTUP_LOCALITY_SIGNATURE: &[u8; 16] = b"StartupLocality\0";
+
+/// Number of PCRs a PCR selection can address.
+const PCR_COUNT: u8 = 32;
+
+/// Size of the SHA-1 digest in the header event, which uses the legacy `TCG_PCR_EVENT` format.
+const SHA1_DIGEST_SIZE: usize = 20;
+
+/// The type of a measured boot event, as defined by the TCG PC Client Platform Firmware
+/// Profile. Each variant corresponds to the `EV_*` constant of the same name.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EventType {
+    PrebootCert,
+    PostCode,
+    NoAction,
+    Separator,
+    Action,
+    EventTag,
+    SCrtmContents,
+    SCrtmVersion,
+    CpuMicrocode,
+    PlatformConfigFlags,
+    TableOfDevices,
+    CompactHash,
+    Ipl,
+    IplPartitionData,
+    NonhostCode,
+    NonhostConfig,
+    NonhostInfo,
+    OmitBootDeviceEvents,
+    EfiVariableDriverConfig,
+    EfiVariableBoot,
+    EfiBootServicesApplication,
+    EfiBootServicesDriver,
+    EfiRuntimeServicesDriver,
+    EfiGptEvent,
+    EfiAction,
+    EfiPlatformFirmwareBlob,
+    EfiHandoffTables,
+    EfiPlatformFirmwareBlob2,
+    EfiHandoffTables2,
+    EfiVariableBoot2,
+    EfiHcrtmEvent,
+    EfiVariableAuthority,
+    /// An event type not known to this parser.
+    Other(u32),
+}
+
+impl From<u32> for EventType {
+    fn from(value: u32) -> Self {
+        match value {
+            0x0000_0000 => Self::PrebootCert,
+            0x0000_0001 => Self::PostCode,
+            0x0000_0003 => Self::NoAction,
+            0x0000_0004 => Self::Separator,
+            0x0000_0005 => Self::Action,
+            0x0000_0006 => Self::EventTag,
+            0x0000_0007 => Self::SCrtmContents,
+            0x0000_0008 => Self::SCrtmVersion,
+            0x0000_0009 => Self::CpuMicrocode,
+            0x0000_000A => Self::PlatformConfigFlags,
+            0x0000_000B => Self::TableOfDevices,
+            0x0000_000C => Self::CompactHash,
+            0x0000_000D => Self::Ipl,
+            0x0000_000E => Self::IplPartitionData,
+            0x0000_000F => Self::NonhostCode,
+            0x0000_0010 => Self::NonhostConfig,
+            0x0000_0011 => Self::NonhostInfo,
+            0x0000_0012 => Self::OmitBootDeviceEvents,
+            0x8000_0001 => Self::EfiVariableDriverConfig,
+            0x8000_0002 => Self::EfiVariableBoot,
+            0x8000_0003 => Self::EfiBootServicesApplication,
+            0x8000_0004 => Self::EfiBootServicesDriver,
+            0x8000_0005 => Self::EfiRuntimeServicesDriver,
+            0x8000_0006 => Self::EfiGptEvent,
+            0x8000_0007 => Self::EfiAction,
+            0x8000_0008 => Self::EfiPlatformFirmwareBlob,
+            0x8000_0009 => Self::EfiHandoffTables,
+            0x8000_000A => Self::EfiPlatformFirmwareBlob2,
+            0x8000_000B => Self::EfiHandoffTables2,
+            0x8000_000C => Self::EfiVariableBoot2,
+            0x8000_0010 => Self::EfiHcrtmEvent,
+            0x8000_00E0 => Self::EfiVariableAuthority,
+            other => Self::Other(other),
+        }
+    }
+}
+
+/// A single `TCG_PCR_EVENT2` entry of the event log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Event {
+    /// The PCR the event was extended into.
+    pub pcr_index: u8,
+    /// The type of the event.
+    pub event_type: EventType,
+    /// The digests extended into each bank.
+    pub digests: Vec<(HashingAlgorithm, Vec<u8>)>,
+    /// The raw event data, whose format depends on `event_type`.
+    pub data: Vec<u8>,
+}
+
+impl Event {
+    /// Returns the digest extended into the given bank, if the event has one.
+    pub fn digest(&self, bank: HashingAlgorithm) -> Option<&[u8]> {
+        self.digests
+            .iter()
+            .find(|(algorithm, _)| *algorithm == bank)
+            .map(|(_, digest)| digest.as_slice())
+    }
+
+    /// Returns a human readable description of the measured component, if the event data
+    /// contains one.
+    ///
+    /// This is the text of action, version and post code events, and the name of the
+    /// variable for UEFI variable events.
+    pub fn description(&self) -> Option<String> {
+        match self.event_type {
+            EventType::PostCode
+            | EventType::Action
+            | EventType::Ipl
+            | EventType::EfiAction
+            | EventType::CompactHash => {
+                let text = String::from_utf8_lossy(&self.data);
+                Some(text.trim_end_matches('\0').to_string())
+            }
+            EventType::SCrtmVersion => Some(utf16(&self.data)),
+            EventType::EfiVariableDriverConfig
+            | EventType::EfiVariableBoot
+            | EventType::EfiVariableBoot2
+            | EventType::EfiVariableAuthority => variable_name(&self.data),
+            _ => None,
+        }
+    }
+}
+
+/// A difference between an event log and a reference log of the same platform.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventDiff {
+    /// The PCR whose events differ.
+    pub pcr_index: u8,
+    /// The position of the event among the events extended into this PCR.
+    pub position: usize,
+    /// The event of the reference log, or `None` if the log has additional events.
+    pub reference: Option<Event>,
+    /// The event of the log, or `None` if the log lacks an event of the reference.
+    pub actual: Option<Event>,
+}
+
+/// A parsed crypto agile TCG PC Client event log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EventLog {
+    /// The banks the log has digests for, with their digest sizes.
+    pub algorithms: Vec<(HashingAlgorithm, usize)>,
+    /// The locality the TPM was started from, which determines the initial value of PCR 0.
+    pub startup_locality: u8,
+    /// The events following the header, in the order they were measured.
+    pub events: Vec<Event>,
+}
+
+impl EventLog {
+    /// Parses a binary event log in the format of `binary_bios_measurements`.
+    ///
+    /// Only crypto agile logs, starting with a `Spec ID Event03` header, are supported.
+    pub fn parse(data: &[u8]) -> Result<Self, SecurityModuleError> {
+        let mut reader = Reader::new(data);
+        let algorithms = read_header(&mut reader)?;
+
+        let mut startup_locality = 0;
+        let mut events = Vec::new();
+        while !reader.is_empty() {
+            let pcr_index = u8::try_from(reader.read_u32_le()?)
+                .ok()
+                .filter(|&index| index < PCR_COUNT)
+                .ok_or_else(|| invalid_data("PCR index out of range"))?;
+            let event_type = EventType::from(reader.read_u32_le()?);
+
+            let count = reader.read_u32_le()? as usize;
+            let mut digests = Vec::with_capacity(count.min(algorithms.len()));
+            for _ in 0..count {
+                let algorithm_id = reader.read_u16_le()?;
+                let (algorithm, size) = algorithms
+                    .iter()
+                    .find(|(algorithm, _)| u16::from(*algorithm) == algorithm_id)
+                    .copied()
+                    .ok_or_else(|| invalid_data("Event digest of an undeclared algorithm"))?;
+                digests.push((algorithm, reader.read_bytes(size)?.to_vec()));
+            }
+            let size = reader.read_u32_le()? as usize;
+            let data = reader.read_bytes(size)?.to_vec();
+
+            if event_type == EventType::NoAction
+                && data.len() > STARTUP_LOCALITY_SIGNATURE.len()
+                && data.starts_with(STARTUP_LOCALITY_SIGNATURE)
+            {
+                startup_locality = data[STARTUP_LOCALITY_SIGNATURE.len()];
+            }
+            events.push(Event {
+                pcr_index,
+                event_type,
+                digests,
+                data,
+            });
+        }
+
+        Ok(Self {
+            algorithms,
+            startup_locality,
+            events,
+        })
+    }
+
+    /// Reads and parses an event log from a file, such as [`BIOS_MEASUREMENTS_PATH`].
+    pub fn read(path: impl AsRef<Path>) -> Result<Self, SecurityModuleError> {
+        let data = fs::read(path).map_err(TpmError::Io)?;
+        Self::parse(&data)
+    }
+
+    /// Replays the log and returns the PCR values it results in.
+    ///
+    /// Only PCRs with at least one extended event are included. `EV_NO_ACTION` events are
+    /// never extended.
+    pub fn replay(&self) -> Result<PcrValues, SecurityModuleError> {
+        let mut pcrs = PcrValues::new();
+        for event in self.extended_events() {
+            for (bank, digest) in &event.digests {
+                let mut data = match pcrs.get(*bank, event.pcr_index) {
+                    Some(value) => value.to_vec(),
+                    None => self.initial_value(*bank, event.pcr_index)?,
+                };
+                data.extend_from_slice(digest);
+                let value = hash(message_digest(*bank)?, &data)
+                    .map_err(|e| TpmError::InternalError(Box::new(e)))?;
+                pcrs.insert(*bank, event.pcr_index, value.to_vec());
+            }
+        }
+
+        Ok(pcrs)
+    }
+
+    /// Replays the log and compares the result with the PCR values read from a TPM.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the PCRs whose value differs from the replayed log, which is
+    /// empty if the log matches.
+    pub fn verify(&self, pcrs: &PcrValues) -> Result<Vec<PcrMismatch>, SecurityModuleError> {
+        Ok(self
+            .replay()?
+            .iter()
+            .filter_map(|(bank, index, expected)| {
+                let reported = pcrs.get(bank, index);
+                (reported != Some(expected)).then(|| PcrMismatch {
+                    bank,
+                    index,
+                    expected: expected.to_vec(),
+                    reported: reported.map(<[u8]>::to_vec),
+                })
+            })
+            .collect())
+    }
+
+    /// Compares the events of this log with those of a known good `reference` log,
+    /// pointing out which measured components changed.
+    ///
+    /// Events are compared PCR by PCR in the order they were extended. Two events differ if
+    /// their type or any digest of a bank present in both differs.
+    pub fn diff(&self, reference: &EventLog) -> Vec<EventDiff> {
+        let mut diffs = Vec::new();
+        for pcr_index in 0..PCR_COUNT {
+            let actual: Vec<_> = self.events_of(pcr_index).collect();
+            let expected: Vec<_> = reference.events_of(pcr_index).collect();
+            for position in 0..actual.len().max(expected.len()) {
+                let (actual, expected) = (actual.get(position), expected.get(position));
+                let same = match (actual, expected) {
+                    (Some(actual), Some(expected)) => same_event(actual, expected),
+                    _ => false,
+                };
+                if !same {
+                    diffs.push(EventDiff {
+                        pcr_index,
+                        position,
+                        reference: expected.map(|&event| event.clone()),
+                        actual: actual.map(|&event| event.clone()),
+                    });
+                }
+            }
+        }
+
+        diffs
+    }
+
+    /// Returns the PCR selection covering all PCRs and banks extended by the log.
+    pub fn pcr_selection(&self) -> Result<PcrSelectionList, SecurityModuleError> {
+        let mut builder = PcrSelectionList::builder();
+        for (bank, _) in &self.algorithms {
+            let mut slots: Vec<PcrSlot> = Vec::new();
+            for event in self.extended_events() {
+                let slot = 1u32
+                    .checked_shl(event.pcr_index.into())
+                    .ok_or_else(|| invalid_data("PCR index out of range"))
+                    .and_then(|bit| {
+                        PcrSlot::try_from(bit).map_err(|e| TpmError::InternalError(Box::new(e)))
+                    })?;
+                if event.digest(*bank).is_some() && !slots.contains(&slot) {
+                    slots.push(slot);
+                }
+            }
+            if !slots.is_empty() {
+                builder = builder.with_selection(*bank, &slots);
+            }
+        }
+
+        builder.build().map_err(tpm_error)
+    }
+
+    fn extended_events(&self) -> impl Iterator<Item = &Event> {
+        self.events
+            .iter()
+            .filter(|event| event.event_type != EventType::NoAction)
+    }
+
+    fn events_of(&self, pcr_index: u8) -> impl Iterator<Item = &Event> {
+        self.extended_events()
+            .filter(move |event| event.pcr_index == pcr_index)
+    }
+
+    /// PCRs start out as zero, except for PCR 0, whose last byte holds the startup locality.
+    fn initial_value(&self, bank: HashingAlgorithm, pcr_index: u8) -> Result<Vec<u8>, TpmError> {
+        let size = self
+            .algorithms
+            .iter()
+            .find(|(algorithm, _)| *algorithm == bank)
+            .map(|(_, size)| *size)
+            .ok_or_else(|| invalid_data("Event digest of an undeclared algorithm"))?;
+        let mut value = vec![0; size];
+        if pcr_index == 0 {
+            value[size - 1] = self.startup_locality;
+        }
+
+        Ok(value)
+    }
+}
+
+impl TpmProvider {
+    /// Reads the PCRs extended by `log` from the TPM and compares them with the replayed log.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the PCRs whose value differs from the replayed log, which is
+    /// empty if the log matches the TPM, or a `SecurityModuleError` on failure.
+    #[instrument(skip(log))]
+    pub fn verify_event_log(
+        &self,
+        log: &EventLog,
+    ) -> Result<Vec<PcrMismatch>, SecurityModuleError> {
+        let selection = log.pcr_selection()?;
+        let pcr_data = read_all(&mut *self.context()?, selection).map_err(tpm_error)?;
+
+        let mut pcrs = PcrValues::new();
+        for (bank, values) in pcr_data {
+            for (slot, digest) in &values {
+                let index = u32::from(*slot).trailing_zeros() as u8;
+                pcrs.insert(bank, index, digest.value().to_vec());
+            }
+        }
+
+        log.verify(&pcrs)
+    }
+}
+
+/// Parses the `TCG_PCR_EVENT` header and its `TCG_EfiSpecIDEvent`, returning the declared
+/// algorithms and digest sizes.
+fn read_header(reader: &mut Reader) -> Result<Vec<(HashingAlgorithm, usize)>, TpmError> {
+    let _pcr_index = reader.read_u32_le()?;
+    let event_type = EventType::from(reader.read_u32_le()?);
+    reader.read_bytes(SHA1_DIGEST_SIZE)?;
+    let size = reader.read_u32_le()? as usize;
+    let mut spec_id = Reader::new(reader.read_bytes(size)?);
+
+    if event_type != EventType::NoAction
+        || spec_id.read_bytes(SPEC_ID_SIGNATURE.len())? != SPEC_ID_SIGNATURE
+    {
+        return Err(invalid_data("Not a crypto agile event log"));
+    }
+    // Platform class, spec version, errata and UINTN size.
+    spec_id.read_bytes(8)?;
+
+    let count = spec_id.read_u32_le()?;
+    let mut algorithms = Vec::new();
+    for _ in 0..count {
+        let algorithm_id = spec_id.read_u16_le()?;
+        let size = spec_id.read_u16_le()? as usize;
+        let algorithm = HashingAlgorithm::try_from(algorithm_id)
+            .map_err(|_| invalid_data("Unsupported event log digest algorithm"))?;
+        algorithms.push((algorithm, size));
+    }
+
+    Ok(algorithms)
+}
+
+fn same_event(actual: &Event, expected: &Event) -> bool {
+    actual.event_type == expected.event_type
+        && actual
+            .digests
+            .iter()
+            .all(|(bank, digest)| expected.digest(*bank).is_none_or(|other| other == digest))
+}
+
+/// Reads the name of a `UEFI_VARIABLE_DATA` structure.
+fn variable_name(data: &[u8]) -> Option<String> {
+    let mut reader = Reader::new(data);
+    // Variable vendor GUID.
+    reader.read_bytes(16).ok()?;
+    let name_length = usize::try_from(reader.read_u64_le().ok()?).ok()?;
+    let _data_length = reader.read_u64_le().ok()?;
+    let name = reader.read_bytes(name_length.checked_mul(2)?).ok()?;
+    Some(utf16(name))
+}
+
+/// Decodes a little-endian UTF-16 string, dropping the terminating null character.
+fn utf16(data: &[u8]) -> String {
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|unit| u16::from_le_bytes([unit[0], unit[1]]))
+        .collect();
+    String::from_utf16_lossy(&units)
+        .trim_end_matches('\0')
+        .to_string()
+}
@@ -0,0 +1,500 @@
+use super::{
+    policy::start_session,
+    resources::ResourceManager,
+    utils::{flush, invalid_data, marshal_tpm2b, public_name, tpm_error, Reader},
+    verifier::{message_digest, public_key_to_pkey},
+    TpmProvider,
+};
+use crate::{common::error::SecurityModuleError, tpm::core::error::TpmError};
+use openssl::{
+    bn::BigNumContext,
+    derive::Deriver,
+    ec::EcKey,
+    hash::{Hasher, MessageDigest},
+    md::Md,
+    pkey::PKey,
+    pkey_ctx::PkeyCtx,
+    rand::rand_bytes,
+    rsa::Padding,
+    sign::Signer,
+    symm::{encrypt, Cipher},
+};
+use tracing::instrument;
+use tss_esapi::{
+    abstraction::{ak, ek},
+    constants::SessionType,
+    handles::{AuthHandle, KeyHandle as TssKeyHandle},
+    interface_types::{
+        algorithm::{AsymmetricAlgorithm, HashingAlgorithm, SignatureSchemeAlgorithm},
+        key_bits::AesKeyBits,
+        session_handles::{AuthSession, PolicySession},
+    },
+    structures::{EncryptedSecret, IdObject, Private, Public, SymmetricDefinitionObject},
+    traits::{Marshall, UnMarshall},
+    Context,
+};
+
+/// Identifies a serialized attestation key and its format version.
+const ATTESTATION_KEY_MAGIC: &[u8; 4] = b"TPAK";
+const ATTESTATION_KEY_VERSION: u8 = 1;
+
+/// A restricted signing key created under the endorsement key.
+///
+/// The private area is encrypted by the endorsement key, so the serialized form can be stored
+/// anywhere and is only usable on the TPM that created it.
+#[derive(Debug, Clone)]
+pub struct AttestationKey {
+    /// The algorithm of the endorsement key the attestation key was created under.
+    pub ek_algorithm: AsymmetricAlgorithm,
+    /// The public area of the attestation key.
+    pub public: Public,
+    /// The private area of the attestation key, encrypted by the endorsement key.
+    pub private: Private,
+}
+
+impl AttestationKey {
+    /// Returns the TPM name of the attestation key, as needed by [`make_credential`].
+    pub fn name(&self) -> Result<Vec<u8>, SecurityModuleError> {
+        Ok(public_name(&self.public)?.value().to_vec())
+    }
+
+    /// Serializes the key into its portable binary form.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SecurityModuleError> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(ATTESTATION_KEY_MAGIC);
+        buffer.push(ATTESTATION_KEY_VERSION);
+        buffer.push(match self.ek_algorithm {
+            AsymmetricAlgorithm::Ecc => 1,
+            _ => 0,
+        });
+        let public = self.public.marshall().map_err(tpm_error)?;
+        marshal_tpm2b(&public, &mut buffer);
+        marshal_tpm2b(self.private.value(), &mut buffer);
+        Ok(buffer)
+    }
+
+    /// Parses a key previously produced by [`AttestationKey::to_bytes`].
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SecurityModuleError> {
+        let mut reader = Reader::new(data);
+        if reader.read_bytes(ATTESTATION_KEY_MAGIC.len())? != ATTESTATION_KEY_MAGIC
+            || reader.read_u8()? != ATTESTATION_KEY_VERSION
+        {
+            return Err(invalid_data("Not an attestation key or unsupported version").into());
+        }
+        let ek_algorithm = match reader.read_u8()? {
+            1 => AsymmetricAlgorithm::Ecc,
+            _ => AsymmetricAlgorithm::Rsa,
+        };
+        let public = Public::unmarshall(reader.read_tpm2b()?).map_err(tpm_error)?;
+        let private = Private::try_from(reader.read_tpm2b()?).map_err(tpm_error)?;
+
+        Ok(Self {
+            ek_algorithm,
+            public,
+            private,
+        })
+    }
+}
+
+/// A credential encrypted to an endorsement key by [`make_credential`].
+#[derive(Debug, Clone)]
+pub struct MadeCredential {
+    /// The `TPM2B_ID_OBJECT` contents holding the encrypted and integrity protected credential.
+    pub credential_blob: Vec<u8>,
+    /// The `TPM2B_ENCRYPTED_SECRET` contents holding the seed, encrypted to the endorsement key.
+    pub secret: Vec<u8>,
+}
+
+impl TpmProvider {
+    /// Returns the public area of the endorsement key created from the default template.
+    ///
+    /// # Arguments
+    ///
+    /// * `algorithm` - Whether the RSA 2048 or the ECC P-256 template is used.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the public area on success, or a `SecurityModuleError` on failure.
+    #[instrument]
+    pub fn endorsement_key(
+        &self,
+        algorithm: AsymmetricAlgorithm,
+    ) -> Result<Public, SecurityModuleError> {
+        let mut context = self.context()?;
+        let ek = create_ek(&mut context, &self.resources, algorithm)?;
+        let public = context.read_public(ek);
+        flush(&mut context, ek.into());
+
+        Ok(public.map_err(tpm_error)?.0)
+    }
+
+    /// Reads the DER encoded endorsement key certificate provisioned by the manufacturer.
+    ///
+    /// # Arguments
+    ///
+    /// * `algorithm` - Whether the certificate of the RSA 2048 or the ECC P-256 key is read.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the certificate on success, or a `SecurityModuleError` on failure.
+    #[instrument]
+    pub fn endorsement_certificate(
+        &self,
+        algorithm: AsymmetricAlgorithm,
+    ) -> Result<Vec<u8>, SecurityModuleError> {
+        ek::retrieve_ek_pubcert(&mut *self.context()?, algorithm).map_err(tpm_error)
+    }
+
+    /// Creates a restricted signing key under the endorsement key.
+    ///
+    /// # Arguments
+    ///
+    /// * `ek_algorithm` - The template of the endorsement key used as parent.
+    /// * `signature_scheme` - The signature scheme of the attestation key, which also
+    ///   determines its key type.
+    /// * `hashing_algorithm` - The hash algorithm used for signing.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the [`AttestationKey`] on success, or a `SecurityModuleError` on failure.
+    #[instrument]
+    pub fn create_attestation_key(
+        &self,
+        ek_algorithm: AsymmetricAlgorithm,
+        signature_scheme: SignatureSchemeAlgorithm,
+        hashing_algorithm: HashingAlgorithm,
+    ) -> Result<AttestationKey, SecurityModuleError> {
+        let mut context = self.context()?;
+        let ek = create_ek(&mut context, &self.resources, ek_algorithm)?;
+        let result = self.resources.retry(&mut context, |ctx| {
+            ak::create_ak(ctx, ek, hashing_algorithm, signature_scheme, None, None)
+        });
+        flush(&mut context, ek.into());
+        let result = result.map_err(tpm_error)?;
+
+        Ok(AttestationKey {
+            ek_algorithm,
+            public: result.out_public,
+            private: result.out_private,
+        })
+    }
+
+    /// Loads an attestation key and returns its handle together with the handle of its parent
+    /// endorsement key. The caller is responsible for flushing both.
+    pub(super) fn load_attestation_key(
+        &self,
+        context: &mut Context,
+        key: &AttestationKey,
+    ) -> Result<(TssKeyHandle, TssKeyHandle), SecurityModuleError> {
+        let ek = create_ek(context, &self.resources, key.ek_algorithm)?;
+        let ak = self.resources.retry(context, |ctx| {
+            ak::load_ak(ctx, ek, None, key.private.clone(), key.public.clone())
+        });
+        match ak {
+            Ok(ak) => Ok((ak, ek)),
+            Err(e) => {
+                flush(context, ek.into());
+                Err(tpm_error(e))
+            }
+        }
+    }
+
+    /// Recovers a credential made with [`make_credential`] for the given attestation key.
+    ///
+    /// This only succeeds on the TPM holding both the endorsement key the credential was
+    /// encrypted to and the attestation key whose name it was bound to.
+    ///
+    /// # Arguments
+    ///
+    /// * `key` - The attestation key the credential was made for.
+    /// * `credential` - The credential received from the server.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the decrypted credential on success, or a `SecurityModuleError` on failure.
+    #[instrument(skip(credential))]
+    pub fn activate_credential(
+        &self,
+        key: &AttestationKey,
+        credential: &MadeCredential,
+    ) -> Result<Vec<u8>, SecurityModuleError> {
+        let credential_blob = IdObject::try_from(credential.credential_blob.as_slice())
+            .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))?;
+        let secret = EncryptedSecret::try_from(credential.secret.as_slice())
+            .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))?;
+        let mut context = self.context()?;
+
+        let (ak, ek) = self.load_attestation_key(&mut context, key)?;
+        let result = endorsement_session(&mut context, &self.resources).and_then(|session| {
+            let result = context
+                .execute_with_sessions((Some(AuthSession::Password), Some(session), None), |ctx| {
+                    ctx.activate_credential(ak, ek, credential_blob, secret)
+                });
+            self.resources.flush_session(&mut context, session);
+            result.map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))
+        });
+        flush(&mut context, ak.into());
+        flush(&mut context, ek.into());
+
+        result.map(|credential| credential.value().to_vec())
+    }
+}
+
+/// Encrypts `credential` so that only the TPM holding the endorsement key `ek_public` and a
+/// key named `ak_name` can recover it with `TPM2_ActivateCredential`.
+///
+/// This is the software equivalent of `TPM2_MakeCredential` and needs no TPM, so it can run
+/// on the server enrolling the device.
+///
+/// # Arguments
+///
+/// * `ek_public` - The public area of the device's endorsement key.
+/// * `ak_name` - The name of the attestation key, see [`AttestationKey::name`].
+/// * `credential` - The secret to transfer, at most the digest size of the EK name algorithm.
+///
+/// # Returns
+///
+/// A `Result` containing the [`MadeCredential`] on success, or a `SecurityModuleError` on failure.
+pub fn make_credential(
+    ek_public: &Public,
+    ak_name: &[u8],
+    credential: &[u8],
+) -> Result<MadeCredential, SecurityModuleError> {
+    let name_algorithm = ek_public.name_hashing_algorithm();
+    let digest = message_digest(name_algorithm)?;
+    if credential.len() > digest.size() {
+        return Err(SecurityModuleError::EncryptionError(
+            "The credential is larger than the digest of the EK name algorithm".to_string(),
+        ));
+    }
+
+    let (symmetric, (seed, secret)) = match ek_public {
+        Public::Rsa { parameters, .. } => (
+            parameters.symmetric_definition_object(),
+            rsa_seed(ek_public, digest)?,
+        ),
+        Public::Ecc { parameters, .. } => (
+            parameters.symmetric_definition_object(),
+            ecc_seed(ek_public, digest)?,
+        ),
+        _ => {
+            return Err(TpmError::UnsupportedOperation(
+                "The endorsement key must be an RSA or ECC key".to_string(),
+            )
+            .into())
+        }
+    };
+    let (cipher, key_bits) = match symmetric {
+        SymmetricDefinitionObject::Aes {
+            key_bits: AesKeyBits::Aes128,
+            ..
+        } => (Cipher::aes_128_cfb128(), 128),
+        SymmetricDefinitionObject::Aes {
+            key_bits: AesKeyBits::Aes256,
+            ..
+        } => (Cipher::aes_256_cfb128(), 256),
+        symmetric => {
+            return Err(TpmError::UnsupportedOperation(format!(
+                "Symmetric algorithm {:?} of the endorsement key is not supported",
+                symmetric
+            ))
+            .into())
+        }
+    };
+
+    // Encrypt the credential as a TPM2B_DIGEST with a key bound to the AK name.
+    let sym_key = kdf_a(digest, &seed, b"STORAGE", ak_name, &[], key_bits)?;
+    let mut plaintext = Vec::new();
+    marshal_tpm2b(credential, &mut plaintext);
+    let enc_identity =
+        encrypt(cipher, &sym_key, Some(&[0; 16]), &plaintext).map_err(encryption_error)?;
+
+    let hmac_key = kdf_a(digest, &seed, b"INTEGRITY", &[], &[], digest.size() * 8)?;
+    let hmac_key = PKey::hmac(&hmac_key).map_err(encryption_error)?;
+    let mut signer = Signer::new(digest, &hmac_key).map_err(encryption_error)?;
+    signer.update(&enc_identity).map_err(encryption_error)?;
+    signer.update(ak_name).map_err(encryption_error)?;
+    let outer_hmac = signer.sign_to_vec().map_err(encryption_error)?;
+
+    let mut credential_blob = Vec::new();
+    marshal_tpm2b(&outer_hmac, &mut credential_blob);
+    credential_blob.extend_from_slice(&enc_identity);
+
+    Ok(MadeCredential {
+        credential_blob,
+        secret,
+    })
+}
+
+fn encryption_error(e: openssl::error::ErrorStack) -> SecurityModuleError {
+    SecurityModuleError::EncryptionError(e.to_string())
+}
+
+fn create_ek(
+    context: &mut Context,
+    resources: &ResourceManager,
+    algorithm: AsymmetricAlgorithm,
+) -> Result<TssKeyHandle, SecurityModuleError> {
+    resources
+        .retry(context, |ctx| ek::create_ek_object(ctx, algorithm, None))
+        .map_err(tpm_error)
+}
+
+/// Starts a policy session satisfying the default endorsement key policy, i.e.
+/// `PolicySecret(TPM_RH_ENDORSEMENT)`.
+fn endorsement_session(
+    context: &mut Context,
+    resources: &ResourceManager,
+) -> Result<AuthSession, SecurityModuleError> {
+    let session = start_session(
+        context,
+        resources,
+        SessionType::Policy,
+        HashingAlgorithm::Sha256,
+        None,
+        None,
+    )?;
+    let result = PolicySession::try_from(session).and_then(|policy_session| {
+        context.execute_with_nullauth_session(|ctx| {
+            ctx.policy_secret(
+                policy_session,
+                AuthHandle::Endorsement,
+                Default::default(),
+                Default::default(),
+                Default::default(),
+                None,
+            )
+        })
+    });
+    if let Err(e) = result {
+        resources.flush_session(context, session);
+        return Err(tpm_error(e));
+    }
+
+    Ok(session)
+}
+
+/// Generates a random seed and encrypts it to an RSA endorsement key with OAEP.
+fn rsa_seed(
+    ek_public: &Public,
+    digest: MessageDigest,
+) -> Result<(Vec<u8>, Vec<u8>), SecurityModuleError> {
+    let mut seed = vec![0; digest.size()];
+    rand_bytes(&mut seed).map_err(encryption_error)?;
+
+    let pkey = public_key_to_pkey(ek_public)?;
+    let md = Md::from_nid(digest.type_()).ok_or_else(|| {
+        SecurityModuleError::EncryptionError("Unsupported OAEP digest".to_string())
+    })?;
+    let mut ctx = PkeyCtx::new(&pkey).map_err(encryption_error)?;
+    ctx.encrypt_init().map_err(encryption_error)?;
+    ctx.set_rsa_padding(Padding::PKCS1_OAEP)
+        .map_err(encryption_error)?;
+    ctx.set_rsa_oaep_md(md).map_err(encryption_error)?;
+    ctx.set_rsa_mgf1_md(md).map_err(encryption_error)?;
+    ctx.set_rsa_oaep_label(b"IDENTITY\0")
+        .map_err(encryption_error)?;
+    let mut secret = Vec::new();
+    ctx.encrypt_to_vec(&seed, &mut secret)
+        .map_err(encryption_error)?;
+
+    Ok((seed, secret))
+}
+
+/// Derives a seed from an ephemeral ECDH exchange with an ECC endorsement key. The secret is
+/// the marshalled ephemeral public point.
+fn ecc_seed(
+    ek_public: &Public,
+    digest: MessageDigest,
+) -> Result<(Vec<u8>, Vec<u8>), SecurityModuleError> {
+    let ek_pkey = public_key_to_pkey(ek_public)?;
+    let ek_key = ek_pkey.ec_key().map_err(encryption_error)?;
+    let group = ek_key.group();
+    let coordinate_size = (group.degree() as usize).div_ceil(8);
+
+    let ephemeral = EcKey::generate(group).map_err(encryption_error)?;
+    let mut ctx = BigNumContext::new().map_err(encryption_error)?;
+    let mut x = openssl::bn::BigNum::new().map_err(encryption_error)?;
+    let mut y = openssl::bn::BigNum::new().map_err(encryption_error)?;
+    ephemeral
+        .public_key()
+        .affine_coordinates_gfp(group, &mut x, &mut y, &mut ctx)
+        .map_err(encryption_error)?;
+    let x = x
+        .to_vec_padded(coordinate_size as i32)
+        .map_err(encryption_error)?;
+    let y = y
+        .to_vec_padded(coordinate_size as i32)
+        .map_err(encryption_error)?;
+
+    let ephemeral = PKey::from_ec_key(ephemeral).map_err(encryption_error)?;
+    let mut deriver = Deriver::new(&ephemeral).map_err(encryption_error)?;
+    deriver.set_peer(&ek_pkey).map_err(encryption_error)?;
+    let z = deriver.derive_to_vec().map_err(encryption_error)?;
+
+    let ek_x = match ek_public {
+        Public::Ecc { unique, .. } => unique.x().value().to_vec(),
+        _ => unreachable!(),
+    };
+    let seed = kdf_e(digest, &z, b"IDENTITY", &x, &ek_x, digest.size() * 8)?;
+
+    let mut secret = Vec::new();
+    marshal_tpm2b(&x, &mut secret);
+    marshal_tpm2b(&y, &mut secret);
+
+    Ok((seed, secret))
+}
+
+/// The counter mode KDF of the TPM specification, `KDFa`, based on HMAC.
+fn kdf_a(
+    digest: MessageDigest,
+    key: &[u8],
+    label: &[u8],
+    context_u: &[u8],
+    context_v: &[u8],
+    bits: usize,
+) -> Result<Vec<u8>, SecurityModuleError> {
+    let key = PKey::hmac(key).map_err(encryption_error)?;
+    let mut output = Vec::with_capacity(bits / 8 + digest.size());
+    let mut counter = 1u32;
+    while output.len() < bits / 8 {
+        let mut signer = Signer::new(digest, &key).map_err(encryption_error)?;
+        for part in [
+            &counter.to_be_bytes()[..],
+            label,
+            &[0],
+            context_u,
+            context_v,
+            &(bits as u32).to_be_bytes(),
+        ] {
+            signer.update(part).map_err(encryption_error)?;
+        }
+        output.extend_from_slice(&signer.sign_to_vec().map_err(encryption_error)?);
+        counter += 1;
+    }
+    output.truncate(bits / 8);
+    Ok(output)
+}
+
+/// The concatenation KDF of the TPM specification, `KDFe`, used with ECDH.
+fn kdf_e(
+    digest: MessageDigest,
+    z: &[u8],
+    label: &[u8],
+    party_u: &[u8],
+    party_v: &[u8],
+    bits: usize,
+) -> Result<Vec<u8>, SecurityModuleError> {
+    let mut output = Vec::with_capacity(bits / 8 + digest.size());
+    let mut counter = 1u32;
+    while output.len() < bits / 8 {
+        let mut hasher = Hasher::new(digest).map_err(encryption_error)?;
+        for part in [&counter.to_be_bytes()[..], z, label, &[0], party_u, party_v] {
+            hasher.update(part).map_err(encryption_error)?;
+        }
+        output.extend_from_slice(&hasher.finish().map_err(encryption_error)?);
+        counter += 1;
+    }
+    output.truncate(bits / 8);
+    Ok(output)
+}
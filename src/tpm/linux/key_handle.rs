@@ -1,16 +1,26 @@
-use super::TpmProvider;
+use super::{
+    policy::ParameterEncryption,
+    utils::{flush, null_hashcheck_ticket},
+    verifier::message_digest,
+    TpmProvider,
+};
 use crate::common::{
-    crypto::algorithms::encryption::AsymmetricEncryption, error::SecurityModuleError,
+    crypto::algorithms::{encryption::AsymmetricEncryption, hashes::Hash},
+    error::SecurityModuleError,
     traits::key_handle::KeyHandle,
 };
 use tracing::instrument;
 use tss_esapi::{
-    interface_types::{algorithm::SymmetricMode, resource_handles::Hierarchy},
+    handles::KeyHandle as TssKeyHandle,
+    interface_types::{
+        algorithm::HashingAlgorithm, resource_handles::Hierarchy, session_handles::AuthSession,
+    },
     structures::{
-        Data, EccParameter, EccSignature, HashScheme, InitialValue, MaxBuffer, PublicKeyRsa,
-        RsaDecryptionScheme, RsaSignature, Signature, SignatureScheme,
+        Data, Digest, EccParameter, EccSignature, HashScheme, HashcheckTicket, MaxBuffer,
+        PublicKeyRsa, RsaDecryptionScheme, RsaSignature, Signature, SignatureScheme,
     },
     traits::Marshall,
+    Context,
 };
 
 impl KeyHandle for TpmProvider {
@@ -25,48 +35,26 @@ impl KeyHandle for TpmProvider {
     /// A `Result` containing the signature as a `Vec<u8>` on success, or a `SecurityModuleError` on failure.
     #[instrument]
     fn sign_data(&self, data: &[u8]) -> Result<Vec<u8>, SecurityModuleError> {
-        let key_handle = *self.key_handle.as_ref().unwrap().lock().unwrap();
-        let ticket = self
-            .handle
-            .as_ref()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .hash(
-                MaxBuffer::try_from(data).unwrap(),
-                self.hash.unwrap().into(),
-                Hierarchy::Null,
-            )
-            .map_err(|e| SecurityModuleError::SigningError(e.to_string()))?;
-
-        let signature = match self.key_algorithm.as_ref().unwrap() {
-            AsymmetricEncryption::Rsa(_) => self
-                .handle
-                .as_ref()
-                .unwrap()
-                .lock()
-                .unwrap()
-                .sign(
-                    key_handle,
-                    ticket.0,
-                    SignatureScheme::RsaSsa {
-                        hash_scheme: HashScheme::new(self.hash.unwrap().into()),
-                    },
-                    ticket.1,
-                )
-                .map_err(|e| SecurityModuleError::SigningError(e.to_string()))?,
-            AsymmetricEncryption::Ecc(ecc_scheme) => {
-                let signature_scheme: SignatureScheme = (*ecc_scheme).into();
-                self.handle
-                    .as_ref()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
-                    .sign(key_handle, ticket.0, signature_scheme, ticket.1)
-                    .map_err(|e| SecurityModuleError::SigningError(e.to_string()))?
-            }
+        let scheme = match self.key_algorithm.as_ref().unwrap() {
+            AsymmetricEncryption::Rsa(_) => SignatureScheme::RsaSsa {
+                hash_scheme: HashScheme::new(self.hash.unwrap().into()),
+            },
+            AsymmetricEncryption::Ecc(ecc_scheme) => (*ecc_scheme).into(),
         };
 
+        let mut context = self.context()?;
+        let key_handle = self.loaded_key(&mut context)?;
+        let (digest, ticket) = self.sign_digest(&mut context, key_handle, data)?;
+        // Only a response starting with a sized buffer can be encrypted, and the TPM rejects
+        // the encrypt attribute for TPM2_Sign, which returns a TPMT_SIGNATURE. The session
+        // HMAC still protects the integrity of the signature.
+        let session = self.key_session(&mut context, key_handle, ParameterEncryption::Command)?;
+        let signature = context.execute_with_session(Some(session), |ctx| {
+            ctx.sign(key_handle, digest, scheme, ticket)
+        });
+        self.resources.flush_session(&mut context, session);
+        let signature = signature.map_err(|e| SecurityModuleError::SigningError(e.to_string()))?;
+
         signature
             .marshall()
             .map_err(|e| SecurityModuleError::SigningError(e.to_string()))
@@ -83,55 +71,35 @@ impl KeyHandle for TpmProvider {
     /// A `Result` containing the decrypted data as a `Vec<u8>` on success, or a `SecurityModuleError` on failure.
     #[instrument]
     fn decrypt_data(&self, encrypted_data: &[u8]) -> Result<Vec<u8>, SecurityModuleError> {
-        let key_handle = *self.key_handle.as_ref().unwrap().lock().unwrap();
-
         match self.key_algorithm.as_ref().unwrap() {
             AsymmetricEncryption::Rsa(_) => {
-                let scheme =
-                    RsaDecryptionScheme::Oaep(HashScheme::new(self.hash.unwrap().into()));
+                let scheme = RsaDecryptionScheme::Oaep(HashScheme::new(self.hash.unwrap().into()));
                 let pub_key = PublicKeyRsa::try_from(encrypted_data)
                     .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))?;
-                let decryption_result = self
-                    .handle
-                    .as_ref()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
-                    .rsa_decrypt(
-                        key_handle,
-                        pub_key,
-                        scheme,
-                        Data::try_from(encrypted_data)
-                            .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))?,
-                    )
+                // Data is encrypted without a label.
+                let label = Data::default();
+                let mut context = self.context()?;
+                let key_handle = self.loaded_key(&mut context)?;
+                let session =
+                    self.key_session(&mut context, key_handle, ParameterEncryption::Both)?;
+                let decryption_result = context.execute_with_session(Some(session), |ctx| {
+                    ctx.rsa_decrypt(key_handle, pub_key, scheme, label)
+                });
+                self.resources.flush_session(&mut context, session);
+                let decryption_result = decryption_result
                     .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))?;
                 Ok(decryption_result.to_vec())
             }
-            AsymmetricEncryption::Ecc(_) => {
-                let initial_value = InitialValue::try_from(vec![0u8; 16])
-                    .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))?;
-                let (decrypted_data, _) = self
-                    .handle
-                    .as_ref()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
-                    .encrypt_decrypt_2(
-                        key_handle,
-                        true,
-                        SymmetricMode::Cfb,
-                        MaxBuffer::try_from(encrypted_data)
-                            .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))?,
-                        initial_value,
-                    )
-                    .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))?;
-                Ok(decrypted_data.to_vec())
-            }
+            AsymmetricEncryption::Ecc(_) => self.decrypt_symmetric(encrypted_data),
         }
     }
 
     /// Encrypts the given data using the cryptographic key managed by the TPM provider.
     ///
+    /// ECC keys encrypt with the configured block cipher, which has to use a mode
+    /// implemented by the TPM, i.e. CBC, CFB, OFB or CTR. GCM is rejected with
+    /// `UnsupportedOperation`.
+    ///
     /// # Arguments
     ///
     /// * `data` - A byte slice representing the data to be encrypted.
@@ -141,50 +109,26 @@ impl KeyHandle for TpmProvider {
     /// A `Result` containing the encrypted data as a `Vec<u8>` on success, or a `SecurityModuleError` on failure.
     #[instrument]
     fn encrypt_data(&self, data: &[u8]) -> Result<Vec<u8>, SecurityModuleError> {
-        let key_handle = *self.key_handle.as_ref().unwrap().lock().unwrap();
-
         match self.key_algorithm.as_ref().unwrap() {
             AsymmetricEncryption::Rsa(_) => {
-                let scheme =
-                    RsaDecryptionScheme::Oaep(HashScheme::new(self.hash.unwrap().into()));
+                let scheme = RsaDecryptionScheme::Oaep(HashScheme::new(self.hash.unwrap().into()));
                 let message = PublicKeyRsa::try_from(data)
                     .map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))?;
-                let encryption_result = self
-                    .handle
-                    .as_ref()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
-                    .rsa_encrypt(
-                        key_handle,
-                        message,
-                        scheme,
-                        Data::try_from(data)
-                            .map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))?,
-                    )
+                let mut context = self.context()?;
+                let key_handle = self.loaded_key(&mut context)?;
+                // TPM2_RSA_Encrypt needs no authorization, the session only keeps the
+                // plaintext off the bus.
+                let session =
+                    self.encryption_session(&mut context, ParameterEncryption::Command)?;
+                let encryption_result = context.execute_with_session(Some(session), |ctx| {
+                    ctx.rsa_encrypt(key_handle, message, scheme, Data::default())
+                });
+                self.resources.flush_session(&mut context, session);
+                let encryption_result = encryption_result
                     .map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))?;
                 Ok(encryption_result.value().to_vec())
             }
-            AsymmetricEncryption::Ecc(_) => {
-                let initial_value = InitialValue::try_from(vec![0u8; 16])
-                    .map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))?;
-                let (encrypted_data, _) = self
-                    .handle
-                    .as_ref()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
-                    .encrypt_decrypt_2(
-                        key_handle,
-                        false,
-                        SymmetricMode::Cfb,
-                        MaxBuffer::try_from(data)
-                            .map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))?,
-                        initial_value,
-                    )
-                    .map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))?;
-                Ok(encrypted_data.to_vec())
-            }
+            AsymmetricEncryption::Ecc(_) => self.encrypt_symmetric(data),
         }
     }
 
@@ -201,34 +145,18 @@ impl KeyHandle for TpmProvider {
     /// or a `SecurityModuleError` on failure.
     #[instrument]
     fn verify_signature(&self, data: &[u8], signature: &[u8]) -> Result<bool, SecurityModuleError> {
-        let key_handle = *self.key_handle.as_ref().unwrap().lock().unwrap();
-        let digest = self
-            .handle
-            .as_ref()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .hash(
-                MaxBuffer::try_from(data).unwrap(),
-                self.hash.unwrap().into(),
-                Hierarchy::Null,
-            )
-            .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?
-            .0;
+        let digest = software_digest(data, self.hash.unwrap())
+            .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
 
         let verification_result = match self.key_algorithm.as_ref().unwrap() {
             AsymmetricEncryption::Rsa(_) => {
                 let signature = PublicKeyRsa::try_from(signature)
                     .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
-                let rsa_signature =
-                    RsaSignature::create(self.hash.unwrap().into(), signature).map_err(
-                        |e| SecurityModuleError::SignatureVerificationError(e.to_string()),
-                    )?;
-                self.handle
-                    .as_ref()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
+                let rsa_signature = RsaSignature::create(self.hash.unwrap().into(), signature)
+                    .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
+                let mut context = self.context()?;
+                let key_handle = self.loaded_key(&mut context)?;
+                context
                     .verify_signature(key_handle, digest, Signature::RsaSsa(rsa_signature))
                     .is_ok()
             }
@@ -249,24 +177,20 @@ impl KeyHandle for TpmProvider {
                         })?,
                     ),
                 };
-                let ecc_signature = EccSignature::create(
-                    self.hash.unwrap().into(),
-                    signature_r,
-                    signature_s,
-                )
-                .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
+                let ecc_signature =
+                    EccSignature::create(self.hash.unwrap().into(), signature_r, signature_s)
+                        .map_err(|e| {
+                            SecurityModuleError::SignatureVerificationError(e.to_string())
+                        })?;
                 let signature = match signature_scheme {
                     SignatureScheme::EcDsa { .. } => Signature::EcDsa(ecc_signature),
-                    SignatureScheme::EcDaa { .. } => Signature::EcDaa(ecc_signature),
                     SignatureScheme::Sm2 { .. } => Signature::Sm2(ecc_signature),
                     SignatureScheme::EcSchnorr { .. } => Signature::EcSchnorr(ecc_signature),
                     _ => unreachable!(),
                 };
-                self.handle
-                    .as_ref()
-                    .unwrap()
-                    .lock()
-                    .unwrap()
+                let mut context = self.context()?;
+                let key_handle = self.loaded_key(&mut context)?;
+                context
                     .verify_signature(key_handle, digest, signature)
                     .is_ok()
             }
@@ -274,4 +198,88 @@ impl KeyHandle for TpmProvider {
 
         Ok(verification_result)
     }
+
+    /// Certifies the key managed by the TPM provider with its attestation key.
+    ///
+    /// # Arguments
+    ///
+    /// * `nonce` - The nonce of the relying party, reported as `extraData` of the attestation.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the serialized [`KeyAttestation`](super::attestation::KeyAttestation)
+    /// on success, or a `SecurityModuleError` on failure.
+    #[instrument(skip(nonce))]
+    fn attest_key(&self, nonce: &[u8]) -> Result<Vec<u8>, SecurityModuleError> {
+        Ok(self.certify_key(nonce)?.to_bytes())
+    }
+}
+
+impl TpmProvider {
+    /// Computes the digest to sign and the ticket proving that the TPM did not produce it.
+    ///
+    /// Unrestricted keys sign any digest, so the message is hashed in software and a null
+    /// ticket is used. Restricted keys require the TPM to hash the message. Messages of up to
+    /// `MaxBuffer::MAX_SIZE` bytes are hashed with `TPM2_Hash` in an encryption session,
+    /// longer ones in a hash sequence.
+    fn sign_digest(
+        &self,
+        context: &mut Context,
+        key_handle: TssKeyHandle,
+        data: &[u8],
+    ) -> Result<(Digest, HashcheckTicket), SecurityModuleError> {
+        let hash = self.hash.unwrap();
+        let signing_error = |e: tss_esapi::Error| SecurityModuleError::SigningError(e.to_string());
+        let (public, _, _) = context.read_public(key_handle).map_err(signing_error)?;
+
+        if !public.object_attributes().restricted() {
+            let ticket = null_hashcheck_ticket().map_err(signing_error)?;
+            return Ok((software_digest(data, hash)?, ticket));
+        }
+
+        if data.len() > MaxBuffer::MAX_SIZE {
+            return hash_sequence(context, data, hash.into()).map_err(signing_error);
+        }
+
+        let data = MaxBuffer::try_from(data).map_err(signing_error)?;
+        let session = self.encryption_session(context, ParameterEncryption::Both)?;
+        let result = context.execute_with_session(Some(session), |ctx| {
+            ctx.hash(data, hash.into(), Hierarchy::Owner)
+        });
+        self.resources.flush_session(context, session);
+        result.map_err(signing_error)
+    }
+}
+
+/// Hashes `data` of any length in a hash sequence, whose empty auth value is used in password
+/// sessions. The last chunk is passed to `TPM2_SequenceComplete`, which also flushes the
+/// sequence.
+fn hash_sequence(
+    context: &mut Context,
+    data: &[u8],
+    hashing_algorithm: HashingAlgorithm,
+) -> tss_esapi::Result<(Digest, HashcheckTicket)> {
+    let sequence =
+        context.execute_without_session(|ctx| ctx.hash_sequence_start(hashing_algorithm, None))?;
+
+    let split = data.len().saturating_sub(1) / MaxBuffer::MAX_SIZE * MaxBuffer::MAX_SIZE;
+    let (updates, last) = data.split_at(split);
+    let result = context.execute_with_session(Some(AuthSession::Password), |ctx| {
+        for chunk in updates.chunks(MaxBuffer::MAX_SIZE) {
+            ctx.sequence_update(sequence, MaxBuffer::try_from(chunk)?)?;
+        }
+        ctx.sequence_complete(sequence, MaxBuffer::try_from(last)?, Hierarchy::Owner)
+    });
+    if result.is_err() {
+        flush(context, sequence);
+    }
+
+    result
+}
+
+/// Hashes `data` in software with the given algorithm.
+fn software_digest(data: &[u8], hash: Hash) -> Result<Digest, SecurityModuleError> {
+    let digest = openssl::hash::hash(message_digest(hash.into())?, data)
+        .map_err(|e| SecurityModuleError::SigningError(e.to_string()))?;
+    Digest::try_from(digest.to_vec()).map_err(|e| SecurityModuleError::SigningError(e.to_string()))
 }
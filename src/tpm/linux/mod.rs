@@ -8,20 +8,38 @@ use crate::common::crypto::{
     },
     KeyUsage,
 };
+use crate::common::traits::credential_provider::CredentialProvider;
+use attestation::KeyCreation;
+use identity::AttestationKey;
+use policy::{KeyAuth, KeyPolicy};
+use resources::{ResourceManager, StorageRoot, TpmObject};
 use std::sync::{Arc, Mutex};
+use symmetric::SymmetricKeys;
 use tss_esapi::{
-    handles::KeyHandle as TssKeyHandle,
     interface_types::{
         algorithm::{HashingAlgorithm, PublicAlgorithm, SymmetricMode as TssSymmetricMode},
         ecc::EccCurve,
         key_bits::{AesKeyBits, CamelliaKeyBits, RsaKeyBits},
     },
-    structures::{EcDaaScheme, EccScheme, HashScheme, SignatureScheme, SymmetricDefinitionObject},
+    structures::{EccScheme, HashScheme, SignatureScheme, SymmetricDefinitionObject},
     Context,
 };
 
+pub mod attestation;
+pub mod device;
+pub mod duplication;
+pub mod event_log;
+pub mod identity;
 pub mod key_handle;
+pub mod nv;
+pub mod policy;
 pub mod provider;
+mod resources;
+pub mod seal;
+mod symmetric;
+pub mod systemd_creds;
+mod utils;
+pub mod verifier;
 
 /// A TPM-based cryptographic provider for managing cryptographic keys and performing
 /// cryptographic operations.
@@ -34,8 +52,28 @@ pub mod provider;
 pub struct TpmProvider {
     /// A unique identifier for the cryptographic key managed by this provider.
     key_id: String,
-    pub(super) key_handle: Option<Arc<Mutex<TssKeyHandle>>>,
+    pub(super) key_handle: Option<Arc<TpmObject>>,
+    /// The TPM-resident symmetric keys used for bulk encryption with ECC keys, loaded on
+    /// first use.
+    pub(super) symmetric_keys: Arc<Mutex<Option<SymmetricKeys>>>,
     pub(super) handle: Option<Arc<Mutex<Context>>>,
+    /// Swaps out and flushes the transient objects loaded through `handle`.
+    pub(super) resources: Arc<ResourceManager>,
+    /// The storage root salting the sessions, kept loaded as long as `handle` is used.
+    pub(super) storage_root: Arc<Mutex<StorageRoot>>,
+    /// The auth value of the key.
+    pub(super) key_auth: Option<KeyAuth>,
+    /// Asked for the auth value of keys if none was set explicitly.
+    pub(super) credentials: Option<Arc<dyn CredentialProvider>>,
+    /// The key id `key_auth` was requested from `credentials` for, or `None` if it was set
+    /// explicitly.
+    pub(super) key_auth_id: Option<String>,
+    /// The policy that has to be satisfied to use the key.
+    pub(super) key_policy: Option<KeyPolicy>,
+    /// The attestation key used to certify the key.
+    pub(super) attestation_key: Option<AttestationKey>,
+    /// What the TPM reported when it created the key, needed to certify it.
+    pub(super) key_creation: Option<KeyCreation>,
     pub(super) key_algorithm: Option<AsymmetricEncryption>,
     pub(super) sym_algorithm: Option<BlockCiphers>,
     pub(super) hash: Option<Hash>,
@@ -52,7 +90,16 @@ impl TpmProvider {
         Self {
             key_id,
             key_handle: None,
+            symmetric_keys: Arc::default(),
             handle: None,
+            resources: Arc::default(),
+            storage_root: Arc::default(),
+            key_auth: None,
+            credentials: None,
+            key_auth_id: None,
+            key_policy: None,
+            attestation_key: None,
+            key_creation: None,
             key_algorithm: None,
             sym_algorithm: None,
             hash: None,
@@ -94,9 +141,6 @@ impl From<EccSchemeAlgorithm> for SignatureScheme {
             EccSchemeAlgorithm::EcDsa(_) => SignatureScheme::EcDsa {
                 hash_scheme: HashScheme::new(HashingAlgorithm::Sha512),
             },
-            EccSchemeAlgorithm::EcDaa(_) => Self::EcDaa {
-                ecdaa_scheme: EcDaaScheme::new(HashingAlgorithm::Sha512, 0),
-            },
             EccSchemeAlgorithm::Sm2(_) => Self::Sm2 {
                 hash_scheme: HashScheme::new(HashingAlgorithm::Sha512),
             },
@@ -117,9 +161,6 @@ impl From<EccSchemeAlgorithm> for EccScheme {
             EccSchemeAlgorithm::EcDh(_) => {
                 EccScheme::EcDh(HashScheme::new(HashingAlgorithm::Sha512))
             }
-            EccSchemeAlgorithm::EcDaa(_) => {
-                EccScheme::EcDaa(EcDaaScheme::new(HashingAlgorithm::Sha512, 0))
-            }
             EccSchemeAlgorithm::Sm2(_) => EccScheme::Sm2(HashScheme::new(HashingAlgorithm::Sha512)),
             EccSchemeAlgorithm::EcSchnorr(_) => {
                 EccScheme::EcSchnorr(HashScheme::new(HashingAlgorithm::Sha512))
@@ -127,7 +168,8 @@ impl From<EccSchemeAlgorithm> for EccScheme {
             EccSchemeAlgorithm::EcMqv(_) => {
                 EccScheme::EcMqv(HashScheme::new(HashingAlgorithm::Sha512))
             }
-            EccSchemeAlgorithm::Null => unimplemented!(),
+            // ECDAA keys are rejected by `key_template`, see there.
+            EccSchemeAlgorithm::EcDaa(_) | EccSchemeAlgorithm::Null => unimplemented!(),
         }
     }
 }
@@ -165,8 +207,8 @@ impl From<KeyBits> for RsaKeyBits {
 impl From<AsymmetricEncryption> for PublicAlgorithm {
     fn from(val: AsymmetricEncryption) -> Self {
         match val {
-            AsymmetricEncryption::Rsa(_) => todo!(),
-            AsymmetricEncryption::Ecc(_) => todo!(),
+            AsymmetricEncryption::Rsa(_) => PublicAlgorithm::Rsa,
+            AsymmetricEncryption::Ecc(_) => PublicAlgorithm::Ecc,
         }
     }
 }
@@ -0,0 +1,451 @@
+use super::{
+    policy::ParameterEncryption,
+    utils::{invalid_data, tpm_error},
+    TpmProvider,
+};
+use crate::{common::error::SecurityModuleError, tpm::core::error::TpmError};
+use std::io;
+use tracing::instrument;
+use tss_esapi::{
+    abstraction::nv::max_nv_buffer_size,
+    attributes::NvIndexAttributesBuilder,
+    constants::{response_code::Tss2ResponseCodeKind, NvIndexType},
+    handles::{NvIndexHandle, NvIndexTpmHandle, ObjectHandle, TpmHandle},
+    interface_types::{
+        algorithm::HashingAlgorithm,
+        resource_handles::{NvAuth, Provision},
+        session_handles::AuthSession,
+    },
+    structures::{Auth, MaxNvBuffer, NvPublicBuilder},
+    Context,
+};
+
+/// Size of the data area of counter and bit field indices.
+const NV_U64_SIZE: usize = 8;
+
+/// Determines which authorization is required to access an NV index.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NvAuthorization {
+    /// Reads and writes are authorized by the owner hierarchy.
+    Owner,
+    /// Reads and writes are authorized by the auth value of the index itself.
+    Index(Vec<u8>),
+}
+
+/// The type of data an NV index holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NvIndexKind {
+    /// An ordinary index holding up to the given number of bytes.
+    Ordinary(usize),
+    /// A 64 bit monotonic counter that can only be incremented.
+    Counter,
+    /// A 64 bit field whose bits can only be set, never cleared.
+    Bits,
+}
+
+impl From<NvIndexKind> for NvIndexType {
+    fn from(value: NvIndexKind) -> Self {
+        match value {
+            NvIndexKind::Ordinary(_) => NvIndexType::Ordinary,
+            NvIndexKind::Counter => NvIndexType::Counter,
+            NvIndexKind::Bits => NvIndexType::Bits,
+        }
+    }
+}
+
+impl TpmProvider {
+    /// Defines a new NV index.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The NV index handle, in the range `0x01000000..=0x01FFFFFF`.
+    /// * `kind` - The type and size of the index.
+    /// * `authorization` - Who is allowed to read and write the index. For
+    ///   `NvAuthorization::Index`, the given value becomes the auth value of the index.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` that, on success, contains `Ok(())`. On failure, it returns a `SecurityModuleError`.
+    #[instrument(skip(authorization))]
+    pub fn nv_define(
+        &self,
+        index: u32,
+        kind: NvIndexKind,
+        authorization: &NvAuthorization,
+    ) -> Result<(), SecurityModuleError> {
+        let owner = *authorization == NvAuthorization::Owner;
+        let attributes = NvIndexAttributesBuilder::new()
+            .with_nv_index_type(kind.into())
+            .with_owner_read(owner)
+            .with_owner_write(owner)
+            .with_auth_read(!owner)
+            .with_auth_write(!owner)
+            .build()
+            .map_err(tpm_error)?;
+        let size = match kind {
+            NvIndexKind::Ordinary(size) => size,
+            NvIndexKind::Counter | NvIndexKind::Bits => NV_U64_SIZE,
+        };
+        let public = NvPublicBuilder::new()
+            .with_nv_index(nv_index_handle(index)?)
+            .with_index_name_algorithm(HashingAlgorithm::Sha256)
+            .with_index_attributes(attributes)
+            .with_data_area_size(size)
+            .build()
+            .map_err(tpm_error)?;
+        let auth = match authorization {
+            NvAuthorization::Owner => None,
+            NvAuthorization::Index(auth) => {
+                Some(Auth::try_from(auth.as_slice()).map_err(tpm_error)?)
+            }
+        };
+
+        let mut context = self.context()?;
+        // The auth value of the index is sent encrypted.
+        let session = self.encryption_session(&mut context, ParameterEncryption::Command)?;
+        let handle = context.execute_with_session(Some(session), |ctx| {
+            ctx.nv_define_space(Provision::Owner, auth, public)
+        });
+        self.resources.flush_session(&mut context, session);
+        close(&mut context, handle.map_err(tpm_error)?.into());
+
+        Ok(())
+    }
+
+    /// Deletes an NV index that was defined by the owner hierarchy.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The NV index handle.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` that, on success, contains `Ok(())`. On failure, it returns a `SecurityModuleError`.
+    #[instrument]
+    pub fn nv_undefine(&self, index: u32) -> Result<(), SecurityModuleError> {
+        let mut context = self.context()?;
+        let handle = open(&mut context, index, &NvAuthorization::Owner)?;
+        let result = context
+            .execute_with_nullauth_session(|ctx| ctx.nv_undefine_space(Provision::Owner, handle));
+        if result.is_err() {
+            close(&mut context, handle.into());
+        }
+
+        result.map_err(tpm_error)
+    }
+
+    /// Returns whether the NV index `index` is defined.
+    pub(super) fn nv_defined(&self, index: u32) -> Result<bool, SecurityModuleError> {
+        let tpm_handle = TpmHandle::NvIndex(nv_index_handle(index)?);
+        let mut context = self.context()?;
+        match context.execute_without_session(|ctx| ctx.tr_from_tpm_public(tpm_handle)) {
+            Ok(handle) => {
+                close(&mut context, handle);
+                Ok(true)
+            }
+            Err(tss_esapi::Error::Tss2Error(rc))
+                if rc.kind() == Some(Tss2ResponseCodeKind::Handle) =>
+            {
+                Ok(false)
+            }
+            Err(e) => Err(tpm_error(e)),
+        }
+    }
+
+    /// Writes `data` to the beginning of an ordinary NV index.
+    ///
+    /// Data larger than the TPM's NV buffer is written in multiple chunks.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The NV index handle.
+    /// * `data` - The data to write, at most the size the index was defined with.
+    /// * `authorization` - The authorization the index was defined with.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` that, on success, contains `Ok(())`. On failure, it returns a `SecurityModuleError`.
+    #[instrument(skip(data, authorization))]
+    pub fn nv_write(
+        &self,
+        index: u32,
+        data: &[u8],
+        authorization: &NvAuthorization,
+    ) -> Result<(), SecurityModuleError> {
+        let mut context = self.context()?;
+        let chunk_size = max_nv_buffer_size(&mut context).map_err(tpm_error)?;
+        let handle = open(&mut context, index, authorization)?;
+        let auth_handle = nv_auth(authorization, handle);
+        let session = match self.encryption_session(&mut context, ParameterEncryption::Command) {
+            Ok(session) => session,
+            Err(e) => {
+                close(&mut context, handle.into());
+                return Err(e);
+            }
+        };
+
+        let result = data
+            .chunks(chunk_size)
+            .enumerate()
+            .try_for_each(|(i, chunk)| {
+                let buffer = MaxNvBuffer::try_from(chunk)?;
+                let offset = u16::try_from(i * chunk_size).map_err(|_| {
+                    tss_esapi::Error::WrapperError(tss_esapi::WrapperErrorKind::WrongParamSize)
+                })?;
+                context.execute_with_session(Some(session), |ctx| {
+                    ctx.nv_write(auth_handle, handle, buffer, offset)
+                })
+            });
+        self.resources.flush_session(&mut context, session);
+        close(&mut context, handle.into());
+
+        result.map_err(tpm_error)
+    }
+
+    /// Reads the full contents of an ordinary NV index.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The NV index handle.
+    /// * `authorization` - The authorization the index was defined with.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the data on success, or a `SecurityModuleError` on failure.
+    #[instrument(skip(authorization))]
+    pub fn nv_read(
+        &self,
+        index: u32,
+        authorization: &NvAuthorization,
+    ) -> Result<Vec<u8>, SecurityModuleError> {
+        let mut context = self.context()?;
+        let chunk_size = max_nv_buffer_size(&mut context).map_err(tpm_error)?;
+        let handle = open(&mut context, index, authorization)?;
+        let result = self
+            .encryption_session(&mut context, ParameterEncryption::Response)
+            .and_then(|session| {
+                let result = read_all(&mut context, session, handle, authorization, chunk_size);
+                self.resources.flush_session(&mut context, session);
+                result
+            });
+        close(&mut context, handle.into());
+
+        result
+    }
+
+    /// Increments an NV counter and returns its new value.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The NV index handle of a counter index.
+    /// * `authorization` - The authorization the index was defined with.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the incremented counter value on success, or a `SecurityModuleError` on failure.
+    #[instrument(skip(authorization))]
+    pub fn nv_increment(
+        &self,
+        index: u32,
+        authorization: &NvAuthorization,
+    ) -> Result<u64, SecurityModuleError> {
+        let mut context = self.context()?;
+        let handle = open(&mut context, index, authorization)?;
+        let auth_handle = nv_auth(authorization, handle);
+        // TPM2_NV_Increment has no parameters to encrypt, only the value read afterwards.
+        let result = self
+            .encryption_session(&mut context, ParameterEncryption::None)
+            .and_then(|session| {
+                let result = context.execute_with_session(Some(session), |ctx| {
+                    ctx.nv_increment(auth_handle, handle)
+                });
+                self.resources.flush_session(&mut context, session);
+                result.map_err(tpm_error)
+            })
+            .and_then(|_| self.encryption_session(&mut context, ParameterEncryption::Response))
+            .and_then(|session| {
+                let result = read_all(&mut context, session, handle, authorization, NV_U64_SIZE);
+                self.resources.flush_session(&mut context, session);
+                result
+            })
+            .and_then(|data| to_u64(&data));
+        close(&mut context, handle.into());
+
+        result
+    }
+
+    /// Reads the current value of an NV counter.
+    ///
+    /// Counters that have never been incremented can not be read.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The NV index handle of a counter index.
+    /// * `authorization` - The authorization the index was defined with.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the counter value on success, or a `SecurityModuleError` on failure.
+    #[instrument(skip(authorization))]
+    pub fn nv_read_counter(
+        &self,
+        index: u32,
+        authorization: &NvAuthorization,
+    ) -> Result<u64, SecurityModuleError> {
+        to_u64(&self.nv_read(index, authorization)?)
+    }
+
+    /// Sets bits in an NV bit field and returns its new value.
+    ///
+    /// Bits that are already set stay set, the new value is the bitwise OR of the current
+    /// value and `bits`.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The NV index handle of a bit field index.
+    /// * `bits` - The bits to set.
+    /// * `authorization` - The authorization the index was defined with.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the bit field on success, or a `SecurityModuleError` on failure.
+    #[instrument(skip(authorization))]
+    pub fn nv_set_bits(
+        &self,
+        index: u32,
+        bits: u64,
+        authorization: &NvAuthorization,
+    ) -> Result<u64, SecurityModuleError> {
+        let mut context = self.context()?;
+        let handle = open(&mut context, index, authorization)?;
+        let auth_handle = nv_auth(authorization, handle);
+        // The bits are a plain integer parameter, which sessions do not encrypt.
+        let result = self
+            .encryption_session(&mut context, ParameterEncryption::None)
+            .and_then(|session| {
+                let result = context.execute_with_session(Some(session), |ctx| {
+                    ctx.nv_set_bits(auth_handle, handle, bits)
+                });
+                self.resources.flush_session(&mut context, session);
+                result.map_err(tpm_error)
+            })
+            .and_then(|_| self.encryption_session(&mut context, ParameterEncryption::Response))
+            .and_then(|session| {
+                let result = read_all(&mut context, session, handle, authorization, NV_U64_SIZE);
+                self.resources.flush_session(&mut context, session);
+                result
+            })
+            .and_then(|data| to_u64(&data));
+        close(&mut context, handle.into());
+
+        result
+    }
+
+    /// Reads the current value of an NV bit field.
+    ///
+    /// Bit field indices can not be read until a bit has been set with `nv_set_bits`.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - The NV index handle of a bit field index.
+    /// * `authorization` - The authorization the index was defined with.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the bit field on success, or a `SecurityModuleError` on failure.
+    #[instrument(skip(authorization))]
+    pub fn nv_read_bits(
+        &self,
+        index: u32,
+        authorization: &NvAuthorization,
+    ) -> Result<u64, SecurityModuleError> {
+        to_u64(&self.nv_read(index, authorization)?)
+    }
+}
+
+fn nv_index_handle(index: u32) -> Result<NvIndexTpmHandle, SecurityModuleError> {
+    NvIndexTpmHandle::new(index).map_err(tpm_error)
+}
+
+/// Creates an ESYS handle for an existing NV index and sets its auth value if required.
+///
+/// The handle has to be released with [`close`].
+fn open(
+    context: &mut Context,
+    index: u32,
+    authorization: &NvAuthorization,
+) -> Result<NvIndexHandle, SecurityModuleError> {
+    let tpm_handle = TpmHandle::NvIndex(nv_index_handle(index)?);
+    let mut handle = context
+        .execute_without_session(|ctx| ctx.tr_from_tpm_public(tpm_handle))
+        .map_err(tpm_error)?;
+
+    if let NvAuthorization::Index(auth) = authorization {
+        let result =
+            Auth::try_from(auth.as_slice()).and_then(|auth| context.tr_set_auth(handle, auth));
+        if let Err(e) = result {
+            let _ = context.tr_close(&mut handle);
+            return Err(tpm_error(e));
+        }
+    }
+
+    Ok(handle.into())
+}
+
+/// Releases an ESYS handle, logging failures instead of propagating them.
+fn close(context: &mut Context, mut handle: ObjectHandle) {
+    if let Err(e) = context.tr_close(&mut handle) {
+        tracing::warn!("Failed to close NV index handle: {}", e);
+    }
+}
+
+fn nv_auth(authorization: &NvAuthorization, handle: NvIndexHandle) -> NvAuth {
+    match authorization {
+        NvAuthorization::Owner => NvAuth::Owner,
+        NvAuthorization::Index(_) => NvAuth::NvIndex(handle),
+    }
+}
+
+/// Reads an NV index in chunks of at most `chunk_size` bytes, authorized by `session`.
+fn read_all(
+    context: &mut Context,
+    session: AuthSession,
+    handle: NvIndexHandle,
+    authorization: &NvAuthorization,
+    chunk_size: usize,
+) -> Result<Vec<u8>, SecurityModuleError> {
+    let auth_handle = nv_auth(authorization, handle);
+    let size = context
+        .execute_without_session(|ctx| ctx.nv_read_public(handle))
+        .map_err(tpm_error)?
+        .0
+        .data_size();
+
+    let mut data = Vec::with_capacity(size);
+    while data.len() < size {
+        let len = (size - data.len()).min(chunk_size) as u16;
+        let offset = data.len() as u16;
+        let chunk = context
+            .execute_with_session(Some(session), |ctx| {
+                ctx.nv_read(auth_handle, handle, len, offset)
+            })
+            .map_err(tpm_error)?;
+        if chunk.value().is_empty() {
+            return Err(TpmError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "The TPM returned no data for the NV index",
+            ))
+            .into());
+        }
+        data.extend_from_slice(chunk.value());
+    }
+
+    Ok(data)
+}
+
+fn to_u64(data: &[u8]) -> Result<u64, SecurityModuleError> {
+    let bytes = <[u8; NV_U64_SIZE]>::try_from(data)
+        .map_err(|_| invalid_data("NV index does not hold a 64 bit value"))?;
+
+    Ok(u64::from_be_bytes(bytes))
+}
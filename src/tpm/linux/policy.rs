@@ -0,0 +1,851 @@
+use super::{
+    resources::ResourceManager,
+    utils::{flush, public_name, tpm_error},
+    verifier::message_digest,
+    TpmProvider,
+};
+use crate::{
+    common::{error::SecurityModuleError, traits::credential_provider::Credential},
+    tpm::core::error::TpmError,
+};
+use openssl::hash::hash;
+use std::fmt;
+use tracing::instrument;
+use tss_esapi::{
+    abstraction::pcr::read_all,
+    attributes::SessionAttributesBuilder,
+    constants::tss::{TPM2_RH_NULL, TPM2_ST_VERIFIED},
+    constants::SessionType,
+    handles::{AuthHandle, KeyHandle as TssKeyHandle, ObjectHandle},
+    interface_types::{
+        algorithm::HashingAlgorithm,
+        resource_handles::Hierarchy,
+        session_handles::{AuthSession, PolicySession},
+    },
+    structures::{
+        Auth, Digest, DigestList, Name, Nonce, PcrSelectionList, Public, Signature,
+        SymmetricDefinition, VerifiedTicket,
+    },
+    tss2_esys::{TPM2B_DIGEST, TPMT_TK_VERIFIED},
+    Context,
+};
+
+/// The parameters protected by session based parameter encryption with AES-CFB.
+///
+/// The TPM can only encrypt the first command and the first response parameter, and only if
+/// it is a sized buffer. Requesting encryption for any other parameter fails with
+/// `TPM_RC_ATTRIBUTES`, so the setting has to match the command the session is used for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum ParameterEncryption {
+    /// The first command parameter, e.g. the digest passed to `TPM2_Sign`.
+    Command,
+    /// The first response parameter, e.g. the secret returned by `TPM2_Unseal`.
+    Response,
+    /// The first command and the first response parameter, e.g. of `TPM2_RSA_Decrypt`.
+    Both,
+    /// No parameter, for commands without sized buffer parameters, e.g. `TPM2_NV_Increment`.
+    None,
+}
+
+/// A single assertion of a [`KeyPolicy`].
+///
+/// `TPM2_PolicyCounterTimer` is not supported. `tss-esapi` neither wraps it nor exposes the
+/// ESAPI context of the provider, and the assertion has to be made in the policy session of
+/// that context. Keys that must expire have to be revoked by the application instead.
+#[derive(Debug, Clone)]
+pub enum PolicyStep {
+    /// `TPM2_PolicyPCR`: the selected PCRs must hash to `digest`, as computed by
+    /// [`TpmProvider::pcr_digest`] with the name algorithm of the key.
+    Pcr {
+        selection: PcrSelectionList,
+        digest: Digest,
+    },
+    /// `TPM2_PolicyAuthValue`: the auth value of the key must be presented.
+    AuthValue,
+    /// `TPM2_PolicySecret`: the auth value of the given entity, usually a hierarchy, must be
+    /// presented. The auth value of the entity is expected to be empty.
+    Secret(AuthHandle),
+    /// `TPM2_PolicyOR`: one of the branches must be satisfied. At least two branches are
+    /// required, and the first branch whose assertions succeed is used.
+    Or(Vec<KeyPolicy>),
+    /// `TPM2_PolicyAuthorize`: a policy approved by the owner of `authority` must be satisfied.
+    ///
+    /// This resets the policy digest, so it has to be the first step of a policy. The approval
+    /// is only needed when the key is used, not when it is created.
+    Authorize {
+        authority: Public,
+        policy_ref: Vec<u8>,
+        approval: Option<PolicyApproval>,
+    },
+    /// `TPM2_PolicyDuplicationSelect`: the key may be duplicated to `new_parent`.
+    ///
+    /// A branch containing this step is only used when duplicating the key, so it is
+    /// usually combined with the regular usage policy in a [`PolicyStep::Or`].
+    Duplication { new_parent: Public },
+}
+
+/// A policy approved by the authority of a [`PolicyStep::Authorize`] step.
+#[derive(Debug, Clone)]
+pub struct PolicyApproval {
+    /// The approved policy that is satisfied in place of the authorize step.
+    pub policy: KeyPolicy,
+    /// The authority's signature over the digest of the approved policy and the policy ref,
+    /// see [`TpmProvider::approval_digest`].
+    pub signature: Signature,
+}
+
+/// An authorization policy attached to a key template.
+///
+/// Steps are asserted in order. The policy is built with chained calls:
+///
+/// ```ignore
+/// let policy = KeyPolicy::new()
+///     .pcr(selection, provider.pcr_digest(&selection, HashingAlgorithm::Sha256)?)
+///     .auth_value();
+/// ```
+#[derive(Debug, Clone, Default)]
+pub struct KeyPolicy {
+    steps: Vec<PolicyStep>,
+}
+
+impl KeyPolicy {
+    /// Creates an empty policy.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a `TPM2_PolicyPCR` step.
+    pub fn pcr(mut self, selection: PcrSelectionList, digest: Digest) -> Self {
+        self.steps.push(PolicyStep::Pcr { selection, digest });
+        self
+    }
+
+    /// Adds a `TPM2_PolicyAuthValue` step.
+    pub fn auth_value(mut self) -> Self {
+        self.steps.push(PolicyStep::AuthValue);
+        self
+    }
+
+    /// Adds a `TPM2_PolicySecret` step.
+    pub fn secret(mut self, entity: AuthHandle) -> Self {
+        self.steps.push(PolicyStep::Secret(entity));
+        self
+    }
+
+    /// Adds a `TPM2_PolicyOR` step over the given branches.
+    pub fn or(mut self, branches: Vec<KeyPolicy>) -> Self {
+        self.steps.push(PolicyStep::Or(branches));
+        self
+    }
+
+    /// Adds a `TPM2_PolicyAuthorize` step.
+    pub fn authorize(
+        mut self,
+        authority: Public,
+        policy_ref: &[u8],
+        approval: Option<PolicyApproval>,
+    ) -> Self {
+        self.steps.push(PolicyStep::Authorize {
+            authority,
+            policy_ref: policy_ref.to_vec(),
+            approval,
+        });
+        self
+    }
+
+    /// Adds a `TPM2_PolicyDuplicationSelect` step.
+    pub fn duplication(mut self, new_parent: Public) -> Self {
+        self.steps.push(PolicyStep::Duplication { new_parent });
+        self
+    }
+
+    /// Returns the steps of the policy.
+    pub fn steps(&self) -> &[PolicyStep] {
+        &self.steps
+    }
+
+    /// Returns `true` if the policy has no steps.
+    pub fn is_empty(&self) -> bool {
+        self.steps.is_empty()
+    }
+
+    /// Returns `true` if the policy contains a [`PolicyStep::Duplication`] step.
+    fn allows_duplication(&self) -> bool {
+        self.steps
+            .iter()
+            .any(|step| matches!(step, PolicyStep::Duplication { .. }))
+    }
+}
+
+/// The auth value of a key, kept out of debug output.
+#[derive(Clone)]
+pub(crate) struct KeyAuth(pub(crate) Auth);
+
+impl fmt::Debug for KeyAuth {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("KeyAuth(..)")
+    }
+}
+
+impl TpmProvider {
+    /// Sets the auth value of keys created or loaded afterwards.
+    ///
+    /// Without a policy, the auth value is presented in a salted HMAC session bound to the key.
+    /// With a policy, it is only required if the policy contains [`PolicyStep::AuthValue`].
+    pub fn set_key_auth(&mut self, auth: Option<&[u8]>) -> Result<(), SecurityModuleError> {
+        self.key_auth = auth
+            .map(|auth| Auth::try_from(auth).map(KeyAuth))
+            .transpose()
+            .map_err(tpm_error)?;
+        self.key_auth_id = None;
+        Ok(())
+    }
+
+    /// Asks the credential provider for the auth value of `key_id`, unless one was set with
+    /// [`TpmProvider::set_key_auth`]. Keys without an auth value are created if the credential
+    /// provider has none.
+    ///
+    /// An auth value requested for another key id is replaced, so every key is used with its
+    /// own auth value.
+    pub(super) fn request_key_auth(&mut self, key_id: &str) -> Result<(), SecurityModuleError> {
+        let credentials = match &self.credentials {
+            Some(credentials) => credentials.clone(),
+            None => return Ok(()),
+        };
+        match &self.key_auth_id {
+            None if self.key_auth.is_some() => return Ok(()),
+            Some(requested) if requested == key_id => return Ok(()),
+            _ => {}
+        }
+
+        let auth = credentials.credential(&Credential::AuthValue(key_id.to_string()))?;
+        self.set_key_auth(auth.as_ref().map(|auth| auth.expose()))?;
+        self.key_auth_id = Some(key_id.to_string());
+        Ok(())
+    }
+
+    /// Sets the policy of keys created or loaded afterwards.
+    ///
+    /// Keys created with a policy can only be used by satisfying it, regardless of their
+    /// key usages.
+    pub fn set_key_policy(&mut self, policy: Option<KeyPolicy>) {
+        self.key_policy = policy.filter(|policy| !policy.is_empty());
+    }
+
+    /// Computes the digest of the current values of the selected PCRs, as expected by
+    /// [`PolicyStep::Pcr`].
+    #[instrument]
+    pub fn pcr_digest(
+        &self,
+        selection: &PcrSelectionList,
+        hashing_algorithm: HashingAlgorithm,
+    ) -> Result<Digest, SecurityModuleError> {
+        let pcr_data = read_all(&mut *self.context()?, selection.clone()).map_err(tpm_error)?;
+        let mut concatenated = Vec::new();
+        for (_, bank) in pcr_data {
+            for (_, digest) in &bank {
+                concatenated.extend_from_slice(digest.value());
+            }
+        }
+
+        software_digest(hashing_algorithm, &concatenated)
+    }
+
+    /// Computes the policy digest of `policy` in a trial session.
+    #[instrument(skip(policy))]
+    pub fn policy_digest(
+        &self,
+        policy: &KeyPolicy,
+        hashing_algorithm: HashingAlgorithm,
+    ) -> Result<Digest, SecurityModuleError> {
+        trial_digest(
+            &mut *self.context()?,
+            &self.resources,
+            policy.steps(),
+            hashing_algorithm,
+            None,
+        )
+    }
+
+    /// Computes the digest an authority has to sign to approve `policy` for a
+    /// [`PolicyStep::Authorize`] step with the given `policy_ref`, in the policy of a key
+    /// of this provider.
+    ///
+    /// The approved policy is digested with the hash algorithm of the provider's keys, which
+    /// their policy sessions use, and the approval digest with the name algorithm of the
+    /// authority key.
+    #[instrument(skip(policy, authority))]
+    pub fn approval_digest(
+        &self,
+        policy: &KeyPolicy,
+        authority: &Public,
+        policy_ref: &[u8],
+    ) -> Result<Digest, SecurityModuleError> {
+        let hashing_algorithm = self
+            .hash
+            .map(Into::into)
+            .unwrap_or(HashingAlgorithm::Sha256);
+        let approved = trial_digest(
+            &mut *self.context()?,
+            &self.resources,
+            policy.steps(),
+            hashing_algorithm,
+            None,
+        )?;
+        let mut data = approved.value().to_vec();
+        data.extend_from_slice(policy_ref);
+        software_digest(authority.name_hashing_algorithm(), &data)
+    }
+
+    /// Starts the session authorizing the use of `key`.
+    ///
+    /// For keys with a policy this is a policy session in which the policy has already been
+    /// satisfied. Otherwise it is an HMAC session bound to the key. Both are salted with the
+    /// storage root, so the session key is never exposed, and encrypt the parameters selected
+    /// by `encryption`. The caller is responsible for flushing the returned session.
+    pub(super) fn key_session(
+        &self,
+        context: &mut Context,
+        key: TssKeyHandle,
+        encryption: ParameterEncryption,
+    ) -> Result<AuthSession, SecurityModuleError> {
+        let hashing_algorithm = self
+            .hash
+            .map(Into::into)
+            .unwrap_or(HashingAlgorithm::Sha256);
+        if let Some(KeyAuth(auth)) = &self.key_auth {
+            context
+                .tr_set_auth(key.into(), auth.clone())
+                .map_err(tpm_error)?;
+        }
+
+        let salt = self.storage_root(context)?;
+        let session = match &self.key_policy {
+            Some(_) => start_session(
+                context,
+                &self.resources,
+                SessionType::Policy,
+                hashing_algorithm,
+                Some(salt),
+                None,
+            ),
+            None => start_session(
+                context,
+                &self.resources,
+                SessionType::Hmac,
+                hashing_algorithm,
+                Some(salt),
+                Some(key.into()),
+            ),
+        }?;
+        encrypt_parameters(context, &self.resources, session, encryption)?;
+
+        if let Some(policy) = &self.key_policy {
+            satisfy(
+                context,
+                &self.resources,
+                session,
+                policy,
+                hashing_algorithm,
+                None,
+            )?;
+        }
+
+        Ok(session)
+    }
+
+    /// Starts an HMAC session salted with the storage root that encrypts the parameters
+    /// selected by `encryption`, for commands that are not authorized by the key of this
+    /// provider. The caller is responsible for flushing the returned session.
+    pub(super) fn encryption_session(
+        &self,
+        context: &mut Context,
+        encryption: ParameterEncryption,
+    ) -> Result<AuthSession, SecurityModuleError> {
+        let salt = self.storage_root(context)?;
+        start_encryption_session(context, &self.resources, salt, encryption)
+    }
+
+    /// Starts the policy session authorizing the duplication of `key`, in which the branch
+    /// of the policy containing [`PolicyStep::Duplication`] has been satisfied for the key
+    /// named `key_name`. The caller is responsible for flushing the returned session.
+    pub(super) fn duplication_session(
+        &self,
+        context: &mut Context,
+        key: TssKeyHandle,
+        key_name: &Name,
+    ) -> Result<AuthSession, SecurityModuleError> {
+        let policy = self.key_policy.as_ref().ok_or_else(|| {
+            TpmError::UnsupportedOperation("Duplication requires a key policy".to_string())
+        })?;
+        let hashing_algorithm = self
+            .hash
+            .map(Into::into)
+            .unwrap_or(HashingAlgorithm::Sha256);
+        if let Some(KeyAuth(auth)) = &self.key_auth {
+            context
+                .tr_set_auth(key.into(), auth.clone())
+                .map_err(tpm_error)?;
+        }
+
+        let session = start_session(
+            context,
+            &self.resources,
+            SessionType::Policy,
+            hashing_algorithm,
+            None,
+            None,
+        )?;
+        satisfy(
+            context,
+            &self.resources,
+            session,
+            policy,
+            hashing_algorithm,
+            Some(key_name),
+        )?;
+
+        Ok(session)
+    }
+}
+
+fn software_digest(
+    hashing_algorithm: HashingAlgorithm,
+    data: &[u8],
+) -> Result<Digest, SecurityModuleError> {
+    let digest = hash(message_digest(hashing_algorithm)?, data)
+        .map_err(|e| TpmError::InternalError(Box::new(e)))?;
+    Digest::try_from(digest.to_vec()).map_err(tpm_error)
+}
+
+/// Starts an authorization session that stays open until it is flushed.
+pub(super) fn start_session(
+    context: &mut Context,
+    resources: &ResourceManager,
+    session_type: SessionType,
+    hashing_algorithm: HashingAlgorithm,
+    salt: Option<TssKeyHandle>,
+    bind: Option<ObjectHandle>,
+) -> Result<AuthSession, SecurityModuleError> {
+    let session = context
+        .start_auth_session(
+            salt,
+            bind,
+            None,
+            session_type,
+            SymmetricDefinition::AES_128_CFB,
+            hashing_algorithm,
+        )
+        .map_err(tpm_error)?
+        .ok_or_else(|| {
+            TpmError::InitializationError("Failed to start authorization session".to_string())
+        })?;
+    resources.track_session(session);
+
+    let (attributes, mask) = SessionAttributesBuilder::new()
+        .with_continue_session(true)
+        .build();
+    if let Err(e) = context.tr_sess_set_attributes(session, attributes, mask) {
+        resources.flush_session(context, session);
+        return Err(tpm_error(e));
+    }
+
+    Ok(session)
+}
+
+/// Starts an unbound HMAC session salted with `salt` that encrypts the parameters selected
+/// by `encryption`.
+///
+/// As the session is unbound, it can authorize any entity whose auth value has been set
+/// with `tr_set_auth`, including the hierarchies with their empty default auth.
+pub(super) fn start_encryption_session(
+    context: &mut Context,
+    resources: &ResourceManager,
+    salt: TssKeyHandle,
+    encryption: ParameterEncryption,
+) -> Result<AuthSession, SecurityModuleError> {
+    let session = start_session(
+        context,
+        resources,
+        SessionType::Hmac,
+        HashingAlgorithm::Sha256,
+        Some(salt),
+        None,
+    )?;
+    encrypt_parameters(context, resources, session, encryption)?;
+    Ok(session)
+}
+
+/// Sets the `decrypt` and `encrypt` attributes of `session` according to `encryption`,
+/// flushing the session on failure.
+pub(super) fn encrypt_parameters(
+    context: &mut Context,
+    resources: &ResourceManager,
+    session: AuthSession,
+    encryption: ParameterEncryption,
+) -> Result<(), SecurityModuleError> {
+    let (attributes, mask) = SessionAttributesBuilder::new()
+        .with_decrypt(matches!(
+            encryption,
+            ParameterEncryption::Command | ParameterEncryption::Both
+        ))
+        .with_encrypt(matches!(
+            encryption,
+            ParameterEncryption::Response | ParameterEncryption::Both
+        ))
+        .build();
+    if let Err(e) = context.tr_sess_set_attributes(session, attributes, mask) {
+        resources.flush_session(context, session);
+        return Err(tpm_error(e));
+    }
+
+    Ok(())
+}
+
+/// Satisfies `policy` in `session`, flushing the session on failure.
+fn satisfy(
+    context: &mut Context,
+    resources: &ResourceManager,
+    session: AuthSession,
+    policy: &KeyPolicy,
+    hashing_algorithm: HashingAlgorithm,
+    duplicate: Option<&Name>,
+) -> Result<(), SecurityModuleError> {
+    let result = PolicySession::try_from(session)
+        .map_err(tpm_error)
+        .and_then(|policy_session| {
+            apply(
+                context,
+                resources,
+                policy_session,
+                policy.steps(),
+                &mut Vec::new(),
+                hashing_algorithm,
+                false,
+                duplicate,
+            )
+        });
+    if result.is_err() {
+        resources.flush_session(context, session);
+    }
+    result
+}
+
+/// Computes the digest of `steps` in a fresh trial session.
+///
+/// `duplicate` is the name of the key being duplicated, if any. It does not change the
+/// digest, as `TPM2_PolicyDuplicationSelect` is asserted without `includeObject`.
+fn trial_digest(
+    context: &mut Context,
+    resources: &ResourceManager,
+    steps: &[PolicyStep],
+    hashing_algorithm: HashingAlgorithm,
+    duplicate: Option<&Name>,
+) -> Result<Digest, SecurityModuleError> {
+    let session = start_session(
+        context,
+        resources,
+        SessionType::Trial,
+        hashing_algorithm,
+        None,
+        None,
+    )?;
+    let result = PolicySession::try_from(session)
+        .map_err(tpm_error)
+        .and_then(|policy_session| {
+            apply(
+                context,
+                resources,
+                policy_session,
+                steps,
+                &mut Vec::new(),
+                hashing_algorithm,
+                true,
+                duplicate,
+            )?;
+            context.policy_get_digest(policy_session).map_err(tpm_error)
+        });
+    resources.flush_session(context, session);
+    result
+}
+
+/// Returns `true` if `steps` can be asserted in a fresh policy session.
+fn satisfiable(
+    context: &mut Context,
+    resources: &ResourceManager,
+    steps: &[PolicyStep],
+    hashing_algorithm: HashingAlgorithm,
+    duplicate: Option<&Name>,
+) -> Result<bool, SecurityModuleError> {
+    let session = start_session(
+        context,
+        resources,
+        SessionType::Policy,
+        hashing_algorithm,
+        None,
+        None,
+    )?;
+    let result = PolicySession::try_from(session)
+        .map_err(tpm_error)
+        .map(|policy_session| {
+            apply(
+                context,
+                resources,
+                policy_session,
+                steps,
+                &mut Vec::new(),
+                hashing_algorithm,
+                false,
+                duplicate,
+            )
+            .is_ok()
+        });
+    resources.flush_session(context, session);
+    result
+}
+
+/// Asserts `steps` in `session`.
+///
+/// `asserted` holds the steps asserted in the session so far. It is needed to compute the
+/// branch digests of `TPM2_PolicyOR`, which depend on everything asserted before them.
+/// `duplicate` is the name of the key if the session authorizes its duplication, and selects
+/// whether branches for duplicating or for using the key are chosen.
+#[allow(clippy::too_many_arguments)]
+fn apply(
+    context: &mut Context,
+    resources: &ResourceManager,
+    session: PolicySession,
+    steps: &[PolicyStep],
+    asserted: &mut Vec<PolicyStep>,
+    hashing_algorithm: HashingAlgorithm,
+    trial: bool,
+    duplicate: Option<&Name>,
+) -> Result<(), SecurityModuleError> {
+    for step in steps {
+        match step {
+            PolicyStep::Pcr { selection, digest } => context
+                .policy_pcr(session, digest.clone(), selection.clone())
+                .map_err(tpm_error)?,
+            PolicyStep::AuthValue => context.policy_auth_value(session).map_err(tpm_error)?,
+            PolicyStep::Secret(entity) => {
+                context
+                    .execute_with_nullauth_session(|ctx| {
+                        ctx.policy_secret(
+                            session,
+                            *entity,
+                            Nonce::default(),
+                            Digest::default(),
+                            Nonce::default(),
+                            None,
+                        )
+                    })
+                    .map_err(tpm_error)?;
+            }
+            PolicyStep::Or(branches) => apply_or(
+                context,
+                resources,
+                session,
+                branches,
+                asserted,
+                hashing_algorithm,
+                trial,
+                duplicate,
+            )?,
+            PolicyStep::Authorize {
+                authority,
+                policy_ref,
+                approval,
+            } => apply_authorize(
+                context,
+                resources,
+                session,
+                authority,
+                policy_ref,
+                approval.as_ref(),
+                hashing_algorithm,
+                trial,
+                duplicate,
+            )?,
+            PolicyStep::Duplication { new_parent } => {
+                // TPM2_Duplicate checks the name of the duplicated key against the session,
+                // so it has to be the real one outside of trial sessions.
+                let object_name = match duplicate {
+                    Some(name) => name.clone(),
+                    None if trial => Name::try_from(Vec::new()).map_err(tpm_error)?,
+                    None => {
+                        return Err(TpmError::UnsupportedOperation(
+                            "The policy step only authorizes duplication".to_string(),
+                        )
+                        .into())
+                    }
+                };
+                context
+                    .policy_duplication_select(
+                        session,
+                        object_name,
+                        public_name(new_parent)?,
+                        false,
+                    )
+                    .map_err(tpm_error)?
+            }
+        }
+        asserted.push(step.clone());
+    }
+
+    Ok(())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn apply_or(
+    context: &mut Context,
+    resources: &ResourceManager,
+    session: PolicySession,
+    branches: &[KeyPolicy],
+    asserted: &[PolicyStep],
+    hashing_algorithm: HashingAlgorithm,
+    trial: bool,
+    duplicate: Option<&Name>,
+) -> Result<(), SecurityModuleError> {
+    let branch_steps = |branch: &KeyPolicy| {
+        let mut steps = asserted.to_vec();
+        steps.extend_from_slice(branch.steps());
+        steps
+    };
+
+    let mut digest_list = DigestList::new();
+    for branch in branches {
+        let digest = trial_digest(
+            context,
+            resources,
+            &branch_steps(branch),
+            hashing_algorithm,
+            duplicate,
+        )?;
+        digest_list.add(digest).map_err(tpm_error)?;
+    }
+
+    // Any branch yields the same digest in a trial session. Otherwise only branches for the
+    // intended purpose are considered.
+    let mut chosen = None;
+    for branch in branches {
+        if trial
+            || (branch.allows_duplication() == duplicate.is_some()
+                && satisfiable(
+                    context,
+                    resources,
+                    &branch_steps(branch),
+                    hashing_algorithm,
+                    duplicate,
+                )?)
+        {
+            chosen = Some(branch);
+            break;
+        }
+    }
+    let chosen = chosen.ok_or_else(|| {
+        TpmError::InternalError("No branch of the policy can be satisfied".into())
+    })?;
+
+    apply(
+        context,
+        resources,
+        session,
+        chosen.steps(),
+        &mut asserted.to_vec(),
+        hashing_algorithm,
+        trial,
+        duplicate,
+    )?;
+    context.policy_or(session, digest_list).map_err(tpm_error)
+}
+
+/// Asserts `TPM2_PolicyAuthorize` in `session`, whose digest uses `hashing_algorithm`.
+///
+/// The approved policy is satisfied and digested with the algorithm of the session, while the
+/// approval digest signed by the authority uses its name algorithm.
+#[allow(clippy::too_many_arguments)]
+fn apply_authorize(
+    context: &mut Context,
+    resources: &ResourceManager,
+    session: PolicySession,
+    authority: &Public,
+    policy_ref: &[u8],
+    approval: Option<&PolicyApproval>,
+    hashing_algorithm: HashingAlgorithm,
+    trial: bool,
+    duplicate: Option<&Name>,
+) -> Result<(), SecurityModuleError> {
+    let name = public_name(authority)?;
+    let policy_ref_nonce = Nonce::try_from(policy_ref).map_err(tpm_error)?;
+
+    if trial {
+        // The approved policy and the ticket are not checked in trial sessions.
+        return context
+            .policy_authorize(
+                session,
+                Digest::default(),
+                policy_ref_nonce,
+                &name,
+                null_verified_ticket()?,
+            )
+            .map_err(tpm_error);
+    }
+
+    let approval = approval.ok_or_else(|| {
+        TpmError::InitializationError("The policy requires an approved policy".to_string())
+    })?;
+    apply(
+        context,
+        resources,
+        session,
+        approval.policy.steps(),
+        &mut Vec::new(),
+        hashing_algorithm,
+        false,
+        duplicate,
+    )?;
+    let approved = trial_digest(
+        context,
+        resources,
+        approval.policy.steps(),
+        hashing_algorithm,
+        duplicate,
+    )?;
+    let mut data = approved.value().to_vec();
+    data.extend_from_slice(policy_ref);
+    let approval_digest = software_digest(authority.name_hashing_algorithm(), &data)?;
+
+    let authority_handle = resources
+        .retry(context, |ctx| {
+            ctx.load_external_public(authority.clone(), Hierarchy::Owner)
+        })
+        .map_err(tpm_error)?;
+    let ticket = context.verify_signature(
+        authority_handle,
+        approval_digest,
+        approval.signature.clone(),
+    );
+    flush(context, authority_handle.into());
+
+    context
+        .policy_authorize(
+            session,
+            approved,
+            policy_ref_nonce,
+            &name,
+            ticket.map_err(tpm_error)?,
+        )
+        .map_err(tpm_error)
+}
+
+fn null_verified_ticket() -> Result<VerifiedTicket, SecurityModuleError> {
+    VerifiedTicket::try_from(TPMT_TK_VERIFIED {
+        tag: TPM2_ST_VERIFIED,
+        hierarchy: TPM2_RH_NULL,
+        digest: TPM2B_DIGEST {
+            size: 0,
+            buffer: [0; 64],
+        },
+    })
+    .map_err(tpm_error)
+}
@@ -1,31 +1,43 @@
-use super::TpmProvider;
+use super::{
+    attestation::KeyCreation,
+    policy::{KeyAuth, ParameterEncryption},
+    resources::StorageRoot,
+    utils::{flush, key_id_digest, tpm_error},
+    TpmProvider,
+};
 use crate::{
     common::{
-        crypto::{algorithms::encryption::AsymmetricEncryption, KeyUsage},
+        crypto::{
+            algorithms::{
+                encryption::{AsymmetricEncryption, EccCurves, EccSchemeAlgorithm},
+                KeyBits,
+            },
+            KeyUsage,
+        },
         error::SecurityModuleError,
-        traits::module_provider::Provider,
+        traits::{credential_provider::CredentialProvider, module_provider::Provider},
     },
-    tpm::TpmConfig,
+    tpm::core::error::TpmError,
 };
 use std::any::Any;
 use std::sync::{Arc, Mutex};
 use tracing::instrument;
 use tss_esapi::{
-    attributes::{ObjectAttributesBuilder, SessionAttributesBuilder},
-    constants::SessionType,
-    handles::{KeyHandle as TssKeyHandle, PersistentTpmHandle},
+    attributes::ObjectAttributesBuilder,
+    handles::{PersistentTpmHandle, TpmHandle},
     interface_types::{
-        algorithm::{HashingAlgorithm, SymmetricMode},
-        key_bits::AesKeyBits,
+        algorithm::HashingAlgorithm,
+        dynamic_handles::Persistent,
+        ecc::EccCurve,
+        key_bits::RsaKeyBits,
         resource_handles::{Hierarchy, Provision},
     },
     structures::{
-        Digest, EccPoint, HashScheme, KeyDerivationFunctionScheme, Private, PublicBuilder,
-        PublicKeyRsa, PublicRsaParameters, RsaExponent, RsaScheme,
+        Digest, EccPoint, KeyDerivationFunctionScheme, Public, PublicBuilder, PublicEccParameters,
+        PublicKeyRsa, PublicRsaParameters, RsaExponent, RsaScheme, SymmetricDefinitionObject,
     },
     Context, TctiNameConf,
 };
-use tss_esapi::{interface_types::dynamic_handles::Persistent, structures::PublicEccParameters};
 
 /// Implements the `Provider` trait, providing cryptographic operations utilizing a TPM.
 impl Provider for TpmProvider {
@@ -53,124 +65,62 @@ impl Provider for TpmProvider {
         key_id: &str,
         config: Box<dyn Any>,
     ) -> Result<(), SecurityModuleError> {
-        let config = config.downcast_ref::<TpmConfig>().unwrap();
+        self.configure(config)?;
+        self.request_key_auth(key_id)?;
 
-        self.key_algorithm = Some(config.key_algorithm);
-        self.sym_algorithm = Some(config.sym_algorithm);
-        self.hash = Some(config.hash);
-        self.key_usages = Some(config.key_usages.clone());
+        let primary_pub = self.key_template(true)?;
+        let persistent_handle = persistent_key_handle(key_id)?;
 
-        let primary_pub = match self.key_algorithm.as_ref().unwrap() {
-            AsymmetricEncryption::Rsa(key_bits) => PublicBuilder::new()
-                .with_public_algorithm((*self.key_algorithm.as_ref().unwrap()).into())
-                .with_name_hashing_algorithm((*self.hash.as_ref().unwrap()).into())
-                .with_rsa_parameters(PublicRsaParameters::new(
-                    self.sym_algorithm.unwrap().into(),
-                    RsaScheme::Null,
-                    (*key_bits).into(),
-                    RsaExponent::default(),
-                ))
-                .with_rsa_unique_identifier(PublicKeyRsa::default()),
-            AsymmetricEncryption::Ecc(ecc_scheme) => PublicBuilder::new()
-                .with_public_algorithm((*self.key_algorithm.as_ref().unwrap()).into())
-                .with_name_hashing_algorithm((*self.hash.as_ref().unwrap()).into())
-                .with_ecc_parameters(PublicEccParameters::new(
-                    self.sym_algorithm.unwrap().into(),
-                    (*ecc_scheme).into(),
-                    self.key_algorithm
-                        .as_ref()
-                        .unwrap()
-                        .ecc_curve()
-                        .unwrap()
-                        .into(),
-                    KeyDerivationFunctionScheme::Kdf2(HashScheme::new(
-                        (*self.hash.as_ref().unwrap()).into(),
-                    )),
-                ))
-                .with_ecc_unique_identifier(EccPoint::default()),
-        };
+        let auth = self.key_auth.as_ref().map(|KeyAuth(auth)| auth.clone());
+        let mut context = self.context()?;
+        // The auth value of the key is sent encrypted.
+        let session = self.encryption_session(&mut context, ParameterEncryption::Command)?;
+        let key_handle = self.resources.retry(&mut context, |ctx| {
+            ctx.execute_with_session(Some(session), |ctx| {
+                ctx.create_primary(
+                    Hierarchy::Owner,
+                    primary_pub.clone(),
+                    auth.clone(),
+                    None,
+                    None,
+                    None,
+                )
+            })
+        });
+        self.resources.flush_session(&mut context, session);
+        let key_handle = key_handle.map_err(tpm_error)?;
 
-        let primary_pub = primary_pub
-            .with_object_attributes(
-                // Private Key attributes
-                ObjectAttributesBuilder::new()
-                    // Indicate the key can only exist within this tpm and can not be exported.
-                    .with_fixed_tpm(true)
-                    // The primary key and it's descendent keys can't be moved to other primary
-                    // keys.
-                    .with_fixed_parent(true)
-                    // The primary key will persist over suspend and resume of the system.
-                    .with_st_clear(true)
-                    // The primary key was generated entirely inside the TPM - only this TPM
-                    // knows it's content.
-                    .with_sensitive_data_origin(true)
-                    // This key requires "authentication" to the TPM to access - this can be
-                    // an HMAC or password session. HMAC sessions are used by default with
-                    // the "execute_with_nullauth_session" function.
-                    .with_user_with_auth(
-                        self.key_usages
-                            .as_ref()
-                            .unwrap()
-                            .contains(&KeyUsage::ClientAuth),
-                    )
-                    // This key has the ability to decrypt
-                    .with_decrypt(
-                        self.key_usages
-                            .as_ref()
-                            .unwrap()
-                            .contains(&KeyUsage::Decrypt),
-                    )
-                    // This key has the ability to sign
-                    .with_sign_encrypt(
-                        self.key_usages
-                            .as_ref()
-                            .unwrap()
-                            .contains(&KeyUsage::SignEncrypt),
-                    )
-                    // Create self-signed certificates
-                    .with_x509_sign(
-                        self.key_usages
-                            .as_ref()
-                            .unwrap()
-                            .contains(&KeyUsage::CreateX509),
-                    )
-                    // This key may only be used to encrypt or sign objects that are within
-                    // the TPM - it can not encrypt or sign external data.
-                    .with_restricted(false)
-                    .build()
-                    .unwrap(),
+        // The key is kept at a persistent handle derived from its id, where `load_key` finds it.
+        let persisted = context.execute_with_nullauth_session(|ctx| {
+            ctx.evict_control(
+                Provision::Owner,
+                key_handle.key_handle.into(),
+                Persistent::Persistent(persistent_handle),
             )
-            .build()
-            .unwrap();
-
-        let key_handle = self
-            .handle
-            .as_ref()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .create_primary(Hierarchy::Owner, primary_pub, None, None, None, None)
-            .unwrap();
-
-        let persistent_handle =
-            Persistent::Persistent(PersistentTpmHandle::new(key_handle.key_handle.into()).unwrap());
-
-        self.key_handle = Some(Arc::new(Mutex::new(key_handle.key_handle)));
-
-        self.key_id = key_handle.key_handle.value().to_string();
-        self.handle
-            .as_ref()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .evict_control(
-                Provision::Owner, // TPM owner authorization
-                (*self.key_handle.as_ref().unwrap().lock().unwrap()).into(),
-                persistent_handle, // Chosen persistent handle
-            )
-            .expect("Failed to make key persistent");
+        });
+        if let Err(e) = persisted {
+            flush(&mut context, key_handle.key_handle.into());
+            return Err(TpmError::InitializationError(format!(
+                "Failed to make key {} persistent, a key with this id may already exist: {}",
+                key_id, e
+            ))
+            .into());
+        }
+        drop(context);
+        self.key_handle = Some(self.track(key_handle.key_handle)?);
+        self.key_creation = Some(KeyCreation::new(
+            key_handle.creation_data,
+            key_handle.creation_hash,
+            key_handle.creation_ticket,
+        )?);
 
         self.key_id = key_id.to_string();
+        // ECC keys can not encrypt bulk data, so they are paired with symmetric keys, which
+        // are created when data is encrypted for the first time.
+        self.symmetric_keys = Arc::default();
+        if let Some(AsymmetricEncryption::Ecc(_)) = self.key_algorithm {
+            self.delete_symmetric_keys()?;
+        }
 
         Ok(())
     }
@@ -195,150 +145,228 @@ impl Provider for TpmProvider {
     /// On failure, it returns a `SecurityModuleError`.
     #[instrument]
     fn load_key(&mut self, key_id: &str, config: Box<dyn Any>) -> Result<(), SecurityModuleError> {
-        let config = config.downcast_ref::<TpmConfig>().unwrap();
+        self.configure(config)?;
+        self.request_key_auth(key_id)?;
+
+        let persistent_handle = persistent_key_handle(key_id)?;
+        let key_handle = {
+            let mut context = self.context()?;
+            let key_handle = context
+                .execute_without_session(|ctx| {
+                    ctx.tr_from_tpm_public(TpmHandle::Persistent(persistent_handle))
+                })
+                .map_err(|e| {
+                    TpmError::InitializationError(format!("Key {} not found: {}", key_id, e))
+                })?;
+            // Keys are used with the algorithms of the configuration, which therefore has to
+            // match the algorithm the key was created with.
+            let result = match context.read_public(key_handle.into()) {
+                Ok((Public::Rsa { .. }, _, _))
+                    if matches!(self.key_algorithm, Some(AsymmetricEncryption::Rsa(_))) =>
+                {
+                    Ok(())
+                }
+                Ok((Public::Ecc { .. }, _, _))
+                    if matches!(self.key_algorithm, Some(AsymmetricEncryption::Ecc(_))) =>
+                {
+                    Ok(())
+                }
+                Ok(_) => Err(TpmError::InitializationError(format!(
+                    "Key {} was created with another algorithm",
+                    key_id
+                ))
+                .into()),
+                Err(e) => Err(tpm_error(e)),
+            };
+            if let Err(e) = result {
+                let mut handle = key_handle;
+                if let Err(e) = context.tr_close(&mut handle) {
+                    tracing::warn!("Failed to close TPM handle: {}", e);
+                }
+                return Err(e);
+            }
+            key_handle
+        };
+
+        self.key_handle = Some(self.track_persistent(key_handle.into())?);
+        self.key_creation = None;
+        self.key_id = key_id.to_string();
+        self.symmetric_keys = Arc::default();
+        if let Some(AsymmetricEncryption::Ecc(_)) = self.key_algorithm {
+            self.load_existing_symmetric_keys()?;
+        }
 
-        self.key_algorithm = Some(config.key_algorithm);
-        self.sym_algorithm = Some(config.sym_algorithm);
-        self.hash = Some(config.hash);
-        self.key_usages = Some(config.key_usages.clone());
+        Ok(())
+    }
 
-        // Start an authorization session
-        let session = self
-            .handle
-            .as_ref()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .start_auth_session(
-                None,
-                None,
-                None,
-                SessionType::Hmac,
-                tss_esapi::structures::SymmetricDefinition::Aes {
-                    key_bits: AesKeyBits::Aes256,
-                    mode: SymmetricMode::Cbc,
-                },
-                HashingAlgorithm::Sha512,
+    /// Initializes the TPM module and returns a handle for further operations.
+    ///
+    /// This method initializes the TPM context and prepares it for use. It should be called
+    /// before performing any other operations with the TPM.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` that, on success, contains `Ok(())`, indicating that the module was initialized successfully.
+    /// On failure, it returns a `SecurityModuleError`.
+    #[instrument]
+    fn initialize_module(&mut self) -> Result<(), SecurityModuleError> {
+        let tcti = TctiNameConf::from_environment_variable()
+            .map_err(|e| SecurityModuleError::InitializationError(e.to_string()))?;
+
+        let context = Context::new(tcti)
+            .map_err(|e| SecurityModuleError::InitializationError(e.to_string()))?;
+
+        self.handle = Some(Arc::new(Mutex::new(context)));
+        // A storage root loaded through a previous context cannot be used with the new one.
+        let name = self.storage_root.lock().unwrap().name.clone();
+        self.storage_root = Arc::new(Mutex::new(StorageRoot { object: None, name }));
+
+        Ok(())
+    }
+
+    /// Sets the credential provider asked for the auth value of keys created or loaded
+    /// afterwards, unless one was set with `set_key_auth`.
+    ///
+    /// # Arguments
+    ///
+    /// * `credentials` - The `CredentialProvider` to ask for auth values.
+    fn set_credential_provider(&mut self, credentials: Arc<dyn CredentialProvider>) {
+        self.credentials = Some(credentials);
+    }
+}
+
+impl TpmProvider {
+    /// Builds the public template of a key with the configured algorithms and usages.
+    ///
+    /// # Arguments
+    ///
+    /// * `fixed` - Whether the key is bound to this TPM and its parent. Only keys created
+    ///   with `fixed` set to `false` can be duplicated.
+    pub(super) fn key_template(&self, fixed: bool) -> Result<Public, SecurityModuleError> {
+        let (Some(key_algorithm), Some(hash), Some(key_usages)) =
+            (self.key_algorithm, self.hash, self.key_usages.as_ref())
+        else {
+            return Err(SecurityModuleError::InitializationError(
+                "No key algorithm configured".to_string(),
+            ));
+        };
+        // ECDAA signatures are only useful with an issuer credential, and issuing and verifying
+        // it needs a constant-time BN_P256 pairing, which none of our dependencies provide.
+        if let AsymmetricEncryption::Ecc(EccSchemeAlgorithm::EcDaa(_)) = key_algorithm {
+            return Err(TpmError::UnsupportedOperation(
+                "ECDAA keys are not supported, as there is no BN_P256 pairing for the issuer \
+                 and verifier"
+                    .to_string(),
             )
-            .unwrap();
-        let session_attr = SessionAttributesBuilder::new()
-            .with_decrypt(true)
-            .with_encrypt(true)
-            .with_continue_session(true)
-            .build();
+            .into());
+        }
+        let name_hashing_algorithm: HashingAlgorithm = hash.into();
 
-        self.handle
-            .as_ref()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .tr_sess_set_attributes(session.unwrap(), session_attr.0, session_attr.1)
-            .unwrap();
+        // Only storage keys have a symmetric algorithm. Keys that sign or decrypt data must
+        // use TPM_ALG_NULL, the configured block cipher is used by the symmetric keys paired
+        // with ECC keys.
+        let primary_pub = match key_algorithm {
+            AsymmetricEncryption::Rsa(key_bits) => PublicBuilder::new()
+                .with_public_algorithm(key_algorithm.into())
+                .with_name_hashing_algorithm(name_hashing_algorithm)
+                .with_rsa_parameters(PublicRsaParameters::new(
+                    SymmetricDefinitionObject::Null,
+                    RsaScheme::Null,
+                    rsa_key_bits(key_bits)?,
+                    RsaExponent::default(),
+                ))
+                .with_rsa_unique_identifier(PublicKeyRsa::default()),
+            AsymmetricEncryption::Ecc(ecc_scheme) => PublicBuilder::new()
+                .with_public_algorithm(key_algorithm.into())
+                .with_name_hashing_algorithm(name_hashing_algorithm)
+                .with_ecc_parameters(PublicEccParameters::new(
+                    SymmetricDefinitionObject::Null,
+                    ecc_scheme.into(),
+                    ecc_curve(key_algorithm)?,
+                    // The TPM does not implement any KDF for ECC keys.
+                    KeyDerivationFunctionScheme::Null,
+                ))
+                .with_ecc_unique_identifier(EccPoint::default()),
+        };
 
-        let primary_pub = match self.key_algorithm.as_ref().unwrap() {
-            AsymmetricEncryption::Rsa(_) => todo!(),
-            AsymmetricEncryption::Ecc(ecc_scheme) => PublicEccParameters::new(
-                self.sym_algorithm.unwrap().into(),
-                (*ecc_scheme).into(),
-                self.key_algorithm
-                    .as_ref()
-                    .unwrap()
-                    .ecc_curve()
-                    .unwrap()
-                    .into(),
-                KeyDerivationFunctionScheme::Kdf2(HashScheme::new(self.hash.unwrap().into())),
-            ),
+        let auth_policy = match &self.key_policy {
+            Some(policy) => self.policy_digest(policy, name_hashing_algorithm)?,
+            None => Digest::default(),
         };
 
-        let obj_attributes = ObjectAttributesBuilder::new()
+        let object_attributes = ObjectAttributesBuilder::new()
             // Indicate the key can only exist within this tpm and can not be exported.
-            .with_fixed_tpm(true)
+            .with_fixed_tpm(fixed)
             // The primary key and it's descendent keys can't be moved to other primary
             // keys.
-            .with_fixed_parent(true)
-            // The primary key will persist over suspend and resume of the system.
-            .with_st_clear(true)
+            .with_fixed_parent(fixed)
+            // Persistent keys must survive TPM2_Startup(TPM_SU_CLEAR), so they can not be
+            // flushed on a TPM reset or restart.
+            .with_st_clear(false)
             // The primary key was generated entirely inside the TPM - only this TPM
             // knows it's content.
             .with_sensitive_data_origin(true)
             // This key requires "authentication" to the TPM to access - this can be
             // an HMAC or password session. HMAC sessions are used by default with
-            // the "execute_with_nullauth_session" function.
+            // the "execute_with_nullauth_session" function. Keys with a policy can
+            // only be used by satisfying it.
             .with_user_with_auth(
-                self.key_usages
-                    .as_ref()
-                    .unwrap()
-                    .contains(&KeyUsage::ClientAuth),
+                self.key_policy.is_none() && key_usages.contains(&KeyUsage::ClientAuth),
             )
             // This key has the ability to decrypt
-            .with_decrypt(
-                self.key_usages
-                    .as_ref()
-                    .unwrap()
-                    .contains(&KeyUsage::Decrypt),
-            )
+            .with_decrypt(key_usages.contains(&KeyUsage::Decrypt))
             // This key has the ability to sign
-            .with_sign_encrypt(
-                self.key_usages
-                    .as_ref()
-                    .unwrap()
-                    .contains(&KeyUsage::SignEncrypt),
-            )
+            .with_sign_encrypt(key_usages.contains(&KeyUsage::SignEncrypt))
             // Create self-signed certificates
-            .with_x509_sign(
-                self.key_usages
-                    .as_ref()
-                    .unwrap()
-                    .contains(&KeyUsage::CreateX509),
-            )
+            .with_x509_sign(key_usages.contains(&KeyUsage::CreateX509))
             // This key may only be used to encrypt or sign objects that are within
             // the TPM - it can not encrypt or sign external data.
             .with_restricted(false)
             .build()
-            .unwrap();
-
-        let private = Private::default();
-        let public = tss_esapi::structures::Public::Ecc {
-            object_attributes: obj_attributes,
-            name_hashing_algorithm: self.hash.unwrap().into(),
-            auth_policy: Digest::default(),
-            parameters: primary_pub,
-            unique: EccPoint::default(),
-        };
-
-        let key_handle = self
-            .handle
-            .as_ref()
-            .unwrap()
-            .lock()
-            .unwrap()
-            .load(TssKeyHandle::Null, private, public)
-            .unwrap();
+            .map_err(tpm_error)?;
 
-        self.key_handle = Some(Arc::new(Mutex::new(key_handle)));
-        self.key_id = key_id.to_string();
-
-        Ok(())
+        primary_pub
+            .with_auth_policy(auth_policy)
+            .with_object_attributes(object_attributes)
+            .build()
+            .map_err(tpm_error)
     }
+}
 
-    /// Initializes the TPM module and returns a handle for further operations.
-    ///
-    /// This method initializes the TPM context and prepares it for use. It should be called
-    /// before performing any other operations with the TPM.
-    ///
-    /// # Returns
-    ///
-    /// A `Result` that, on success, contains `Ok(())`, indicating that the module was initialized successfully.
-    /// On failure, it returns a `SecurityModuleError`.
-    #[instrument]
-    fn initialize_module(&mut self) -> Result<(), SecurityModuleError> {
-        let tcti = TctiNameConf::from_environment_variable().unwrap();
-
-        let context = Context::new(tcti)
-            .map_err(|e| SecurityModuleError::InitializationError(e.to_string()))?;
+/// Returns the persistent handle of the key `key_id`, taken from the owner range
+/// `0x81000000..=0x817FFFFF` by a digest of `key_id`.
+fn persistent_key_handle(key_id: &str) -> Result<PersistentTpmHandle, SecurityModuleError> {
+    PersistentTpmHandle::new(0x8100_0000 | (key_id_digest(key_id)? & 0x007f_ffff))
+        .map_err(tpm_error)
+}
 
-        self.handle = Some(Arc::new(Mutex::new(context)));
+fn rsa_key_bits(key_bits: KeyBits) -> Result<RsaKeyBits, SecurityModuleError> {
+    match key_bits {
+        KeyBits::Bits1024 | KeyBits::Bits2048 | KeyBits::Bits3072 | KeyBits::Bits4096 => {
+            Ok(key_bits.into())
+        }
+        _ => Err(TpmError::UnsupportedOperation(format!(
+            "RSA keys of {:?} are not supported by the TPM",
+            key_bits
+        ))
+        .into()),
+    }
+}
 
-        Ok(())
+fn ecc_curve(key_algorithm: AsymmetricEncryption) -> Result<EccCurve, SecurityModuleError> {
+    match key_algorithm.ecc_curve() {
+        Some(
+            curve @ (EccCurves::P256
+            | EccCurves::P384
+            | EccCurves::P521
+            | EccCurves::Secp256k1
+            | EccCurves::BrainpoolP256r1
+            | EccCurves::BrainpoolP638),
+        ) => Ok(curve.into()),
+        curve => Err(TpmError::UnsupportedOperation(format!(
+            "Curve {:?} is not supported by the TPM",
+            curve
+        ))
+        .into()),
     }
 }
@@ -0,0 +1,316 @@
+use super::{utils::flush, TpmProvider};
+use crate::{common::error::SecurityModuleError, tpm::core::error::TpmError};
+use std::sync::{
+    atomic::{AtomicU64, Ordering},
+    Arc, Mutex, TryLockError, Weak,
+};
+use tracing::instrument;
+use tss_esapi::{
+    constants::response_code::Tss2ResponseCodeKind,
+    handles::{KeyHandle as TssKeyHandle, ObjectHandle, SessionHandle},
+    interface_types::session_handles::AuthSession,
+    structures::Name,
+    utils::TpmsContext,
+    Context, Error,
+};
+
+/// Tracks the transient objects and authorization sessions of the providers sharing a TPM
+/// context.
+///
+/// The TPM only has a few object slots, so objects that are not used by the current
+/// operation can be swapped out with `TPM2_ContextSave` and are loaded again on their next
+/// use. Sessions are flushed right after the command they authorize, so any session still
+/// tracked when the next operation begins has been leaked and is flushed then.
+#[derive(Debug, Default)]
+pub(crate) struct ResourceManager {
+    objects: Mutex<Vec<Weak<TpmObject>>>,
+    /// The sessions started on the context that have not been flushed yet.
+    sessions: Mutex<Vec<SessionHandle>>,
+    /// Objects dropped while the context was locked, flushed when it is locked again.
+    released: Mutex<Vec<ObjectHandle>>,
+    /// Incremented whenever the TPM context is locked for a new operation.
+    generation: AtomicU64,
+}
+
+impl ResourceManager {
+    /// Starts tracking the transient object `handle`, which is flushed when the returned
+    /// object is dropped.
+    pub(super) fn track(
+        self: &Arc<Self>,
+        context: &Arc<Mutex<Context>>,
+        handle: TssKeyHandle,
+    ) -> Arc<TpmObject> {
+        self.track_state(context, ObjectState::Loaded(handle))
+    }
+
+    /// Wraps the ESYS handle of the persistent object `handle`, which is never swapped out
+    /// and only closed when the returned object is dropped.
+    pub(super) fn track_persistent(
+        self: &Arc<Self>,
+        context: &Arc<Mutex<Context>>,
+        handle: TssKeyHandle,
+    ) -> Arc<TpmObject> {
+        self.track_state(context, ObjectState::Persistent(handle))
+    }
+
+    fn track_state(
+        self: &Arc<Self>,
+        context: &Arc<Mutex<Context>>,
+        state: ObjectState,
+    ) -> Arc<TpmObject> {
+        let object = Arc::new(TpmObject {
+            manager: Arc::clone(self),
+            context: Arc::downgrade(context),
+            state: Mutex::new(state),
+            last_used: AtomicU64::new(self.generation.load(Ordering::SeqCst)),
+        });
+
+        let mut objects = self.objects.lock().unwrap();
+        objects.retain(|object| object.strong_count() > 0);
+        objects.push(Arc::downgrade(&object));
+        object
+    }
+
+    /// Marks the start of a new operation on the just locked `context`. Objects not used
+    /// since are considered idle.
+    ///
+    /// Objects released while the context was locked and sessions left over by earlier
+    /// operations are flushed first.
+    pub(super) fn begin(&self, context: &mut Context) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+
+        let released = std::mem::take(&mut *self.released.lock().unwrap());
+        for handle in released {
+            flush(context, handle);
+        }
+        let leaked = std::mem::take(&mut *self.sessions.lock().unwrap());
+        if !leaked.is_empty() {
+            tracing::warn!("Flushing {} leaked TPM sessions", leaked.len());
+        }
+        for session in leaked {
+            flush(context, session.into());
+        }
+    }
+
+    /// Starts tracking `session`, which has to be flushed with
+    /// [`ResourceManager::flush_session`] before the current operation ends.
+    pub(super) fn track_session(&self, session: AuthSession) {
+        self.sessions
+            .lock()
+            .unwrap()
+            .push(SessionHandle::from(session));
+    }
+
+    /// Flushes an authorization session and stops tracking it, logging failures instead of
+    /// propagating them.
+    pub(super) fn flush_session(&self, context: &mut Context, session: AuthSession) {
+        let handle = SessionHandle::from(session);
+        self.sessions
+            .lock()
+            .unwrap()
+            .retain(|tracked| *tracked != handle);
+        flush(context, handle.into());
+    }
+
+    /// Swaps out all idle objects and returns how many were swapped out.
+    pub(super) fn reclaim(&self, context: &mut Context) -> usize {
+        let generation = self.generation.load(Ordering::SeqCst);
+        let objects: Vec<_> = self
+            .objects
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(Weak::upgrade)
+            .collect();
+
+        objects
+            .iter()
+            .filter(|object| object.last_used.load(Ordering::SeqCst) < generation)
+            .filter(|object| match object.swap_out(context) {
+                Ok(swapped) => swapped,
+                Err(e) => {
+                    tracing::warn!("Failed to swap out TPM object: {}", e);
+                    false
+                }
+            })
+            .count()
+    }
+
+    /// Runs `f`, retrying once after swapping out idle objects if the TPM is out of object
+    /// memory.
+    pub(super) fn retry<T>(
+        &self,
+        context: &mut Context,
+        mut f: impl FnMut(&mut Context) -> Result<T, Error>,
+    ) -> Result<T, Error> {
+        match f(context) {
+            Err(e) if is_out_of_object_memory(&e) && self.reclaim(context) > 0 => f(context),
+            result => result,
+        }
+    }
+}
+
+/// The storage root key shared by the providers using a TPM context.
+#[derive(Debug, Default)]
+pub(crate) struct StorageRoot {
+    /// The loaded storage root, created on first use.
+    pub(super) object: Option<Arc<TpmObject>>,
+    /// The name the storage root has to have, pinned by the application or on first use.
+    pub(super) name: Option<Name>,
+}
+
+/// A transient object owned by a provider, flushed from the TPM when dropped.
+#[derive(Debug)]
+pub(crate) struct TpmObject {
+    manager: Arc<ResourceManager>,
+    context: Weak<Mutex<Context>>,
+    state: Mutex<ObjectState>,
+    last_used: AtomicU64,
+}
+
+#[derive(Debug)]
+enum ObjectState {
+    Loaded(TssKeyHandle),
+    Saved(TpmsContext),
+    /// A persistent object, which stays in the TPM.
+    Persistent(TssKeyHandle),
+}
+
+impl TpmObject {
+    /// Returns the handle of the object, loading it again if it has been swapped out.
+    ///
+    /// The handle is only valid while `context` stays locked, since other operations may swap
+    /// the object out again.
+    pub(super) fn handle(
+        &self,
+        context: &mut Context,
+    ) -> Result<TssKeyHandle, SecurityModuleError> {
+        self.last_used.store(
+            self.manager.generation.load(Ordering::SeqCst),
+            Ordering::SeqCst,
+        );
+        let mut state = self.state.lock().unwrap();
+        let handle = match &*state {
+            ObjectState::Loaded(handle) | ObjectState::Persistent(handle) => return Ok(*handle),
+            ObjectState::Saved(saved) => self
+                .manager
+                .retry(context, |ctx| ctx.context_load(saved.clone()))
+                .map_err(|e| TpmError::InternalError(Box::new(e)))?
+                .into(),
+        };
+        *state = ObjectState::Loaded(handle);
+
+        Ok(handle)
+    }
+
+    /// Saves the context of the object and flushes it from the TPM. Returns `false` if the
+    /// object was already swapped out.
+    fn swap_out(&self, context: &mut Context) -> Result<bool, SecurityModuleError> {
+        let mut state = self.state.lock().unwrap();
+        let handle = match &*state {
+            ObjectState::Loaded(handle) => *handle,
+            ObjectState::Saved(_) | ObjectState::Persistent(_) => return Ok(false),
+        };
+
+        let saved = context
+            .context_save(handle.into())
+            .map_err(|e| TpmError::InternalError(Box::new(e)))?;
+        flush(context, handle.into());
+        *state = ObjectState::Saved(saved);
+
+        Ok(true)
+    }
+}
+
+impl Drop for TpmObject {
+    fn drop(&mut self) {
+        let (handle, persistent) = match self.state.get_mut() {
+            Ok(ObjectState::Loaded(handle)) => (*handle, false),
+            Ok(ObjectState::Persistent(handle)) => (*handle, true),
+            _ => return,
+        };
+        let Some(context) = self.context.upgrade() else {
+            return;
+        };
+        // Persistent objects must not be flushed, only their ESYS handle is released. It is
+        // left to the context if the context is locked.
+        if persistent {
+            if let Ok(mut context) = context.try_lock() {
+                let mut handle = ObjectHandle::from(handle);
+                if let Err(e) = context.tr_close(&mut handle) {
+                    tracing::warn!("Failed to close TPM handle: {}", e);
+                }
+            }
+            return;
+        }
+        // The context may already be locked by the operation dropping the object, in which
+        // case the object is flushed as soon as the context is locked again. The TPM never
+        // evicts transient objects on its own.
+        match context.try_lock() {
+            Ok(mut context) => flush(&mut context, handle.into()),
+            Err(TryLockError::Poisoned(poisoned)) => {
+                flush(&mut poisoned.into_inner(), handle.into())
+            }
+            Err(TryLockError::WouldBlock) => {
+                self.manager.released.lock().unwrap().push(handle.into())
+            }
+        };
+    }
+}
+
+impl TpmProvider {
+    /// Swaps out all transient objects of this provider and the providers sharing its
+    /// context, freeing their TPM object slots. They are loaded again on their next use.
+    ///
+    /// Long-running processes without a kernel resource manager should call this between
+    /// operations, so other TPM clients are not starved of object slots.
+    #[instrument]
+    pub fn swap_out_objects(&self) -> Result<(), SecurityModuleError> {
+        let mut context = self.context()?;
+        self.resources.reclaim(&mut context);
+        Ok(())
+    }
+
+    /// Tracks the transient object `handle`, so it is swapped out under memory pressure and
+    /// flushed once it is no longer referenced.
+    pub(super) fn track(
+        &self,
+        handle: TssKeyHandle,
+    ) -> Result<Arc<TpmObject>, SecurityModuleError> {
+        let context = self.handle.as_ref().ok_or_else(|| {
+            SecurityModuleError::InitializationError("TPM module is not initialized".to_string())
+        })?;
+        Ok(self.resources.track(context, handle))
+    }
+
+    /// Tracks the ESYS handle of a persistent object, which is closed once it is no longer
+    /// referenced.
+    pub(super) fn track_persistent(
+        &self,
+        handle: TssKeyHandle,
+    ) -> Result<Arc<TpmObject>, SecurityModuleError> {
+        let context = self.handle.as_ref().ok_or_else(|| {
+            SecurityModuleError::InitializationError("TPM module is not initialized".to_string())
+        })?;
+        Ok(self.resources.track_persistent(context, handle))
+    }
+
+    /// Returns the handle of the key managed by this provider, loading it again if it has
+    /// been swapped out.
+    pub(super) fn loaded_key(
+        &self,
+        context: &mut Context,
+    ) -> Result<TssKeyHandle, SecurityModuleError> {
+        self.key_handle
+            .as_ref()
+            .ok_or_else(|| SecurityModuleError::InitializationError("No key loaded".to_string()))?
+            .handle(context)
+    }
+}
+
+fn is_out_of_object_memory(error: &Error) -> bool {
+    matches!(
+        error,
+        Error::Tss2Error(rc) if rc.kind() == Some(Tss2ResponseCodeKind::ObjectMemory)
+    )
+}
@@ -0,0 +1,334 @@
+use super::{
+    policy::{encrypt_parameters, start_encryption_session, start_session, ParameterEncryption},
+    resources::ResourceManager,
+    utils::{flush, marshal_pcr_selection, marshal_tpm2b, unmarshal_pcr_selection, Reader},
+    TpmProvider,
+};
+use crate::{common::error::SecurityModuleError, tpm::core::error::TpmError};
+use std::io;
+use tracing::instrument;
+use tss_esapi::{
+    attributes::ObjectAttributesBuilder,
+    constants::SessionType,
+    handles::KeyHandle as TssKeyHandle,
+    interface_types::{
+        algorithm::{HashingAlgorithm, PublicAlgorithm},
+        session_handles::{AuthSession, PolicySession},
+    },
+    structures::{
+        Auth, Digest, KeyedHashScheme, PcrSelectionList, Private, Public, PublicBuilder,
+        PublicKeyedHashParameters, SensitiveData,
+    },
+    traits::{Marshall, UnMarshall},
+    Context,
+};
+
+/// Identifies a serialized sealed blob and its format version.
+const SEALED_BLOB_MAGIC: &[u8; 4] = b"TPMS";
+const SEALED_BLOB_VERSION: u8 = 1;
+
+/// Hashing algorithm used for the name and the policy of sealed objects.
+const SEAL_HASH: HashingAlgorithm = HashingAlgorithm::Sha256;
+
+/// A secret sealed to the TPM, bound to a set of PCR values and optionally an auth value.
+///
+/// The serialized form starts with a magic and version, followed by a flags byte, the
+/// `TPML_PCR_SELECTION` of the policy, and the `TPM2B_PUBLIC` and `TPM2B_PRIVATE` areas of the
+/// sealed object. It can be stored anywhere, as only the TPM that sealed it can unseal it.
+#[derive(Debug, Clone)]
+pub struct SealedBlob {
+    /// The PCRs the secret is bound to.
+    pub pcr_selection: PcrSelectionList,
+    /// Whether the auth value given at sealing time is required to unseal.
+    pub with_auth: bool,
+    /// The public area of the sealed object.
+    pub public: Public,
+    /// The private area of the sealed object, encrypted by the storage root.
+    pub private: Private,
+}
+
+impl SealedBlob {
+    /// Serializes the blob into its portable binary form.
+    pub fn to_bytes(&self) -> Result<Vec<u8>, SecurityModuleError> {
+        let mut buffer = Vec::new();
+        buffer.extend_from_slice(SEALED_BLOB_MAGIC);
+        buffer.push(SEALED_BLOB_VERSION);
+        buffer.push(self.with_auth as u8);
+        marshal_pcr_selection(&self.pcr_selection, &mut buffer);
+        let public = self
+            .public
+            .marshall()
+            .map_err(|e| TpmError::InternalError(Box::new(e)))?;
+        marshal_tpm2b(&public, &mut buffer);
+        marshal_tpm2b(self.private.value(), &mut buffer);
+        Ok(buffer)
+    }
+
+    /// Parses a blob previously produced by [`SealedBlob::to_bytes`].
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SecurityModuleError> {
+        let mut reader = Reader::new(data);
+        if reader.read_bytes(SEALED_BLOB_MAGIC.len())? != SEALED_BLOB_MAGIC
+            || reader.read_u8()? != SEALED_BLOB_VERSION
+        {
+            return Err(TpmError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "Not a sealed blob or unsupported version",
+            ))
+            .into());
+        }
+        let with_auth = reader.read_u8()? != 0;
+        let pcr_selection = unmarshal_pcr_selection(&mut reader)?;
+        let public = Public::unmarshall(reader.read_tpm2b()?)
+            .map_err(|e| TpmError::InternalError(Box::new(e)))?;
+        let private = Private::try_from(reader.read_tpm2b()?)
+            .map_err(|e| TpmError::InternalError(Box::new(e)))?;
+
+        Ok(Self {
+            pcr_selection,
+            with_auth,
+            public,
+            private,
+        })
+    }
+}
+
+impl TpmProvider {
+    /// Seals `data` to the TPM so that it can only be unsealed while the selected PCRs hold
+    /// their current values.
+    ///
+    /// The secret is stored in a keyed hash object under the storage root. Its policy is a
+    /// `PolicyPCR` over `pcr_selection`, extended by `PolicyAuthValue` if `auth` is given.
+    ///
+    /// # Arguments
+    ///
+    /// * `data` - The secret to seal, at most 128 bytes.
+    /// * `pcr_selection` - The PCRs the secret is bound to.
+    /// * `auth` - An optional password that additionally has to be presented on unseal.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the serialized [`SealedBlob`] on success, or a `SecurityModuleError` on failure.
+    #[instrument(skip(data, auth))]
+    pub fn seal(
+        &self,
+        data: &[u8],
+        pcr_selection: PcrSelectionList,
+        auth: Option<&[u8]>,
+    ) -> Result<Vec<u8>, SecurityModuleError> {
+        let mut context = self.context()?;
+        let storage_root = self.storage_root(&mut context)?;
+        self.seal_blob(&mut context, storage_root, data, pcr_selection, auth)?
+            .to_bytes()
+    }
+
+    /// Unseals a secret previously sealed with [`TpmProvider::seal`].
+    ///
+    /// Unsealing only succeeds if the PCRs the secret is bound to still hold the values they
+    /// had when it was sealed and, if the secret was sealed with an auth value, `auth` matches it.
+    ///
+    /// # Arguments
+    ///
+    /// * `sealed` - The serialized [`SealedBlob`].
+    /// * `auth` - The password given at sealing time, if any.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the unsealed secret on success, or a `SecurityModuleError` on failure.
+    #[instrument(skip(sealed, auth))]
+    pub fn unseal(
+        &self,
+        sealed: &[u8],
+        auth: Option<&[u8]>,
+    ) -> Result<Vec<u8>, SecurityModuleError> {
+        let blob = SealedBlob::from_bytes(sealed)?;
+        let mut context = self.context()?;
+        let storage_root = self.storage_root(&mut context)?;
+        self.unseal_blob(&mut context, storage_root, &blob, auth)
+    }
+
+    /// Seals `data` under the storage key `parent`, see [`TpmProvider::seal`].
+    pub(super) fn seal_blob(
+        &self,
+        context: &mut Context,
+        parent: TssKeyHandle,
+        data: &[u8],
+        pcr_selection: PcrSelectionList,
+        auth: Option<&[u8]>,
+    ) -> Result<SealedBlob, SecurityModuleError> {
+        let sensitive_data = SensitiveData::try_from(data)
+            .map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))?;
+        let auth_value = auth
+            .map(Auth::try_from)
+            .transpose()
+            .map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))?;
+
+        let policy_digest = sealing_policy_digest(
+            context,
+            &self.resources,
+            &pcr_selection,
+            auth_value.is_some(),
+        )?;
+
+        let public = PublicBuilder::new()
+            .with_public_algorithm(PublicAlgorithm::KeyedHash)
+            .with_name_hashing_algorithm(SEAL_HASH)
+            .with_auth_policy(policy_digest)
+            .with_object_attributes(
+                ObjectAttributesBuilder::new()
+                    .with_fixed_tpm(true)
+                    .with_fixed_parent(true)
+                    // The object can only be used by satisfying its policy.
+                    .with_user_with_auth(false)
+                    .with_no_da(auth_value.is_none())
+                    .build()
+                    .map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))?,
+            )
+            .with_keyed_hash_parameters(PublicKeyedHashParameters::new(KeyedHashScheme::Null))
+            .with_keyed_hash_unique_identifier(Digest::default())
+            .build()
+            .map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))?;
+
+        // The secret and its auth value are sent encrypted with a session salted to the parent.
+        let session = start_encryption_session(
+            context,
+            &self.resources,
+            parent,
+            ParameterEncryption::Command,
+        )?;
+        let result = context.execute_with_session(Some(session), |ctx| {
+            ctx.create(parent, public, auth_value, Some(sensitive_data), None, None)
+        });
+        self.resources.flush_session(context, session);
+        let result = result.map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))?;
+
+        Ok(SealedBlob {
+            pcr_selection,
+            with_auth: auth.is_some(),
+            public: result.out_public,
+            private: result.out_private,
+        })
+    }
+
+    /// Unseals a secret sealed under the storage key `parent`, see [`TpmProvider::unseal`].
+    pub(super) fn unseal_blob(
+        &self,
+        context: &mut Context,
+        parent: TssKeyHandle,
+        blob: &SealedBlob,
+        auth: Option<&[u8]>,
+    ) -> Result<Vec<u8>, SecurityModuleError> {
+        if blob.with_auth && auth.is_none() {
+            return Err(SecurityModuleError::DecryptionError(
+                "The sealed secret requires an auth value".to_string(),
+            ));
+        }
+
+        let object = self
+            .resources
+            .retry(context, |ctx| {
+                ctx.execute_with_nullauth_session(|ctx| {
+                    ctx.load(parent, blob.private.clone(), blob.public.clone())
+                })
+            })
+            .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))?;
+        let result = unseal_with_policy(context, &self.resources, object, parent, blob, auth);
+        flush(context, object.into());
+        result
+    }
+}
+
+/// Runs the steps of the sealing policy on a fresh session of the given type, salted with
+/// `salt` if given.
+///
+/// `PolicyPCR` is skipped for an empty PCR selection, leaving the policy digest all zeros.
+fn start_sealing_policy(
+    context: &mut Context,
+    resources: &ResourceManager,
+    session_type: SessionType,
+    salt: Option<TssKeyHandle>,
+    pcr_selection: &PcrSelectionList,
+    with_auth: bool,
+) -> Result<AuthSession, SecurityModuleError> {
+    let session = start_session(context, resources, session_type, SEAL_HASH, salt, None)?;
+    let policy_session =
+        PolicySession::try_from(session).map_err(|e| TpmError::InternalError(Box::new(e)))?;
+
+    let bound_to_pcrs = pcr_selection
+        .get_selections()
+        .iter()
+        .any(|selection| !selection.is_empty());
+    let policy = if bound_to_pcrs {
+        context.policy_pcr(policy_session, Digest::default(), pcr_selection.clone())
+    } else {
+        Ok(())
+    }
+    .and_then(|_| {
+        if with_auth {
+            context.policy_auth_value(policy_session)
+        } else {
+            Ok(())
+        }
+    });
+    if let Err(e) = policy {
+        resources.flush_session(context, session);
+        return Err(TpmError::InternalError(Box::new(e)).into());
+    }
+
+    Ok(session)
+}
+
+/// Computes the policy digest of a sealed object in a trial session.
+fn sealing_policy_digest(
+    context: &mut Context,
+    resources: &ResourceManager,
+    pcr_selection: &PcrSelectionList,
+    with_auth: bool,
+) -> Result<Digest, SecurityModuleError> {
+    let session = start_sealing_policy(
+        context,
+        resources,
+        SessionType::Trial,
+        None,
+        pcr_selection,
+        with_auth,
+    )?;
+    let digest = PolicySession::try_from(session)
+        .and_then(|policy_session| context.policy_get_digest(policy_session));
+    resources.flush_session(context, session);
+    digest.map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))
+}
+
+/// Satisfies the policy of the loaded sealed object and unseals it in a session salted with
+/// `salt`, so the secret is returned encrypted.
+fn unseal_with_policy(
+    context: &mut Context,
+    resources: &ResourceManager,
+    object: TssKeyHandle,
+    salt: TssKeyHandle,
+    blob: &SealedBlob,
+    auth: Option<&[u8]>,
+) -> Result<Vec<u8>, SecurityModuleError> {
+    if let Some(auth) = auth {
+        let auth = Auth::try_from(auth)
+            .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))?;
+        context
+            .tr_set_auth(object.into(), auth)
+            .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))?;
+    }
+
+    let session = start_sealing_policy(
+        context,
+        resources,
+        SessionType::Policy,
+        Some(salt),
+        &blob.pcr_selection,
+        blob.with_auth,
+    )?;
+    encrypt_parameters(context, resources, session, ParameterEncryption::Response)?;
+    let unsealed = context.execute_with_session(Some(session), |ctx| ctx.unseal(object.into()));
+    resources.flush_session(context, session);
+
+    unsealed
+        .map(|data| data.value().to_vec())
+        .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))
+}
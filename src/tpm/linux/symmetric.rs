@@ -0,0 +1,509 @@
+use super::{
+    duplication::WrappedKey,
+    nv::{NvAuthorization, NvIndexKind},
+    policy::{KeyAuth, ParameterEncryption},
+    resources::{ResourceManager, TpmObject},
+    utils::{flush, invalid_data, key_id_digest, marshal_tpm2b, tpm_error, Reader},
+    verifier::message_digest,
+    TpmProvider,
+};
+use crate::{
+    common::{
+        crypto::algorithms::encryption::{BlockCiphers, SymmetricMode},
+        error::SecurityModuleError,
+    },
+    tpm::core::error::TpmError,
+};
+use openssl::{hash::hash, memcmp};
+use std::sync::Arc;
+use tss_esapi::{
+    attributes::ObjectAttributesBuilder,
+    handles::KeyHandle as TssKeyHandle,
+    interface_types::{
+        algorithm::{HashingAlgorithm, PublicAlgorithm, SymmetricMode as TssSymmetricMode},
+        session_handles::AuthSession,
+    },
+    structures::{
+        Auth, Digest, HmacScheme, InitialValue, KeyedHashScheme, MaxBuffer, Public, PublicBuilder,
+        PublicKeyedHashParameters, SymmetricCipherParameters, SymmetricDefinitionObject,
+    },
+    Context,
+};
+
+/// Block and IV size of the block ciphers supported by the TPM.
+const BLOCK_SIZE: usize = 16;
+
+/// The first NV index of the range holding the symmetric keys paired with ECC keys.
+const SYMMETRIC_KEYS_INDEX: u32 = 0x0100_0000;
+
+/// Identifies the serialized symmetric keys of a key and their format version.
+const SYMMETRIC_KEYS_MAGIC: &[u8; 4] = b"TPSK";
+const SYMMETRIC_KEYS_VERSION: u8 = 1;
+
+/// The symmetric cipher key and the keyed hash key used for bulk encryption with ECC keys.
+#[derive(Debug, Clone)]
+pub(crate) struct SymmetricKeys {
+    cipher: Arc<TpmObject>,
+    hmac: Arc<TpmObject>,
+}
+
+impl TpmProvider {
+    /// Encrypts `data` with the TPM-resident symmetric key and authenticates the result.
+    ///
+    /// A fresh IV is drawn from the TPM for every call. The output is the IV, followed by the
+    /// ciphertext and an HMAC computed by the TPM's keyed hash key over the digest of the IV
+    /// and the ciphertext.
+    ///
+    /// The keys are created on first use, which fails with `UnsupportedOperation` if the
+    /// configured block cipher or mode is not implemented by `TPM2_EncryptDecrypt2`. In
+    /// particular GCM is rejected, as the TPM does not provide authenticated encryption.
+    pub(super) fn encrypt_symmetric(&self, data: &[u8]) -> Result<Vec<u8>, SecurityModuleError> {
+        let mode = cipher_mode(self.sym_algorithm.as_ref())?;
+        let hashing_algorithm = self.hmac_algorithm()?;
+        let keys = self.symmetric_keys(true)?;
+        let mut context = self.context()?;
+        let cipher_key = keys.cipher.handle(&mut context)?;
+        let hmac_key = keys.hmac.handle(&mut context)?;
+
+        let iv = context
+            .execute_without_session(|ctx| ctx.get_random(BLOCK_SIZE))
+            .map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))?;
+        let plaintext = match mode {
+            TssSymmetricMode::Cbc => pad(data),
+            _ => data.to_vec(),
+        };
+        let ciphertext = self
+            .encrypt_decrypt(
+                &mut context,
+                cipher_key,
+                false,
+                mode,
+                &plaintext,
+                iv.value(),
+            )
+            .map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))?;
+
+        let mut output = iv.value().to_vec();
+        output.extend_from_slice(&ciphertext);
+        let tag = self
+            .authenticate(&mut context, hmac_key, hashing_algorithm, &output)
+            .map_err(|e| SecurityModuleError::EncryptionError(e.to_string()))?;
+        output.extend_from_slice(&tag);
+
+        Ok(output)
+    }
+
+    /// Verifies and decrypts data produced by [`TpmProvider::encrypt_symmetric`].
+    ///
+    /// The HMAC is checked before anything is decrypted, so tampered data is rejected
+    /// without touching the cipher key.
+    pub(super) fn decrypt_symmetric(
+        &self,
+        encrypted_data: &[u8],
+    ) -> Result<Vec<u8>, SecurityModuleError> {
+        let mode = cipher_mode(self.sym_algorithm.as_ref())?;
+        let hashing_algorithm = self.hmac_algorithm()?;
+        let tag_len = message_digest(hashing_algorithm)?.size();
+        if encrypted_data.len() < BLOCK_SIZE + tag_len {
+            return Err(SecurityModuleError::DecryptionError(
+                "Encrypted data is too short".to_string(),
+            ));
+        }
+        let (authenticated, tag) = encrypted_data.split_at(encrypted_data.len() - tag_len);
+        let (iv, ciphertext) = authenticated.split_at(BLOCK_SIZE);
+        let keys = self.symmetric_keys(false)?;
+        let mut context = self.context()?;
+        let cipher_key = keys.cipher.handle(&mut context)?;
+        let hmac_key = keys.hmac.handle(&mut context)?;
+
+        let expected = self
+            .authenticate(&mut context, hmac_key, hashing_algorithm, authenticated)
+            .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))?;
+        if !memcmp::eq(&expected, tag) {
+            return Err(SecurityModuleError::DecryptionError(
+                "Authentication of the encrypted data failed".to_string(),
+            ));
+        }
+
+        let plaintext = self
+            .encrypt_decrypt(&mut context, cipher_key, true, mode, ciphertext, iv)
+            .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))?;
+        match mode {
+            TssSymmetricMode::Cbc => unpad(plaintext),
+            _ => Ok(plaintext),
+        }
+    }
+
+    /// Returns the symmetric cipher key and the HMAC key paired with the key of this
+    /// provider, loading them on first use.
+    ///
+    /// The keys are created under the storage root the first time data is encrypted, and
+    /// their wrapped private areas are stored in an NV index belonging to the key. Later
+    /// calls, also from other processes, load them from there. Unless `create` is set, an
+    /// error is returned if the keys have not been created yet.
+    fn symmetric_keys(&self, create: bool) -> Result<SymmetricKeys, SecurityModuleError> {
+        let mut keys = self.symmetric_keys.lock().unwrap();
+        if let Some(keys) = &*keys {
+            return Ok(keys.clone());
+        }
+
+        let index = symmetric_keys_index(&self.key_id)?;
+        let loaded = if self.nv_defined(index)? {
+            let blob = self.nv_read(index, &NvAuthorization::Owner)?;
+            let (cipher_key, hmac_key) = unmarshal_symmetric_keys(&blob)?;
+            self.load_symmetric_keys(&cipher_key, &hmac_key)?
+        } else if create {
+            let (cipher_key, hmac_key) = self.create_symmetric_keys()?;
+            let blob = marshal_symmetric_keys(&cipher_key, &hmac_key)?;
+            self.nv_define(
+                index,
+                NvIndexKind::Ordinary(blob.len()),
+                &NvAuthorization::Owner,
+            )?;
+            if let Err(e) = self.nv_write(index, &blob, &NvAuthorization::Owner) {
+                if let Err(e) = self.nv_undefine(index) {
+                    tracing::warn!("Failed to delete NV index of symmetric keys: {:?}", e);
+                }
+                return Err(e);
+            }
+            self.load_symmetric_keys(&cipher_key, &hmac_key)?
+        } else {
+            return Err(SecurityModuleError::InitializationError(
+                "No symmetric key has been created for the key".to_string(),
+            ));
+        };
+        *keys = Some(loaded.clone());
+
+        Ok(loaded)
+    }
+
+    /// Loads the symmetric keys paired with the key of this provider, if they have been
+    /// created.
+    pub(super) fn load_existing_symmetric_keys(&self) -> Result<(), SecurityModuleError> {
+        if self.nv_defined(symmetric_keys_index(&self.key_id)?)? {
+            self.symmetric_keys(false)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the symmetric keys stored for a previous key with the id of this provider,
+    /// so that a new key does not inherit them.
+    pub(super) fn delete_symmetric_keys(&self) -> Result<(), SecurityModuleError> {
+        let index = symmetric_keys_index(&self.key_id)?;
+        if self.nv_defined(index)? {
+            self.nv_undefine(index)?;
+        }
+        Ok(())
+    }
+
+    /// Creates the symmetric cipher key and the HMAC key under the storage root, with the
+    /// auth value and policy of the key of this provider.
+    fn create_symmetric_keys(&self) -> Result<(WrappedKey, WrappedKey), SecurityModuleError> {
+        let sym_algorithm = self.sym_algorithm.ok_or_else(|| {
+            SecurityModuleError::InitializationError(
+                "No symmetric algorithm configured".to_string(),
+            )
+        })?;
+        let hashing_algorithm = self.hmac_algorithm()?;
+        let auth_policy = self
+            .key_policy
+            .as_ref()
+            .map(|policy| self.policy_digest(policy, hashing_algorithm))
+            .transpose()?;
+        let cipher_public =
+            symmetric_cipher_public(sym_algorithm, hashing_algorithm, auth_policy.clone())?;
+        let hmac_public = hmac_public(hashing_algorithm, auth_policy)?;
+        let auth = self.key_auth.as_ref().map(|KeyAuth(auth)| auth.clone());
+
+        let mut context = self.context()?;
+        let parent = self.storage_root(&mut context)?;
+        // The auth value of the keys is sent encrypted.
+        let session = self.encryption_session(&mut context, ParameterEncryption::Command)?;
+        let keys = create(&mut context, session, parent, cipher_public, auth.clone()).and_then(
+            |cipher_key| {
+                let hmac_key = create(&mut context, session, parent, hmac_public, auth)?;
+                Ok((cipher_key, hmac_key))
+            },
+        );
+        self.resources.flush_session(&mut context, session);
+
+        keys
+    }
+
+    /// Loads the wrapped symmetric keys under the storage root.
+    fn load_symmetric_keys(
+        &self,
+        cipher_key: &WrappedKey,
+        hmac_key: &WrappedKey,
+    ) -> Result<SymmetricKeys, SecurityModuleError> {
+        let (cipher, hmac) = {
+            let mut context = self.context()?;
+            let parent = self.storage_root(&mut context)?;
+            let cipher = load(&mut context, &self.resources, parent, cipher_key)?;
+            match load(&mut context, &self.resources, parent, hmac_key) {
+                Ok(hmac) => (cipher, hmac),
+                Err(e) => {
+                    flush(&mut context, cipher.into());
+                    return Err(e);
+                }
+            }
+        };
+
+        Ok(SymmetricKeys {
+            cipher: self.track(cipher)?,
+            hmac: self.track(hmac)?,
+        })
+    }
+
+    fn hmac_algorithm(&self) -> Result<HashingAlgorithm, SecurityModuleError> {
+        self.hash.map(Into::into).ok_or_else(|| {
+            SecurityModuleError::InitializationError("No hash algorithm configured".to_string())
+        })
+    }
+
+    /// Runs `TPM2_EncryptDecrypt2` over `data`, chaining the IV across buffer sized chunks.
+    fn encrypt_decrypt(
+        &self,
+        context: &mut Context,
+        key: TssKeyHandle,
+        decrypt: bool,
+        mode: TssSymmetricMode,
+        data: &[u8],
+        iv: &[u8],
+    ) -> Result<Vec<u8>, SecurityModuleError> {
+        let mut iv = InitialValue::try_from(iv).map_err(tpm_error)?;
+        let mut output = Vec::with_capacity(data.len());
+        // The maximum buffer size is a multiple of the block size, so chunks never split a block.
+        for chunk in data.chunks(MaxBuffer::MAX_SIZE) {
+            let buffer = MaxBuffer::try_from(chunk).map_err(tpm_error)?;
+            // Policy sessions are reset after every use, so each chunk gets its own session.
+            let session = self.key_session(context, key, ParameterEncryption::Both)?;
+            let result = context.execute_with_session(Some(session), |ctx| {
+                ctx.encrypt_decrypt_2(key, decrypt, mode, buffer, iv.clone())
+            });
+            self.resources.flush_session(context, session);
+            let (out, iv_out) = result.map_err(tpm_error)?;
+            output.extend_from_slice(out.value());
+            iv = iv_out;
+        }
+
+        Ok(output)
+    }
+
+    /// Computes the TPM HMAC over the digest of `data`.
+    ///
+    /// `TPM2_HMAC` only accepts a single buffer, so the data is hashed first to support
+    /// messages of any length.
+    fn authenticate(
+        &self,
+        context: &mut Context,
+        key: TssKeyHandle,
+        hashing_algorithm: HashingAlgorithm,
+        data: &[u8],
+    ) -> Result<Vec<u8>, SecurityModuleError> {
+        let digest = hash(message_digest(hashing_algorithm)?, data)
+            .map_err(|e| TpmError::InternalError(Box::new(e)))?;
+        let buffer = MaxBuffer::try_from(digest.to_vec()).map_err(tpm_error)?;
+
+        let session = self.key_session(context, key, ParameterEncryption::Both)?;
+        let tag = context.execute_with_session(Some(session), |ctx| {
+            ctx.hmac(key.into(), buffer, hashing_algorithm)
+        });
+        self.resources.flush_session(context, session);
+
+        tag.map(|tag| tag.value().to_vec()).map_err(tpm_error)
+    }
+}
+
+/// Returns the NV index holding the symmetric keys paired with the key `key_id`.
+///
+/// The index is taken from the owner range `0x01000000..=0x013FFFFF` by a digest of
+/// `key_id`. It only stores the wrapped keys, which are of no use without the storage root
+/// and the auth value of the key.
+fn symmetric_keys_index(key_id: &str) -> Result<u32, SecurityModuleError> {
+    Ok(SYMMETRIC_KEYS_INDEX | (key_id_digest(key_id)? & 0x003f_ffff))
+}
+
+fn marshal_symmetric_keys(
+    cipher_key: &WrappedKey,
+    hmac_key: &WrappedKey,
+) -> Result<Vec<u8>, SecurityModuleError> {
+    let mut buffer = Vec::new();
+    buffer.extend_from_slice(SYMMETRIC_KEYS_MAGIC);
+    buffer.push(SYMMETRIC_KEYS_VERSION);
+    marshal_tpm2b(&cipher_key.to_bytes()?, &mut buffer);
+    marshal_tpm2b(&hmac_key.to_bytes()?, &mut buffer);
+    Ok(buffer)
+}
+
+fn unmarshal_symmetric_keys(data: &[u8]) -> Result<(WrappedKey, WrappedKey), SecurityModuleError> {
+    let mut reader = Reader::new(data);
+    if reader.read_bytes(SYMMETRIC_KEYS_MAGIC.len())? != SYMMETRIC_KEYS_MAGIC
+        || reader.read_u8()? != SYMMETRIC_KEYS_VERSION
+    {
+        return Err(invalid_data("Unknown symmetric key format or unsupported version").into());
+    }
+
+    Ok((
+        WrappedKey::from_bytes(reader.read_tpm2b()?)?,
+        WrappedKey::from_bytes(reader.read_tpm2b()?)?,
+    ))
+}
+
+/// Creates a key under `parent`, which is authorized by `session`.
+fn create(
+    context: &mut Context,
+    session: AuthSession,
+    parent: TssKeyHandle,
+    public: Public,
+    auth: Option<Auth>,
+) -> Result<WrappedKey, SecurityModuleError> {
+    context
+        .execute_with_session(Some(session), |ctx| {
+            ctx.create(parent, public, auth, None, None, None)
+        })
+        .map(|result| WrappedKey {
+            public: result.out_public,
+            private: result.out_private,
+        })
+        .map_err(tpm_error)
+}
+
+fn load(
+    context: &mut Context,
+    resources: &ResourceManager,
+    parent: TssKeyHandle,
+    key: &WrappedKey,
+) -> Result<TssKeyHandle, SecurityModuleError> {
+    resources
+        .retry(context, |ctx| {
+            ctx.execute_with_nullauth_session(|ctx| {
+                ctx.load(parent, key.private.clone(), key.public.clone())
+            })
+        })
+        .map_err(tpm_error)
+}
+
+fn symmetric_cipher_public(
+    sym_algorithm: BlockCiphers,
+    hashing_algorithm: HashingAlgorithm,
+    auth_policy: Option<Digest>,
+) -> Result<Public, SecurityModuleError> {
+    let mode = cipher_mode(Some(&sym_algorithm))?;
+    let definition = match sym_algorithm {
+        BlockCiphers::Aes(_, key_bits) => SymmetricDefinitionObject::Aes {
+            key_bits: key_bits.into(),
+            mode,
+        },
+        BlockCiphers::Camellia(_, key_bits) => SymmetricDefinitionObject::Camellia {
+            key_bits: key_bits.into(),
+            mode,
+        },
+        _ => {
+            return Err(TpmError::UnsupportedOperation(format!(
+                "Block cipher {:?} is not supported by the TPM",
+                sym_algorithm
+            ))
+            .into())
+        }
+    };
+
+    PublicBuilder::new()
+        .with_public_algorithm(PublicAlgorithm::SymCipher)
+        .with_name_hashing_algorithm(hashing_algorithm)
+        .with_auth_policy(auth_policy.clone().unwrap_or_default())
+        .with_object_attributes(
+            ObjectAttributesBuilder::new()
+                .with_fixed_tpm(true)
+                .with_fixed_parent(true)
+                .with_sensitive_data_origin(true)
+                // Keys with a policy can only be used by satisfying it.
+                .with_user_with_auth(auth_policy.is_none())
+                .with_decrypt(true)
+                .with_sign_encrypt(true)
+                .build()
+                .map_err(tpm_error)?,
+        )
+        .with_symmetric_cipher_parameters(SymmetricCipherParameters::new(definition))
+        .with_symmetric_cipher_unique_identifier(Digest::default())
+        .build()
+        .map_err(tpm_error)
+}
+
+fn hmac_public(
+    hashing_algorithm: HashingAlgorithm,
+    auth_policy: Option<Digest>,
+) -> Result<Public, SecurityModuleError> {
+    PublicBuilder::new()
+        .with_public_algorithm(PublicAlgorithm::KeyedHash)
+        .with_name_hashing_algorithm(hashing_algorithm)
+        .with_auth_policy(auth_policy.clone().unwrap_or_default())
+        .with_object_attributes(
+            ObjectAttributesBuilder::new()
+                .with_fixed_tpm(true)
+                .with_fixed_parent(true)
+                .with_sensitive_data_origin(true)
+                // Keys with a policy can only be used by satisfying it.
+                .with_user_with_auth(auth_policy.is_none())
+                .with_sign_encrypt(true)
+                .build()
+                .map_err(tpm_error)?,
+        )
+        .with_keyed_hash_parameters(PublicKeyedHashParameters::new(KeyedHashScheme::Hmac {
+            hmac_scheme: HmacScheme::new(hashing_algorithm),
+        }))
+        .with_keyed_hash_unique_identifier(Digest::default())
+        .build()
+        .map_err(tpm_error)
+}
+
+/// Returns the TPM cipher mode for the configured block cipher.
+///
+/// Only modes that take an IV and are implemented by `TPM2_EncryptDecrypt2` are accepted.
+fn cipher_mode(
+    sym_algorithm: Option<&BlockCiphers>,
+) -> Result<TssSymmetricMode, SecurityModuleError> {
+    match sym_algorithm {
+        Some(BlockCiphers::Aes(mode, _)) | Some(BlockCiphers::Camellia(mode, _)) => match mode {
+            SymmetricMode::Cbc => Ok(TssSymmetricMode::Cbc),
+            SymmetricMode::Cfb => Ok(TssSymmetricMode::Cfb),
+            SymmetricMode::Ofb => Ok(TssSymmetricMode::Ofb),
+            SymmetricMode::Ctr => Ok(TssSymmetricMode::Ctr),
+            _ => Err(TpmError::UnsupportedOperation(format!(
+                "Cipher mode {:?} is not supported by the TPM",
+                mode
+            ))
+            .into()),
+        },
+        _ => Err(TpmError::UnsupportedOperation(
+            "Only AES and Camellia are supported by the TPM".to_string(),
+        )
+        .into()),
+    }
+}
+
+/// Applies PKCS#7 padding to a multiple of the block size.
+fn pad(data: &[u8]) -> Vec<u8> {
+    let padding = BLOCK_SIZE - data.len() % BLOCK_SIZE;
+    let mut padded = data.to_vec();
+    padded.resize(data.len() + padding, padding as u8);
+    padded
+}
+
+/// Removes PKCS#7 padding.
+fn unpad(mut data: Vec<u8>) -> Result<Vec<u8>, SecurityModuleError> {
+    let padding = data.last().copied().unwrap_or(0) as usize;
+    if padding == 0
+        || padding > BLOCK_SIZE
+        || padding > data.len()
+        || !data[data.len() - padding..]
+            .iter()
+            .all(|&b| b as usize == padding)
+    {
+        return Err(SecurityModuleError::DecryptionError(
+            "Invalid padding".to_string(),
+        ));
+    }
+    data.truncate(data.len() - padding);
+    Ok(data)
+}
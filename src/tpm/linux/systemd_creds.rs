@@ -0,0 +1,470 @@
+use super::{
+    seal::SealedBlob,
+    utils::{flush, invalid_data, marshal_tpm2b, tpm_error, Reader},
+    TpmProvider,
+};
+use crate::{common::error::SecurityModuleError, tpm::core::error::TpmError};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use openssl::{
+    hash::{hash, MessageDigest},
+    rand::rand_bytes,
+    symm::{decrypt_aead, encrypt_aead, Cipher},
+};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::instrument;
+use tss_esapi::{
+    attributes::ObjectAttributesBuilder,
+    handles::{KeyHandle as TssKeyHandle, ObjectHandle, PersistentTpmHandle, TpmHandle},
+    interface_types::{
+        algorithm::{HashingAlgorithm, PublicAlgorithm},
+        ecc::EccCurve,
+        key_bits::RsaKeyBits,
+        resource_handles::Hierarchy,
+    },
+    structures::{
+        EccPoint, PcrSelectionList, PcrSlot, Private, Public, PublicBuilder,
+        PublicEccParametersBuilder, PublicKeyRsa, PublicRsaParametersBuilder, RsaExponent,
+        SymmetricDefinitionObject,
+    },
+    traits::{Marshall, UnMarshall},
+    Context,
+};
+
+/// `CRED_AES256_GCM_BY_TPM2_HMAC`, the credential type of `systemd-creds encrypt --with-key=tpm2`.
+const CRED_AES256_GCM_BY_TPM2_HMAC: [u8; 16] = [
+    0x0c, 0x7c, 0xc0, 0x7b, 0x11, 0x76, 0x45, 0x91, 0x9c, 0x4b, 0x0b, 0xea, 0x08, 0xbc, 0x20, 0xfe,
+];
+
+/// Persistent handle at which systemd looks for the storage root key before creating one.
+const SRK_HANDLE: u32 = 0x8100_0001;
+
+/// Key, block, IV and tag sizes of AES-256-GCM as recorded in the credential header.
+const KEY_SIZE: usize = 32;
+const BLOCK_SIZE: usize = 1;
+const IV_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+
+/// Sizes of the fixed parts of the credential, metadata and TPM2 headers.
+const CREDENTIAL_HEADER_SIZE: usize = 32;
+const TPM2_HEADER_SIZE: usize = 20;
+const METADATA_HEADER_SIZE: usize = 20;
+
+/// Size of the random secret sealed to the TPM, from which the credential key is derived.
+const SECRET_SIZE: usize = 32;
+
+/// `TPM2_ALG_*` identifiers of the primary key algorithms systemd supports.
+const TPM2_ALG_RSA: u16 = 0x0001;
+const TPM2_ALG_ECC: u16 = 0x0023;
+
+/// Marks a credential that never expires.
+const USEC_INFINITY: u64 = u64::MAX;
+
+/// The contents of a decrypted systemd credential.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credential {
+    /// The name embedded in the credential, which may be empty.
+    pub name: String,
+    /// When the credential was created.
+    pub timestamp: SystemTime,
+    /// When the credential expires, if ever.
+    pub not_after: Option<SystemTime>,
+    /// The secret.
+    pub data: Vec<u8>,
+}
+
+impl TpmProvider {
+    /// Encrypts `data` into a credential in the format of
+    /// `systemd-creds encrypt --with-key=tpm2`, which systemd units can decrypt with
+    /// `LoadCredentialEncrypted=` on this machine.
+    ///
+    /// The credential key is derived from a random secret sealed under the storage root key
+    /// systemd uses, bound to the current values of the PCRs in `pcr_mask` of the SHA-256 bank.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The credential name, which systemd checks against the name it is loaded as.
+    /// * `data` - The secret to encrypt.
+    /// * `pcr_mask` - A bit mask of the PCRs the credential is bound to, e.g. `1 << 7`.
+    /// * `not_after` - An optional expiry time.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the Base64 encoded credential on success, or a `SecurityModuleError` on failure.
+    #[instrument(skip(data))]
+    pub fn encrypt_credential(
+        &self,
+        name: &str,
+        data: &[u8],
+        pcr_mask: u32,
+        not_after: Option<SystemTime>,
+    ) -> Result<String, SecurityModuleError> {
+        let pcr_bank = HashingAlgorithm::Sha256;
+        let pcr_selection = pcr_selection(pcr_mask, pcr_bank)?;
+        let mut secret = [0; SECRET_SIZE];
+        rand_bytes(&mut secret).map_err(encryption_error)?;
+
+        let (blob, primary_alg) = {
+            let mut context = self.context()?;
+            let (srk, primary_alg) = storage_root_key(&mut context, None)?;
+            let blob = self.seal_blob(&mut context, srk.key, &secret, pcr_selection, None);
+            srk.release(&mut context);
+            (blob?, primary_alg)
+        };
+
+        let mut sealed = Vec::new();
+        marshal_tpm2b(blob.private.value(), &mut sealed);
+        marshal_tpm2b(&blob.public.marshall().map_err(tpm_error)?, &mut sealed);
+        let policy_hash = blob.public.auth_policy().value();
+
+        let mut iv = [0; IV_SIZE];
+        rand_bytes(&mut iv).map_err(encryption_error)?;
+        let mut header = Vec::new();
+        header.extend_from_slice(&CRED_AES256_GCM_BY_TPM2_HMAC);
+        for size in [KEY_SIZE, BLOCK_SIZE, IV_SIZE, TAG_SIZE] {
+            header.extend_from_slice(&(size as u32).to_le_bytes());
+        }
+        header.extend_from_slice(&iv);
+        pad(&mut header);
+        header.extend_from_slice(&u64::from(pcr_mask).to_le_bytes());
+        header.extend_from_slice(&u16::from(pcr_bank).to_le_bytes());
+        header.extend_from_slice(&primary_alg.to_le_bytes());
+        header.extend_from_slice(&(sealed.len() as u32).to_le_bytes());
+        header.extend_from_slice(&(policy_hash.len() as u32).to_le_bytes());
+        header.extend_from_slice(&sealed);
+        header.extend_from_slice(policy_hash);
+        pad(&mut header);
+
+        let mut plaintext = Vec::new();
+        plaintext.extend_from_slice(&micros(SystemTime::now()).to_le_bytes());
+        plaintext.extend_from_slice(&not_after.map_or(USEC_INFINITY, micros).to_le_bytes());
+        plaintext.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        plaintext.extend_from_slice(name.as_bytes());
+        pad(&mut plaintext);
+        plaintext.extend_from_slice(data);
+
+        let mut tag = [0; TAG_SIZE];
+        let ciphertext = encrypt_aead(
+            Cipher::aes_256_gcm(),
+            &credential_key(&secret)?,
+            Some(&iv),
+            &header,
+            &plaintext,
+            &mut tag,
+        )
+        .map_err(encryption_error)?;
+
+        header.extend_from_slice(&ciphertext);
+        header.extend_from_slice(&tag);
+        Ok(STANDARD.encode(header))
+    }
+
+    /// Decrypts a credential created by `systemd-creds encrypt --with-key=tpm2` on this
+    /// machine or by [`TpmProvider::encrypt_credential`].
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - The name the credential is expected to have. If given, credentials with a
+    ///   different, non-empty embedded name are rejected, like systemd does.
+    /// * `credential` - The credential, Base64 encoded or raw.
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing the decrypted [`Credential`] on success, or a `SecurityModuleError` on failure.
+    #[instrument(skip(credential))]
+    pub fn decrypt_credential(
+        &self,
+        name: Option<&str>,
+        credential: &[u8],
+    ) -> Result<Credential, SecurityModuleError> {
+        let encoded: Vec<u8> = credential
+            .iter()
+            .copied()
+            .filter(|byte| !byte.is_ascii_whitespace())
+            .collect();
+        let credential = STANDARD
+            .decode(encoded)
+            .unwrap_or_else(|_| credential.to_vec());
+
+        let mut reader = Reader::new(&credential);
+        if reader.read_bytes(CRED_AES256_GCM_BY_TPM2_HMAC.len())? != CRED_AES256_GCM_BY_TPM2_HMAC {
+            return Err(TpmError::UnsupportedOperation(
+                "Only credentials encrypted with --with-key=tpm2 are supported".to_string(),
+            )
+            .into());
+        }
+        let sizes = [
+            reader.read_u32_le()?,
+            reader.read_u32_le()?,
+            reader.read_u32_le()?,
+            reader.read_u32_le()?,
+        ];
+        if sizes.map(|size| size as usize) != [KEY_SIZE, BLOCK_SIZE, IV_SIZE, TAG_SIZE] {
+            return Err(invalid_data("Unsupported credential cipher parameters").into());
+        }
+        let iv = reader.read_bytes(IV_SIZE)?;
+        reader.read_bytes(padding(CREDENTIAL_HEADER_SIZE + IV_SIZE))?;
+
+        let pcr_mask = reader.read_u64_le()?;
+        let pcr_bank = HashingAlgorithm::try_from(reader.read_u16_le()?)
+            .map_err(|_| invalid_data("Unsupported PCR bank"))?;
+        let primary_alg = reader.read_u16_le()?;
+        let blob_size = reader.read_u32_le()? as usize;
+        let policy_hash_size = reader.read_u32_le()? as usize;
+        let mut sealed = Reader::new(reader.read_bytes(blob_size)?);
+        let policy_hash = reader.read_bytes(policy_hash_size)?;
+        reader.read_bytes(padding(TPM2_HEADER_SIZE + blob_size + policy_hash_size))?;
+
+        let private = Private::try_from(sealed.read_tpm2b()?).map_err(tpm_error)?;
+        let public = Public::unmarshall(sealed.read_tpm2b()?).map_err(tpm_error)?;
+        if !sealed.is_empty() {
+            return Err(TpmError::UnsupportedOperation(
+                "Credentials sealed without access to the TPM are not supported".to_string(),
+            )
+            .into());
+        }
+        if public.auth_policy().value() != policy_hash {
+            return Err(invalid_data("Policy hash does not match the sealed object").into());
+        }
+        let pcr_mask = u32::try_from(pcr_mask).map_err(|_| invalid_data("Invalid PCR mask"))?;
+        let blob = SealedBlob {
+            pcr_selection: pcr_selection(pcr_mask, pcr_bank)?,
+            with_auth: false,
+            public,
+            private,
+        };
+
+        let secret = {
+            let mut context = self.context()?;
+            let (srk, _) = storage_root_key(&mut context, Some(primary_alg))?;
+            let secret = self.unseal_blob(&mut context, srk.key, &blob, None);
+            srk.release(&mut context);
+            secret?
+        };
+
+        // Everything up to the encrypted metadata header is authenticated as AAD.
+        let aad_size = credential.len() - reader.remaining();
+        let encrypted = reader.read_bytes(
+            reader
+                .remaining()
+                .checked_sub(TAG_SIZE)
+                .ok_or_else(|| invalid_data("Credential is truncated"))?,
+        )?;
+        let tag = reader.read_bytes(TAG_SIZE)?;
+        let plaintext = decrypt_aead(
+            Cipher::aes_256_gcm(),
+            &credential_key(&secret)?,
+            Some(iv),
+            &credential[..aad_size],
+            encrypted,
+            tag,
+        )
+        .map_err(|e| SecurityModuleError::DecryptionError(e.to_string()))?;
+
+        let mut reader = Reader::new(&plaintext);
+        let timestamp = reader.read_u64_le()?;
+        let not_after = reader.read_u64_le()?;
+        let name_size = reader.read_u32_le()? as usize;
+        let embedded_name = String::from_utf8(reader.read_bytes(name_size)?.to_vec())
+            .map_err(|_| invalid_data("Credential name is not valid UTF-8"))?;
+        reader.read_bytes(padding(METADATA_HEADER_SIZE + name_size))?;
+
+        if let Some(name) = name {
+            if !embedded_name.is_empty() && embedded_name != name {
+                return Err(SecurityModuleError::DecryptionError(format!(
+                    "Embedded credential name '{}' does not match '{}'",
+                    embedded_name, name
+                )));
+            }
+        }
+        let not_after = (not_after != USEC_INFINITY).then(|| time(not_after));
+        if not_after.is_some_and(|not_after| not_after < SystemTime::now()) {
+            return Err(SecurityModuleError::DecryptionError(
+                "Credential has expired".to_string(),
+            ));
+        }
+
+        Ok(Credential {
+            name: embedded_name,
+            timestamp: time(timestamp),
+            not_after,
+            data: reader.read_bytes(reader.remaining())?.to_vec(),
+        })
+    }
+}
+
+/// The storage root key used by systemd, which has to be released after use.
+struct StorageRootKey {
+    key: TssKeyHandle,
+    persistent: bool,
+}
+
+impl StorageRootKey {
+    fn release(self, context: &mut Context) {
+        if self.persistent {
+            let mut handle = ObjectHandle::from(self.key);
+            if let Err(e) = context.tr_close(&mut handle) {
+                tracing::warn!("Failed to close storage root key handle: {}", e);
+            }
+        } else {
+            flush(context, self.key.into());
+        }
+    }
+}
+
+/// Returns the storage root key systemd seals credentials under, along with its `TPM2_ALG_*`
+/// algorithm.
+///
+/// Like systemd, this uses the key persisted at `0x81000001` if there is one. Otherwise the
+/// key is created from the SRK template of the TCG provisioning guidance, preferring ECC
+/// unless `algorithm` requires RSA.
+fn storage_root_key(
+    context: &mut Context,
+    algorithm: Option<u16>,
+) -> Result<(StorageRootKey, u16), SecurityModuleError> {
+    let persistent = PersistentTpmHandle::new(SRK_HANDLE).map_err(tpm_error)?;
+    if let Ok(handle) = context
+        .execute_without_session(|ctx| ctx.tr_from_tpm_public(TpmHandle::Persistent(persistent)))
+    {
+        let srk = StorageRootKey {
+            key: handle.into(),
+            persistent: true,
+        };
+        let srk_algorithm = match context.read_public(srk.key).map(|(public, _, _)| public) {
+            Ok(Public::Rsa { .. }) => Some(TPM2_ALG_RSA),
+            Ok(Public::Ecc { .. }) => Some(TPM2_ALG_ECC),
+            _ => None,
+        };
+        match srk_algorithm {
+            Some(srk_algorithm) if algorithm.unwrap_or(srk_algorithm) == srk_algorithm => {
+                return Ok((srk, srk_algorithm))
+            }
+            _ => srk.release(context),
+        }
+    }
+
+    let algorithms = match algorithm {
+        Some(algorithm) => vec![algorithm],
+        None => vec![TPM2_ALG_ECC, TPM2_ALG_RSA],
+    };
+    let mut last_error = None;
+    for algorithm in algorithms {
+        let template = srk_template(algorithm)?;
+        match context.execute_with_nullauth_session(|ctx| {
+            ctx.create_primary(Hierarchy::Owner, template, None, None, None, None)
+        }) {
+            Ok(result) => {
+                let srk = StorageRootKey {
+                    key: result.key_handle,
+                    persistent: false,
+                };
+                return Ok((srk, algorithm));
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+
+    Err(last_error.map_or_else(
+        || invalid_data("No storage root key algorithm").into(),
+        tpm_error,
+    ))
+}
+
+/// The SRK template of the TCG provisioning guidance, as used by systemd.
+fn srk_template(algorithm: u16) -> Result<Public, SecurityModuleError> {
+    let attributes = ObjectAttributesBuilder::new()
+        .with_fixed_tpm(true)
+        .with_fixed_parent(true)
+        .with_sensitive_data_origin(true)
+        .with_user_with_auth(true)
+        .with_no_da(true)
+        .with_restricted(true)
+        .with_decrypt(true)
+        .build()
+        .map_err(tpm_error)?;
+    let builder = PublicBuilder::new()
+        .with_name_hashing_algorithm(HashingAlgorithm::Sha256)
+        .with_object_attributes(attributes);
+
+    match algorithm {
+        TPM2_ALG_ECC => builder
+            .with_public_algorithm(PublicAlgorithm::Ecc)
+            .with_ecc_parameters(
+                PublicEccParametersBuilder::new_restricted_decryption_key(
+                    SymmetricDefinitionObject::AES_128_CFB,
+                    EccCurve::NistP256,
+                )
+                .build()
+                .map_err(tpm_error)?,
+            )
+            .with_ecc_unique_identifier(EccPoint::default()),
+        TPM2_ALG_RSA => builder
+            .with_public_algorithm(PublicAlgorithm::Rsa)
+            .with_rsa_parameters(
+                PublicRsaParametersBuilder::new_restricted_decryption_key(
+                    SymmetricDefinitionObject::AES_128_CFB,
+                    RsaKeyBits::Rsa2048,
+                    RsaExponent::default(),
+                )
+                .build()
+                .map_err(tpm_error)?,
+            )
+            .with_rsa_unique_identifier(PublicKeyRsa::default()),
+        _ => {
+            return Err(TpmError::UnsupportedOperation(format!(
+                "Unsupported primary key algorithm {:#06x}",
+                algorithm
+            ))
+            .into())
+        }
+    }
+    .build()
+    .map_err(tpm_error)
+}
+
+/// Selects the PCRs set in `mask` in the given bank.
+fn pcr_selection(
+    mask: u32,
+    bank: HashingAlgorithm,
+) -> Result<PcrSelectionList, SecurityModuleError> {
+    let slots = (0..32)
+        .filter(|index| mask & (1 << index) != 0)
+        .map(|index| PcrSlot::try_from(1u32 << index))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(tpm_error)?;
+    let builder = PcrSelectionList::builder();
+    if slots.is_empty() {
+        builder.build()
+    } else {
+        builder.with_selection(bank, &slots).build()
+    }
+    .map_err(tpm_error)
+}
+
+/// Derives the AES-256-GCM key from the sealed secret, like systemd does without a host key.
+fn credential_key(secret: &[u8]) -> Result<Vec<u8>, SecurityModuleError> {
+    hash(MessageDigest::sha256(), secret)
+        .map(|digest| digest.to_vec())
+        .map_err(encryption_error)
+}
+
+/// Returns the number of NUL bytes that pad a structure of `size` bytes to 8 byte alignment.
+fn padding(size: usize) -> usize {
+    size.next_multiple_of(8) - size
+}
+
+fn pad(buffer: &mut Vec<u8>) {
+    buffer.resize(buffer.len() + padding(buffer.len()), 0);
+}
+
+fn micros(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map_or(0, |duration| duration.as_micros() as u64)
+}
+
+fn time(micros: u64) -> SystemTime {
+    UNIX_EPOCH + Duration::from_micros(micros)
+}
+
+fn encryption_error(e: openssl::error::ErrorStack) -> SecurityModuleError {
+    SecurityModuleError::EncryptionError(e.to_string())
+}
@@ -0,0 +1,299 @@
+use super::{verifier::message_digest, TpmProvider};
+use crate::{common::error::SecurityModuleError, tpm::core::error::TpmError};
+use openssl::hash::hash;
+use std::{io, sync::MutexGuard};
+use tss_esapi::{
+    constants::{
+        response_code::Tss2ResponseCode,
+        tss::{TPM2_RH_NULL, TPM2_ST_HASHCHECK},
+    },
+    handles::{KeyHandle as TssKeyHandle, ObjectHandle},
+    interface_types::{
+        algorithm::HashingAlgorithm, key_bits::RsaKeyBits, resource_handles::Hierarchy,
+    },
+    structures::{
+        CreationData, HashcheckTicket, Name, PcrSelectionList, Public, RsaExponent,
+        SymmetricDefinitionObject,
+    },
+    traits::Marshall,
+    tss2_esys::{
+        Tss2_MU_TPMS_CREATION_DATA_Marshal, TPM2B_DIGEST, TPM2_ALG_ID, TPML_PCR_SELECTION,
+        TPMS_CREATION_DATA, TPMS_PCR_SELECTION, TPMT_TK_HASHCHECK, TSS2_RC,
+    },
+    utils::create_restricted_decryption_rsa_public,
+    Context,
+};
+
+impl TpmProvider {
+    /// Returns the locked TPM context, or an error if `initialize_module` has not been called.
+    pub(super) fn context(&self) -> Result<MutexGuard<'_, Context>, SecurityModuleError> {
+        self.handle
+            .as_ref()
+            .ok_or_else(|| {
+                SecurityModuleError::InitializationError(
+                    "TPM module is not initialized".to_string(),
+                )
+            })?
+            .lock()
+            .map_err(|e| SecurityModuleError::InitializationError(e.to_string()))
+            .map(|mut context| {
+                self.resources.begin(&mut context);
+                context
+            })
+    }
+
+    /// Returns the handle of the storage root key under the owner hierarchy, creating it on
+    /// first use.
+    ///
+    /// The key is derived from the standard RSA 2048 storage template, so it is the same key
+    /// for as long as the owner seed is unchanged. It is created once and then kept loaded,
+    /// or swapped out like any other object, until the providers sharing the context are
+    /// dropped. Its name has to match the one pinned with
+    /// [`TpmProvider::set_storage_root`], or the one seen on first use otherwise. The handle
+    /// is only valid while `context` stays locked and must not be flushed.
+    pub(super) fn storage_root(
+        &self,
+        context: &mut Context,
+    ) -> Result<TssKeyHandle, SecurityModuleError> {
+        let mut storage_root = self.storage_root.lock().unwrap();
+        if let Some(object) = &storage_root.object {
+            return object.handle(context);
+        }
+
+        let public = create_restricted_decryption_rsa_public(
+            SymmetricDefinitionObject::AES_128_CFB,
+            RsaKeyBits::Rsa2048,
+            RsaExponent::default(),
+        )
+        .map_err(|e| TpmError::InternalError(Box::new(e)))?;
+        let result = self
+            .resources
+            .retry(context, |ctx| {
+                ctx.execute_with_nullauth_session(|ctx| {
+                    ctx.create_primary(Hierarchy::Owner, public.clone(), None, None, None, None)
+                })
+            })
+            .map_err(|e| TpmError::InternalError(Box::new(e)))?;
+        // The object is flushed again if the name does not match.
+        let object = self.track(result.key_handle)?;
+
+        let name = public_name(&result.out_public)?;
+        if storage_root
+            .name
+            .as_ref()
+            .is_some_and(|pinned| pinned.value() != name.value())
+        {
+            return Err(TpmError::InitializationError(
+                "The storage root does not match the pinned key".to_string(),
+            )
+            .into());
+        }
+        storage_root.name = Some(name);
+        storage_root.object = Some(object);
+
+        Ok(result.key_handle)
+    }
+}
+
+/// Flushes a transient object or session, logging failures instead of propagating them.
+pub(super) fn flush(context: &mut Context, handle: ObjectHandle) {
+    if let Err(e) = context.flush_context(handle) {
+        tracing::warn!("Failed to flush TPM handle: {}", e);
+    }
+}
+
+/// Returns the first four bytes of the SHA-256 digest of `key_id`, from which the TPM
+/// handles belonging to a key are taken.
+pub(super) fn key_id_digest(key_id: &str) -> Result<u32, SecurityModuleError> {
+    let digest = hash(message_digest(HashingAlgorithm::Sha256)?, key_id.as_bytes())
+        .map_err(|e| TpmError::InternalError(Box::new(e)))?;
+    Ok(u32::from_be_bytes([
+        digest[0], digest[1], digest[2], digest[3],
+    ]))
+}
+
+/// Computes the name of a public area, i.e. its name algorithm followed by the digest of the
+/// marshalled area.
+pub(super) fn public_name(public: &Public) -> Result<Name, SecurityModuleError> {
+    let hashing_algorithm = public.name_hashing_algorithm();
+    let marshalled = public
+        .marshall()
+        .map_err(|e| TpmError::InternalError(Box::new(e)))?;
+    let digest = hash(message_digest(hashing_algorithm)?, &marshalled)
+        .map_err(|e| TpmError::InternalError(Box::new(e)))?;
+
+    let mut name = TPM2_ALG_ID::from(hashing_algorithm).to_be_bytes().to_vec();
+    name.extend_from_slice(&digest);
+    Ok(Name::try_from(name).map_err(|e| TpmError::InternalError(Box::new(e)))?)
+}
+
+/// Marshals a PCR selection list as a `TPML_PCR_SELECTION` and appends it to `buffer`.
+pub(super) fn marshal_pcr_selection(selection: &PcrSelectionList, buffer: &mut Vec<u8>) {
+    let tpml = TPML_PCR_SELECTION::from(selection.clone());
+    buffer.extend_from_slice(&tpml.count.to_be_bytes());
+    for tpms in &tpml.pcrSelections[..tpml.count as usize] {
+        buffer.extend_from_slice(&tpms.hash.to_be_bytes());
+        buffer.push(tpms.sizeofSelect);
+        buffer.extend_from_slice(&tpms.pcrSelect[..tpms.sizeofSelect as usize]);
+    }
+}
+
+/// Reads a `TPML_PCR_SELECTION` from the front of `reader`.
+pub(super) fn unmarshal_pcr_selection(
+    reader: &mut Reader<'_>,
+) -> Result<PcrSelectionList, TpmError> {
+    let mut tpml = TPML_PCR_SELECTION {
+        count: reader.read_u32()?,
+        pcrSelections: [TPMS_PCR_SELECTION {
+            hash: 0,
+            sizeofSelect: 0,
+            pcrSelect: [0; 4],
+        }; 16],
+    };
+    if tpml.count as usize > tpml.pcrSelections.len() {
+        return Err(TpmError::Io(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "PCR selection contains too many banks",
+        )));
+    }
+    for tpms in &mut tpml.pcrSelections[..tpml.count as usize] {
+        tpms.hash = reader.read_u16()?;
+        tpms.sizeofSelect = reader.read_u8()?;
+        if tpms.sizeofSelect as usize > tpms.pcrSelect.len() {
+            return Err(TpmError::Io(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "PCR selection is too large",
+            )));
+        }
+        tpms.pcrSelect[..tpms.sizeofSelect as usize]
+            .copy_from_slice(reader.read_bytes(tpms.sizeofSelect as usize)?);
+    }
+
+    PcrSelectionList::try_from(tpml).map_err(|e| TpmError::InternalError(Box::new(e)))
+}
+
+/// Returns the ticket for signing digests that were not produced by the TPM, which is only
+/// accepted by unrestricted keys.
+pub(super) fn null_hashcheck_ticket() -> Result<HashcheckTicket, tss_esapi::Error> {
+    HashcheckTicket::try_from(TPMT_TK_HASHCHECK {
+        tag: TPM2_ST_HASHCHECK,
+        hierarchy: TPM2_RH_NULL,
+        digest: TPM2B_DIGEST {
+            size: 0,
+            buffer: [0; 64],
+        },
+    })
+}
+
+/// Marshals creation data as a `TPMS_CREATION_DATA`, the form the TPM computes the creation
+/// hash over.
+pub(super) fn marshal_creation_data(
+    creation_data: CreationData,
+) -> Result<Vec<u8>, SecurityModuleError> {
+    let creation_data = TPMS_CREATION_DATA::from(creation_data);
+    let mut buffer = vec![0; std::mem::size_of::<TPMS_CREATION_DATA>()];
+    let mut offset = 0;
+    check(unsafe {
+        Tss2_MU_TPMS_CREATION_DATA_Marshal(
+            &creation_data,
+            buffer.as_mut_ptr(),
+            buffer.len() as _,
+            &mut offset,
+        )
+    })?;
+    buffer.truncate(offset as usize);
+
+    Ok(buffer)
+}
+
+/// Wraps an error of tss-esapi.
+pub(super) fn tpm_error(e: tss_esapi::Error) -> SecurityModuleError {
+    TpmError::InternalError(Box::new(e)).into()
+}
+
+/// Creates the error reported for malformed serialized data.
+pub(super) fn invalid_data(message: &str) -> TpmError {
+    TpmError::Io(io::Error::new(io::ErrorKind::InvalidData, message))
+}
+
+/// Converts the response code of a marshalling call into a `Result`.
+fn check(rc: TSS2_RC) -> Result<(), SecurityModuleError> {
+    let error = tss_esapi::Error::Tss2Error(Tss2ResponseCode::from(rc));
+    if error.is_success() {
+        Ok(())
+    } else {
+        Err(TpmError::InternalError(Box::new(error)).into())
+    }
+}
+
+/// Appends a TPM2B style buffer, i.e. a big endian `u16` size followed by the data.
+pub(super) fn marshal_tpm2b(data: &[u8], buffer: &mut Vec<u8>) {
+    buffer.extend_from_slice(&(data.len() as u16).to_be_bytes());
+    buffer.extend_from_slice(data);
+}
+
+/// A minimal big endian reader for TPM marshalled data.
+pub(super) struct Reader<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    pub(super) fn new(data: &'a [u8]) -> Self {
+        Self { data }
+    }
+
+    pub(super) fn read_bytes(&mut self, len: usize) -> Result<&'a [u8], TpmError> {
+        if self.data.len() < len {
+            return Err(TpmError::Io(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Unexpected end of marshalled data",
+            )));
+        }
+        let (head, tail) = self.data.split_at(len);
+        self.data = tail;
+        Ok(head)
+    }
+
+    pub(super) fn read_u8(&mut self) -> Result<u8, TpmError> {
+        Ok(self.read_bytes(1)?[0])
+    }
+
+    pub(super) fn read_u16(&mut self) -> Result<u16, TpmError> {
+        Ok(u16::from_be_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    pub(super) fn read_u32(&mut self) -> Result<u32, TpmError> {
+        Ok(u32::from_be_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    /// Reads a little-endian `u16`, as used by UEFI and TCG PC Client structures.
+    pub(super) fn read_u16_le(&mut self) -> Result<u16, TpmError> {
+        Ok(u16::from_le_bytes(self.read_bytes(2)?.try_into().unwrap()))
+    }
+
+    /// Reads a little-endian `u32`, as used by UEFI and TCG PC Client structures.
+    pub(super) fn read_u32_le(&mut self) -> Result<u32, TpmError> {
+        Ok(u32::from_le_bytes(self.read_bytes(4)?.try_into().unwrap()))
+    }
+
+    /// Reads a little-endian `u64`, as used by UEFI and TCG PC Client structures.
+    pub(super) fn read_u64_le(&mut self) -> Result<u64, TpmError> {
+        Ok(u64::from_le_bytes(self.read_bytes(8)?.try_into().unwrap()))
+    }
+
+    /// Returns the number of bytes left to read.
+    pub(super) fn remaining(&self) -> usize {
+        self.data.len()
+    }
+
+    /// Returns `true` if all data has been read.
+    pub(super) fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+
+    /// Reads a TPM2B style buffer.
+    pub(super) fn read_tpm2b(&mut self) -> Result<&'a [u8], TpmError> {
+        let len = self.read_u16()? as usize;
+        self.read_bytes(len)
+    }
+}
@@ -0,0 +1,447 @@
+use super::{attestation::KeyAttestation, utils::public_name};
+use crate::{common::error::SecurityModuleError, tpm::core::error::TpmError};
+use openssl::{
+    bn::{BigNum, BigNumContext},
+    ec::{EcGroup, EcKey, EcPoint},
+    ecdsa::EcdsaSig,
+    hash::{hash, MessageDigest},
+    nid::Nid,
+    pkey::{PKey, Public as OpenSslPublic},
+    rsa::{Padding, Rsa},
+    sign::{RsaPssSaltlen, Verifier},
+};
+use std::collections::{BTreeMap, HashMap};
+use tracing::instrument;
+use tss_esapi::{
+    interface_types::{algorithm::HashingAlgorithm, ecc::EccCurve},
+    structures::{Attest, AttestInfo, Public, Signature},
+    traits::UnMarshall,
+};
+
+/// PCR values grouped by bank, as reported by a device or expected by a reference policy.
+///
+/// Each bank is identified by its hashing algorithm and maps PCR indices to their digests.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct PcrValues {
+    banks: HashMap<HashingAlgorithm, BTreeMap<u8, Vec<u8>>>,
+}
+
+impl PcrValues {
+    /// Creates an empty set of PCR values.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the digest of PCR `index` in the given `bank`, replacing any previous value.
+    pub fn insert(&mut self, bank: HashingAlgorithm, index: u8, digest: Vec<u8>) {
+        self.banks.entry(bank).or_default().insert(index, digest);
+    }
+
+    /// Returns the digest of PCR `index` in the given `bank`, if present.
+    pub fn get(&self, bank: HashingAlgorithm, index: u8) -> Option<&[u8]> {
+        self.banks
+            .get(&bank)
+            .and_then(|pcrs| pcrs.get(&index))
+            .map(Vec::as_slice)
+    }
+
+    /// Iterates over all `(bank, index, digest)` entries.
+    pub fn iter(&self) -> impl Iterator<Item = (HashingAlgorithm, u8, &[u8])> {
+        self.banks.iter().flat_map(|(bank, pcrs)| {
+            pcrs.iter()
+                .map(move |(index, digest)| (*bank, *index, digest.as_slice()))
+        })
+    }
+}
+
+/// A PCR whose reported value does not match the reference policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PcrMismatch {
+    /// The PCR bank the mismatch was found in.
+    pub bank: HashingAlgorithm,
+    /// The PCR index.
+    pub index: u8,
+    /// The digest required by the reference policy.
+    pub expected: Vec<u8>,
+    /// The digest covered by the quote, or `None` if the quote does not cover this PCR.
+    pub reported: Option<Vec<u8>>,
+}
+
+/// The detailed result of verifying a TPM quote.
+///
+/// A quote can only be trusted if every individual check succeeded, see [`QuoteVerdict::is_trusted`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuoteVerdict {
+    /// The signature over the quote was produced by the attestation key.
+    pub signature_valid: bool,
+    /// The attestation key is a restricted signing key, which only signs data produced by the
+    /// TPM itself.
+    pub attestation_key_valid: bool,
+    /// The quote's `extraData` equals the expected nonce.
+    pub nonce_valid: bool,
+    /// The PCR digest in the quote matches the reported PCR values.
+    pub pcr_digest_valid: bool,
+    /// PCRs whose reported value deviates from the reference policy.
+    pub pcr_mismatches: Vec<PcrMismatch>,
+    /// The TPM clock at the time of the quote, in milliseconds.
+    pub clock: u64,
+    /// Number of TPM resets since the last clear.
+    pub reset_count: u32,
+    /// Number of TPM restarts since the last reset.
+    pub restart_count: u32,
+    /// Whether the clock value is guaranteed to not have been reported before.
+    pub safe: bool,
+    /// The vendor specific firmware version of the TPM.
+    pub firmware_version: u64,
+}
+
+impl QuoteVerdict {
+    /// Returns `true` if the quote passed all checks.
+    pub fn is_trusted(&self) -> bool {
+        self.signature_valid
+            && self.attestation_key_valid
+            && self.nonce_valid
+            && self.pcr_digest_valid
+            && self.pcr_mismatches.is_empty()
+    }
+}
+
+/// Verifies a TPM quote without requiring access to a TPM.
+///
+/// # Arguments
+///
+/// * `quote` - The marshalled `TPMS_ATTEST` structure returned by `TPM2_Quote`.
+/// * `signature` - The marshalled `TPMT_SIGNATURE` over `quote`.
+/// * `ak_public` - The marshalled `TPMT_PUBLIC` area of the attestation key.
+/// * `nonce` - The nonce the verifier sent to the device.
+/// * `reported_pcrs` - The PCR values the device reported alongside the quote.
+/// * `reference` - The PCR values the device is expected to have.
+///
+/// # Returns
+///
+/// A `Result` containing the `QuoteVerdict` on success. Malformed input results in a
+/// `SecurityModuleError`, while failed checks are reported through the verdict.
+#[instrument(skip(quote, signature, ak_public))]
+pub fn verify_quote(
+    quote: &[u8],
+    signature: &[u8],
+    ak_public: &[u8],
+    nonce: &[u8],
+    reported_pcrs: &PcrValues,
+    reference: &PcrValues,
+) -> Result<QuoteVerdict, SecurityModuleError> {
+    let attest = Attest::unmarshall(quote)
+        .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
+    let signature = Signature::unmarshall(signature)
+        .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
+    let ak_public = Public::unmarshall(ak_public)
+        .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
+
+    let quote_info = match attest.attested() {
+        AttestInfo::Quote { info } => info,
+        _ => {
+            return Err(SecurityModuleError::SignatureVerificationError(
+                "Attestation structure is not a quote".to_string(),
+            ))
+        }
+    };
+
+    let signature_valid = verify_tpm_signature(&ak_public, quote, &signature)?;
+    let attestation_key_valid = is_attestation_key(&ak_public);
+    let nonce_valid = attest.extra_data().value() == nonce;
+
+    // The PCR digest is computed with the hash algorithm of the signing scheme over the
+    // concatenation of all selected PCRs, banks in selection order and PCRs in ascending order.
+    let digest_alg = signature_hash(&signature)?;
+    let mut quoted_pcrs = PcrValues::new();
+    let mut concatenated = Vec::new();
+    let mut pcr_digest_valid = true;
+    for selection in quote_info.pcr_selection().get_selections() {
+        let bank = selection.hashing_algorithm();
+        for slot in selection.selected() {
+            let index = u32::from(slot).trailing_zeros() as u8;
+            match reported_pcrs.get(bank, index) {
+                Some(value) => {
+                    concatenated.extend_from_slice(value);
+                    quoted_pcrs.insert(bank, index, value.to_vec());
+                }
+                None => pcr_digest_valid = false,
+            }
+        }
+    }
+    let computed = hash(message_digest(digest_alg)?, &concatenated)
+        .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
+    pcr_digest_valid &= computed.as_ref() == quote_info.pcr_digest().value();
+
+    let pcr_mismatches = reference
+        .iter()
+        .filter_map(|(bank, index, expected)| {
+            let reported = quoted_pcrs.get(bank, index);
+            (reported != Some(expected)).then(|| PcrMismatch {
+                bank,
+                index,
+                expected: expected.to_vec(),
+                reported: reported.map(<[u8]>::to_vec),
+            })
+        })
+        .collect();
+
+    let clock_info = attest.clock_info();
+    Ok(QuoteVerdict {
+        signature_valid,
+        attestation_key_valid,
+        nonce_valid,
+        pcr_digest_valid,
+        pcr_mismatches,
+        clock: clock_info.clock(),
+        reset_count: clock_info.reset_count(),
+        restart_count: clock_info.restart_count(),
+        safe: clock_info.safe(),
+        firmware_version: attest.firmware_version(),
+    })
+}
+
+/// The detailed result of verifying a [`KeyAttestation`].
+///
+/// A key can only be considered hardware-bound if every check succeeded, see
+/// [`KeyAttestationVerdict::is_hardware_bound`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyAttestationVerdict {
+    /// The signature over the attestation was produced by the attestation key.
+    pub signature_valid: bool,
+    /// The attestation key is a restricted signing key, which only signs data produced by the
+    /// TPM itself.
+    pub attestation_key_valid: bool,
+    /// The attestation's `extraData` equals the expected nonce.
+    pub nonce_valid: bool,
+    /// The certified name matches the reported public area of the key.
+    pub name_valid: bool,
+    /// The attested creation hash is the digest of the reported creation data, so the
+    /// creation data describes how the TPM created the key.
+    pub creation_valid: bool,
+    /// The key can not be duplicated off the TPM.
+    pub fixed_tpm: bool,
+    /// The key's private part was generated inside the TPM.
+    pub sensitive_data_origin: bool,
+}
+
+impl KeyAttestationVerdict {
+    /// Returns `true` if the attestation passed all checks.
+    pub fn is_hardware_bound(&self) -> bool {
+        self.signature_valid
+            && self.attestation_key_valid
+            && self.nonce_valid
+            && self.name_valid
+            && self.creation_valid
+            && self.fixed_tpm
+            && self.sensitive_data_origin
+    }
+}
+
+/// Verifies a key attestation without requiring access to a TPM.
+///
+/// # Arguments
+///
+/// * `attestation` - The attestation produced by `TpmProvider::certify_key`.
+/// * `ak_public` - The marshalled `TPMT_PUBLIC` area of the trusted attestation key.
+/// * `nonce` - The nonce the verifier passed to `attest_key` or `certify_key`.
+///
+/// # Returns
+///
+/// A `Result` containing the `KeyAttestationVerdict` on success. Malformed input results in a
+/// `SecurityModuleError`, while failed checks are reported through the verdict.
+#[instrument(skip(attestation, ak_public))]
+pub fn verify_key_attestation(
+    attestation: &KeyAttestation,
+    ak_public: &[u8],
+    nonce: &[u8],
+) -> Result<KeyAttestationVerdict, SecurityModuleError> {
+    let attest = Attest::unmarshall(&attestation.attest)
+        .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
+    let signature = Signature::unmarshall(&attestation.signature)
+        .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
+    let ak_public = Public::unmarshall(ak_public)
+        .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
+    let key_public = Public::unmarshall(&attestation.key_public)
+        .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
+
+    let (certified_name, creation_hash) = match attest.attested() {
+        AttestInfo::Creation { info } => (info.name(), info.creation_hash()),
+        _ => {
+            return Err(SecurityModuleError::SignatureVerificationError(
+                "Attestation structure is not a key creation certification".to_string(),
+            ))
+        }
+    };
+    let creation_data_hash = hash(
+        message_digest(key_public.name_hashing_algorithm())?,
+        &attestation.creation_data,
+    )
+    .map_err(|e| SecurityModuleError::SignatureVerificationError(e.to_string()))?;
+
+    let attributes = key_public.object_attributes();
+    Ok(KeyAttestationVerdict {
+        signature_valid: verify_tpm_signature(&ak_public, &attestation.attest, &signature)?,
+        attestation_key_valid: is_attestation_key(&ak_public),
+        nonce_valid: attest.extra_data().value() == nonce,
+        name_valid: public_name(&key_public)?.value() == certified_name.value(),
+        creation_valid: *creation_data_hash == *creation_hash.value(),
+        fixed_tpm: attributes.fixed_tpm(),
+        sensitive_data_origin: attributes.sensitive_data_origin(),
+    })
+}
+
+/// Returns `true` if `public` is a restricted signing key that can not decrypt.
+///
+/// Only such keys refuse to sign data that starts with `TPM_GENERATED_VALUE` unless the TPM
+/// produced it, so a quote signed by any other key proves nothing.
+fn is_attestation_key(public: &Public) -> bool {
+    let attributes = public.object_attributes();
+    attributes.restricted() && attributes.sign_encrypt() && !attributes.decrypt()
+}
+
+/// Verifies a TPM generated `signature` over `message` with the given public key in software.
+///
+/// Supports RSASSA, RSAPSS and ECDSA signatures. Returns `Ok(false)` if the signature does not
+/// match and an error if the key or signature scheme is not supported.
+pub(crate) fn verify_tpm_signature(
+    public: &Public,
+    message: &[u8],
+    signature: &Signature,
+) -> Result<bool, SecurityModuleError> {
+    let key = public_key_to_pkey(public)?;
+    let verification_error = |e: openssl::error::ErrorStack| {
+        SecurityModuleError::SignatureVerificationError(e.to_string())
+    };
+
+    match signature {
+        Signature::RsaSsa(rsa_signature) | Signature::RsaPss(rsa_signature) => {
+            let digest = message_digest(rsa_signature.hashing_algorithm())?;
+            let mut verifier = Verifier::new(digest, &key).map_err(verification_error)?;
+            if let Signature::RsaPss(_) = signature {
+                verifier
+                    .set_rsa_padding(Padding::PKCS1_PSS)
+                    .map_err(verification_error)?;
+                // Accept any salt length, TPMs differ in whether they use the digest or the
+                // maximum length.
+                verifier
+                    .set_rsa_pss_saltlen(RsaPssSaltlen::custom(-2))
+                    .map_err(verification_error)?;
+            }
+            verifier.update(message).map_err(verification_error)?;
+            Ok(verifier
+                .verify(rsa_signature.signature().value())
+                .unwrap_or(false))
+        }
+        Signature::EcDsa(ecc_signature) => {
+            let digest = hash(message_digest(ecc_signature.hashing_algorithm())?, message)
+                .map_err(verification_error)?;
+            let ecdsa_signature = EcdsaSig::from_private_components(
+                BigNum::from_slice(ecc_signature.signature_r().value())
+                    .map_err(verification_error)?,
+                BigNum::from_slice(ecc_signature.signature_s().value())
+                    .map_err(verification_error)?,
+            )
+            .map_err(verification_error)?;
+            let ec_key = key.ec_key().map_err(verification_error)?;
+            Ok(ecdsa_signature.verify(&digest, &ec_key).unwrap_or(false))
+        }
+        _ => Err(TpmError::UnsupportedOperation(format!(
+            "Signature scheme {:?} can not be verified in software",
+            signature.algorithm()
+        ))
+        .into()),
+    }
+}
+
+/// Converts the public area of a TPM key into an OpenSSL public key.
+pub(crate) fn public_key_to_pkey(
+    public: &Public,
+) -> Result<PKey<OpenSslPublic>, SecurityModuleError> {
+    let conversion_error = |e: openssl::error::ErrorStack| {
+        SecurityModuleError::SignatureVerificationError(e.to_string())
+    };
+
+    match public {
+        Public::Rsa {
+            parameters, unique, ..
+        } => {
+            // An exponent of zero denotes the default exponent 2^16 + 1.
+            let exponent = match parameters.exponent().value() {
+                0 => 65537,
+                exponent => exponent,
+            };
+            let rsa = Rsa::from_public_components(
+                BigNum::from_slice(unique.value()).map_err(conversion_error)?,
+                BigNum::from_u32(exponent).map_err(conversion_error)?,
+            )
+            .map_err(conversion_error)?;
+            PKey::from_rsa(rsa).map_err(conversion_error)
+        }
+        Public::Ecc {
+            parameters, unique, ..
+        } => {
+            let nid = match parameters.ecc_curve() {
+                EccCurve::NistP256 => Nid::X9_62_PRIME256V1,
+                EccCurve::NistP384 => Nid::SECP384R1,
+                EccCurve::NistP521 => Nid::SECP521R1,
+                curve => {
+                    return Err(TpmError::UnsupportedOperation(format!(
+                        "ECC curve {:?} is not supported",
+                        curve
+                    ))
+                    .into())
+                }
+            };
+            let group = EcGroup::from_curve_name(nid).map_err(conversion_error)?;
+            let mut ctx = BigNumContext::new().map_err(conversion_error)?;
+            let x = BigNum::from_slice(unique.x().value()).map_err(conversion_error)?;
+            let y = BigNum::from_slice(unique.y().value()).map_err(conversion_error)?;
+            let mut point = EcPoint::new(&group).map_err(conversion_error)?;
+            point
+                .set_affine_coordinates_gfp(&group, &x, &y, &mut ctx)
+                .map_err(conversion_error)?;
+            let ec_key = EcKey::from_public_key(&group, &point).map_err(conversion_error)?;
+            PKey::from_ec_key(ec_key).map_err(conversion_error)
+        }
+        _ => Err(TpmError::UnsupportedOperation(
+            "Only RSA and ECC public keys are supported".to_string(),
+        )
+        .into()),
+    }
+}
+
+/// Maps a TPM hashing algorithm to the corresponding OpenSSL message digest.
+pub(crate) fn message_digest(
+    hashing_algorithm: HashingAlgorithm,
+) -> Result<MessageDigest, SecurityModuleError> {
+    match hashing_algorithm {
+        HashingAlgorithm::Sha1 => Ok(MessageDigest::sha1()),
+        HashingAlgorithm::Sha256 => Ok(MessageDigest::sha256()),
+        HashingAlgorithm::Sha384 => Ok(MessageDigest::sha384()),
+        HashingAlgorithm::Sha512 => Ok(MessageDigest::sha512()),
+        HashingAlgorithm::Sha3_256 => Ok(MessageDigest::sha3_256()),
+        HashingAlgorithm::Sha3_384 => Ok(MessageDigest::sha3_384()),
+        HashingAlgorithm::Sha3_512 => Ok(MessageDigest::sha3_512()),
+        HashingAlgorithm::Sm3_256 => Ok(MessageDigest::sm3()),
+        _ => Err(TpmError::UnsupportedOperation(format!(
+            "Hashing algorithm {:?} is not supported",
+            hashing_algorithm
+        ))
+        .into()),
+    }
+}
+
+/// Returns the hashing algorithm used by a signature.
+fn signature_hash(signature: &Signature) -> Result<HashingAlgorithm, SecurityModuleError> {
+    match signature {
+        Signature::RsaSsa(s) | Signature::RsaPss(s) => Ok(s.hashing_algorithm()),
+        Signature::EcDsa(s) | Signature::EcDaa(s) | Signature::Sm2(s) | Signature::EcSchnorr(s) => {
+            Ok(s.hashing_algorithm())
+        }
+        _ => Err(TpmError::UnsupportedOperation(
+            "Signature does not carry a hashing algorithm".to_string(),
+        )
+        .into()),
+    }
+}